@@ -13,7 +13,10 @@ fn main() {
         .add_plugins(DefaultPlugins)
         .insert_resource(ClearColor(Color::BLACK))
         .insert_resource(Msaa::Off)
-        .add_plugin(VertexPullingRenderPlugin { outlines: true })
+        .add_plugin(VertexPullingRenderPlugin {
+            outlines: true,
+            ..default()
+        })
         .add_plugin(LookTransformPlugin)
         .add_plugin(FpsCameraPlugin::default())
         .add_startup_system(setup)
@@ -44,10 +47,9 @@ fn setup(mut commands: Commands) {
     }
 
     let cuboids = Cuboids::new(cuboids);
-    let aabb = cuboids.aabb();
     commands
         .spawn(SpatialBundle::default())
-        .insert((cuboids, aabb, CuboidMaterialId(0)));
+        .insert((cuboids, CuboidMaterialId(0)));
 
     commands
         .spawn((
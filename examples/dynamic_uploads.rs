@@ -0,0 +1,94 @@
+//! Measures frame times of a large batch that is rewritten every frame, with
+//! and without [`Cuboids::dynamic`].
+//!
+//! A static batch has a single instance buffer, so each upload may have to
+//! wait for the GPU to finish drawing the previous frame from it. A dynamic
+//! batch cycles through several. The average and longest frame and the CPU
+//! time of the uploads are logged every few seconds. Compare
+//! `cargo run --release --example dynamic_uploads` with
+//! `cargo run --release --example dynamic_uploads -- --dynamic`.
+
+use bevy::prelude::*;
+use bevy_aabb_instancing::{
+    Cuboid, CuboidMaterialId, Cuboids, CuboidsDrawStats, VertexPullingRenderPlugin,
+};
+
+const SIDE: usize = 1000;
+const MEASURED_FRAMES: u32 = 300;
+
+#[derive(Resource)]
+struct Dynamic(bool);
+
+fn main() {
+    let dynamic = std::env::args().any(|arg| arg == "--dynamic");
+    App::new()
+        .add_plugins(DefaultPlugins)
+        .insert_resource(Msaa::Off)
+        .insert_resource(Dynamic(dynamic))
+        .add_plugin(VertexPullingRenderPlugin {
+            gpu_timestamps: true,
+            ..default()
+        })
+        .add_startup_system(setup)
+        .add_system(animate)
+        .add_system(measure)
+        .run();
+}
+
+fn setup(mut commands: Commands, dynamic: Res<Dynamic>) {
+    let mut cuboids = Cuboids::new(vec![
+        Cuboid::new(Vec3::ZERO, Vec3::ZERO, 0xFFFFFFFF);
+        SIDE * SIDE
+    ]);
+    cuboids.dynamic = dynamic.0;
+    commands.spawn((SpatialBundle::default(), cuboids, CuboidMaterialId(0)));
+    commands.spawn(Camera3dBundle {
+        transform: Transform::from_xyz(0.0, 800.0, 800.0).looking_at(Vec3::ZERO, Vec3::Y),
+        ..default()
+    });
+}
+
+fn animate(time: Res<Time>, mut batches: Query<&mut Cuboids>) {
+    let t = time.elapsed_seconds();
+    for mut cuboids in batches.iter_mut() {
+        for (i, cuboid) in cuboids.instances.iter_mut().enumerate() {
+            let x = (i % SIDE) as f32 - SIDE as f32 / 2.0;
+            let z = (i / SIDE) as f32 - SIDE as f32 / 2.0;
+            let y = 10.0 * (0.05 * x + t).sin() * (0.05 * z + t).cos();
+            let c = Vec3::new(x, y, z);
+            cuboid.minimum = c - Vec3::splat(0.4);
+            cuboid.maximum = c + Vec3::splat(0.4);
+        }
+    }
+}
+
+fn measure(
+    mut frames: Local<u32>,
+    mut total_ms: Local<f32>,
+    mut longest_ms: Local<f32>,
+    mut prepare_ms: Local<f32>,
+    time: Res<Time>,
+    stats: Res<CuboidsDrawStats>,
+    dynamic: Res<Dynamic>,
+) {
+    let frame_ms = time.delta_seconds() * 1000.0;
+    *frames += 1;
+    *total_ms += frame_ms;
+    *longest_ms = longest_ms.max(frame_ms);
+    *prepare_ms += stats.timings.map_or(0.0, |timings| timings.prepare_ms);
+    if *frames == MEASURED_FRAMES {
+        let n = *frames as f32;
+        info!(
+            "dynamic: {}, frame {:.2} ms on average, {:.2} ms at most, uploads {:.2} ms on \
+             average",
+            dynamic.0,
+            *total_ms / n,
+            *longest_ms,
+            *prepare_ms / n
+        );
+        *frames = 0;
+        *total_ms = 0.0;
+        *longest_ms = 0.0;
+        *prepare_ms = 0.0;
+    }
+}
@@ -0,0 +1,262 @@
+//! Renders a batch into an image without a window, and saves it as a PNG.
+//!
+//! This is the setup for regression images in CI, or thumbnails of block
+//! models on a server: the camera renders to an [`Image`], which a render
+//! graph node copies into a buffer that is read back once the scene had a few
+//! frames to upload. Run with
+//! `cargo run --example headless --features bevy/png -- out.png`.
+
+use bevy::{
+    app::{AppExit, ScheduleRunnerPlugin},
+    prelude::*,
+    render::{
+        camera::RenderTarget,
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        main_graph::node::CAMERA_DRIVER,
+        render_asset::RenderAssets,
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext},
+        render_resource::{
+            Buffer, BufferDescriptor, BufferUsages, Extent3d, ImageCopyBuffer, ImageDataLayout,
+            Maintain, MapMode, TextureDimension, TextureFormat, TextureUsages,
+        },
+        renderer::{RenderContext, RenderDevice},
+        RenderApp, RenderSet,
+    },
+    winit::WinitPlugin,
+};
+use bevy_aabb_instancing::{
+    Cuboid, CuboidMaterial, CuboidMaterialMap, Cuboids, VertexPullingRenderPlugin,
+    COLOR_MODE_SCALAR_HUE,
+};
+use std::num::NonZeroU32;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+const SIZE: u32 = 512;
+/// Frames to render before the image is read back, so that every batch has
+/// been uploaded and drawn.
+const WARMUP_FRAMES: u32 = 5;
+/// Rows of a texture copy are padded to 256 bytes.
+const PADDED_BYTES_PER_ROW: u32 = (4 * SIZE + 255) / 256 * 256;
+
+fn main() {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "headless.png".into());
+    let done = Arc::new(AtomicBool::new(false));
+
+    let mut app = App::new();
+    app.add_plugins(
+        DefaultPlugins
+            .set(WindowPlugin {
+                primary_window: None,
+                exit_condition: bevy::window::ExitCondition::DontExit,
+                close_when_requested: false,
+            })
+            .disable::<WinitPlugin>(),
+    )
+    .add_plugin(ScheduleRunnerPlugin::default())
+    .insert_resource(Msaa::Off)
+    .add_plugin(VertexPullingRenderPlugin::default())
+    .add_plugin(ExtractResourcePlugin::<Snapshot>::default())
+    .insert_resource(SnapshotDone(done.clone()))
+    .add_startup_system(setup)
+    .add_system(count_frames)
+    .add_system(exit_when_done);
+
+    let render_app = app.sub_app_mut(RenderApp);
+    let buffer = render_app
+        .world
+        .resource::<RenderDevice>()
+        .create_buffer(&BufferDescriptor {
+            label: Some("headless_snapshot_buffer"),
+            size: u64::from(PADDED_BYTES_PER_ROW * SIZE),
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+    render_app
+        .insert_resource(SnapshotReadback { buffer, path, done })
+        .add_system(save_snapshot.in_set(RenderSet::Cleanup));
+    let mut graph = render_app.world.resource_mut::<RenderGraph>();
+    graph.add_node("headless_snapshot", SnapshotNode);
+    graph.add_node_edge(CAMERA_DRIVER, "headless_snapshot");
+
+    app.run();
+}
+
+/// The image that the camera renders to, and whether it's ready to be read.
+#[derive(Clone, ExtractResource, Resource)]
+struct Snapshot {
+    image: Handle<Image>,
+    frames: u32,
+}
+
+impl Snapshot {
+    fn is_ready(&self) -> bool {
+        self.frames == WARMUP_FRAMES
+    }
+}
+
+#[derive(Resource)]
+struct SnapshotDone(Arc<AtomicBool>);
+
+#[derive(Resource)]
+struct SnapshotReadback {
+    buffer: Buffer,
+    path: String,
+    done: Arc<AtomicBool>,
+}
+
+fn setup(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut material_map: ResMut<CuboidMaterialMap>,
+) {
+    let mut image = Image::new_fill(
+        Extent3d {
+            width: SIZE,
+            height: SIZE,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0; 4],
+        TextureFormat::Rgba8UnormSrgb,
+    );
+    image.texture_descriptor.usage = TextureUsages::TEXTURE_BINDING
+        | TextureUsages::COPY_SRC
+        | TextureUsages::COPY_DST
+        | TextureUsages::RENDER_ATTACHMENT;
+    let image = images.add(image);
+    commands.insert_resource(Snapshot {
+        image: image.clone(),
+        frames: 0,
+    });
+
+    let material_id = material_map.push(CuboidMaterial {
+        color_mode: COLOR_MODE_SCALAR_HUE,
+        ..default()
+    });
+    let mut instances = Vec::new();
+    for x in 0..32 {
+        for z in 0..32 {
+            let height = 1.0 + ((0.3 * x as f32).sin() * (0.3 * z as f32).cos() + 1.0) * 4.0;
+            let min = Vec3::new(x as f32, 0.0, z as f32);
+            let max = min + Vec3::new(0.9, height, 0.9);
+            let scalar = 1000.0 * height / 9.0;
+            instances.push(Cuboid::new(min, max, scalar.to_bits()));
+        }
+    }
+    commands.spawn((
+        SpatialBundle::default(),
+        Cuboids::new(instances),
+        material_id,
+    ));
+
+    commands.spawn(Camera3dBundle {
+        camera: Camera {
+            target: RenderTarget::Image(image),
+            ..default()
+        },
+        transform: Transform::from_xyz(-16.0, 32.0, -16.0)
+            .looking_at(Vec3::new(16.0, 0.0, 16.0), Vec3::Y),
+        ..default()
+    });
+}
+
+fn count_frames(mut snapshot: ResMut<Snapshot>) {
+    snapshot.frames = (snapshot.frames + 1).min(WARMUP_FRAMES);
+}
+
+fn exit_when_done(done: Res<SnapshotDone>, mut exit: EventWriter<AppExit>) {
+    if done.0.load(Ordering::Acquire) {
+        exit.send(AppExit);
+    }
+}
+
+/// Copies the camera's image into [`SnapshotReadback::buffer`], after every
+/// camera has rendered.
+struct SnapshotNode;
+
+impl Node for SnapshotNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let (Some(snapshot), Some(readback)) = (
+            world.get_resource::<Snapshot>(),
+            world.get_resource::<SnapshotReadback>(),
+        ) else {
+            return Ok(());
+        };
+        let Some(image) = world.resource::<RenderAssets<Image>>().get(&snapshot.image) else {
+            return Ok(());
+        };
+        if !snapshot.is_ready() || readback.done.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        render_context.command_encoder().copy_texture_to_buffer(
+            image.texture.as_image_copy(),
+            ImageCopyBuffer {
+                buffer: &readback.buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(PADDED_BYTES_PER_ROW),
+                    rows_per_image: None,
+                },
+            },
+            Extent3d {
+                width: SIZE,
+                height: SIZE,
+                depth_or_array_layers: 1,
+            },
+        );
+        Ok(())
+    }
+}
+
+/// Waits for the copy of [`SnapshotNode`], which was submitted in this
+/// frame's render set, and writes it to the PNG.
+fn save_snapshot(
+    snapshot: Option<Res<Snapshot>>,
+    readback: Res<SnapshotReadback>,
+    render_device: Res<RenderDevice>,
+) {
+    if !snapshot.map_or(false, |s| s.is_ready()) || readback.done.load(Ordering::Acquire) {
+        return;
+    }
+    let slice = readback.buffer.slice(..);
+    slice.map_async(MapMode::Read, |result| result.unwrap());
+    render_device.poll(Maintain::Wait);
+
+    let mut pixels = Vec::with_capacity((4 * SIZE * SIZE) as usize);
+    {
+        let data = slice.get_mapped_range();
+        for row in data.chunks_exact(PADDED_BYTES_PER_ROW as usize) {
+            pixels.extend_from_slice(&row[..(4 * SIZE) as usize]);
+        }
+    }
+    readback.buffer.unmap();
+
+    let image = Image::new(
+        Extent3d {
+            width: SIZE,
+            height: SIZE,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        pixels,
+        TextureFormat::Rgba8UnormSrgb,
+    );
+    match image.try_into_dynamic() {
+        Ok(image) => match image.save(&readback.path) {
+            Ok(()) => info!("Saved {}", readback.path),
+            Err(err) => error!("Failed to save {}: {err}", readback.path),
+        },
+        Err(err) => error!("Failed to convert the snapshot: {err:?}"),
+    }
+    readback.done.store(true, Ordering::Release);
+}
@@ -0,0 +1,91 @@
+//! Measures frame times with tens of thousands of small batches that move
+//! every frame.
+//!
+//! Moving a batch only rewrites its entry in the transform storage buffer,
+//! which every draw reads through its base vertex, so the cost should grow
+//! with the number of draws rather than with uploads. The average and longest
+//! frame are logged every few seconds. Run with
+//! `cargo run --release --example many_batches -- 20000`.
+
+use bevy::prelude::*;
+use bevy_aabb_instancing::{
+    Cuboid, CuboidMaterialId, Cuboids, CuboidsDrawStats, VertexPullingRenderPlugin,
+};
+
+const DEFAULT_BATCHES: usize = 20_000;
+const MEASURED_FRAMES: u32 = 300;
+
+#[derive(Component)]
+struct Origin(Vec3);
+
+fn main() {
+    let num_batches = std::env::args()
+        .nth(1)
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(DEFAULT_BATCHES);
+    App::new()
+        .add_plugins(DefaultPlugins)
+        .insert_resource(Msaa::Off)
+        .add_plugin(VertexPullingRenderPlugin::default())
+        .add_startup_system(move |commands: Commands| setup(commands, num_batches))
+        .add_system(move_batches)
+        .add_system(measure)
+        .run();
+}
+
+fn setup(mut commands: Commands, num_batches: usize) {
+    let side = (num_batches as f32).sqrt().ceil() as usize;
+    for i in 0..num_batches {
+        let origin = 4.0 * Vec3::new((i % side) as f32, 0.0, (i / side) as f32);
+        let instances = (0..8)
+            .map(|j| {
+                let c = Vec3::new(0.0, j as f32, 0.0);
+                Cuboid::new(c - Vec3::splat(0.4), c + Vec3::splat(0.4), 0xFFFFFFFF)
+            })
+            .collect();
+        commands.spawn((
+            SpatialBundle::from_transform(Transform::from_translation(origin)),
+            Cuboids::new(instances),
+            CuboidMaterialId(0),
+            Origin(origin),
+        ));
+    }
+    let center = 2.0 * Vec3::new(side as f32, 0.0, side as f32);
+    commands.spawn(Camera3dBundle {
+        transform: Transform::from_translation(center + Vec3::new(0.0, 300.0, 300.0))
+            .looking_at(center, Vec3::Y),
+        ..default()
+    });
+}
+
+fn move_batches(time: Res<Time>, mut batches: Query<(&Origin, &mut Transform)>) {
+    let t = time.elapsed_seconds();
+    for (origin, mut transform) in batches.iter_mut() {
+        let phase = 0.1 * (origin.0.x + origin.0.z);
+        transform.translation = origin.0 + Vec3::new(0.0, (t + phase).sin(), 0.0);
+    }
+}
+
+fn measure(
+    mut frames: Local<u32>,
+    mut total_ms: Local<f32>,
+    mut longest_ms: Local<f32>,
+    time: Res<Time>,
+    stats: Res<CuboidsDrawStats>,
+) {
+    let frame_ms = time.delta_seconds() * 1000.0;
+    *frames += 1;
+    *total_ms += frame_ms;
+    *longest_ms = longest_ms.max(frame_ms);
+    if *frames == MEASURED_FRAMES {
+        info!(
+            "{} batches drawn, frame {:.2} ms on average, {:.2} ms at most",
+            stats.batches_drawn,
+            *total_ms / *frames as f32,
+            *longest_ms
+        );
+        *frames = 0;
+        *total_ms = 0.0;
+        *longest_ms = 0.0;
+    }
+}
@@ -0,0 +1,81 @@
+//! Measures the hitch of the first frame that draws a large batch, with and
+//! without [`VertexPullingRenderPlugin::prewarm`].
+//!
+//! The batch is spawned once startup has settled, and the longest frame after
+//! it is logged along with the CPU time of its upload. Compare
+//! `cargo run --release --example prewarm` with
+//! `cargo run --release --example prewarm -- --prewarm`.
+
+use bevy::prelude::*;
+use bevy_aabb_instancing::{
+    Cuboid, CuboidMaterialId, Cuboids, CuboidsDrawStats, VertexPullingRenderPlugin,
+};
+
+const NUM_CUBOIDS: usize = 4_000_000;
+/// Frames to wait before spawning, so that startup work isn't measured.
+const SETTLE_FRAMES: u32 = 60;
+/// Frames measured after spawning. The batch is extracted and drawn a frame
+/// after it's spawned, and pipelined rendering delays it by one more.
+const MEASURED_FRAMES: u32 = 10;
+
+fn main() {
+    let prewarm = std::env::args().any(|arg| arg == "--prewarm");
+    App::new()
+        .add_plugins(DefaultPlugins)
+        .insert_resource(Msaa::Off)
+        .add_plugin(
+            VertexPullingRenderPlugin {
+                gpu_timestamps: true,
+                ..default()
+            }
+            .prewarm(if prewarm { NUM_CUBOIDS } else { 0 }),
+        )
+        .add_startup_system(setup)
+        .add_system(spawn_and_measure)
+        .run();
+}
+
+fn setup(mut commands: Commands) {
+    commands.spawn(Camera3dBundle {
+        transform: Transform::from_xyz(0.0, 1500.0, 1500.0).looking_at(Vec3::ZERO, Vec3::Y),
+        ..default()
+    });
+}
+
+fn spawn_and_measure(
+    mut commands: Commands,
+    mut frame: Local<u32>,
+    mut longest_frame: Local<f32>,
+    mut longest_prepare: Local<f32>,
+    time: Res<Time>,
+    stats: Res<CuboidsDrawStats>,
+) {
+    *frame += 1;
+    if *frame == SETTLE_FRAMES {
+        let side = (NUM_CUBOIDS as f32).sqrt() as usize;
+        let instances = (0..side * side)
+            .map(|i| {
+                let c = Vec3::new((i % side) as f32, 0.0, (i / side) as f32)
+                    - Vec3::new(side as f32 / 2.0, 0.0, side as f32 / 2.0);
+                Cuboid::new(c - Vec3::splat(0.4), c + Vec3::splat(0.4), 0xFFFFFFFF)
+            })
+            .collect();
+        commands.spawn((
+            SpatialBundle::default(),
+            Cuboids::new(instances),
+            CuboidMaterialId(0),
+        ));
+    } else if *frame > SETTLE_FRAMES && *frame <= SETTLE_FRAMES + MEASURED_FRAMES {
+        *longest_frame = longest_frame.max(time.delta_seconds() * 1000.0);
+        if let Some(timings) = stats.timings {
+            *longest_prepare = longest_prepare.max(timings.prepare_ms);
+        }
+        if *frame == SETTLE_FRAMES + MEASURED_FRAMES {
+            info!(
+                "Longest frame after spawning {NUM_CUBOIDS} cuboids: {:.1} ms, of which {:.1} ms \
+                 uploading",
+                *longest_frame, *longest_prepare
+            );
+        }
+    }
+}
@@ -9,7 +9,10 @@ fn main() {
     App::new()
         .add_plugins(DefaultPlugins)
         .insert_resource(Msaa::Off)
-        .add_plugin(VertexPullingRenderPlugin { outlines: true })
+        .add_plugin(VertexPullingRenderPlugin {
+            outlines: true,
+            ..default()
+        })
         .add_plugin(LookTransformPlugin)
         .add_plugin(FpsCameraPlugin::default())
         .add_startup_system(setup)
@@ -48,10 +51,9 @@ fn setup(mut commands: Commands, mut material_map: ResMut<CuboidMaterialMap>) {
                 }
             }
             let cuboids = Cuboids::new(instances);
-            let aabb = cuboids.aabb();
             commands
                 .spawn(SpatialBundle::default())
-                .insert((cuboids, aabb, material_id));
+                .insert((cuboids, material_id));
         }
     }
 
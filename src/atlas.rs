@@ -0,0 +1,39 @@
+use bevy::{prelude::*, render::extract_resource::ExtractResource};
+
+/// [`Cuboids::atlas_tiles`](crate::Cuboids::atlas_tiles) of instances that
+/// show no tile.
+pub const NO_ATLAS_TILE: u32 = u32::MAX;
+
+/// A texture of equally sized tiles, e.g. icons or patterns, that cuboid faces
+/// sample by their [`Cuboids::atlas_tiles`](crate::Cuboids::atlas_tiles).
+///
+/// Tiles are numbered row by row from the top left. Each tile is stretched
+/// over a whole face, and modulates its color: white or transparent texels
+/// keep the face color, so that lighting, edges and fog still apply on top.
+/// Tiles are drawn on exterior faces only.
+///
+/// There is a single atlas for all batches. Without this resource, or while
+/// its image is loading, tiles are not drawn.
+#[derive(Clone, Debug, ExtractResource, Resource)]
+pub struct CuboidsAtlas {
+    pub image: Handle<Image>,
+    pub columns: u32,
+    pub rows: u32,
+}
+
+impl CuboidsAtlas {
+    pub fn new(image: Handle<Image>, columns: u32, rows: u32) -> Self {
+        assert!(columns > 0 && rows > 0);
+        Self {
+            image,
+            columns,
+            rows,
+        }
+    }
+
+    /// The index of the tile in `column` and `row`.
+    pub fn tile(&self, column: u32, row: u32) -> u32 {
+        debug_assert!(column < self.columns && row < self.rows);
+        row * self.columns + column
+    }
+}
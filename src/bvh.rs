@@ -0,0 +1,173 @@
+use crate::Cuboids;
+
+use bevy::math::Vec3A;
+use std::sync::{Arc, Mutex};
+
+/// Most instances in a leaf of a [`CuboidsBvh`].
+const LEAF_INSTANCES: usize = 4;
+
+#[derive(Clone, Copy)]
+struct BvhNode {
+    minimum: Vec3A,
+    maximum: Vec3A,
+    /// Index of the first of both children of an inner node, or of the first
+    /// instance of a leaf in [`CuboidsBvh::indices`].
+    start: u32,
+    /// Instances of a leaf, zero for inner nodes.
+    count: u32,
+}
+
+/// A bounding volume hierarchy of the instances of a [`Cuboids`], in the local
+/// space of the batch, for [`Cuboids::iter_in_aabb`].
+pub(crate) struct CuboidsBvh {
+    nodes: Vec<BvhNode>,
+    /// Instance indices, grouped by leaf.
+    indices: Vec<u32>,
+    /// Bounds of each instance of `indices`.
+    bounds: Vec<(Vec3A, Vec3A)>,
+}
+
+impl CuboidsBvh {
+    pub fn build(cuboids: &Cuboids) -> Self {
+        let num_instances = cuboids.instances.len();
+        let bounds: Vec<(Vec3A, Vec3A)> = (0..num_instances)
+            .map(|i| {
+                let (minimum, maximum) = cuboids.instance_bounds(i);
+                (minimum.into(), maximum.into())
+            })
+            .collect();
+        let mut indices: Vec<u32> = (0..num_instances as u32).collect();
+        let mut nodes = Vec::new();
+        if num_instances > 0 {
+            nodes.push(BvhNode {
+                minimum: Vec3A::ZERO,
+                maximum: Vec3A::ZERO,
+                start: 0,
+                count: 0,
+            });
+            build_node(&mut nodes, 0, &bounds, &mut indices, 0);
+        }
+        let bounds = indices.iter().map(|&i| bounds[i as usize]).collect();
+        Self {
+            nodes,
+            indices,
+            bounds,
+        }
+    }
+
+    /// Pushes the index of every instance overlapping `minimum..maximum` onto
+    /// `out`, in no particular order.
+    pub fn query(&self, minimum: Vec3A, maximum: Vec3A, out: &mut Vec<usize>) {
+        let overlaps =
+            |min: Vec3A, max: Vec3A| min.cmple(maximum).all() && max.cmpge(minimum).all();
+        let mut stack = Vec::new();
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+        while let Some(node) = stack.pop() {
+            let node: BvhNode = self.nodes[node];
+            if !overlaps(node.minimum, node.maximum) {
+                continue;
+            }
+            let start = node.start as usize;
+            if node.count == 0 {
+                stack.extend([start, start + 1]);
+                continue;
+            }
+            for i in start..start + node.count as usize {
+                let (min, max) = self.bounds[i];
+                if overlaps(min, max) {
+                    out.push(self.indices[i] as usize);
+                }
+            }
+        }
+    }
+}
+
+/// Fits `node` around `indices`, and splits it at the median instance along
+/// its longest axis until its leaves are small enough.
+fn build_node(
+    nodes: &mut Vec<BvhNode>,
+    node: usize,
+    bounds: &[(Vec3A, Vec3A)],
+    indices: &mut [u32],
+    offset: usize,
+) {
+    let mut minimum = Vec3A::splat(f32::MAX);
+    let mut maximum = Vec3A::splat(f32::MIN);
+    let mut centers_min = Vec3A::splat(f32::MAX);
+    let mut centers_max = Vec3A::splat(f32::MIN);
+    for &i in indices.iter() {
+        let (min, max) = bounds[i as usize];
+        minimum = minimum.min(min);
+        maximum = maximum.max(max);
+        let center = 0.5 * (min + max);
+        centers_min = centers_min.min(center);
+        centers_max = centers_max.max(center);
+    }
+    nodes[node].minimum = minimum;
+    nodes[node].maximum = maximum;
+    if indices.len() <= LEAF_INSTANCES {
+        nodes[node].start = offset as u32;
+        nodes[node].count = indices.len() as u32;
+        return;
+    }
+
+    let extent = centers_max - centers_min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z {
+        0
+    } else if extent.y >= extent.z {
+        1
+    } else {
+        2
+    };
+    let center = |i: &u32| {
+        let (min, max) = bounds[*i as usize];
+        min[axis] + max[axis]
+    };
+    let mid = indices.len() / 2;
+    indices.select_nth_unstable_by(mid, |a, b| center(a).total_cmp(&center(b)));
+
+    let children = nodes.len();
+    nodes[node].start = children as u32;
+    nodes[node].count = 0;
+    let child = nodes[node];
+    nodes.extend([child; 2]);
+    let (left, right) = indices.split_at_mut(mid);
+    build_node(nodes, children, bounds, left, offset);
+    build_node(nodes, children + 1, bounds, right, offset + mid);
+}
+
+/// The [`CuboidsBvh`] of a [`Cuboids`], built on the first query and dropped
+/// when the batch changes, see [`Cuboids::bvh`].
+#[derive(Default)]
+pub(crate) struct CuboidsBvhCache(Mutex<Option<Arc<CuboidsBvh>>>);
+
+impl CuboidsBvhCache {
+    pub fn get_or_build(&self, cuboids: &Cuboids) -> Arc<CuboidsBvh> {
+        self.0
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| Arc::new(CuboidsBvh::build(cuboids)))
+            .clone()
+    }
+
+    pub fn clear(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+}
+
+/// Clones share the hierarchy, since they start with the same instances.
+impl Clone for CuboidsBvhCache {
+    fn clone(&self) -> Self {
+        Self(Mutex::new(self.0.lock().unwrap().clone()))
+    }
+}
+
+impl std::fmt::Debug for CuboidsBvhCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("CuboidsBvhCache")
+            .field(&self.0.lock().unwrap().is_some())
+            .finish()
+    }
+}
@@ -1,15 +1,69 @@
-use bevy::{prelude::*, render::render_resource::ShaderType};
+mod gizmos;
+mod sweep;
+mod tween;
+mod volumes;
+
+pub use gizmos::*;
+pub use sweep::*;
+pub use tween::*;
+pub use volumes::*;
+
+use bevy::{
+    prelude::*,
+    render::render_resource::{encase, BufferSize, ShaderType},
+};
 
 /// The range of signed distances from the plane that don't get clipped.
 ///
 /// The plane origin and normal will be extracted from the [`GlobalTransform`],
 /// assuming normal axis is pointing
-#[derive(Clone, Component, Debug, ShaderType)]
+///
+/// A range with both bounds finite is a slab: the band between a pair of
+/// parallel planes, which costs a single plane against
+/// [`VertexPullingRenderPlugin::max_clipping_planes`](crate::VertexPullingRenderPlugin::max_clipping_planes).
+/// How the ranges of several planes combine is set per camera with
+/// [`ViewClipping::combine`].
+#[derive(Clone, Component, Debug)]
 pub struct ClippingPlaneRange {
     /// The minimum (signed) distance from a visible cuboid's centroid to the plane.
     pub min_sdist: f32,
     /// The maximum (signed) distance from a visible cuboid's centroid to the plane.
     pub max_sdist: f32,
+    /// Keeps the cuboids outside of the range instead, e.g. to cut a slab out
+    /// of a model, or to keep the other half-space of a plane.
+    pub keep_outside: bool,
+}
+
+impl ClippingPlaneRange {
+    /// Keeps everything on the side of the plane that its normal points to.
+    pub fn half_space() -> Self {
+        Self::default()
+    }
+
+    /// Keeps cuboids with centroids within `thickness / 2` of the plane, on
+    /// either side, for cross sections. Move the plane along its normal, e.g.
+    /// with a [`ClippingPlaneTween`], to sweep the section through a model.
+    pub fn slab(thickness: f32) -> Self {
+        let half_thickness = 0.5 * thickness.abs();
+        Self {
+            min_sdist: -half_thickness,
+            max_sdist: half_thickness,
+            keep_outside: false,
+        }
+    }
+
+    /// Keeps what this range would clip, and clips what it would keep.
+    pub fn inverted(mut self) -> Self {
+        self.keep_outside = !self.keep_outside;
+        self
+    }
+
+    /// Whether cuboids whose centroid is at the signed distance `sdist` from
+    /// the plane are kept.
+    pub fn keeps(&self, sdist: f32) -> bool {
+        let inside = sdist >= self.min_sdist && sdist <= self.max_sdist;
+        inside != self.keep_outside
+    }
 }
 
 impl Default for ClippingPlaneRange {
@@ -17,6 +71,7 @@ impl Default for ClippingPlaneRange {
         Self {
             min_sdist: 0.0,
             max_sdist: f32::INFINITY,
+            keep_outside: false,
         }
     }
 }
@@ -28,19 +83,116 @@ pub struct ClippingPlaneBundle {
     pub transform: TransformBundle,
 }
 
+/// Per-camera clipping controls.
+///
+/// Cameras without this component have clipping enabled, and combine planes
+/// with [`ClippingCombine::All`], like shadow views always do.
+#[derive(Clone, Component, Debug)]
+pub struct ViewClipping {
+    /// When `false`, no [`ClippingPlaneRange`] applies to cuboids rendered by
+    /// this camera.
+    pub enabled: bool,
+    pub combine: ClippingCombine,
+}
+
+impl Default for ViewClipping {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            combine: default(),
+        }
+    }
+}
+
+/// Which cuboids survive the [`ClippingPlaneRange`]s of a camera. Clipping
+/// boxes and spheres apply on top of either.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ClippingCombine {
+    /// Keeps the cuboids that every range keeps, so the half-spaces of a few
+    /// planes carve out a convex region.
+    #[default]
+    All,
+    /// Keeps the cuboids that any range keeps, e.g. to show the union of a
+    /// few slabs.
+    Any,
+}
+
+/// Restricts a [`ClippingPlaneRange`] to cuboids rendered by a single camera.
+///
+/// Planes without a target apply to every camera, and to shadow views. Each
+/// camera's planes, targeted or not, count against
+/// [`VertexPullingRenderPlugin::max_clipping_planes`](crate::VertexPullingRenderPlugin::max_clipping_planes)
+/// separately.
+#[derive(Clone, Component, Copy, Debug)]
+pub struct ClippingPlaneTarget(pub Entity);
+
 #[derive(Clone, Component, Debug, Default, ShaderType)]
 pub(crate) struct GpuClippingPlaneRange {
     pub origin: Vec3,
     pub unit_normal: Vec3,
     pub min_sdist: f32,
     pub max_sdist: f32,
+    pub keep_outside: u32,
 }
 
-#[derive(Debug, Default, ShaderType)]
+#[derive(ShaderType)]
+struct GpuClippingPlaneRangesHeader {
+    num_ranges: u32,
+    combine_any: u32,
+}
+
+/// The active clipping planes, encoded into a uniform that holds `capacity`
+/// ranges after the header.
+///
+/// The capacity is only known at runtime, so this can't be a fixed-size array
+/// in a [`ShaderType`].
+#[derive(Debug, Default)]
 pub(crate) struct GpuClippingPlaneRanges {
-    pub ranges: [GpuClippingPlaneRange; MAX_CLIPPING_PLANES],
-    pub num_ranges: u32,
+    pub ranges: Vec<GpuClippingPlaneRange>,
+    pub combine: ClippingCombine,
+}
+
+impl GpuClippingPlaneRanges {
+    /// Uniform arrays of structs have 16-byte aligned elements.
+    const HEADER_SIZE: u64 = 16;
+
+    fn stride() -> u64 {
+        let size = GpuClippingPlaneRange::min_size().get();
+        (size + 15) / 16 * 16
+    }
+
+    pub fn uniform_size(capacity: usize) -> BufferSize {
+        BufferSize::new(Self::HEADER_SIZE + Self::stride() * capacity as u64).unwrap()
+    }
+
+    /// The most ranges that fit in a uniform binding of `max_size` bytes.
+    pub fn max_capacity(max_size: u32) -> usize {
+        (u64::from(max_size).saturating_sub(Self::HEADER_SIZE) / Self::stride()) as usize
+    }
+
+    pub fn encode(&self, capacity: usize) -> Vec<u8> {
+        let num_ranges = self.ranges.len().min(capacity);
+        let mut bytes = encode_uniform(&GpuClippingPlaneRangesHeader {
+            num_ranges: num_ranges as u32,
+            combine_any: (self.combine == ClippingCombine::Any) as u32,
+        });
+        bytes.resize(Self::HEADER_SIZE as usize, 0);
+        for range in &self.ranges[..num_ranges] {
+            let start = bytes.len();
+            bytes.extend(encode_uniform(range));
+            bytes.resize(start + Self::stride() as usize, 0);
+        }
+        bytes.resize(Self::uniform_size(capacity).get() as usize, 0);
+        bytes
+    }
+}
+
+fn encode_uniform(value: &(impl ShaderType + encase::internal::WriteInto)) -> Vec<u8> {
+    let mut buffer = encase::UniformBuffer::new(Vec::new());
+    buffer.write(value).unwrap();
+    buffer.into_inner()
 }
 
-/// The clipping shader is `O(planes * cuboids)`, so we set a reasonable limit.
+/// The clipping shader is `O(planes * cuboids)`, so we set a reasonable default
+/// for [`VertexPullingRenderPlugin::max_clipping_planes`](crate::VertexPullingRenderPlugin::max_clipping_planes).
 pub const MAX_CLIPPING_PLANES: usize = 16;
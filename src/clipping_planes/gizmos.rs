@@ -0,0 +1,253 @@
+use super::ClippingPlaneRange;
+use crate::material::InternalCuboidMaterials;
+use crate::{Color, Cuboid, CuboidMaterial, CuboidMaterialId, Cuboids, CuboidsBundle};
+
+use bevy::{prelude::*, render::primitives::Aabb, utils::HashMap};
+
+/// Controls the visualization of [`ClippingPlaneRange`] entities.
+///
+/// When visible, every finite boundary of each plane's range is drawn as a
+/// rectangular frame that covers all [`Cuboids`] in the scene, filled with a
+/// translucent quad, with an arrow pointing along the plane normal. Gizmos are
+/// rendered as regular cuboids, since Bevy 0.10 has no gizmo drawing, so they
+/// can be cut by the other clipping planes. Their materials are not in the
+/// [`CuboidMaterialMap`](crate::CuboidMaterialMap), and they are tagged with
+/// [`ClippingPlaneGizmo`].
+#[derive(Clone, Debug, Resource)]
+pub struct ClippingPlaneGizmos {
+    pub visible: bool,
+    /// RGB color of the gizmos.
+    pub color: Color,
+    /// Width of the frame edges, relative to the frame size.
+    pub thickness: f32,
+    /// Opacity of the quad that fills each frame, zero for none. The quad is
+    /// alpha blended, see [`CuboidMaterial::alpha_blend`].
+    pub fill_alpha: f32,
+}
+
+impl Default for ClippingPlaneGizmos {
+    fn default() -> Self {
+        Self {
+            visible: false,
+            color: 0xFFFFFFFF,
+            thickness: 0.005,
+            fill_alpha: 0.15,
+        }
+    }
+}
+
+/// Marks the [`Cuboids`] entities that visualize a [`ClippingPlaneRange`],
+/// with one entity for the opaque frames and arrow, and one for the
+/// translucent fill.
+///
+/// Gizmos are never picked, selected or counted in the
+/// [`CuboidsDrawStats`](crate::CuboidsDrawStats), and they don't count towards
+/// the scene bounds that they cover. Queries over all batches, e.g. for
+/// [`cuboids_in_screen_region`](crate::cuboids_in_screen_region), can skip
+/// them with `Without<ClippingPlaneGizmo>`.
+#[derive(Component)]
+pub struct ClippingPlaneGizmo {
+    plane: Entity,
+    fill: bool,
+}
+
+#[allow(clippy::type_complexity)]
+pub(crate) fn update_clipping_plane_gizmos(
+    mut commands: Commands,
+    settings: Res<ClippingPlaneGizmos>,
+    mut gizmo_materials: Local<Option<(CuboidMaterialId, CuboidMaterialId)>>,
+    mut internal_materials: ResMut<InternalCuboidMaterials>,
+    planes: Query<(Entity, &ClippingPlaneRange, &GlobalTransform)>,
+    batches: Query<(&Aabb, &GlobalTransform), (With<Cuboids>, Without<ClippingPlaneGizmo>)>,
+    mut gizmos: Query<(Entity, &ClippingPlaneGizmo, &mut Transform, &mut Cuboids)>,
+) {
+    let scene_bounds = settings.visible.then(|| union_aabb(&batches)).flatten();
+    let Some((scene_min, scene_max)) = scene_bounds else {
+        for (entity, ..) in gizmos.iter() {
+            commands.entity(entity).despawn();
+        }
+        return;
+    };
+    let scene_center = 0.5 * (scene_min + scene_max);
+    let scene_radius = 0.5 * (scene_max - scene_min).length();
+
+    let (material_id, fill_material_id) = *gizmo_materials.get_or_insert_with(|| {
+        (
+            internal_materials.push(CuboidMaterial::default()),
+            internal_materials.push(CuboidMaterial {
+                alpha_blend: 1,
+                ..default()
+            }),
+        )
+    });
+    let draw_fill = settings.fill_alpha > 0.0;
+
+    let mut plane_gizmos = HashMap::default();
+    for (entity, gizmo, transform, cuboids) in gizmos.iter_mut() {
+        if planes.contains(gizmo.plane) && (draw_fill || !gizmo.fill) {
+            plane_gizmos.insert((gizmo.plane, gizmo.fill), (transform, cuboids));
+        } else {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    for (plane_entity, range, plane_transform) in planes.iter() {
+        let (_, rotation, translation) = plane_transform.to_scale_rotation_translation();
+        // The plane normal is the X axis of the gizmo's local space, which is
+        // centered on the plane across from the scene center, so that moving
+        // the plane only moves the gizmo, without changing its instances.
+        let local_center = rotation.inverse() * (scene_center - translation);
+        let gizmo_origin = translation + rotation * Vec3::new(0.0, local_center.y, local_center.z);
+        let gizmo_transform = Transform::from_translation(gizmo_origin).with_rotation(rotation);
+        let mut kinds = vec![(
+            false,
+            material_id,
+            plane_gizmo_instances(&settings, range, Vec3::ZERO, scene_radius),
+        )];
+        if draw_fill {
+            kinds.push((
+                true,
+                fill_material_id,
+                plane_fill_instances(&settings, range, Vec3::ZERO, scene_radius),
+            ));
+        }
+
+        for (fill, material_id, instances) in kinds {
+            if let Some((mut transform, mut cuboids)) = plane_gizmos.remove(&(plane_entity, fill)) {
+                // Avoid triggering change detection when nothing moved.
+                if *transform != gizmo_transform {
+                    *transform = gizmo_transform;
+                }
+                if cuboids.instances != instances {
+                    cuboids.instances = instances;
+                }
+            } else {
+                commands.spawn((
+                    CuboidsBundle {
+                        material_id,
+                        cuboids: Cuboids::new(instances),
+                        spatial: SpatialBundle::from_transform(gizmo_transform),
+                    },
+                    ClippingPlaneGizmo {
+                        plane: plane_entity,
+                        fill,
+                    },
+                ));
+            }
+        }
+    }
+}
+
+/// World-space bounds covering all `batches`, if there are any.
+fn union_aabb(
+    batches: &Query<(&Aabb, &GlobalTransform), (With<Cuboids>, Without<ClippingPlaneGizmo>)>,
+) -> Option<(Vec3, Vec3)> {
+    let mut min = Vec3::splat(f32::MAX);
+    let mut max = Vec3::splat(f32::MIN);
+    for (aabb, transform) in batches.iter() {
+        let center = Vec3::from(aabb.center);
+        let half_extents = Vec3::from(aabb.half_extents);
+        for i in 0..8 {
+            let sign = Vec3::new(
+                if i & 1 == 0 { -1.0 } else { 1.0 },
+                if i & 2 == 0 { -1.0 } else { 1.0 },
+                if i & 4 == 0 { -1.0 } else { 1.0 },
+            );
+            let corner = transform.transform_point(center + sign * half_extents);
+            min = min.min(corner);
+            max = max.max(corner);
+        }
+    }
+    min.cmple(max).all().then_some((min, max))
+}
+
+/// The X extents of a frame on each finite boundary of `range`, nudged to the
+/// un-clipped side of the boundary, so that it doesn't get clipped by its own
+/// plane.
+fn boundaries(range: &ClippingPlaneRange, t: f32) -> Vec<(f32, f32)> {
+    let mut boundaries = Vec::new();
+    if range.min_sdist.is_finite() {
+        boundaries.push((range.min_sdist, range.min_sdist + t));
+    }
+    if range.max_sdist.is_finite() {
+        boundaries.push((range.max_sdist - t, range.max_sdist));
+    }
+    boundaries
+}
+
+/// Builds the frame and arrow geometry in the plane's local space.
+fn plane_gizmo_instances(
+    settings: &ClippingPlaneGizmos,
+    range: &ClippingPlaneRange,
+    center: Vec3,
+    radius: f32,
+) -> Vec<Cuboid> {
+    let t = settings.thickness * radius;
+    let (cy, cz) = (center.y, center.z);
+    let mut instances = Vec::new();
+
+    for (x0, x1) in boundaries(range, t) {
+        let edges = [
+            ((cy - radius, cz - radius), (cy - radius + t, cz + radius)),
+            ((cy + radius - t, cz - radius), (cy + radius, cz + radius)),
+            ((cy - radius, cz - radius), (cy + radius, cz - radius + t)),
+            ((cy - radius, cz + radius - t), (cy + radius, cz + radius)),
+        ];
+        for ((y0, z0), (y1, z1)) in edges {
+            instances.push(Cuboid::new(
+                Vec3::new(x0, y0, z0),
+                Vec3::new(x1, y1, z1),
+                settings.color,
+            ));
+        }
+    }
+
+    // Normal arrow, with a stepped head that narrows towards the tip.
+    if range.min_sdist.is_finite() {
+        let x0 = range.min_sdist;
+        let shaft_length = 0.2 * radius;
+        instances.push(Cuboid::new(
+            Vec3::new(x0, cy - t, cz - t),
+            Vec3::new(x0 + shaft_length, cy + t, cz + t),
+            settings.color,
+        ));
+        let step_length = 0.02 * radius;
+        for step in 0..3 {
+            let x = x0 + shaft_length + step as f32 * step_length;
+            let half_width = (4 - step) as f32 * t;
+            instances.push(Cuboid::new(
+                Vec3::new(x, cy - half_width, cz - half_width),
+                Vec3::new(x + step_length, cy + half_width, cz + half_width),
+                settings.color,
+            ));
+        }
+    }
+
+    instances
+}
+
+/// Builds the translucent quads inside the frames, in the plane's local space.
+fn plane_fill_instances(
+    settings: &ClippingPlaneGizmos,
+    range: &ClippingPlaneRange,
+    center: Vec3,
+    radius: f32,
+) -> Vec<Cuboid> {
+    let t = settings.thickness * radius;
+    let (cy, cz) = (center.y, center.z);
+    // Alpha is the high byte of RGB colors.
+    let alpha = (settings.fill_alpha.clamp(0.0, 1.0) * 255.0).round() as u32;
+    let color = (settings.color & 0x00FFFFFF) | (alpha << 24);
+    boundaries(range, t)
+        .into_iter()
+        .map(|(x0, x1)| {
+            // Inside the frame edges, and within the frame's X extents.
+            let x_center = 0.5 * (x0 + x1);
+            Cuboid::new(
+                Vec3::new(x_center - 0.25 * t, cy - radius + t, cz - radius + t),
+                Vec3::new(x_center + 0.25 * t, cy + radius - t, cz + radius - t),
+                color,
+            )
+        })
+        .collect()
+}
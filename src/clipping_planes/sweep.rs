@@ -0,0 +1,122 @@
+use super::ClippingPlaneRange;
+
+use bevy::prelude::*;
+use std::ops::Range;
+
+/// Sweeps a [`ClippingPlaneRange`] entity back and forth, or round and round,
+/// e.g. to present the cross sections of a block model with
+/// [`ClippingPlaneRange::slab`].
+///
+/// The plane's distance along `axis`, `axis.dot(translation)`, moves from
+/// `range.start` to `range.end` at `speed` units per second. Only the
+/// component of the [`Transform`] translation along `axis` is changed, so the
+/// plane keeps its orientation, which normally has its normal along `axis`.
+/// A [`ClippingPlaneSweepEvent`] is sent whenever an end of the range is
+/// reached.
+#[derive(Clone, Component, Debug)]
+pub struct ClippingPlaneSweep {
+    /// Direction the plane moves in, normalized by the driver system.
+    pub axis: Vec3,
+    pub range: Range<f32>,
+    /// Units per second.
+    pub speed: f32,
+    /// Turns around at each end of the range, instead of jumping back to
+    /// `range.start` at `range.end`.
+    pub ping_pong: bool,
+    pub paused: bool,
+    /// Distance travelled from `range.start` towards `range.end`. Reset to
+    /// zero to restart the sweep.
+    pub progress: f32,
+    /// Whether the plane currently moves towards `range.end`.
+    pub forward: bool,
+}
+
+impl ClippingPlaneSweep {
+    pub fn new(axis: Vec3, range: Range<f32>, speed: f32, ping_pong: bool) -> Self {
+        Self {
+            axis,
+            range,
+            speed,
+            ping_pong,
+            paused: false,
+            progress: 0.0,
+            forward: true,
+        }
+    }
+
+    pub fn length(&self) -> f32 {
+        (self.range.end - self.range.start).abs()
+    }
+
+    /// The plane distance along `axis` at the current point of the sweep.
+    pub fn distance(&self) -> f32 {
+        let sign = (self.range.end - self.range.start).signum();
+        self.range.start + sign * self.progress.clamp(0.0, self.length())
+    }
+}
+
+/// Sent when a [`ClippingPlaneSweep`] reaches an end of its range.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ClippingPlaneSweepEvent {
+    pub plane: Entity,
+    pub end: SweepEnd,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SweepEnd {
+    Start,
+    End,
+}
+
+pub(crate) fn update_clipping_plane_sweeps(
+    time: Res<Time>,
+    mut events: EventWriter<ClippingPlaneSweepEvent>,
+    mut sweeps: Query<(Entity, &mut ClippingPlaneSweep, &mut Transform), With<ClippingPlaneRange>>,
+) {
+    for (plane, mut sweep, mut transform) in sweeps.iter_mut() {
+        let length = sweep.length();
+        if !sweep.paused && length > 0.0 {
+            // At most a full cycle per frame, so long frames can't loop here.
+            let mut step = (sweep.speed.abs() * time.delta_seconds()).min(2.0 * length);
+            while step > 0.0 {
+                let remaining = if sweep.forward {
+                    length - sweep.progress
+                } else {
+                    sweep.progress
+                };
+                if step < remaining {
+                    let delta = if sweep.forward { step } else { -step };
+                    sweep.progress += delta;
+                    break;
+                }
+                step -= remaining;
+                if !sweep.forward {
+                    sweep.progress = 0.0;
+                    sweep.forward = true;
+                    events.send(ClippingPlaneSweepEvent {
+                        plane,
+                        end: SweepEnd::Start,
+                    });
+                    continue;
+                }
+                events.send(ClippingPlaneSweepEvent {
+                    plane,
+                    end: SweepEnd::End,
+                });
+                if sweep.ping_pong {
+                    sweep.progress = length;
+                    sweep.forward = false;
+                } else {
+                    sweep.progress = 0.0;
+                }
+            }
+        }
+
+        let axis = sweep.axis.normalize_or_zero();
+        let offset = sweep.distance() - axis.dot(transform.translation);
+        // Avoid triggering change detection when nothing moved.
+        if offset.abs() > f32::EPSILON {
+            transform.translation += offset * axis;
+        }
+    }
+}
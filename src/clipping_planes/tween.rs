@@ -0,0 +1,87 @@
+use super::ClippingPlaneRange;
+
+use bevy::prelude::*;
+
+/// Animates a [`ClippingPlaneRange`] entity along its normal.
+///
+/// The plane's distance along its normal, `normal.dot(translation)`, is
+/// interpolated from `start` to `end` over `duration` seconds. Only the
+/// component of the [`Transform`] translation along the normal is changed, so
+/// the plane keeps its orientation and in-plane position.
+#[derive(Clone, Component, Debug)]
+pub struct ClippingPlaneTween {
+    pub start: f32,
+    pub end: f32,
+    /// Length of the animation in seconds.
+    pub duration: f32,
+    pub easing: TweenEasing,
+    /// Seconds since the animation started. Reset to zero to replay it.
+    pub elapsed: f32,
+}
+
+impl ClippingPlaneTween {
+    pub fn new(start: f32, end: f32, duration: f32, easing: TweenEasing) -> Self {
+        Self {
+            start,
+            end,
+            duration,
+            easing,
+            elapsed: 0.0,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.elapsed >= self.duration
+    }
+
+    /// The plane distance at the current point of the animation.
+    pub fn distance(&self) -> f32 {
+        let t = if self.duration > 0.0 {
+            (self.elapsed / self.duration).clamp(0.0, 1.0)
+        } else {
+            1.0
+        };
+        self.start + (self.end - self.start) * self.easing.apply(t)
+    }
+}
+
+/// Easing curves for [`ClippingPlaneTween`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TweenEasing {
+    #[default]
+    Linear,
+    /// Quadratic, starting slow.
+    EaseIn,
+    /// Quadratic, ending slow.
+    EaseOut,
+    /// Smoothstep, slow at both ends.
+    EaseInOut,
+}
+
+impl TweenEasing {
+    /// Maps `t` in `[0, 1]` to the eased progress in `[0, 1]`.
+    pub fn apply(self, t: f32) -> f32 {
+        match self {
+            Self::Linear => t,
+            Self::EaseIn => t * t,
+            Self::EaseOut => t * (2.0 - t),
+            Self::EaseInOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+pub(crate) fn update_clipping_plane_tweens(
+    time: Res<Time>,
+    mut tweens: Query<(&mut ClippingPlaneTween, &mut Transform), With<ClippingPlaneRange>>,
+) {
+    for (mut tween, mut transform) in tweens.iter_mut() {
+        if tween.is_finished() {
+            continue;
+        }
+        tween.elapsed += time.delta_seconds();
+
+        let normal = transform.rotation * Vec3::X;
+        let offset = tween.distance() - normal.dot(transform.translation);
+        transform.translation += offset * normal;
+    }
+}
@@ -0,0 +1,103 @@
+use bevy::{prelude::*, render::render_resource::ShaderType};
+
+/// Which side of a [`ClippingBox`] or [`ClippingSphere`] gets clipped.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ClippingVolumeMode {
+    /// Carves the volume out of the scene.
+    #[default]
+    ClipInside,
+    /// Only keeps what is inside of the volume.
+    ClipOutside,
+}
+
+/// Clips cuboids with centroids inside (or outside) of a box.
+///
+/// The box is centered on the origin of the entity's [`GlobalTransform`], and
+/// is rotated and scaled with it. Like [`ClippingPlaneRange`](crate::ClippingPlaneRange)s,
+/// volumes can be disabled per camera with [`ViewClipping`](crate::ViewClipping).
+#[derive(Clone, Component, Debug)]
+pub struct ClippingBox {
+    pub half_extents: Vec3,
+    pub mode: ClippingVolumeMode,
+}
+
+impl Default for ClippingBox {
+    fn default() -> Self {
+        Self {
+            half_extents: Vec3::ONE,
+            mode: default(),
+        }
+    }
+}
+
+#[derive(Bundle, Default)]
+pub struct ClippingBoxBundle {
+    pub clipping_box: ClippingBox,
+    #[bundle]
+    pub transform: TransformBundle,
+}
+
+/// Clips cuboids with centroids inside (or outside) of a sphere.
+///
+/// The sphere is centered on the origin of the entity's [`GlobalTransform`].
+/// Non-uniform scales stretch it into an ellipsoid.
+#[derive(Clone, Component, Debug)]
+pub struct ClippingSphere {
+    pub radius: f32,
+    pub mode: ClippingVolumeMode,
+}
+
+impl Default for ClippingSphere {
+    fn default() -> Self {
+        Self {
+            radius: 1.0,
+            mode: default(),
+        }
+    }
+}
+
+#[derive(Bundle, Default)]
+pub struct ClippingSphereBundle {
+    pub clipping_sphere: ClippingSphere,
+    #[bundle]
+    pub transform: TransformBundle,
+}
+
+#[derive(Clone, Debug, Default, ShaderType)]
+pub(crate) struct GpuClippingVolume {
+    pub volume_from_world: Mat4,
+    /// Only `x` is used for spheres, as the radius.
+    pub half_extents: Vec3,
+    pub is_sphere: u32,
+    pub clip_outside: u32,
+}
+
+impl GpuClippingVolume {
+    pub fn new_box(clipping_box: &ClippingBox, transform: &GlobalTransform) -> Self {
+        Self {
+            volume_from_world: transform.compute_matrix().inverse(),
+            half_extents: clipping_box.half_extents,
+            is_sphere: 0,
+            clip_outside: (clipping_box.mode == ClippingVolumeMode::ClipOutside).into(),
+        }
+    }
+
+    pub fn new_sphere(sphere: &ClippingSphere, transform: &GlobalTransform) -> Self {
+        Self {
+            volume_from_world: transform.compute_matrix().inverse(),
+            half_extents: Vec3::splat(sphere.radius),
+            is_sphere: 1,
+            clip_outside: (sphere.mode == ClippingVolumeMode::ClipOutside).into(),
+        }
+    }
+}
+
+#[derive(Debug, Default, ShaderType)]
+pub(crate) struct GpuClippingVolumes {
+    pub volumes: [GpuClippingVolume; MAX_CLIPPING_VOLUMES],
+    pub num_volumes: u32,
+}
+
+/// The most [`ClippingBox`] and [`ClippingSphere`] entities that apply at once,
+/// combined.
+pub const MAX_CLIPPING_VOLUMES: usize = 8;
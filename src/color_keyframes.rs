@@ -0,0 +1,98 @@
+use crate::Color;
+
+use bevy::{prelude::*, render::render_resource::ShaderType};
+
+/// The largest number of sequences a [`ColorKeyframes`] table can hold, since
+/// sequence IDs are stored in 6 bits of [`MetaBits`](crate::MetaBits).
+pub const MAX_COLOR_SEQUENCES: usize = 63;
+
+/// Identifies a sequence in the [`ColorKeyframes`] table.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ColorSequenceId(pub(crate) u8);
+
+/// Global table of color sequences for time-series playback.
+///
+/// A cuboid assigned a sequence with
+/// [`Cuboid::set_color_sequence`](crate::Cuboid::set_color_sequence) ignores
+/// its own `color` and instead linearly interpolates between the two keyframes
+/// that bracket `time`. Keyframe values are interpreted according to the
+/// cuboid's [`ColorMode`](crate::ColorMode), so scalar sequences are
+/// interpolated before hue mapping.
+///
+/// All sequences have the same number of keyframes, spaced one unit of `time`
+/// apart, and playback clamps to the first and last keyframes. There can be up
+/// to [`MAX_COLOR_SEQUENCES`] sequences. The number of keyframes is only
+/// limited by the device's maximum storage buffer binding size, which must fit
+/// `4 * num_keyframes * num_sequences` bytes.
+///
+/// Changing `time` is cheap; the table itself is only re-uploaded when
+/// sequences are modified.
+#[derive(Clone, Debug, Default, Resource)]
+pub struct ColorKeyframes {
+    /// Playback time, measured in keyframes.
+    pub time: f32,
+    num_keyframes: usize,
+    colors: Vec<Color>,
+    revision: u64,
+}
+
+impl ColorKeyframes {
+    pub fn new(num_keyframes: usize) -> Self {
+        assert!(num_keyframes > 0);
+        Self {
+            num_keyframes,
+            ..default()
+        }
+    }
+
+    pub fn num_keyframes(&self) -> usize {
+        self.num_keyframes
+    }
+
+    pub fn num_sequences(&self) -> usize {
+        self.colors.len() / self.num_keyframes.max(1)
+    }
+
+    /// Adds a sequence with exactly [`Self::num_keyframes`] colors.
+    pub fn push_sequence(&mut self, colors: &[Color]) -> ColorSequenceId {
+        assert_eq!(colors.len(), self.num_keyframes);
+        assert!(
+            self.num_sequences() < MAX_COLOR_SEQUENCES,
+            "At most {MAX_COLOR_SEQUENCES} color sequences are supported"
+        );
+        let id = ColorSequenceId(self.num_sequences() as u8);
+        self.colors.extend_from_slice(colors);
+        self.revision += 1;
+        id
+    }
+
+    pub fn sequence(&self, id: ColorSequenceId) -> &[Color] {
+        let start = id.0 as usize * self.num_keyframes;
+        &self.colors[start..start + self.num_keyframes]
+    }
+
+    pub fn sequence_mut(&mut self, id: ColorSequenceId) -> &mut [Color] {
+        self.revision += 1;
+        let start = id.0 as usize * self.num_keyframes;
+        &mut self.colors[start..start + self.num_keyframes]
+    }
+
+    pub fn clear(&mut self) {
+        self.colors.clear();
+        self.revision += 1;
+    }
+
+    pub(crate) fn colors(&self) -> &[Color] {
+        &self.colors
+    }
+
+    pub(crate) fn revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+#[derive(Clone, Debug, Default, ShaderType)]
+pub(crate) struct GpuColorKeyframesHeader {
+    pub num_keyframes: u32,
+    pub time: f32,
+}
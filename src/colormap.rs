@@ -0,0 +1,160 @@
+use bevy::{
+    prelude::*,
+    render::{extract_resource::ExtractResource, render_resource::ShaderType},
+};
+
+/// The number of evenly spaced color stops in a [`Colormap`], which are
+/// interpolated linearly.
+pub const COLORMAP_STOPS: usize = 16;
+
+/// The number of colormaps in [`CuboidColormaps`].
+pub const MAX_COLORMAPS: usize = 16;
+
+/// A color ramp for [`COLOR_MODE_SCALAR_HUE`](crate::COLOR_MODE_SCALAR_HUE),
+/// that replaces the HSL hue ramp of a material's
+/// [`ScalarHueOptions`](crate::ScalarHueOptions), see
+/// [`CuboidMaterial::colormap`](crate::CuboidMaterial::colormap).
+///
+/// Scalars are normalized by `clamp_min` and `clamp_max` as before, and
+/// scalars outside of `min_visible..=max_visible` are still discarded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Colormap {
+    /// Nonlinear sRGB, from the scalar at `clamp_min` to the one at
+    /// `clamp_max`.
+    stops: [Vec3; COLORMAP_STOPS],
+}
+
+impl Colormap {
+    /// Resamples evenly spaced `colors` to [`COLORMAP_STOPS`] stops.
+    pub fn from_colors(colors: &[Color]) -> Self {
+        assert!(!colors.is_empty());
+        let srgb: Vec<Vec3> = colors
+            .iter()
+            .map(|c| Vec4::from(c.as_rgba_f32()).truncate())
+            .collect();
+        Self {
+            stops: std::array::from_fn(|i| {
+                let x = i as f32 / (COLORMAP_STOPS - 1) as f32 * (srgb.len() - 1) as f32;
+                let below = (x as usize).min(srgb.len() - 1);
+                let above = (below + 1).min(srgb.len() - 1);
+                srgb[below].lerp(srgb[above], x - below as f32)
+            }),
+        }
+    }
+
+    /// The perceptually uniform default colormap of matplotlib.
+    pub fn viridis() -> Self {
+        Self::from_srgb([
+            [0.267, 0.004, 0.329],
+            [0.282, 0.102, 0.424],
+            [0.278, 0.184, 0.490],
+            [0.255, 0.267, 0.529],
+            [0.224, 0.337, 0.549],
+            [0.192, 0.408, 0.557],
+            [0.165, 0.471, 0.557],
+            [0.137, 0.533, 0.557],
+            [0.122, 0.596, 0.545],
+            [0.133, 0.659, 0.518],
+            [0.208, 0.718, 0.475],
+            [0.329, 0.773, 0.408],
+            [0.478, 0.820, 0.318],
+            [0.647, 0.859, 0.212],
+            [0.824, 0.886, 0.106],
+            [0.992, 0.906, 0.145],
+        ])
+    }
+
+    /// Google's Turbo rainbow colormap, from its polynomial approximation.
+    pub fn turbo() -> Self {
+        Self::from_srgb([
+            [0.136, 0.091, 0.107],
+            [0.290, 0.255, 0.711],
+            [0.260, 0.438, 0.950],
+            [0.184, 0.618, 0.960],
+            [0.145, 0.777, 0.845],
+            [0.184, 0.900, 0.679],
+            [0.304, 0.975, 0.512],
+            [0.486, 0.994, 0.370],
+            [0.691, 0.955, 0.265],
+            [0.876, 0.862, 0.196],
+            [0.999, 0.721, 0.152],
+            [1.000, 0.548, 0.121],
+            [0.958, 0.363, 0.091],
+            [0.808, 0.193, 0.053],
+            [0.640, 0.075, 0.009],
+            [0.566, 0.050, 0.000],
+        ])
+    }
+
+    fn from_srgb(stops: [[f32; 3]; COLORMAP_STOPS]) -> Self {
+        Self {
+            stops: stops.map(Vec3::from),
+        }
+    }
+
+    /// The color at `s` from 0 to 1, as the shader interpolates it.
+    pub fn sample(&self, s: f32) -> Color {
+        let x = s.clamp(0.0, 1.0) * (COLORMAP_STOPS - 1) as f32;
+        let below = (x as usize).min(COLORMAP_STOPS - 2);
+        let rgb = self.stops[below].lerp(self.stops[below + 1], x - below as f32);
+        Color::rgb(rgb.x, rgb.y, rgb.z)
+    }
+}
+
+/// The colormaps that materials can select with
+/// [`CuboidMaterial::colormap`](crate::CuboidMaterial::colormap).
+#[derive(Clone, Debug, Default, ExtractResource, Resource)]
+pub struct CuboidColormaps {
+    colormaps: Vec<Colormap>,
+}
+
+impl CuboidColormaps {
+    /// Adds a colormap, and returns the value of
+    /// [`CuboidMaterial::colormap`](crate::CuboidMaterial::colormap) that
+    /// selects it.
+    pub fn push(&mut self, colormap: Colormap) -> u32 {
+        assert!(
+            self.colormaps.len() < MAX_COLORMAPS,
+            "at most {MAX_COLORMAPS} colormaps are supported"
+        );
+        self.colormaps.push(colormap);
+        self.colormaps.len() as u32
+    }
+
+    /// The colormap selected by a [`CuboidMaterial::colormap`](crate::CuboidMaterial::colormap)
+    /// value, or `None` for the HSL hue ramp.
+    pub fn get(&self, colormap: u32) -> Option<&Colormap> {
+        colormap
+            .checked_sub(1)
+            .and_then(|i| self.colormaps.get(i as usize))
+    }
+
+    pub fn get_mut(&mut self, colormap: u32) -> Option<&mut Colormap> {
+        colormap
+            .checked_sub(1)
+            .and_then(|i| self.colormaps.get_mut(i as usize))
+    }
+
+    pub(crate) fn to_gpu(&self) -> GpuColormaps {
+        let mut gpu = GpuColormaps::default();
+        for (i, colormap) in self.colormaps.iter().enumerate() {
+            for (j, stop) in colormap.stops.iter().enumerate() {
+                gpu.stops[i * COLORMAP_STOPS + j] = stop.extend(1.0);
+            }
+        }
+        gpu
+    }
+}
+
+#[derive(Clone, ShaderType)]
+pub(crate) struct GpuColormaps {
+    pub stops: [Vec4; MAX_COLORMAPS * COLORMAP_STOPS],
+}
+
+impl Default for GpuColormaps {
+    fn default() -> Self {
+        Self {
+            stops: [Vec4::ONE; MAX_COLORMAPS * COLORMAP_STOPS],
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use crate::{Cuboid, Cuboids};
+
+use bevy::{
+    prelude::*,
+    render::{extract_component::ExtractComponent, render_resource::Buffer},
+};
+use std::borrow::Cow;
+
+/// Generates the instances of a [`Cuboids`] entity on the GPU, with a compute
+/// shader that reads a storage buffer of the application, e.g. the particles
+/// of a GPU simulation, and writes straight into the batch's instance buffers.
+///
+/// The shader runs every frame before any camera draws, once per instance
+/// chunk of the batch, with these bindings:
+/// ```wgsl
+/// struct Cuboid {
+///     minimum: vec3<f32>,
+///     meta_bits: u32,
+///     maximum: vec3<f32>,
+///     color: u32,
+/// };
+///
+/// struct ChunkParams {
+///     // Index of the first instance of the chunk in the batch.
+///     first_instance: u32,
+///     num_instances: u32,
+/// };
+///
+/// @group(0) @binding(0) var<storage, read> source: YourSourceData;
+/// @group(0) @binding(1) var<storage, read_write> instances: array<Cuboid>;
+/// // Read by the shaders in place of `Cuboid::color`.
+/// @group(0) @binding(2) var<storage, read_write> colors: array<u32>;
+/// @group(0) @binding(3) var<uniform> chunk: ChunkParams;
+/// ```
+/// and dispatches `num_instances / workgroup_size` workgroups, rounded up, so
+/// the shader must skip invocations past `chunk.num_instances`.
+///
+/// The `Cuboids` of the entity only sets the instance count, and holds what
+/// the CPU knows of the instances: batch bounds for culling, CPU picking, and
+/// so on all see it rather than the GPU data. Start from
+/// [`CuboidsComputeSource::placeholder`] for invisible instances that span the
+/// domain of the source. Edits to the `Cuboids` are uploaded as usual, and
+/// overwritten by the shader in the same frame.
+///
+/// Only batches with [`CuboidsInstanceFormat::Full`](crate::CuboidsInstanceFormat::Full)
+/// in storage buffers can be written; others report
+/// [`CuboidsError::UnsupportedComputeSource`](crate::CuboidsError::UnsupportedComputeSource).
+/// Until the shader compiles the placeholder instances are drawn.
+#[derive(Clone, Component)]
+pub struct CuboidsComputeSource {
+    /// Created with the [`RenderDevice`](bevy::render::renderer::RenderDevice)
+    /// resource of the main world, with `BufferUsages::STORAGE`.
+    pub source: Buffer,
+    pub shader: Handle<Shader>,
+    pub entry_point: Cow<'static, str>,
+    /// The `@workgroup_size` of `entry_point`, along X only.
+    pub workgroup_size: u32,
+}
+
+impl CuboidsComputeSource {
+    pub fn new(source: Buffer, shader: Handle<Shader>, entry_point: &'static str) -> Self {
+        Self {
+            source,
+            shader,
+            entry_point: entry_point.into(),
+            workgroup_size: 64,
+        }
+    }
+
+    /// A batch of `num_instances` points, the first at `minimum` and the
+    /// others at `maximum`, so that its bounds span the domain. Points have
+    /// no area, so none of them are drawn.
+    pub fn placeholder(num_instances: usize, minimum: Vec3, maximum: Vec3) -> Cuboids {
+        let instances = (0..num_instances)
+            .map(|i| {
+                let corner = if i == 0 { minimum } else { maximum };
+                Cuboid::new(corner, corner, 0)
+            })
+            .collect();
+        Cuboids::new(instances)
+    }
+}
+
+impl ExtractComponent for CuboidsComputeSource {
+    type Query = &'static Self;
+    type Filter = With<Cuboids>;
+    type Out = Self;
+
+    fn extract_component(source: &Self) -> Option<Self> {
+        Some(source.clone())
+    }
+}
@@ -0,0 +1,131 @@
+use crate::cuboids::NO_SPAWN_TIMES;
+use crate::{CuboidMaterialId, Cuboids, CuboidsBundle};
+
+use bevy::{prelude::*, utils::HashMap};
+
+/// A huge batch split into one [`Cuboids`] entity per cell of a regular grid.
+///
+/// Each chunk is culled, streamed and evicted from the GPU on its own, so
+/// only the chunks in view cost anything to draw, and a change to one region
+/// only uploads its chunk. This component lives on the parent of the chunk
+/// entities, which are in its local space, and maps each chunk to its entity.
+///
+/// Instances are assigned to the chunk that contains their center, so chunk
+/// bounds may overlap a little. Rotations, user data, face colors, atlas
+/// tiles, scalars and hidden instances are carried over.
+#[derive(Clone, Component, Debug)]
+pub struct CuboidChunks {
+    chunk_size: Vec3,
+    entities: HashMap<IVec3, Entity>,
+}
+
+impl CuboidChunks {
+    /// Spawns a parent entity with a [`CuboidChunks`], and a child with the
+    /// instances of `cuboids` in each non-empty chunk of edge lengths
+    /// `chunk_size`. Returns the parent.
+    pub fn spawn(
+        commands: &mut Commands,
+        cuboids: &Cuboids,
+        chunk_size: Vec3,
+        material_id: CuboidMaterialId,
+    ) -> Entity {
+        let entities: HashMap<_, _> = Self::split(cuboids, chunk_size)
+            .into_iter()
+            .map(|(chunk, cuboids)| {
+                let entity = commands
+                    .spawn(CuboidsBundle {
+                        material_id,
+                        cuboids,
+                        spatial: default(),
+                    })
+                    .id();
+                (chunk, entity)
+            })
+            .collect();
+        let children: Vec<_> = entities.values().copied().collect();
+        commands
+            .spawn((
+                SpatialBundle::default(),
+                Self {
+                    chunk_size,
+                    entities,
+                },
+            ))
+            .push_children(&children)
+            .id()
+    }
+
+    /// Splits `cuboids` into chunks of edge lengths `chunk_size`, keyed by
+    /// [`Self::chunk_of`], without spawning them.
+    pub fn split(cuboids: &Cuboids, chunk_size: Vec3) -> HashMap<IVec3, Cuboids> {
+        let mut chunks: HashMap<IVec3, Cuboids> = HashMap::default();
+        for (i, instance) in cuboids.instances.iter().enumerate() {
+            let center = 0.5 * (instance.minimum + instance.maximum);
+            let chunk = chunks
+                .entry(chunk_of(center, chunk_size))
+                .or_insert_with(|| Cuboids {
+                    dynamic: cuboids.dynamic,
+                    ..default()
+                });
+            chunk.instances.push(*instance);
+            if !cuboids.rotations.is_empty() {
+                chunk.rotations.push(cuboids.rotation(i));
+            }
+            if !cuboids.user_data.is_empty() {
+                chunk.user_data.push(cuboids.user_data[i]);
+            }
+            if !cuboids.face_colors.is_empty() {
+                chunk.face_colors.push(cuboids.face_colors[i]);
+            }
+            if !cuboids.atlas_tiles.is_empty() {
+                chunk.atlas_tiles.push(cuboids.atlas_tiles[i]);
+            }
+            if !cuboids.scalars.is_empty() {
+                chunk.scalars.push(cuboids.scalars[i]);
+            }
+            if !cuboids.spawn_times.is_empty() {
+                // Instances pushed onto `instances` directly have no times yet.
+                let times = cuboids.spawn_times.get(i).copied();
+                chunk.spawn_times.push(times.unwrap_or(NO_SPAWN_TIMES));
+            }
+            if !cuboids.is_visible(i) {
+                let index = chunk.instances.len() - 1;
+                chunk.set_visible(index..index + 1, false);
+            }
+        }
+        chunks
+    }
+
+    /// The chunk that contains `point`, in the local space of the parent.
+    pub fn chunk_of(&self, point: Vec3) -> IVec3 {
+        chunk_of(point, self.chunk_size)
+    }
+
+    pub fn chunk_size(&self) -> Vec3 {
+        self.chunk_size
+    }
+
+    /// The entity of `chunk`, if it has any instances.
+    pub fn entity(&self, chunk: IVec3) -> Option<Entity> {
+        self.entities.get(&chunk).copied()
+    }
+
+    /// The non-empty chunks and their entities, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (IVec3, Entity)> + '_ {
+        self.entities
+            .iter()
+            .map(|(&chunk, &entity)| (chunk, entity))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+}
+
+fn chunk_of(point: Vec3, chunk_size: Vec3) -> IVec3 {
+    (point / chunk_size).floor().as_ivec3()
+}
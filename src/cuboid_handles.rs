@@ -0,0 +1,122 @@
+use crate::{Cuboid, Cuboids};
+
+/// Refers to one instance of a [`Cuboids`], and keeps referring to it when
+/// [`Cuboids::remove`] or [`Cuboids::sort_back_to_front`] move it to another
+/// index.
+///
+/// Look up its current index with [`Cuboids::index_of`], e.g. to keep a
+/// selection or application metadata across deletions. Handles of removed
+/// instances are never handed out again, even though their slot is reused.
+/// They only belong to the batch that issued them, and don't survive
+/// serialization or snapshots.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct CuboidHandle {
+    slot: u32,
+    generation: u32,
+}
+
+const FREE_SLOT: u32 = u32::MAX;
+
+/// Maps [`CuboidHandle`]s to instance indices. Empty until the first handle
+/// is issued, so batches without handles cost nothing to edit.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CuboidHandles {
+    /// Instance index and generation of each slot, with [`FREE_SLOT`] as the
+    /// index of removed instances.
+    slots: Vec<(u32, u32)>,
+    free_slots: Vec<u32>,
+    /// Slot of each instance. Instances added since the last handle was issued
+    /// don't have one yet.
+    slot_of_index: Vec<u32>,
+}
+
+impl CuboidHandles {
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Gives every instance up to `len` a slot.
+    pub fn fill(&mut self, len: usize) {
+        while self.slot_of_index.len() < len {
+            let index = self.slot_of_index.len() as u32;
+            let slot = match self.free_slots.pop() {
+                Some(slot) => {
+                    self.slots[slot as usize].0 = index;
+                    slot
+                }
+                None => {
+                    self.slots.push((index, 0));
+                    self.slots.len() as u32 - 1
+                }
+            };
+            self.slot_of_index.push(slot);
+        }
+    }
+
+    pub fn get(&mut self, index: usize) -> CuboidHandle {
+        self.fill(index + 1);
+        let slot = self.slot_of_index[index];
+        CuboidHandle {
+            slot,
+            generation: self.slots[slot as usize].1,
+        }
+    }
+
+    pub fn index_of(&self, handle: CuboidHandle) -> Option<usize> {
+        let &(index, generation) = self.slots.get(handle.slot as usize)?;
+        (index != FREE_SLOT && generation == handle.generation).then_some(index as usize)
+    }
+
+    /// Follows `Vec::swap_remove(index)` of the instances, which must all have
+    /// a slot.
+    pub fn swap_remove(&mut self, index: usize) {
+        let slot = self.slot_of_index.swap_remove(index);
+        let (slot_index, generation) = &mut self.slots[slot as usize];
+        *slot_index = FREE_SLOT;
+        *generation = generation.wrapping_add(1);
+        self.free_slots.push(slot);
+        if let Some(&moved) = self.slot_of_index.get(index) {
+            self.slots[moved as usize].0 = index as u32;
+        }
+    }
+
+    /// Follows a reordering of the instances, with `order[new] == old`.
+    pub fn reorder(&mut self, order: &[usize]) {
+        if self.is_empty() {
+            return;
+        }
+        self.fill(order.len());
+        self.slot_of_index = order.iter().map(|&old| self.slot_of_index[old]).collect();
+        for (index, &slot) in self.slot_of_index.iter().enumerate() {
+            self.slots[slot as usize].0 = index as u32;
+        }
+    }
+}
+
+impl Cuboids {
+    /// Adds `cuboid` at the end of the batch like [`Cuboids::append`], and
+    /// returns a handle to it.
+    pub fn insert(&mut self, cuboid: Cuboid) -> CuboidHandle {
+        let index = self.instances.len();
+        self.append([cuboid]);
+        self.handles.get(index)
+    }
+
+    /// A handle to the instance at `index`, e.g. for instances given to
+    /// [`Cuboids::new`].
+    pub fn handle(&mut self, index: usize) -> CuboidHandle {
+        assert!(index < self.instances.len());
+        self.handles.get(index)
+    }
+
+    /// The current index of the instance of `handle`, or `None` once it's
+    /// removed.
+    ///
+    /// Instances moved by changing `instances` directly, rather than with
+    /// [`Cuboids::remove`] or [`Cuboids::sort_back_to_front`], aren't tracked.
+    pub fn index_of(&self, handle: CuboidHandle) -> Option<usize> {
+        self.handles
+            .index_of(handle)
+            .filter(|&index| index < self.instances.len())
+    }
+}
@@ -2,8 +2,17 @@ use bevy::{
     prelude::*,
     render::{primitives::Aabb, render_resource::ShaderType},
 };
+use std::{
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+use crate::bvh::CuboidsBvhCache;
+use crate::cuboid_handles::CuboidHandles;
+use crate::{pack_color, ColorSpace, CuboidMaterialId, MAX_LOD_LEVEL, MAX_MATERIAL_SLOT};
 
-use crate::CuboidMaterialId;
+#[cfg(feature = "color_keyframes")]
+use crate::ColorSequenceId;
 
 /// Value that determines the color of a [`Cuboid`] based on the associated
 /// [`CuboidMaterial`](crate::CuboidMaterial).
@@ -14,20 +23,58 @@ pub type Color = u32;
 /// - `0x000000FF`
 ///     - bit 0 = 0 for visible or 1 for invisible
 ///     - bit 1 = 0 for non-emissive or 1 for emissive
-///     - bits 2-7 = unused
-/// - `0x0000FF00` = unused
-/// - `0xFFFF0000` = depth bias (u16)
-///   - Multiplies the depth of each cuboid vertex by `1 - bias * eps` where
-///     `eps = 8e-8`. This can be used with random biases to avoid Z-fighting.
+///     - bits 2-3 = LOD level, see [`CuboidsLod`](crate::CuboidsLod)
+///     - bits 4-7 = material slot, see [`CuboidMaterialSlots`](crate::CuboidMaterialSlots)
+/// - `0x0000FF00`
+///     - bits 8-13 = color keyframe sequence (`color_keyframes` feature), 0
+///       for none, otherwise the sequence ID + 1
+///     - bit 14 = 1 for pulsing, see [`Cuboid::make_pulsing`]
+///     - bit 15 = 1 for flashing, see [`Cuboid::make_flashing`]
+/// - `0xFFFF0000`
+///     - bits 16-28 = depth bias, see [`Cuboid::set_depth_bias`]
+///     - bit 29 = 1 to always darken edges, see [`Cuboid::set_outline`]
+///     - bit 30 = 1 to never darken edges
+///     - bit 31 = 1 to ignore the depth test, see
+///       [`Cuboid::make_depth_test_ignored`]
 pub type MetaBits = u32;
 
+/// Relative depth of one unit of [`Cuboid::set_depth_bias`].
+pub const DEPTH_BIAS_EPSILON: f32 = 8e-8;
+
+/// The largest [`Cuboid::set_depth_bias`].
+pub const MAX_DEPTH_BIAS: u16 = 0x1FFF;
+
+/// The [`Cuboids::spawn_times`] of an instance that is neither spawning nor
+/// despawning.
+pub(crate) const NO_SPAWN_TIMES: [f32; 2] = [f32::NEG_INFINITY, f32::INFINITY];
+
+/// Whether the edges of a cuboid are darkened, see [`Cuboid::set_outline`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CuboidOutline {
+    /// Edges are darkened with
+    /// [`VertexPullingRenderPlugin::outlines`](crate::VertexPullingRenderPlugin::outlines).
+    #[default]
+    Default,
+    /// Edges are darkened even without `outlines`, e.g. to emphasize a few
+    /// cuboids of a batch.
+    Always,
+    /// Edges are never darkened, e.g. for cuboids that are too small for
+    /// outlines to look good.
+    Never,
+}
+
 /// An axis-aligned box, extending from `minimum` to `maximum`.
-#[derive(Clone, Copy, Debug, ShaderType)]
+///
+/// The box can be rotated about its center with [`Cuboids::rotations`].
+#[derive(Clone, Copy, Debug, PartialEq, ShaderType)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 #[repr(C)]
 pub struct Cuboid {
     pub minimum: Vec3,
     pub meta_bits: MetaBits,
     pub maximum: Vec3,
+    /// Uploaded to a separate buffer from the bounds, so it can be changed on
+    /// its own with [`Cuboids::recolor`].
     pub color: Color,
 }
 
@@ -42,6 +89,30 @@ impl Cuboid {
         }
     }
 
+    /// A cuboid of size `2 * half_extents` around `center`.
+    pub fn from_center_half_extents(center: Vec3, half_extents: Vec3, color: u32) -> Self {
+        Self::new(center - half_extents, center + half_extents, color)
+    }
+
+    /// A cuboid of size `extent` from its `minimum` corner.
+    pub fn from_min_extent(minimum: Vec3, extent: Vec3, color: u32) -> Self {
+        Self::new(minimum, minimum + extent, color)
+    }
+
+    /// Whether the bounds are finite and `minimum <= maximum` on every axis.
+    ///
+    /// Flat cuboids with `minimum == maximum` on an axis are valid.
+    pub fn is_valid(&self) -> bool {
+        self.minimum.is_finite()
+            && self.maximum.is_finite()
+            && self.minimum.cmple(self.maximum).all()
+    }
+
+    #[inline]
+    pub fn is_visible(&self) -> bool {
+        self.meta_bits & 1 == 0
+    }
+
     #[inline]
     pub fn make_visible(&mut self) -> &mut Self {
         self.meta_bits &= !1;
@@ -66,47 +137,1025 @@ impl Cuboid {
         self
     }
 
+    /// Pulses the brightness, and optionally the size, of this cuboid along a
+    /// sine wave, see [`CuboidEffects`](crate::CuboidEffects).
+    #[inline]
+    pub fn make_pulsing(&mut self) -> &mut Self {
+        self.meta_bits |= 1 << 14;
+        self
+    }
+
+    #[inline]
+    pub fn make_non_pulsing(&mut self) -> &mut Self {
+        self.meta_bits &= !(1 << 14);
+        self
+    }
+
+    /// Blinks this cuboid between its color and a dimmed or brightened one,
+    /// see [`CuboidEffects`](crate::CuboidEffects).
+    #[inline]
+    pub fn make_flashing(&mut self) -> &mut Self {
+        self.meta_bits |= 1 << 15;
+        self
+    }
+
+    #[inline]
+    pub fn make_non_flashing(&mut self) -> &mut Self {
+        self.meta_bits &= !(1 << 15);
+        self
+    }
+
+    #[inline]
+    pub fn lod_level(&self) -> u8 {
+        ((self.meta_bits >> 2) & 0b11) as u8
+    }
+
+    /// Sets the LOD level in `0..=MAX_LOD_LEVEL`. Higher levels are hidden
+    /// first as [`CuboidsLod::current_level`](crate::CuboidsLod) decreases.
+    #[inline]
+    pub fn set_lod_level(&mut self, level: u8) -> &mut Self {
+        debug_assert!(level <= MAX_LOD_LEVEL);
+        self.meta_bits &= !0b1100; // clear
+        self.meta_bits |= ((level & 0b11) as u32) << 2; // set
+        self
+    }
+
+    /// Sets [`Cuboid::color`] to `color`, packed for a material in
+    /// `color_space` with [`pack_color`].
+    #[inline]
+    pub fn set_color(
+        &mut self,
+        color: bevy::render::color::Color,
+        color_space: ColorSpace,
+    ) -> &mut Self {
+        self.color = pack_color(color, color_space);
+        self
+    }
+
+    #[inline]
+    pub fn material_slot(&self) -> u8 {
+        ((self.meta_bits >> 4) & 0b1111) as u8
+    }
+
+    /// Sets the material slot in `0..=MAX_MATERIAL_SLOT`, which selects a
+    /// material from the batch's [`CuboidMaterialSlots`](crate::CuboidMaterialSlots).
+    #[inline]
+    pub fn set_material_slot(&mut self, slot: u8) -> &mut Self {
+        debug_assert!(slot <= MAX_MATERIAL_SLOT);
+        self.meta_bits &= !0b1111_0000; // clear
+        self.meta_bits |= ((slot & 0b1111) as u32) << 4; // set
+        self
+    }
+
+    /// Animates this cuboid's color with a sequence from the
+    /// [`ColorKeyframes`](crate::ColorKeyframes) table, or uses `color` again if
+    /// `sequence` is `None`.
+    #[cfg(feature = "color_keyframes")]
+    #[inline]
+    pub fn set_color_sequence(&mut self, sequence: Option<ColorSequenceId>) -> &mut Self {
+        let bits = sequence.map(|s| s.0 as u32 + 1).unwrap_or(0);
+        self.meta_bits &= !0x00003F00; // clear
+        self.meta_bits |= bits << 8; // set
+        self
+    }
+
+    /// Draws the cuboid behind others at the same depth, so coplanar faces can
+    /// be layered without Z-fighting. Biases above [`MAX_DEPTH_BIAS`] are
+    /// clamped to it, since the top bits of [`MetaBits`] hold other flags.
+    ///
+    /// The depth of each vertex is multiplied by `1 - bias * DEPTH_BIAS_EPSILON`,
+    /// so higher biases are drawn behind lower ones. With a perspective camera,
+    /// this moves the cuboid back by about that fraction of its distance to
+    /// the camera, e.g. a bias of 1000 separates faces 100 units away by 8e-3
+    /// units. With an orthographic camera, `bias * DEPTH_BIAS_EPSILON` is
+    /// subtracted from the depth instead, which moves the cuboid back by that
+    /// fraction of the distance between the near and far planes. The
+    /// [`CuboidMaterial::depth_bias`](crate::CuboidMaterial::depth_bias)
+    /// of the instance's material is added, to layer whole batches. Random
+    /// biases also hide Z-fighting of unrelated overlapping cuboids.
+    ///
+    /// Unlike a positive `StandardMaterial::depth_bias`, which draws meshes in
+    /// front, this always draws cuboids behind. It's scaled by the depth
+    /// itself, so it works the same with Bevy's infinite reverse-Z
+    /// projections as with a far plane.
     #[inline]
     pub fn set_depth_bias(&mut self, bias: u16) -> &mut Self {
-        self.meta_bits &= 0x0000FFFF; // clear
-        self.meta_bits |= (bias as u32) << 16; // set
+        self.meta_bits &= !0x1FFF0000; // clear
+        self.meta_bits |= (bias.min(MAX_DEPTH_BIAS) as u32) << 16; // set
+        self
+    }
+
+    #[inline]
+    pub fn depth_bias(&self) -> u16 {
+        ((self.meta_bits >> 16) as u16) & MAX_DEPTH_BIAS
+    }
+
+    /// Overrides whether the edges of this cuboid are darkened. Wireframe
+    /// edges are never darkened.
+    #[inline]
+    pub fn set_outline(&mut self, outline: CuboidOutline) -> &mut Self {
+        self.meta_bits &= !(0b11 << 29); // clear
+        self.meta_bits |= match outline {
+            CuboidOutline::Default => 0,
+            CuboidOutline::Always => 1 << 29,
+            CuboidOutline::Never => 1 << 30,
+        }; // set
+        self
+    }
+
+    #[inline]
+    pub fn outline(&self) -> CuboidOutline {
+        match (self.meta_bits >> 29) & 0b11 {
+            0 => CuboidOutline::Default,
+            1 => CuboidOutline::Always,
+            _ => CuboidOutline::Never,
+        }
+    }
+
+    /// Draws this cuboid in front of everything else, e.g. to keep a marker
+    /// visible inside a solid block model.
+    ///
+    /// The cuboid is moved to the camera's near plane, so it passes the depth
+    /// test against all other geometry, and is drawn over whatever comes
+    /// before it. Among such cuboids, later ones are drawn on top. GPU
+    /// occlusion culling never culls it, and its shadows are unaffected.
+    #[inline]
+    pub fn make_depth_test_ignored(&mut self) -> &mut Self {
+        self.meta_bits |= 1 << 31;
+        self
+    }
+
+    #[inline]
+    pub fn make_depth_tested(&mut self) -> &mut Self {
+        self.meta_bits &= !(1 << 31);
         self
     }
+
+    #[inline]
+    pub fn ignores_depth_test(&self) -> bool {
+        self.meta_bits & (1 << 31) != 0
+    }
 }
 
 /// A set of cuboids to be extracted for rendering.
+///
+/// Like meshes, batches are only drawn and picked by cameras that share one of
+/// their [`RenderLayers`](bevy::render::view::RenderLayers).
 #[derive(Clone, Component, Debug, Default)]
+#[cfg_attr(
+    feature = "serialize",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 pub struct Cuboids {
     /// Instances to be rendered.
     pub instances: Vec<Cuboid>,
+    /// Set this for batches that are modified (nearly) every frame.
+    ///
+    /// Dynamic batches cycle through multiple GPU instance buffers so that an
+    /// upload never has to wait on the GPU to finish reading the previous
+    /// frame's instances. This costs extra GPU memory, so static batches should
+    /// leave this `false`. See
+    /// [`VertexPullingRenderPlugin::dynamic_instance_buffers`](crate::VertexPullingRenderPlugin::dynamic_instance_buffers)
+    /// for how many buffers, and
+    /// [`auto_dynamic_frames`](crate::VertexPullingRenderPlugin::auto_dynamic_frames)
+    /// for buffering batches that change every frame without this.
+    pub dynamic: bool,
+    /// Optional rotation of each instance about its center, turning it into an
+    /// oriented box.
+    ///
+    /// Either empty for axis-aligned instances, or the same length as
+    /// `instances`. Each rotation costs 16 bytes of GPU memory. Instances past
+    /// the end of a shorter `rotations` are drawn axis-aligned, and appending
+    /// without rotations uploads the whole batch.
+    pub rotations: Vec<Quat>,
+    /// Optional word of application data for each instance, e.g. an ID into
+    /// your own tables, which the renderer never interprets.
+    ///
+    /// Either empty, or the same length as `instances`. It is passed to the
+    /// shader and returned in [`CuboidPickedEvent`](crate::CuboidPickedEvent)s,
+    /// so IDs don't have to be packed into the color or meta bits. Each word
+    /// costs 4 bytes of GPU memory.
+    pub user_data: Vec<u32>,
+    /// Optional colors of the six faces of each instance, ordered `-X`, `+X`,
+    /// `-Y`, `+Y`, `-Z`, `+Z` in the instance's local space, e.g. to set the
+    /// tops apart from the sides.
+    ///
+    /// Either empty, or the same length as `instances`. Face colors are
+    /// encoded in the material's color mode, like [`Cuboid::color`], which
+    /// still decides whether an instance is visible in
+    /// [`COLOR_MODE_SCALAR_HUE`](crate::COLOR_MODE_SCALAR_HUE), and colors the
+    /// interior faces. Changes are only uploaded along with all instances, not
+    /// by [`Cuboids::update_range`] or [`Cuboids::recolor`]. Each instance
+    /// costs 24 more bytes of GPU memory. Face colors are ignored on devices
+    /// without storage buffers.
+    pub face_colors: Vec<[Color; 6]>,
+    /// Optional tile of the [`CuboidsAtlas`](crate::CuboidsAtlas) that is drawn
+    /// on every face of each instance, or [`NO_ATLAS_TILE`](crate::NO_ATLAS_TILE).
+    ///
+    /// Either empty, or the same length as `instances`. Like `face_colors`,
+    /// changes are only uploaded along with all instances, and tiles are
+    /// ignored on devices without storage buffers. Each tile costs 4 bytes of
+    /// GPU memory.
+    pub atlas_tiles: Vec<u32>,
+    /// Optional scalar of each instance, e.g. an ore grade or a density, kept
+    /// apart from [`Cuboid::color`].
+    ///
+    /// Either empty, or the same length as `instances`. Scalars are uploaded
+    /// in place of the colors, so that materials in
+    /// [`COLOR_MODE_SCALAR_HUE`](crate::COLOR_MODE_SCALAR_HUE) color instances
+    /// by them while `color` keeps e.g. an RGB color for the application.
+    /// They're returned in [`CuboidPickedEvent`](crate::CuboidPickedEvent)s,
+    /// and can be changed like colors with [`Cuboids::set_scalars`].
+    pub scalars: Vec<f32>,
+    /// Optional spawn and despawn times of each instance, on the clock of
+    /// `Time::elapsed_seconds_wrapped`, set with [`Cuboids::animate_spawn`]
+    /// and [`Cuboids::animate_despawn`].
+    ///
+    /// Either empty, or the same length as `instances`; instances added with
+    /// [`Cuboids::append`] are given none. Instances grow from their centers for
+    /// [`CuboidEffects::spawn_duration`](crate::CuboidEffects::spawn_duration)
+    /// from their spawn time, and shrink and fade out for
+    /// [`CuboidEffects::despawn_duration`](crate::CuboidEffects::despawn_duration)
+    /// from their despawn time, after which they are removed. Times of
+    /// `f32::NEG_INFINITY` and `f32::INFINITY` respectively mean none. Like
+    /// `face_colors`, changes are only uploaded along with all instances, and
+    /// times are ignored on devices without storage buffers. They cost 8 bytes
+    /// of GPU memory per instance until every animation of the batch has
+    /// finished, when they are cleared, see
+    /// [`CuboidsSpawnEvent`](crate::CuboidsSpawnEvent).
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub spawn_times: Vec<[f32; 2]>,
+    /// Keeps a bounding volume hierarchy of the instances for
+    /// [`Cuboids::iter_in_aabb`], instead of testing every instance.
+    ///
+    /// The hierarchy is built by the first query after the batch changes, so
+    /// only set this for batches that are queried more often than they change.
+    /// Changes through [`Cuboids::update_range`], [`Cuboids::append`],
+    /// [`Cuboids::remove`] and the like are seen right away, and direct
+    /// changes to `instances` or `rotations` from the next frame, or right
+    /// away after [`Cuboids::mark_instances_changed`].
+    pub bvh: bool,
+    /// One bit per instance, set for instances hidden with
+    /// [`Cuboids::set_visible`]. Empty until the first call.
+    hidden_mask: Vec<u32>,
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub(crate) edits: CuboidsEdits,
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub(crate) handles: CuboidHandles,
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub(crate) bvh_cache: CuboidsBvhCache,
+}
+
+/// Changes to a [`Cuboids`] since it was last extracted, made through methods
+/// that can upload them on their own.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CuboidsEdits {
+    pub visibility: bool,
+    /// All instances changed.
+    pub instances: bool,
+    /// Instance ranges of [`Cuboids::update_range`], possibly unsorted and
+    /// overlapping.
+    pub ranges: Vec<Range<usize>>,
+    /// Like `ranges`, for instances of which only the color changed.
+    pub colors: Vec<Range<usize>>,
+    /// Index of the first instance added with [`Cuboids::append`].
+    pub appended_from: Option<usize>,
+}
+
+impl CuboidsEdits {
+    /// Whether the edits can be uploaded without uploading all instances.
+    pub fn is_partial(&self) -> bool {
+        !self.instances
+            && (self.visibility
+                || !self.ranges.is_empty()
+                || !self.colors.is_empty()
+                || self.appended_from.is_some())
+    }
+}
+
+fn push_range(ranges: &mut Vec<Range<usize>>, range: Range<usize>) {
+    if range.is_empty() {
+        return;
+    }
+    // Consecutive updates, like a loop over instances, extend the last range.
+    if let Some(last) = ranges.last_mut() {
+        if last.start <= range.start && range.start <= last.end {
+            last.end = last.end.max(range.end);
+            return;
+        }
+    }
+    ranges.push(range);
+}
+
+/// The first instance of a [`Cuboids`] with inverted or non-finite bounds, see
+/// [`Cuboids::validate`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InvalidCuboid {
+    pub index: usize,
+    pub minimum: Vec3,
+    pub maximum: Vec3,
 }
 
 impl Cuboids {
     pub fn new(instances: Vec<Cuboid>) -> Self {
-        Self { instances }
+        Self {
+            instances,
+            dynamic: false,
+            rotations: Vec::new(),
+            user_data: Vec::new(),
+            face_colors: Vec::new(),
+            atlas_tiles: Vec::new(),
+            scalars: Vec::new(),
+            spawn_times: Vec::new(),
+            hidden_mask: Vec::new(),
+            edits: default(),
+            handles: default(),
+            bvh: false,
+            bvh_cache: default(),
+        }
+    }
+
+    /// Like [`Cuboids::new`], with a rotation for each of `instances`.
+    pub fn with_rotations(instances: Vec<Cuboid>, rotations: Vec<Quat>) -> Self {
+        assert_eq!(instances.len(), rotations.len());
+        Self {
+            rotations,
+            ..Self::new(instances)
+        }
+    }
+
+    /// The instance at `index`, with the rest of what the batch holds for it,
+    /// e.g. to map a [`CuboidPickedEvent::index`](crate::CuboidPickedEvent::index)
+    /// back to application data.
+    pub fn get(&self, index: usize) -> Option<CuboidRef<'_>> {
+        let cuboid = self.instances.get(index)?;
+        Some(CuboidRef {
+            index,
+            cuboid,
+            cuboids: self,
+        })
+    }
+
+    /// The instances whose bounds overlap `region`, in the local space of the
+    /// batch, in increasing order of index.
+    ///
+    /// Rotated instances are tested by the box that bounds them, and hidden
+    /// instances are included, see [`CuboidRef::is_visible`]. Every instance
+    /// is tested unless [`Cuboids::bvh`] is set.
+    pub fn iter_in_aabb(&self, region: Aabb) -> impl Iterator<Item = CuboidRef<'_>> + '_ {
+        let minimum = region.min();
+        let maximum = region.max();
+        let mut indices = Vec::new();
+        if self.bvh {
+            self.bvh_cache
+                .get_or_build(self)
+                .query(minimum, maximum, &mut indices);
+            indices.sort_unstable();
+        } else {
+            indices.extend((0..self.instances.len()).filter(|&i| {
+                let (min, max) = self.instance_bounds(i);
+                min.cmple(maximum.into()).all() && max.cmpge(minimum.into()).all()
+            }));
+        }
+        indices.into_iter().map(|index| CuboidRef {
+            index,
+            cuboid: &self.instances[index],
+            cuboids: self,
+        })
+    }
+
+    /// The rotation of the instance at `index`.
+    pub fn rotation(&self, index: usize) -> Quat {
+        self.rotations.get(index).copied().unwrap_or(Quat::IDENTITY)
+    }
+
+    /// The [`Cuboids::user_data`] of the instance at `index`, or zero.
+    pub fn instance_user_data(&self, index: usize) -> u32 {
+        self.user_data.get(index).copied().unwrap_or(0)
+    }
+
+    /// The [`Cuboids::scalars`] entry of the instance at `index`, if the
+    /// batch has scalars.
+    pub fn instance_scalar(&self, index: usize) -> Option<f32> {
+        self.scalars.get(index).copied()
+    }
+
+    /// The color word uploaded for the instance at `index`: its scalar if it
+    /// has one, and its [`Cuboid::color`] otherwise.
+    pub(crate) fn gpu_color(&self, index: usize) -> u32 {
+        self.scalars
+            .get(index)
+            .map_or(self.instances[index].color, |scalar| scalar.to_bits())
+    }
+
+    /// Creates a cube marker of edge length `size` centered on each of `points`.
+    pub fn from_points(points: &[Vec3], size: f32, color: Color) -> Self {
+        let half_extents = Vec3::splat(0.5 * size);
+        let mut instances = Vec::with_capacity(points.len());
+        for &p in points {
+            instances.push(Cuboid::new(p - half_extents, p + half_extents, color));
+        }
+        Self::new(instances)
+    }
+
+    /// Creates unit cubes for the filled voxels of a grid with `dims` voxels
+    /// along each axis, where voxel `xyz` extends from `xyz` to `xyz + 1`.
+    ///
+    /// `voxel` is called once for each voxel, and returns its color or `None`
+    /// for empty space. Neighboring voxels of the same color are greedily
+    /// merged into larger boxes, first along X, then Y, then Z, which usually
+    /// leaves far fewer instances to upload and draw for dense grids.
+    pub fn from_voxel_grid(dims: UVec3, mut voxel: impl FnMut(UVec3) -> Option<Color>) -> Self {
+        let [nx, ny, nz] = dims.to_array().map(|d| d as usize);
+        let index = |x: usize, y: usize, z: usize| x + nx * (y + ny * z);
+        let mut grid = Vec::with_capacity(nx * ny * nz);
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    grid.push(voxel(UVec3::new(x as u32, y as u32, z as u32)));
+                }
+            }
+        }
+
+        let mut instances = Vec::new();
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    let Some(color) = grid[index(x, y, z)] else {
+                        continue;
+                    };
+                    let mut x1 = x + 1;
+                    while x1 < nx && grid[index(x1, y, z)] == Some(color) {
+                        x1 += 1;
+                    }
+                    let row_matches =
+                        |y: usize, z: usize| (x..x1).all(|x| grid[index(x, y, z)] == Some(color));
+                    let mut y1 = y + 1;
+                    while y1 < ny && row_matches(y1, z) {
+                        y1 += 1;
+                    }
+                    let mut z1 = z + 1;
+                    while z1 < nz && (y..y1).all(|y| row_matches(y, z1)) {
+                        z1 += 1;
+                    }
+                    // Merged voxels are cleared, so they aren't visited again.
+                    for z in z..z1 {
+                        for y in y..y1 {
+                            for x in x..x1 {
+                                grid[index(x, y, z)] = None;
+                            }
+                        }
+                    }
+                    instances.push(Cuboid::new(
+                        Vec3::new(x as f32, y as f32, z as f32),
+                        Vec3::new(x1 as f32, y1 as f32, z1 as f32),
+                        color,
+                    ));
+                }
+            }
+        }
+        Self::new(instances)
+    }
+
+    /// Sets the LOD level of the instance at `index`, see [`Cuboid::set_lod_level`].
+    pub fn set_lod_level(&mut self, index: usize, level: u8) {
+        self.update_range(index..index + 1)[0].set_lod_level(level);
+    }
+
+    /// Makes the instances in `range` emissive or not, see
+    /// [`Cuboid::make_emissive`] and [`CuboidMaterial::emissive_gain`](crate::CuboidMaterial::emissive_gain).
+    ///
+    /// Only the instances in `range` are uploaded, like with [`Cuboids::update_range`].
+    pub fn set_emissive(&mut self, range: Range<usize>, emissive: bool) {
+        for cuboid in self.update_range(range) {
+            if emissive {
+                cuboid.make_emissive();
+            } else {
+                cuboid.make_non_emissive();
+            }
+        }
+    }
+
+    /// Makes the instances in `range` pulse or not, see
+    /// [`Cuboid::make_pulsing`].
+    ///
+    /// Only the instances in `range` are uploaded, like with [`Cuboids::update_range`].
+    pub fn set_pulsing(&mut self, range: Range<usize>, pulsing: bool) {
+        for cuboid in self.update_range(range) {
+            if pulsing {
+                cuboid.make_pulsing();
+            } else {
+                cuboid.make_non_pulsing();
+            }
+        }
+    }
+
+    /// Makes the instances in `range` flash or not, see
+    /// [`Cuboid::make_flashing`].
+    ///
+    /// Only the instances in `range` are uploaded, like with [`Cuboids::update_range`].
+    pub fn set_flashing(&mut self, range: Range<usize>, flashing: bool) {
+        for cuboid in self.update_range(range) {
+            if flashing {
+                cuboid.make_flashing();
+            } else {
+                cuboid.make_non_flashing();
+            }
+        }
+    }
+
+    /// Mutable access to the instances in `range`, which are uploaded without
+    /// the rest of the batch.
+    ///
+    /// The rotations and user data in `range` are uploaded as well, so they
+    /// can be changed at the same time. This is much cheaper than uploading
+    /// all instances when a small part of a large batch is animated. Like with
+    /// [`Cuboids::set_visible`], other direct changes made in the same frame
+    /// are only uploaded after [`Cuboids::mark_instances_changed`].
+    pub fn update_range(&mut self, range: Range<usize>) -> &mut [Cuboid] {
+        push_range(&mut self.edits.ranges, range.clone());
+        self.bvh_cache.clear();
+        &mut self.instances[range]
+    }
+
+    /// Adds `instances` at the end of the batch.
+    ///
+    /// With [`VertexPullingRenderPlugin::buffer_growth_factor`](crate::VertexPullingRenderPlugin::buffer_growth_factor),
+    /// they are uploaded into the spare room of the GPU buffers when they fit,
+    /// without uploading the rest of the batch. Batches with rotations or user
+    /// data must be given as many of those in the same frame. Batches with
+    /// face colors or atlas tiles are always uploaded again.
+    pub fn append(&mut self, instances: impl IntoIterator<Item = Cuboid>) {
+        let len = self.instances.len();
+        self.instances.extend(instances);
+        if !self.spawn_times.is_empty() {
+            self.spawn_times
+                .resize(self.instances.len(), NO_SPAWN_TIMES);
+        }
+        if self.instances.len() > len {
+            self.edits.appended_from.get_or_insert(len);
+            self.bvh_cache.clear();
+        }
+    }
+
+    /// Sets the color of the instance at each of `indices` to the matching
+    /// entry of `colors`.
+    ///
+    /// Colors are kept in their own GPU buffer of 4 bytes per instance, and
+    /// only the colors of these instances are uploaded. This is the cheapest
+    /// way to update a large batch whose geometry doesn't change. Indices in
+    /// increasing order are uploaded in fewer writes. Like with
+    /// [`Cuboids::update_range`], other direct changes made in the same frame
+    /// are only uploaded after [`Cuboids::mark_instances_changed`].
+    pub fn recolor(&mut self, indices: &[usize], colors: &[Color]) {
+        assert_eq!(indices.len(), colors.len());
+        for (&index, &color) in indices.iter().zip(colors) {
+            self.instances[index].color = color;
+            push_range(&mut self.edits.colors, index..index + 1);
+        }
+    }
+
+    /// Like [`Cuboids::recolor`], packing each of `colors` for a material in
+    /// `color_space` with [`pack_color`].
+    pub fn set_colors(
+        &mut self,
+        indices: &[usize],
+        colors: &[bevy::render::color::Color],
+        color_space: ColorSpace,
+    ) {
+        let colors: Vec<Color> = colors
+            .iter()
+            .map(|&color| pack_color(color, color_space))
+            .collect();
+        self.recolor(indices, &colors);
+    }
+
+    /// Sets the [`Cuboids::scalars`] entry of the instance at each of
+    /// `indices` to the matching entry of `scalars`, uploading them like
+    /// [`Cuboids::recolor`].
+    ///
+    /// A batch without scalars gets a scalar of zero for every instance, and
+    /// is uploaded again.
+    pub fn set_scalars(&mut self, indices: &[usize], scalars: &[f32]) {
+        assert_eq!(indices.len(), scalars.len());
+        if self.scalars.len() != self.instances.len() {
+            self.scalars.resize(self.instances.len(), 0.0);
+            self.edits.instances = true;
+        }
+        for (&index, &scalar) in indices.iter().zip(scalars) {
+            self.scalars[index] = scalar;
+            push_range(&mut self.edits.colors, index..index + 1);
+        }
+    }
+
+    /// Shows or hides the instances in `range`, without uploading the
+    /// instances again.
+    ///
+    /// This is independent of [`Cuboid::make_invisible`]: an instance is only
+    /// drawn if both allow it. When nothing else changed, only a small buffer
+    /// of one bit per instance is uploaded. Other changes to `instances` or
+    /// `rotations` made directly in the same frame are only uploaded after
+    /// [`Cuboids::mark_instances_changed`].
+    pub fn set_visible(&mut self, range: Range<usize>, visible: bool) {
+        assert!(range.end <= self.instances.len());
+        let num_words = (self.instances.len() + 31) / 32;
+        if self.hidden_mask.len() != num_words {
+            self.hidden_mask.resize(num_words, 0);
+        }
+        for i in range {
+            let bit = 1 << (i % 32);
+            if visible {
+                self.hidden_mask[i / 32] &= !bit;
+            } else {
+                self.hidden_mask[i / 32] |= bit;
+            }
+        }
+        self.edits.visibility = true;
+    }
+
+    /// Whether the instance at `index` is visible, both by its own
+    /// [`Cuboid::is_visible`] and [`Cuboids::set_visible`].
+    pub fn is_visible(&self, index: usize) -> bool {
+        let hidden = self
+            .hidden_mask
+            .get(index / 32)
+            .map_or(false, |word| word & (1 << (index % 32)) != 0);
+        self.instances[index].is_visible() && !hidden
+    }
+
+    /// Makes the next extraction upload all instances, after changing them
+    /// directly in the same frame as calling [`Cuboids::set_visible`],
+    /// [`Cuboids::update_range`], [`Cuboids::recolor`] or [`Cuboids::append`].
+    pub fn mark_instances_changed(&mut self) {
+        self.edits.instances = true;
+        self.bvh_cache.clear();
+    }
+
+    /// Bits of [`Cuboids::set_visible`], one per instance, with all instances
+    /// visible if there are fewer words than instances.
+    pub(crate) fn hidden_mask(&self) -> &[u32] {
+        &self.hidden_mask
+    }
+
+    /// Grows the instances in `range` from their centers, starting at the
+    /// current `Time::elapsed_seconds_wrapped`, e.g. right after
+    /// [`Cuboids::append`] for blocks that stream in, see
+    /// [`Cuboids::spawn_times`]. The whole batch is uploaded again.
+    pub fn animate_spawn(&mut self, range: Range<usize>, time: &Time) {
+        self.spawn_times
+            .resize(self.instances.len(), NO_SPAWN_TIMES);
+        for times in &mut self.spawn_times[range] {
+            times[0] = time.elapsed_seconds_wrapped();
+        }
+        self.edits.instances = true;
+    }
+
+    /// Shrinks and fades out the instances at `indices`, starting at the
+    /// current `Time::elapsed_seconds_wrapped`, and then removes them with
+    /// [`Cuboids::remove`], see [`Cuboids::spawn_times`]. Instances only fade
+    /// with [`CuboidMaterial::alpha_blend`](crate::CuboidMaterial::alpha_blend)
+    /// or [`dither_alpha`](crate::CuboidMaterial::dither_alpha). The whole
+    /// batch is uploaded again.
+    pub fn animate_despawn(&mut self, indices: &[usize], time: &Time) {
+        self.spawn_times
+            .resize(self.instances.len(), NO_SPAWN_TIMES);
+        for &index in indices {
+            self.spawn_times[index][1] = time.elapsed_seconds_wrapped();
+        }
+        self.edits.instances = true;
+    }
+
+    /// Removes the instances at `indices`, in any order, by moving the last
+    /// instances into their place, along with their rotations, user data, face
+    /// colors, atlas tiles, scalars, spawn times and visibility. Those that
+    /// are shorter than `instances` are padded with their defaults first.
+    ///
+    /// Other instances keep their index unless they're moved, so use a
+    /// [`CuboidHandle`](crate::CuboidHandle) from [`Cuboids::insert`] or
+    /// [`Cuboids::handle`] to keep track of them. The whole batch is uploaded
+    /// again.
+    pub fn remove(&mut self, indices: &[usize]) {
+        let len = self.instances.len();
+        let mut indices = indices.to_vec();
+        // From the back, so the instance moved into each hole is never one that
+        // is still to be removed.
+        indices.sort_unstable_by(|a, b| b.cmp(a));
+        indices.dedup();
+        if !self.handles.is_empty() {
+            self.handles.fill(len);
+        }
+        if !self.hidden_mask.is_empty() {
+            // Instances appended since the last `set_visible` have no bits yet.
+            self.hidden_mask.resize((len + 31) / 32, 0);
+        }
+        self.fit_side_vectors();
+        for &index in &indices {
+            assert!(index < self.instances.len());
+            let last = self.instances.len() - 1;
+            self.instances.swap_remove(index);
+            if !self.rotations.is_empty() {
+                self.rotations.swap_remove(index);
+            }
+            if !self.user_data.is_empty() {
+                self.user_data.swap_remove(index);
+            }
+            if !self.face_colors.is_empty() {
+                self.face_colors.swap_remove(index);
+            }
+            if !self.atlas_tiles.is_empty() {
+                self.atlas_tiles.swap_remove(index);
+            }
+            if !self.scalars.is_empty() {
+                self.scalars.swap_remove(index);
+            }
+            if !self.spawn_times.is_empty() {
+                self.spawn_times.swap_remove(index);
+            }
+            if !self.hidden_mask.is_empty() {
+                let hidden = self.hidden_mask[last / 32] & (1 << (last % 32)) != 0;
+                self.hidden_mask[last / 32] &= !(1 << (last % 32));
+                if hidden {
+                    self.hidden_mask[index / 32] |= 1 << (index % 32);
+                } else {
+                    self.hidden_mask[index / 32] &= !(1 << (index % 32));
+                }
+            }
+            if !self.handles.is_empty() {
+                self.handles.swap_remove(index);
+            }
+        }
+        if !self.hidden_mask.is_empty() {
+            let len = self.instances.len();
+            self.hidden_mask.truncate((len + 31) / 32);
+            // A hidden instance removed from the end leaves its bit behind,
+            // which would hide the next appended instance.
+            if len % 32 != 0 {
+                if let Some(word) = self.hidden_mask.last_mut() {
+                    *word &= (1 << (len % 32)) - 1;
+                }
+            }
+        }
+        self.edits.instances = true;
+        self.bvh_cache.clear();
+    }
+
+    /// Reorders instances (and their rotations, user data, face colors, atlas
+    /// tiles, scalars and spawn times) from farthest to nearest to `viewer`,
+    /// given in the local space of this entity.
+    ///
+    /// This improves the blending of overlapping instances with
+    /// [`CuboidMaterial::alpha_blend`](crate::CuboidMaterial::alpha_blend).
+    /// The whole batch is uploaded again, so only call this when the viewer
+    /// has moved significantly.
+    pub fn sort_back_to_front(&mut self, viewer: Vec3) {
+        let mut order: Vec<usize> = (0..self.instances.len()).collect();
+        let distance_sq = |i: usize| {
+            let c = &self.instances[i];
+            (0.5 * (c.minimum + c.maximum)).distance_squared(viewer)
+        };
+        order.sort_by(|&a, &b| distance_sq(b).total_cmp(&distance_sq(a)));
+        self.fit_side_vectors();
+
+        self.instances = order.iter().map(|&i| self.instances[i]).collect();
+        if !self.rotations.is_empty() {
+            self.rotations = order.iter().map(|&i| self.rotations[i]).collect();
+        }
+        if !self.user_data.is_empty() {
+            self.user_data = order.iter().map(|&i| self.user_data[i]).collect();
+        }
+        if !self.face_colors.is_empty() {
+            self.face_colors = order.iter().map(|&i| self.face_colors[i]).collect();
+        }
+        if !self.atlas_tiles.is_empty() {
+            self.atlas_tiles = order.iter().map(|&i| self.atlas_tiles[i]).collect();
+        }
+        if !self.scalars.is_empty() {
+            self.scalars = order.iter().map(|&i| self.scalars[i]).collect();
+        }
+        if !self.spawn_times.is_empty() {
+            self.spawn_times = order.iter().map(|&i| self.spawn_times[i]).collect();
+        }
+        if !self.hidden_mask.is_empty() {
+            // Instances appended since the last `set_visible` have no bits yet.
+            let mut hidden_mask = vec![0; (order.len() + 31) / 32];
+            for (new, &old) in order.iter().enumerate() {
+                let hidden = self
+                    .hidden_mask
+                    .get(old / 32)
+                    .map_or(false, |word| word & (1 << (old % 32)) != 0);
+                if hidden {
+                    hidden_mask[new / 32] |= 1 << (new % 32);
+                }
+            }
+            self.hidden_mask = hidden_mask;
+        }
+        self.handles.reorder(&order);
+        self.edits.instances = true;
+        self.bvh_cache.clear();
+    }
+
+    /// Pads the per-instance vectors that aren't empty to the length of
+    /// `instances` with their defaults, or truncates them, e.g. after
+    /// instances were pushed onto `instances` directly.
+    fn fit_side_vectors(&mut self) {
+        let len = self.instances.len();
+        if !self.rotations.is_empty() {
+            self.rotations.resize(len, Quat::IDENTITY);
+        }
+        if !self.user_data.is_empty() {
+            self.user_data.resize(len, 0);
+        }
+        if !self.face_colors.is_empty() {
+            self.face_colors.truncate(len);
+            let start = self.face_colors.len();
+            self.face_colors
+                .extend(self.instances[start..].iter().map(|c| [c.color; 6]));
+        }
+        if !self.atlas_tiles.is_empty() {
+            self.atlas_tiles.resize(len, crate::NO_ATLAS_TILE);
+        }
+        if !self.scalars.is_empty() {
+            self.scalars.resize(len, 0.0);
+        }
+        if !self.spawn_times.is_empty() {
+            self.spawn_times.resize(len, NO_SPAWN_TIMES);
+        }
+    }
+
+    /// Checks that every instance [`Cuboid::is_valid`], and returns the first
+    /// that isn't.
+    ///
+    /// In debug builds, changed batches are checked before they're extracted,
+    /// and invalid batches are not drawn, see
+    /// [`CuboidsError::InvalidCuboid`](crate::CuboidsError::InvalidCuboid).
+    pub fn validate(&self) -> Result<(), InvalidCuboid> {
+        self.validate_range(0..self.instances.len())
+    }
+
+    fn validate_range(&self, range: Range<usize>) -> Result<(), InvalidCuboid> {
+        let start = range.start;
+        match self.instances[range].iter().position(|c| !c.is_valid()) {
+            Some(i) => {
+                let cuboid = &self.instances[start + i];
+                Err(InvalidCuboid {
+                    index: start + i,
+                    minimum: cuboid.minimum,
+                    maximum: cuboid.maximum,
+                })
+            }
+            None => Ok(()),
+        }
     }
 
     /// Automatically creates an [`Aabb`] that bounds all `instances`.
+    ///
+    /// [`VertexPullingRenderPlugin`](crate::VertexPullingRenderPlugin) keeps
+    /// this up to date as the [`Aabb`] of every [`Cuboids`] entity, so that
+    /// batches out of view are not extracted or drawn.
     pub fn aabb(&self) -> Aabb {
         let mut min = Vec3::splat(f32::MAX);
         let mut max = Vec3::splat(f32::MIN);
-        for i in self.instances.iter() {
-            min = min.min(i.minimum);
-            max = max.max(i.maximum);
+        for index in 0..self.instances.len() {
+            let (minimum, maximum) = self.instance_bounds(index);
+            min = min.min(minimum);
+            max = max.max(maximum);
         }
         Aabb::from_min_max(min, max)
     }
+
+    /// The minimum and maximum of the box that bounds the instance at `index`
+    /// after its rotation.
+    pub(crate) fn instance_bounds(&self, index: usize) -> (Vec3, Vec3) {
+        let i = &self.instances[index];
+        let center = 0.5 * (i.minimum + i.maximum);
+        let half_extents = 0.5 * (i.maximum - i.minimum);
+        // Bounds of the rotated box; this is exact when there's no rotation.
+        let rotation = Mat3::from_quat(self.rotation(index));
+        let rotated_half_extents = Vec3::new(
+            rotation.row(0).abs().dot(half_extents),
+            rotation.row(1).abs().dot(half_extents),
+            rotation.row(2).abs().dot(half_extents),
+        );
+        (center - rotated_half_extents, center + rotated_half_extents)
+    }
+}
+
+/// One instance of a [`Cuboids`], from [`Cuboids::get`] or
+/// [`Cuboids::iter_in_aabb`].
+#[derive(Clone, Copy)]
+pub struct CuboidRef<'a> {
+    pub index: usize,
+    pub cuboid: &'a Cuboid,
+    cuboids: &'a Cuboids,
+}
+
+impl CuboidRef<'_> {
+    pub fn rotation(&self) -> Quat {
+        self.cuboids.rotation(self.index)
+    }
+
+    /// The [`Cuboids::user_data`] of the instance, or zero.
+    pub fn user_data(&self) -> u32 {
+        self.cuboids.instance_user_data(self.index)
+    }
+
+    pub fn scalar(&self) -> Option<f32> {
+        self.cuboids.instance_scalar(self.index)
+    }
+
+    pub fn face_colors(&self) -> Option<[Color; 6]> {
+        self.cuboids.face_colors.get(self.index).copied()
+    }
+
+    pub fn atlas_tile(&self) -> Option<u32> {
+        self.cuboids.atlas_tiles.get(self.index).copied()
+    }
+
+    /// See [`Cuboids::is_visible`].
+    pub fn is_visible(&self) -> bool {
+        self.cuboids.is_visible(self.index)
+    }
+
+    /// The box that bounds the instance after its rotation, in the local space
+    /// of the batch.
+    pub fn aabb(&self) -> Aabb {
+        let (minimum, maximum) = self.cuboids.instance_bounds(self.index);
+        Aabb::from_min_max(minimum, maximum)
+    }
 }
 
+/// Marks a [`Cuboids`] entity as an invisible occluder.
+///
+/// Occluders are drawn with color writes disabled, so they hide any geometry
+/// behind them without being visible themselves. They still cost the same
+/// vertex work and depth writes as regular cuboids.
+#[derive(Clone, Component, Copy, Debug, Default)]
+pub struct CuboidsOccluder;
+
+/// Makes the next extraction upload all instances of a [`Cuboids`] entity,
+/// and recompute its [`Aabb`], even though change detection didn't see it
+/// change.
+///
+/// Instances are only uploaded again when the batch is changed through
+/// `Mut<Cuboids>`, so that static batches cost nothing to upload after their
+/// first frame. Insert this after changing a batch in ways that change
+/// detection misses, like `bypass_change_detection` or interior mutability.
+/// It's removed at the start of the next frame.
+#[derive(Clone, Component, Copy, Debug, Default)]
+pub struct CuboidsDirty;
+
+/// Marks a batch that failed [`validate_cuboids`], so that it isn't extracted.
+#[derive(Clone, Component, Copy, Debug, Default)]
+pub(crate) struct CuboidsInvalid;
+
+/// Overrides the color of back-facing fragments in a [`Cuboids`] batch.
+///
+/// Cuboids are rendered double-sided, so back faces become visible when the
+/// camera is inside of a cuboid, e.g. when flying through a solid block model.
+/// Showing a distinct interior color makes it obvious that you're looking at
+/// the inside of a solid. The color is always RGB-encoded, like in
+/// [`COLOR_MODE_RGB`](crate::COLOR_MODE_RGB). Without this component, back
+/// faces use the same color as front faces.
+#[derive(Clone, Component, Copy, Debug)]
+pub struct CuboidsInteriorColor(pub Color);
+
+/// Per-batch shader data.
 #[derive(Clone, ShaderType)]
 pub(crate) struct CuboidsTransform {
     pub matrix: Mat4,
     pub inv_matrix: Mat4,
+    pub interior_color: Color,
+    pub has_interior_color: u32,
+    /// Interpolation parameter of a [`CuboidsAnimation`](crate::CuboidsAnimation).
+    pub animation_t: f32,
+    /// Bit 0 is set when [`Cuboids::face_colors`], bit 1 when
+    /// [`Cuboids::atlas_tiles`], and bit 3 when [`Cuboids::spawn_times`], are
+    /// uploaded after the instance colors, in that order. Bit 2 is set for
+    /// batches that are never occlusion culled, see
+    /// [`CuboidMaterial::depth_mode`](crate::CuboidMaterial::depth_mode).
+    pub color_layout: u32,
+    /// [`CuboidMaterialSlots`](crate::CuboidMaterialSlots), packed one byte per slot.
+    pub material_slots: UVec4,
+    /// With [`CuboidsInstanceFormat::Quantized`](crate::CuboidsInstanceFormat::Quantized),
+    /// the local position of quantized zero, and the size of one step.
+    pub quantization_min: Vec3,
+    pub quantization_step: Vec3,
 }
 
 impl CuboidsTransform {
     pub fn new(matrix: Mat4, inv_matrix: Mat4) -> Self {
-        Self { matrix, inv_matrix }
+        Self {
+            matrix,
+            inv_matrix,
+            interior_color: 0,
+            has_interior_color: 0,
+            animation_t: 0.0,
+            color_layout: 0,
+            material_slots: UVec4::ZERO,
+            quantization_min: Vec3::ZERO,
+            quantization_step: Vec3::ZERO,
+        }
     }
 
     pub fn from_matrix(m: Mat4) -> Self {
@@ -116,6 +1165,14 @@ impl CuboidsTransform {
     pub fn position(&self) -> Vec3 {
         self.matrix.col(3).truncate()
     }
+
+    pub fn with_interior_color(mut self, color: Option<&CuboidsInteriorColor>) -> Self {
+        if let Some(c) = color {
+            self.interior_color = c.0;
+            self.has_interior_color = 1;
+        }
+        self
+    }
 }
 
 #[derive(Bundle)]
@@ -125,3 +1182,277 @@ pub struct CuboidsBundle {
     #[bundle]
     pub spatial: SpatialBundle,
 }
+
+/// Recomputes the [`Aabb`] of every changed batch before Bevy checks
+/// visibility, unless only colors or the visibility mask changed.
+pub(crate) fn update_cuboids_aabbs(
+    mut commands: Commands,
+    mut batches: Query<
+        (Entity, &Cuboids, Option<&mut Aabb>, Option<&CuboidsDirty>),
+        Or<(Changed<Cuboids>, With<CuboidsDirty>)>,
+    >,
+) {
+    for (entity, cuboids, maybe_aabb, maybe_dirty) in batches.iter_mut() {
+        if cuboids.instances.is_empty() {
+            continue;
+        }
+        let edits = &cuboids.edits;
+        let bounds_changed = maybe_dirty.is_some()
+            || !edits.is_partial()
+            || !edits.ranges.is_empty()
+            || edits.appended_from.is_some();
+        match maybe_aabb {
+            Some(mut aabb) if bounds_changed => {
+                // Also catches direct changes to the instances.
+                cuboids.bvh_cache.clear();
+                *aabb = cuboids.aabb();
+            }
+            Some(_) => {}
+            None => {
+                commands.entity(entity).insert(cuboids.aabb());
+            }
+        }
+    }
+}
+
+/// Checks the bounds of changed batches in debug builds, so that a bad
+/// instance is reported by index instead of drawing degenerate geometry.
+///
+/// Partial edits only check the edited and appended instances, unless the
+/// batch was already invalid. A batch that becomes valid again is uploaded
+/// whole, since the edits it missed in between weren't extracted.
+pub(crate) fn validate_cuboids(
+    mut commands: Commands,
+    batches: Query<(Entity, &Cuboids, Option<&CuboidsInvalid>), Changed<Cuboids>>,
+    errors: Res<crate::CuboidsErrors>,
+    uploads: Res<CuboidsUploads>,
+) {
+    for (entity, cuboids, maybe_invalid) in batches.iter() {
+        let edits = &cuboids.edits;
+        let result = if maybe_invalid.is_none() && edits.is_partial() {
+            let len = cuboids.instances.len();
+            edits
+                .ranges
+                .iter()
+                .cloned()
+                .chain(edits.appended_from.map(|start| start..len))
+                .try_for_each(|range| {
+                    cuboids.validate_range(range.start.min(len)..range.end.min(len))
+                })
+        } else {
+            cuboids.validate()
+        };
+        match (result, maybe_invalid) {
+            (Err(invalid), _) => {
+                errors.send(crate::CuboidsError::InvalidCuboid { entity, invalid });
+                uploads.send_buffer_event(CuboidsBufferEvent::Failed {
+                    entity,
+                    reason: CuboidsBufferFailure::InvalidCuboid(invalid),
+                });
+                commands.entity(entity).insert(CuboidsInvalid);
+            }
+            (Ok(()), Some(_)) => {
+                commands
+                    .entity(entity)
+                    .remove::<CuboidsInvalid>()
+                    .insert(CuboidsDirty);
+            }
+            (Ok(()), None) => {}
+        }
+    }
+}
+
+/// Edits are uploaded once, at the end of the frame they were made in.
+pub(crate) fn clear_cuboids_edits(
+    mut commands: Commands,
+    mut cuboids: Query<&mut Cuboids, Changed<Cuboids>>,
+    dirty: Query<Entity, With<CuboidsDirty>>,
+) {
+    for mut cuboids in cuboids.iter_mut() {
+        cuboids.bypass_change_detection().edits = default();
+    }
+    for entity in dirty.iter() {
+        commands.entity(entity).remove::<CuboidsDirty>();
+    }
+}
+
+/// Sent once a [`Cuboids`] that was streamed to the GPU over several frames, or
+/// held back by the upload budget, is fully uploaded, in the frame it is first
+/// drawn.
+///
+/// See [`VertexPullingRenderPlugin::streaming_chunk_cuboids`](crate::VertexPullingRenderPlugin::streaming_chunk_cuboids)
+/// and [`VertexPullingRenderPlugin::upload_budget_bytes`](crate::VertexPullingRenderPlugin::upload_budget_bytes).
+#[derive(Clone, Copy, Debug)]
+pub struct CuboidsUploadedEvent {
+    pub entity: Entity,
+}
+
+/// Sent as the GPU buffers of a [`Cuboids`] entity are written and freed, or
+/// when it can't be drawn, e.g. to show loading indicators, react to the GPU
+/// memory budget, or log why a batch doesn't show up.
+///
+/// Events from the render world arrive in the main world a frame later. Sizes
+/// are the bytes of instance data that the batch holds on the GPU, including
+/// the spare room of
+/// [`VertexPullingRenderPlugin::buffer_growth_factor`](crate::VertexPullingRenderPlugin::buffer_growth_factor).
+#[derive(Clone, Copy, Debug)]
+pub enum CuboidsBufferEvent {
+    /// All instances of `entity` were uploaded. Sent for every full upload,
+    /// so every frame for batches that change every frame, but not for
+    /// in-place edits and appends. Streamed batches send it once their last
+    /// chunk is written.
+    Uploaded { entity: Entity, bytes: u64 },
+    /// The instance buffers of `entity` were freed to stay within
+    /// [`VertexPullingRenderPlugin::gpu_memory_budget`](crate::VertexPullingRenderPlugin::gpu_memory_budget).
+    /// It's `Uploaded` again once it is visible.
+    Evicted { entity: Entity, bytes: u64 },
+    /// `entity` isn't uploaded or drawn. Sent along with the matching
+    /// [`CuboidsError`](crate::CuboidsError), as often as it fires.
+    Failed {
+        entity: Entity,
+        reason: CuboidsBufferFailure,
+    },
+}
+
+/// Why a [`CuboidsBufferEvent::Failed`] batch isn't drawn.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CuboidsBufferFailure {
+    /// See [`CuboidsError::InvalidMaterialId`](crate::CuboidsError::InvalidMaterialId).
+    InvalidMaterialId { id: usize },
+    /// See [`CuboidsError::InvalidCuboid`](crate::CuboidsError::InvalidCuboid).
+    InvalidCuboid(InvalidCuboid),
+}
+
+/// Streamed uploads and buffer events of the render world, shared by both
+/// worlds like [`CuboidsErrors`](crate::CuboidsErrors).
+#[derive(Clone, Default, Resource)]
+pub(crate) struct CuboidsUploads {
+    queue: Arc<Mutex<Vec<CuboidsUploadedEvent>>>,
+    buffer_events: Arc<Mutex<Vec<CuboidsBufferEvent>>>,
+}
+
+impl CuboidsUploads {
+    pub fn send(&self, event: CuboidsUploadedEvent) {
+        self.queue.lock().unwrap().push(event);
+    }
+
+    pub fn send_buffer_event(&self, event: CuboidsBufferEvent) {
+        self.buffer_events.lock().unwrap().push(event);
+    }
+
+    fn drain(&self) -> Vec<CuboidsUploadedEvent> {
+        std::mem::take(&mut *self.queue.lock().unwrap())
+    }
+
+    fn drain_buffer_events(&self) -> Vec<CuboidsBufferEvent> {
+        std::mem::take(&mut *self.buffer_events.lock().unwrap())
+    }
+}
+
+pub(crate) fn send_cuboids_uploaded(
+    uploads: Res<CuboidsUploads>,
+    mut events: EventWriter<CuboidsUploadedEvent>,
+    mut buffer_events: EventWriter<CuboidsBufferEvent>,
+) {
+    events.send_batch(uploads.drain());
+    buffer_events.send_batch(uploads.drain_buffer_events());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instances(range: Range<usize>) -> Vec<Cuboid> {
+        range
+            .map(|i| {
+                let minimum = Vec3::splat(i as f32);
+                Cuboid::new(minimum, minimum + Vec3::ONE, 0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn remove_instances_appended_after_set_visible() {
+        let mut cuboids = Cuboids::new(instances(0..4));
+        cuboids.set_visible(1..2, false);
+        cuboids.append(instances(4..44));
+        cuboids.remove(&[43, 1]);
+        assert_eq!(cuboids.instances.len(), 42);
+        assert!((0..42).all(|i| cuboids.is_visible(i)));
+    }
+
+    #[test]
+    fn remove_hidden_last_instance() {
+        let mut cuboids = Cuboids::new(instances(0..4));
+        cuboids.set_visible(3..4, false);
+        cuboids.remove(&[3]);
+        cuboids.append(instances(4..5));
+        assert!(cuboids.is_visible(3));
+    }
+
+    #[test]
+    fn sort_instances_appended_after_set_visible() {
+        let mut cuboids = Cuboids::new(instances(0..2));
+        cuboids.set_visible(0..1, false);
+        cuboids.append(instances(2..42));
+        cuboids.sort_back_to_front(Vec3::ZERO);
+        assert!(!cuboids.is_visible(41));
+        assert!((0..41).all(|i| cuboids.is_visible(i)));
+    }
+
+    #[test]
+    fn spawn_times_follow_appended_instances() {
+        let mut cuboids = Cuboids::new(instances(0..2));
+        cuboids.animate_spawn(0..2, &Time::default());
+        cuboids.append(instances(2..4));
+        assert_eq!(cuboids.spawn_times.len(), 4);
+        cuboids.instances.extend(instances(4..6));
+        cuboids.remove(&[0]);
+        assert_eq!(cuboids.spawn_times.len(), 5);
+        assert_eq!(cuboids.spawn_times[0], NO_SPAWN_TIMES);
+        cuboids.instances.extend(instances(6..7));
+        cuboids.sort_back_to_front(Vec3::ZERO);
+        assert_eq!(cuboids.spawn_times.len(), 6);
+    }
+
+    #[test]
+    fn remove_and_sort_with_short_side_vectors() {
+        let mut cuboids = Cuboids::new(instances(0..3));
+        cuboids.user_data = vec![10, 11, 12];
+        cuboids.face_colors = vec![[20; 6], [21; 6], [22; 6]];
+        cuboids.atlas_tiles = vec![30, 31, 32];
+        cuboids.scalars = vec![40.0, 41.0, 42.0];
+        cuboids.instances.extend(instances(3..5));
+
+        cuboids.remove(&[0]);
+        assert_eq!(cuboids.instances.len(), 4);
+        assert_eq!(cuboids.user_data, vec![0, 11, 12, 0]);
+        assert_eq!(cuboids.face_colors, vec![[0; 6], [21; 6], [22; 6], [0; 6]]);
+        assert_eq!(
+            cuboids.atlas_tiles,
+            vec![crate::NO_ATLAS_TILE, 31, 32, crate::NO_ATLAS_TILE]
+        );
+        assert_eq!(cuboids.scalars, vec![0.0, 41.0, 42.0, 0.0]);
+
+        cuboids.instances.extend(instances(5..6));
+        cuboids.sort_back_to_front(Vec3::ZERO);
+        assert_eq!(cuboids.instances[0].minimum, Vec3::splat(5.0));
+        assert_eq!(cuboids.user_data, vec![0, 0, 0, 12, 11]);
+        assert_eq!(cuboids.atlas_tiles.len(), 5);
+        assert_eq!(cuboids.face_colors.len(), 5);
+        assert_eq!(cuboids.scalars, vec![0.0, 0.0, 0.0, 42.0, 41.0]);
+    }
+
+    #[test]
+    fn depth_bias_saturates() {
+        let mut cuboid = Cuboid::new(Vec3::ZERO, Vec3::ONE, 0);
+        cuboid.make_depth_test_ignored();
+        cuboid.set_depth_bias(u16::MAX);
+        assert_eq!(cuboid.depth_bias(), MAX_DEPTH_BIAS);
+        assert_ne!(cuboid.meta_bits & (1 << 31), 0);
+        cuboid.set_depth_bias(MAX_DEPTH_BIAS + 1);
+        assert_eq!(cuboid.depth_bias(), MAX_DEPTH_BIAS);
+        cuboid.set_depth_bias(1000);
+        assert_eq!(cuboid.depth_bias(), 1000);
+    }
+}
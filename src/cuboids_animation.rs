@@ -0,0 +1,129 @@
+use bevy::{
+    prelude::*,
+    render::{primitives::Aabb, render_resource::ShaderType},
+};
+
+use crate::{Cuboid, CuboidMaterialId};
+
+/// Two snapshots of the same cuboids, drawn interpolated by `t` on the GPU.
+///
+/// The vertex shader mixes the bounds and colors of each instance in `from`
+/// with the instance at the same index in `to`, so time-stepped simulation
+/// output can be played back smoothly. Scalar colors are interpolated before
+/// hue mapping. Visibility, LOD and emissive bits are taken from `from`.
+///
+/// Changing `t` is cheap; the snapshots are only re-uploaded when they are
+/// modified, e.g. by [`CuboidsAnimation::push_snapshot`] once `t` reaches 1.
+///
+/// Animations use the same materials, transforms, buffer chunking and clipping
+/// as cuboids, and each instance is clipped as a whole, depending on its
+/// interpolated center. Rotations, edge shading, wireframes, picking and
+/// shadows are not supported, and nothing is drawn on devices without storage
+/// buffers.
+#[derive(Clone, Component, Debug, Default)]
+pub struct CuboidsAnimation {
+    /// Interpolation from `from` at 0 to `to` at 1, clamped to that range.
+    pub t: f32,
+    from: Vec<Cuboid>,
+    to: Vec<Cuboid>,
+    revision: u64,
+    /// The revision that the [`Aabb`] was last computed for.
+    aabb_revision: Option<u64>,
+}
+
+impl CuboidsAnimation {
+    pub fn new(from: Vec<Cuboid>, to: Vec<Cuboid>) -> Self {
+        assert_eq!(from.len(), to.len());
+        Self {
+            from,
+            to,
+            ..default()
+        }
+    }
+
+    pub fn from(&self) -> &[Cuboid] {
+        &self.from
+    }
+
+    pub fn to(&self) -> &[Cuboid] {
+        &self.to
+    }
+
+    /// Mutable access to both snapshots, which are uploaded again.
+    pub fn snapshots_mut(&mut self) -> (&mut [Cuboid], &mut [Cuboid]) {
+        self.revision += 1;
+        (&mut self.from, &mut self.to)
+    }
+
+    /// Replaces both snapshots, which may change the number of instances.
+    pub fn set_snapshots(&mut self, from: Vec<Cuboid>, to: Vec<Cuboid>) {
+        assert_eq!(from.len(), to.len());
+        self.from = from;
+        self.to = to;
+        self.revision += 1;
+    }
+
+    /// Steps to the next interval: `to` becomes `from`, `next` becomes `to`,
+    /// and `t` is reduced by 1.
+    pub fn push_snapshot(&mut self, next: Vec<Cuboid>) {
+        assert_eq!(next.len(), self.to.len());
+        self.from = std::mem::replace(&mut self.to, next);
+        self.t = (self.t - 1.0).max(0.0);
+        self.revision += 1;
+    }
+
+    /// Bounds both snapshots, and so every interpolated instance.
+    pub fn aabb(&self) -> Aabb {
+        let mut min = Vec3::splat(f32::MAX);
+        let mut max = Vec3::splat(f32::MIN);
+        for i in self.from.iter().chain(self.to.iter()) {
+            min = min.min(i.minimum);
+            max = max.max(i.maximum);
+        }
+        Aabb::from_min_max(min, max)
+    }
+
+    pub(crate) fn revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+/// An instance of a [`CuboidsAnimation`] on the GPU.
+#[derive(Clone, Copy, Debug, ShaderType)]
+pub(crate) struct AnimatedCuboid {
+    pub from: Cuboid,
+    pub to: Cuboid,
+}
+
+#[derive(Bundle)]
+pub struct CuboidsAnimationBundle {
+    pub material_id: CuboidMaterialId,
+    pub animation: CuboidsAnimation,
+    #[bundle]
+    pub spatial: SpatialBundle,
+}
+
+/// Recomputes the [`Aabb`] of every changed animation before Bevy checks
+/// visibility.
+pub(crate) fn update_cuboids_animation_aabbs(
+    mut commands: Commands,
+    mut batches: Query<
+        (Entity, &mut CuboidsAnimation, Option<&mut Aabb>),
+        Changed<CuboidsAnimation>,
+    >,
+) {
+    for (entity, mut animation, maybe_aabb) in batches.iter_mut() {
+        // Skipped when only `t` changed.
+        if animation.from.is_empty() || animation.aabb_revision == Some(animation.revision) {
+            continue;
+        }
+        let revision = animation.revision;
+        animation.bypass_change_detection().aabb_revision = Some(revision);
+        match maybe_aabb {
+            Some(mut aabb) => *aabb = animation.aabb(),
+            None => {
+                commands.entity(entity).insert(animation.aabb());
+            }
+        }
+    }
+}
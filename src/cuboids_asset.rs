@@ -0,0 +1,80 @@
+use crate::{CuboidMaterialId, Cuboids};
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+    utils::{BoxedFuture, HashSet},
+};
+
+/// A [`Cuboids`] batch loaded from a `.cuboids` file.
+///
+/// Files are snapshots written by [`Cuboids::write_snapshot`], so batches
+/// built from source data once can be dropped into the asset folder. Spawn a
+/// [`CuboidsAssetBundle`] to draw one.
+#[derive(Clone, Debug, TypeUuid)]
+#[uuid = "2b0d4c63-7a1e-4f3b-9d8e-5c6a1f27b4e9"]
+pub struct CuboidsAsset {
+    pub cuboids: Cuboids,
+}
+
+/// Loads [`CuboidsAsset`]s from files with the `.cuboids` extension.
+#[derive(Default)]
+pub struct CuboidsAssetLoader;
+
+impl AssetLoader for CuboidsAssetLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let cuboids = Cuboids::read_snapshot(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(CuboidsAsset { cuboids }));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["cuboids"]
+    }
+}
+
+/// A batch drawn from a [`CuboidsAsset`].
+///
+/// Its [`Cuboids`] are inserted once the asset loads, as a copy of the
+/// asset's, and replaced whenever the asset is modified, e.g. by hot reloading
+/// with `AssetPlugin::watch_for_changes`. Until then, and after the asset is
+/// removed, nothing is drawn.
+#[derive(Bundle)]
+pub struct CuboidsAssetBundle {
+    pub asset: Handle<CuboidsAsset>,
+    pub material_id: CuboidMaterialId,
+    #[bundle]
+    pub spatial: SpatialBundle,
+}
+
+pub(crate) fn update_cuboids_from_assets(
+    mut commands: Commands,
+    assets: Res<Assets<CuboidsAsset>>,
+    mut events: EventReader<AssetEvent<CuboidsAsset>>,
+    batches: Query<(Entity, Ref<Handle<CuboidsAsset>>)>,
+) {
+    let mut changed_assets = HashSet::new();
+    for event in events.iter() {
+        let (AssetEvent::Created { handle }
+        | AssetEvent::Modified { handle }
+        | AssetEvent::Removed { handle }) = event;
+        changed_assets.insert(handle.id());
+    }
+
+    for (entity, handle) in batches.iter() {
+        if !handle.is_changed() && !changed_assets.contains(&handle.id()) {
+            continue;
+        }
+        match assets.get(&*handle) {
+            Some(asset) => commands.entity(entity).insert(asset.cuboids.clone()),
+            None => commands.entity(entity).remove::<Cuboids>(),
+        };
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{Cuboid, Cuboids};
+
+use bevy::{prelude::*, tasks::AsyncComputeTaskPool};
+use std::sync::{Arc, Mutex};
+
+/// Fills a [`Cuboids`] from plain data, without any ECS access, so that huge
+/// batches can be built on another thread or async task.
+///
+/// Rotations, user data and scalars are only allocated once an instance has
+/// one, and default to the identity, zero and zero for the others. Hand the
+/// result to a [`PendingCuboids`] to swap it into an entity.
+#[derive(Clone, Debug, Default)]
+pub struct CuboidsBuilder {
+    cuboids: Cuboids,
+}
+
+impl CuboidsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            cuboids: Cuboids::new(Vec::with_capacity(capacity)),
+        }
+    }
+
+    /// See [`Cuboids::dynamic`].
+    pub fn dynamic(mut self, dynamic: bool) -> Self {
+        self.cuboids.dynamic = dynamic;
+        self
+    }
+
+    /// Adds `cuboid`, and returns its index.
+    pub fn push(&mut self, cuboid: Cuboid) -> usize {
+        let cuboids = &mut self.cuboids;
+        cuboids.instances.push(cuboid);
+        let len = cuboids.instances.len();
+        if !cuboids.rotations.is_empty() {
+            cuboids.rotations.resize(len, Quat::IDENTITY);
+        }
+        if !cuboids.user_data.is_empty() {
+            cuboids.user_data.resize(len, 0);
+        }
+        if !cuboids.scalars.is_empty() {
+            cuboids.scalars.resize(len, 0.0);
+        }
+        len - 1
+    }
+
+    /// Adds `cuboid` rotated about its center, see [`Cuboids::rotations`].
+    pub fn push_rotated(&mut self, cuboid: Cuboid, rotation: Quat) -> usize {
+        let index = self.push(cuboid);
+        let len = index + 1;
+        self.cuboids.rotations.resize(len, Quat::IDENTITY);
+        self.cuboids.rotations[index] = rotation;
+        index
+    }
+
+    /// Sets the [`Cuboids::user_data`] of the instance at `index`.
+    pub fn set_user_data(&mut self, index: usize, user_data: u32) {
+        let len = self.cuboids.instances.len();
+        self.cuboids.user_data.resize(len, 0);
+        self.cuboids.user_data[index] = user_data;
+    }
+
+    /// Sets the [`Cuboids::scalars`] entry of the instance at `index`.
+    pub fn set_scalar(&mut self, index: usize, scalar: f32) {
+        let len = self.cuboids.instances.len();
+        self.cuboids.scalars.resize(len, 0.0);
+        self.cuboids.scalars[index] = scalar;
+    }
+
+    pub fn len(&self) -> usize {
+        self.cuboids.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cuboids.instances.is_empty()
+    }
+
+    pub fn build(self) -> Cuboids {
+        self.cuboids
+    }
+}
+
+/// A [`Cuboids`] that is being built elsewhere, and replaces the `Cuboids` of
+/// this entity as soon as it's done.
+///
+/// The swap happens in a single frame, before `Update`, and this component is
+/// removed. The entity keeps drawing its previous instances, if any, until
+/// then. With
+/// [`VertexPullingRenderPlugin::streaming_chunk_cuboids`](crate::VertexPullingRenderPlugin::streaming_chunk_cuboids),
+/// large batches are then uploaded over the following frames and drawn once
+/// whole, with a [`CuboidsUploadedEvent`](crate::CuboidsUploadedEvent), so
+/// neither building nor uploading stalls a frame. The entity needs the
+/// remaining components of a [`CuboidsBundle`](crate::CuboidsBundle).
+#[derive(Clone, Component, Debug, Default)]
+pub struct PendingCuboids {
+    slot: Arc<Mutex<Option<Cuboids>>>,
+}
+
+/// Delivers a built batch to its [`PendingCuboids`], from any thread.
+#[derive(Debug)]
+pub struct CuboidsSender {
+    slot: Arc<Mutex<Option<Cuboids>>>,
+}
+
+impl CuboidsSender {
+    pub fn send(self, cuboids: Cuboids) {
+        *self.slot.lock().unwrap() = Some(cuboids);
+    }
+}
+
+impl PendingCuboids {
+    /// A pending batch, and the sender to deliver it from another thread.
+    pub fn new() -> (Self, CuboidsSender) {
+        let pending = Self::default();
+        let sender = CuboidsSender {
+            slot: pending.slot.clone(),
+        };
+        (pending, sender)
+    }
+
+    /// Runs `build` on Bevy's `AsyncComputeTaskPool`.
+    pub fn spawn(build: impl FnOnce() -> Cuboids + Send + 'static) -> Self {
+        let (pending, sender) = Self::new();
+        AsyncComputeTaskPool::get()
+            .spawn(async move { sender.send(build()) })
+            .detach();
+        pending
+    }
+}
+
+pub(crate) fn apply_pending_cuboids(
+    mut commands: Commands,
+    pending: Query<(Entity, &PendingCuboids)>,
+) {
+    for (entity, pending) in pending.iter() {
+        let Some(cuboids) = pending.slot.lock().unwrap().take() else {
+            continue;
+        };
+        commands
+            .entity(entity)
+            .insert(cuboids)
+            .remove::<PendingCuboids>();
+    }
+}
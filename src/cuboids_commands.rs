@@ -0,0 +1,109 @@
+use crate::{Color, Cuboids};
+
+use bevy::prelude::*;
+
+/// Queued edits of single instances of [`Cuboids`] entities.
+///
+/// Systems that only change a few instances can queue them here instead of
+/// querying `Cuboids` mutably. The edits are applied before Bevy checks
+/// visibility through [`Cuboids::recolor`], [`Cuboids::update_range`] and
+/// [`Cuboids::set_visible`], so only the changed instances are uploaded.
+/// Edits of entities without `Cuboids`, or of instances out of range, are
+/// ignored.
+#[derive(Clone, Debug, Default, Resource)]
+pub struct CuboidsCommands {
+    commands: Vec<CuboidsCommand>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum CuboidsCommand {
+    SetColor {
+        entity: Entity,
+        index: usize,
+        color: Color,
+    },
+    SetBounds {
+        entity: Entity,
+        index: usize,
+        minimum: Vec3,
+        maximum: Vec3,
+    },
+    SetVisible {
+        entity: Entity,
+        index: usize,
+        visible: bool,
+    },
+}
+
+impl CuboidsCommands {
+    /// Sets the [`Cuboid::color`](crate::Cuboid::color) of the instance at
+    /// `index`. Only its color is uploaded.
+    pub fn set_color(&mut self, entity: Entity, index: usize, color: Color) {
+        self.commands.push(CuboidsCommand::SetColor {
+            entity,
+            index,
+            color,
+        });
+    }
+
+    /// Moves the instance at `index` to extend from `minimum` to `maximum`.
+    pub fn set_bounds(&mut self, entity: Entity, index: usize, minimum: Vec3, maximum: Vec3) {
+        self.commands.push(CuboidsCommand::SetBounds {
+            entity,
+            index,
+            minimum,
+            maximum,
+        });
+    }
+
+    /// Hides the instance at `index`, see [`Cuboids::set_visible`].
+    pub fn hide(&mut self, entity: Entity, index: usize) {
+        self.commands.push(CuboidsCommand::SetVisible {
+            entity,
+            index,
+            visible: false,
+        });
+    }
+
+    /// Shows the instance at `index` again after [`Self::hide`].
+    pub fn show(&mut self, entity: Entity, index: usize) {
+        self.commands.push(CuboidsCommand::SetVisible {
+            entity,
+            index,
+            visible: true,
+        });
+    }
+}
+
+pub(crate) fn apply_cuboids_commands(
+    mut commands: ResMut<CuboidsCommands>,
+    mut batches: Query<&mut Cuboids>,
+) {
+    if commands.commands.is_empty() {
+        return;
+    }
+    for command in commands.commands.drain(..) {
+        let (CuboidsCommand::SetColor { entity, index, .. }
+        | CuboidsCommand::SetBounds { entity, index, .. }
+        | CuboidsCommand::SetVisible { entity, index, .. }) = command;
+        let Ok(mut cuboids) = batches.get_mut(entity) else {
+            continue;
+        };
+        if index >= cuboids.instances.len() {
+            continue;
+        }
+        match command {
+            CuboidsCommand::SetColor { color, .. } => cuboids.recolor(&[index], &[color]),
+            CuboidsCommand::SetBounds {
+                minimum, maximum, ..
+            } => {
+                let cuboid = &mut cuboids.update_range(index..index + 1)[0];
+                cuboid.minimum = minimum;
+                cuboid.maximum = maximum;
+            }
+            CuboidsCommand::SetVisible { visible, .. } => {
+                cuboids.set_visible(index..index + 1, visible)
+            }
+        }
+    }
+}
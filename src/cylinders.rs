@@ -0,0 +1,156 @@
+use bevy::{
+    prelude::*,
+    render::{primitives::Aabb, render_resource::ShaderType},
+};
+
+use crate::{Color, CuboidMaterialId, MetaBits, MAX_LOD_LEVEL};
+
+/// A capped cylinder of `radius` around the segment from `start` to `end`,
+/// e.g. a drill-hole interval.
+///
+/// Cylinders are ray traced per fragment, inside of the box that bounds them.
+#[derive(Clone, Copy, Debug, PartialEq, ShaderType)]
+#[repr(C)]
+pub struct Cylinder {
+    pub start: Vec3,
+    pub radius: f32,
+    pub end: Vec3,
+    pub color: Color,
+    /// The same bits as [`Cuboid::meta_bits`](crate::Cuboid::meta_bits). The
+    /// depth bias is ignored.
+    pub meta_bits: MetaBits,
+}
+
+impl Cylinder {
+    pub fn new(start: Vec3, end: Vec3, radius: f32, color: Color) -> Self {
+        Self {
+            start,
+            radius,
+            end,
+            color,
+            meta_bits: 0,
+        }
+    }
+
+    #[inline]
+    pub fn is_visible(&self) -> bool {
+        self.meta_bits & 1 == 0
+    }
+
+    #[inline]
+    pub fn make_visible(&mut self) -> &mut Self {
+        self.meta_bits &= !1;
+        self
+    }
+
+    #[inline]
+    pub fn make_invisible(&mut self) -> &mut Self {
+        self.meta_bits |= 1;
+        self
+    }
+
+    #[inline]
+    pub fn make_emissive(&mut self) -> &mut Self {
+        self.meta_bits |= 0b10;
+        self
+    }
+
+    #[inline]
+    pub fn make_non_emissive(&mut self) -> &mut Self {
+        self.meta_bits &= !0b10;
+        self
+    }
+
+    /// Sets the LOD level in `0..=MAX_LOD_LEVEL`, see
+    /// [`Cuboid::set_lod_level`](crate::Cuboid::set_lod_level).
+    #[inline]
+    pub fn set_lod_level(&mut self, level: u8) -> &mut Self {
+        debug_assert!(level <= MAX_LOD_LEVEL);
+        self.meta_bits &= !0b1100; // clear
+        self.meta_bits |= ((level & 0b11) as u32) << 2; // set
+        self
+    }
+
+    /// The exact bounds of the cylinder, including its caps.
+    pub fn aabb(&self) -> Aabb {
+        let axis = (self.end - self.start).normalize_or_zero();
+        // Each cap is a disk, which extends less along directions closer to
+        // the axis.
+        let extents = self.radius * (Vec3::ONE - axis * axis).max(Vec3::ZERO).sqrt();
+        Aabb::from_min_max(
+            self.start.min(self.end) - extents,
+            self.start.max(self.end) + extents,
+        )
+    }
+}
+
+/// A set of cylinders to be extracted for rendering, alongside any
+/// [`Cuboids`](crate::Cuboids).
+///
+/// Like [`Spheres`](crate::Spheres), cylinders use the same materials,
+/// transforms and clipping as cuboids. Each cylinder is clipped as a whole,
+/// depending on the middle of its segment. Wireframes, picking and shadows are
+/// not supported, and nothing is drawn on devices without storage buffers.
+#[derive(Clone, Component, Debug, Default)]
+pub struct Cylinders {
+    /// Instances to be rendered.
+    pub instances: Vec<Cylinder>,
+}
+
+impl Cylinders {
+    pub fn new(instances: Vec<Cylinder>) -> Self {
+        Self { instances }
+    }
+
+    /// Creates a cylinder of `radius` between each pair of consecutive
+    /// `points`, with the matching entry of `colors`, e.g. for the intervals
+    /// down a drill-hole.
+    pub fn from_polyline(points: &[Vec3], radius: f32, colors: &[Color]) -> Self {
+        assert_eq!(points.len().saturating_sub(1), colors.len());
+        Self::new(
+            points
+                .windows(2)
+                .zip(colors)
+                .map(|(segment, &color)| Cylinder::new(segment[0], segment[1], radius, color))
+                .collect(),
+        )
+    }
+
+    pub fn aabb(&self) -> Aabb {
+        let mut min = Vec3::splat(f32::MAX);
+        let mut max = Vec3::splat(f32::MIN);
+        for c in self.instances.iter() {
+            let aabb = c.aabb();
+            min = min.min(aabb.min().into());
+            max = max.max(aabb.max().into());
+        }
+        Aabb::from_min_max(min, max)
+    }
+}
+
+#[derive(Bundle)]
+pub struct CylindersBundle {
+    pub material_id: CuboidMaterialId,
+    pub cylinders: Cylinders,
+    #[bundle]
+    pub spatial: SpatialBundle,
+}
+
+/// Recomputes the [`Aabb`] of every changed batch before Bevy checks
+/// visibility.
+pub(crate) fn update_cylinders_aabbs(
+    mut commands: Commands,
+    mut batches: Query<(Entity, &Cylinders, Option<&mut Aabb>), Changed<Cylinders>>,
+) {
+    for (entity, cylinders, maybe_aabb) in batches.iter_mut() {
+        if cylinders.instances.is_empty() {
+            continue;
+        }
+        match maybe_aabb {
+            Some(mut aabb) => *aabb = cylinders.aabb(),
+            None => {
+                commands.entity(entity).insert(cylinders.aabb());
+            }
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use bevy::diagnostic::{Diagnostic, DiagnosticId, Diagnostics};
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// What the render world did with the [`Cuboids`](crate::Cuboids) batches in
+/// the last frame it rendered.
+///
+/// Updated every frame, and also recorded as [`Diagnostics`] when Bevy's
+/// `DiagnosticsPlugin` is added, e.g. to be graphed next to the frame rate.
+///
+/// Batches drawn by several cameras are only counted once. Culled instances
+/// are those of visible batches outside of every camera. Instances rejected by
+/// GPU culling are only counted in [`Self::gpu_culling`].
+#[derive(Clone, Debug, Default, PartialEq, Resource)]
+pub struct CuboidsDrawStats {
+    pub batches_drawn: usize,
+    pub instances_drawn: usize,
+    pub instances_culled: usize,
+    /// Bytes of instance buffers on the GPU, including prewarmed buffers.
+    pub gpu_bytes: u64,
+    /// Bytes of instance data written to the GPU in the frame.
+    pub upload_bytes: u64,
+    /// With [`VertexPullingRenderPlugin::gpu_timestamps`](crate::VertexPullingRenderPlugin::gpu_timestamps),
+    /// the last timings that were read back.
+    pub timings: Option<CuboidsTimings>,
+    /// With [`VertexPullingRenderPlugin::gpu_culling_stats`](crate::VertexPullingRenderPlugin::gpu_culling_stats),
+    /// the last instance counts that were read back.
+    pub gpu_culling: Option<CuboidsGpuCulling>,
+}
+
+/// The instances that survived GPU culling in a frame, per batch entity, e.g.
+/// to check how much culling saves and to tune
+/// [`VertexPullingRenderPlugin::max_cuboids_per_chunk`](crate::VertexPullingRenderPlugin::max_cuboids_per_chunk).
+///
+/// Counts are read back asynchronously, so they arrive a few frames late, and
+/// are summed over every camera that culled the batch.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CuboidsGpuCulling {
+    pub batches: HashMap<Entity, CuboidsCullingCounts>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CuboidsCullingCounts {
+    /// Chunks that were culled, once per camera.
+    pub chunks: u32,
+    /// Instances that were tested against the view frustum and depth pyramid.
+    pub instances_tested: u32,
+    /// Instances that were drawn.
+    pub instances_visible: u32,
+}
+
+impl CuboidsGpuCulling {
+    pub fn instances_tested(&self) -> u64 {
+        self.batches
+            .values()
+            .map(|c| u64::from(c.instances_tested))
+            .sum()
+    }
+
+    pub fn instances_visible(&self) -> u64 {
+        self.batches
+            .values()
+            .map(|c| u64::from(c.instances_visible))
+            .sum()
+    }
+}
+
+/// Milliseconds spent on cuboids in a frame, summed over every camera.
+///
+/// The culling and draw times are measured with GPU timestamp queries, and
+/// arrive a few frames late. The draw time covers Bevy's whole main 3D pass,
+/// since cuboids are drawn alongside everything else in it. Uploads aren't a
+/// GPU pass, so the prepare time is measured on the CPU.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CuboidsTimings {
+    /// Writing instance buffers in the render world.
+    pub prepare_ms: f32,
+    /// The GPU culling and depth pyramid passes, zero without GPU culling.
+    pub cull_ms: f32,
+    pub draw_ms: f32,
+}
+
+impl CuboidsDrawStats {
+    pub const BATCHES_DRAWN: DiagnosticId =
+        DiagnosticId::from_u128(0x6a2f_0c1e_8d4b_4f7a_9e35_1b6c_d2a0_7f01);
+    pub const INSTANCES_DRAWN: DiagnosticId =
+        DiagnosticId::from_u128(0x6a2f_0c1e_8d4b_4f7a_9e35_1b6c_d2a0_7f02);
+    pub const INSTANCES_CULLED: DiagnosticId =
+        DiagnosticId::from_u128(0x6a2f_0c1e_8d4b_4f7a_9e35_1b6c_d2a0_7f03);
+    pub const GPU_BYTES: DiagnosticId =
+        DiagnosticId::from_u128(0x6a2f_0c1e_8d4b_4f7a_9e35_1b6c_d2a0_7f04);
+    pub const UPLOAD_BYTES: DiagnosticId =
+        DiagnosticId::from_u128(0x6a2f_0c1e_8d4b_4f7a_9e35_1b6c_d2a0_7f05);
+    pub const PREPARE_MS: DiagnosticId =
+        DiagnosticId::from_u128(0x6a2f_0c1e_8d4b_4f7a_9e35_1b6c_d2a0_7f06);
+    pub const CULL_MS: DiagnosticId =
+        DiagnosticId::from_u128(0x6a2f_0c1e_8d4b_4f7a_9e35_1b6c_d2a0_7f07);
+    pub const DRAW_MS: DiagnosticId =
+        DiagnosticId::from_u128(0x6a2f_0c1e_8d4b_4f7a_9e35_1b6c_d2a0_7f08);
+    pub const INSTANCES_GPU_VISIBLE: DiagnosticId =
+        DiagnosticId::from_u128(0x6a2f_0c1e_8d4b_4f7a_9e35_1b6c_d2a0_7f09);
+
+    /// Timings are only measured with GPU timestamps, and GPU culling counts
+    /// with their readback.
+    fn diagnostics(&self) -> [(DiagnosticId, &'static str, Option<f64>); 9] {
+        let timing = |ms: fn(&CuboidsTimings) -> f32| self.timings.as_ref().map(|t| ms(t) as f64);
+        [
+            (
+                Self::BATCHES_DRAWN,
+                "cuboids_batches_drawn",
+                Some(self.batches_drawn as f64),
+            ),
+            (
+                Self::INSTANCES_DRAWN,
+                "cuboids_instances_drawn",
+                Some(self.instances_drawn as f64),
+            ),
+            (
+                Self::INSTANCES_CULLED,
+                "cuboids_instances_culled",
+                Some(self.instances_culled as f64),
+            ),
+            (
+                Self::GPU_BYTES,
+                "cuboids_gpu_bytes",
+                Some(self.gpu_bytes as f64),
+            ),
+            (
+                Self::UPLOAD_BYTES,
+                "cuboids_upload_bytes",
+                Some(self.upload_bytes as f64),
+            ),
+            (
+                Self::PREPARE_MS,
+                "cuboids_prepare_ms",
+                timing(|t| t.prepare_ms),
+            ),
+            (Self::CULL_MS, "cuboids_cull_ms", timing(|t| t.cull_ms)),
+            (Self::DRAW_MS, "cuboids_draw_ms", timing(|t| t.draw_ms)),
+            (
+                Self::INSTANCES_GPU_VISIBLE,
+                "cuboids_instances_gpu_visible",
+                self.gpu_culling
+                    .as_ref()
+                    .map(|c| c.instances_visible() as f64),
+            ),
+        ]
+    }
+}
+
+/// The [`CuboidsDrawStats`] of the render world, shared by both worlds like
+/// [`CuboidsErrors`](crate::CuboidsErrors).
+#[derive(Clone, Default, Resource)]
+pub(crate) struct RenderedCuboidsDrawStats {
+    stats: Arc<Mutex<CuboidsDrawStats>>,
+}
+
+impl RenderedCuboidsDrawStats {
+    /// Keeps the timings and GPU culling counts, which are read back
+    /// separately.
+    pub fn set(&self, stats: CuboidsDrawStats) {
+        let mut current = self.stats.lock().unwrap();
+        *current = CuboidsDrawStats {
+            timings: current.timings,
+            gpu_culling: current.gpu_culling.take(),
+            ..stats
+        };
+    }
+
+    pub fn set_timings(&self, timings: CuboidsTimings) {
+        self.stats.lock().unwrap().timings = Some(timings);
+    }
+
+    pub fn set_gpu_culling(&self, gpu_culling: CuboidsGpuCulling) {
+        self.stats.lock().unwrap().gpu_culling = Some(gpu_culling);
+    }
+
+    fn get(&self) -> CuboidsDrawStats {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+pub(crate) fn setup_cuboids_draw_stats_diagnostics(diagnostics: Option<ResMut<Diagnostics>>) {
+    let Some(mut diagnostics) = diagnostics else {
+        return;
+    };
+    for (id, name, _) in CuboidsDrawStats::default().diagnostics() {
+        diagnostics.add(Diagnostic::new(id, name, 20));
+    }
+}
+
+pub(crate) fn update_cuboids_draw_stats(
+    rendered: Res<RenderedCuboidsDrawStats>,
+    mut stats: ResMut<CuboidsDrawStats>,
+    diagnostics: Option<ResMut<Diagnostics>>,
+) {
+    let rendered = rendered.get();
+    if let Some(mut diagnostics) = diagnostics {
+        for (id, _, value) in rendered.diagnostics() {
+            if let Some(value) = value {
+                diagnostics.add_measurement(id, || value);
+            }
+        }
+    }
+    // Only written when different, so the resource isn't changed every frame.
+    if *stats != rendered {
+        *stats = rendered;
+    }
+}
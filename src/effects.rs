@@ -0,0 +1,249 @@
+use crate::cuboids::NO_SPAWN_TIMES;
+use crate::Cuboids;
+
+use bevy::{
+    prelude::*,
+    render::{extract_resource::ExtractResource, render_resource::ShaderType},
+};
+
+/// Timing and strength of the effects of instances made pulsing with
+/// [`Cuboid::make_pulsing`](crate::Cuboid::make_pulsing) or flashing with
+/// [`Cuboid::make_flashing`](crate::Cuboid::make_flashing), and of the
+/// animations of [`Cuboids::spawn_times`].
+///
+/// Effects are computed in the shader from the time since startup, so they
+/// animate without recoloring or uploading any instances. Only the drawn
+/// color and size change: CPU picking, bounds and culling use the instance as
+/// is.
+#[derive(Clone, Debug, ExtractResource, Resource)]
+pub struct CuboidEffects {
+    /// Seconds per cycle of pulsing instances.
+    pub pulse_period: f32,
+    /// Pulsing instances are brightened and dimmed by up to this fraction of
+    /// their color, along a sine wave.
+    pub pulse_brightness: f32,
+    /// Pulsing instances grow and shrink about their center by up to this
+    /// fraction of their size, in step with their brightness.
+    pub pulse_scale: f32,
+    /// Seconds per cycle of flashing instances, which are drawn with their
+    /// color for the first half of each cycle and with `flash_brightness`
+    /// times their color for the second.
+    pub flash_period: f32,
+    pub flash_brightness: f32,
+    /// Seconds that spawning instances take to grow to their full size, see
+    /// [`Cuboids::animate_spawn`].
+    pub spawn_duration: f32,
+    /// Seconds that despawning instances take to shrink and fade out before
+    /// they're removed, see [`Cuboids::animate_despawn`].
+    pub despawn_duration: f32,
+}
+
+impl Default for CuboidEffects {
+    fn default() -> Self {
+        Self {
+            pulse_period: 1.0,
+            pulse_brightness: 0.5,
+            pulse_scale: 0.0,
+            flash_period: 0.5,
+            flash_brightness: 0.2,
+            spawn_duration: 0.3,
+            despawn_duration: 0.3,
+        }
+    }
+}
+
+/// Sent as the animations of [`Cuboids::spawn_times`] finish.
+#[derive(Clone, Debug)]
+pub enum CuboidsSpawnEvent {
+    /// The instances at `indices` have grown to their full size.
+    Spawned { entity: Entity, indices: Vec<usize> },
+    /// The instances at `indices` have faded out, and were removed with
+    /// [`Cuboids::remove`] after the `Spawned` event of the same frame, so
+    /// other instances may have moved into their place.
+    Despawned { entity: Entity, indices: Vec<usize> },
+}
+
+#[derive(Clone, Debug, Default, ShaderType)]
+pub(crate) struct GpuCuboidEffects {
+    /// Seconds since startup, wrapped to keep precision.
+    pub time: f32,
+    pub pulse_period: f32,
+    pub pulse_brightness: f32,
+    pub pulse_scale: f32,
+    pub flash_period: f32,
+    pub flash_brightness: f32,
+    /// The period that `time` wraps around at.
+    pub time_wrap_period: f32,
+    pub spawn_duration: f32,
+    pub despawn_duration: f32,
+}
+
+impl GpuCuboidEffects {
+    pub fn new(effects: &CuboidEffects, time: &Time) -> Self {
+        Self {
+            time: time.elapsed_seconds_wrapped(),
+            // Zero periods would divide by zero in the shader.
+            pulse_period: effects.pulse_period.max(1e-3),
+            pulse_brightness: effects.pulse_brightness,
+            pulse_scale: effects.pulse_scale,
+            flash_period: effects.flash_period.max(1e-3),
+            flash_brightness: effects.flash_brightness,
+            time_wrap_period: time.wrap_period().as_secs_f32(),
+            spawn_duration: effects.spawn_duration.max(1e-3),
+            despawn_duration: effects.despawn_duration.max(1e-3),
+        }
+    }
+}
+
+/// Sends a [`CuboidsSpawnEvent`] for the animations of each batch that
+/// finished, removing the despawned instances, and clears the spawn times of
+/// batches that no longer animate.
+///
+/// Times are read on the wrapped clock of the shader, so they must be cleared
+/// before it comes around again. Finished spawns are only reset on the CPU,
+/// since the shader also sees them as finished until the next upload.
+pub(crate) fn finish_cuboid_spawn_animations(
+    time: Res<Time>,
+    effects: Res<CuboidEffects>,
+    mut events: EventWriter<CuboidsSpawnEvent>,
+    mut batches: Query<(Entity, &mut Cuboids)>,
+) {
+    let now = time.elapsed_seconds_wrapped();
+    let wrap_period = time.wrap_period().as_secs_f32();
+    for (entity, mut cuboids) in batches.iter_mut() {
+        if cuboids.spawn_times.is_empty() {
+            continue;
+        }
+        // Instances appended without times.
+        let len = cuboids.instances.len();
+        if cuboids.spawn_times.len() != len {
+            cuboids.spawn_times.resize(len, NO_SPAWN_TIMES);
+        }
+
+        let FinishedSpawnAnimations {
+            spawned,
+            despawned,
+            animating,
+        } = finish_spawn_times(
+            &mut cuboids.bypass_change_detection().spawn_times,
+            &effects,
+            now,
+            wrap_period,
+        );
+
+        if !despawned.is_empty() {
+            cuboids.remove(&despawned);
+        }
+        if !animating {
+            cuboids.spawn_times = Vec::new();
+        }
+        if !spawned.is_empty() {
+            events.send(CuboidsSpawnEvent::Spawned {
+                entity,
+                indices: spawned,
+            });
+        }
+        if !despawned.is_empty() {
+            events.send(CuboidsSpawnEvent::Despawned {
+                entity,
+                indices: despawned,
+            });
+        }
+    }
+}
+
+/// The animations of a batch that [`finish_spawn_times`] found finished.
+#[derive(Debug, Default, PartialEq)]
+struct FinishedSpawnAnimations {
+    spawned: Vec<usize>,
+    despawned: Vec<usize>,
+    /// Some animations are still running.
+    animating: bool,
+}
+
+/// Resets the spawn times of instances that have grown to their full size,
+/// and finds those that have faded out, at `now` on a clock that wraps around
+/// at `wrap_period`.
+fn finish_spawn_times(
+    spawn_times: &mut [[f32; 2]],
+    effects: &CuboidEffects,
+    now: f32,
+    wrap_period: f32,
+) -> FinishedSpawnAnimations {
+    let mut finished = FinishedSpawnAnimations::default();
+    for (index, times) in spawn_times.iter_mut().enumerate() {
+        if times[0].is_finite() {
+            if seconds_since(now, times[0], wrap_period) >= effects.spawn_duration {
+                times[0] = NO_SPAWN_TIMES[0];
+                finished.spawned.push(index);
+            } else {
+                finished.animating = true;
+            }
+        }
+        if times[1].is_finite() {
+            if seconds_since(now, times[1], wrap_period) >= effects.despawn_duration {
+                finished.despawned.push(index);
+            } else {
+                finished.animating = true;
+            }
+        }
+    }
+    finished
+}
+
+/// Seconds from `time` to `now`, like `seconds_since` in the shader.
+fn seconds_since(now: f32, time: f32, wrap_period: f32) -> f32 {
+    let age = now - time;
+    if age < 0.0 {
+        age + wrap_period
+    } else {
+        age
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seconds_since_wraps() {
+        assert_eq!(seconds_since(2.0, 1.5, 10.0), 0.5);
+        assert_eq!(seconds_since(0.25, 9.75, 10.0), 0.5);
+    }
+
+    #[test]
+    fn finish_spawn_times_by_age() {
+        let effects = CuboidEffects {
+            spawn_duration: 0.3,
+            despawn_duration: 0.5,
+            ..default()
+        };
+        let mut spawn_times = vec![
+            // Spawned long ago.
+            [1.0, f32::INFINITY],
+            // Still growing, across the wrap.
+            [9.9, f32::INFINITY],
+            // Faded out, across the wrap.
+            [f32::NEG_INFINITY, 9.5],
+            // Still fading out.
+            [f32::NEG_INFINITY, 0.0],
+            NO_SPAWN_TIMES,
+        ];
+        let finished = finish_spawn_times(&mut spawn_times, &effects, 0.1, 10.0);
+        assert_eq!(
+            finished,
+            FinishedSpawnAnimations {
+                spawned: vec![0],
+                despawned: vec![2],
+                animating: true,
+            }
+        );
+        assert_eq!(spawn_times[0], NO_SPAWN_TIMES);
+        assert_eq!(spawn_times[1][0], 9.9);
+
+        let mut spawn_times = vec![[0.0, f32::INFINITY], NO_SPAWN_TIMES];
+        let finished = finish_spawn_times(&mut spawn_times, &effects, 0.3, 10.0);
+        assert!(!finished.animating);
+        assert_eq!(finished.spawned, vec![0]);
+    }
+}
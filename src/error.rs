@@ -0,0 +1,158 @@
+use bevy::{prelude::*, render::render_resource::CachedRenderPipelineId};
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// A recoverable failure while rendering [`Cuboids`](crate::Cuboids).
+///
+/// These are reported as events in the main world, so read them with an
+/// `EventReader<CuboidsError>`. Each error is also logged when it fires.
+#[derive(Clone, Debug)]
+pub enum CuboidsError {
+    /// The device can't bind as many storage buffers in the vertex stage as
+    /// the cuboids shader needs, even when reading instances from data
+    /// textures, e.g. the `color_keyframes` table on WebGL2. Fires once, when
+    /// the plugin is built; nothing will be drawn.
+    UnsupportedDevice { required: u32, available: u32 },
+    /// A render pipeline failed to compile. Fires once per pipeline; cuboids
+    /// using that pipeline are not drawn.
+    PipelineCompileFailed {
+        pipeline: CachedRenderPipelineId,
+        message: String,
+    },
+    /// The data for a buffer exceeds the device's maximum binding size. Fires
+    /// every frame while it holds; no cuboids are drawn.
+    ///
+    /// Instance data is split into chunks automatically, so this only happens
+    /// for per-batch data, i.e. too many [`Cuboids`](crate::Cuboids) entities,
+    /// or for a color keyframe table that is too long. WebGPU in the browser
+    /// allows much smaller bindings than native backends.
+    BufferTooLarge {
+        label: &'static str,
+        size: u64,
+        max_size: u64,
+    },
+    /// The [`CuboidMaterialMap`](crate::CuboidMaterialMap) is empty. Fires
+    /// every frame while it holds; no cuboids are drawn.
+    EmptyMaterialMap,
+    /// The [`CuboidMaterialId`](crate::CuboidMaterialId) of `entity` is not in
+    /// the [`CuboidMaterialMap`](crate::CuboidMaterialMap), or one of its
+    /// [`CuboidMaterialSlots`](crate::CuboidMaterialSlots) can't be slotted.
+    /// Fires every frame while it holds; the entity is not drawn.
+    InvalidMaterialId { entity: Entity, id: usize },
+    /// More than [`VertexPullingRenderPlugin::max_clipping_planes`](crate::VertexPullingRenderPlugin::max_clipping_planes)
+    /// [`ClippingPlaneRange`](crate::ClippingPlaneRange) entities apply to a
+    /// single view, with `count` the most for any view. Fires every frame
+    /// while it holds; the extra planes are ignored.
+    TooManyClippingPlanes { count: usize, max: usize },
+    /// More than [`MAX_CLIPPING_VOLUMES`](crate::MAX_CLIPPING_VOLUMES)
+    /// [`ClippingBox`](crate::ClippingBox) and [`ClippingSphere`](crate::ClippingSphere)
+    /// entities exist. Fires every frame while it holds; the extra volumes are
+    /// ignored.
+    TooManyClippingVolumes { count: usize },
+    /// An instance of `entity` has inverted or non-finite bounds. Only checked
+    /// in debug builds, when the batch changes; the entity is not drawn until
+    /// a change makes it valid again. Release builds can call
+    /// [`Cuboids::validate`](crate::Cuboids::validate) themselves.
+    InvalidCuboid {
+        entity: Entity,
+        invalid: crate::InvalidCuboid,
+    },
+    /// `entity` has a [`CuboidsComputeSource`](crate::CuboidsComputeSource),
+    /// but its instances are compressed or read from data textures. Fires
+    /// every frame while it holds; the shader isn't run.
+    UnsupportedComputeSource { entity: Entity },
+    /// `entity` was about to be drawn without its instance buffers written.
+    /// This is a bug in the plugin; the entity is skipped for a frame and
+    /// uploaded again.
+    BuffersNotReady { entity: Entity },
+}
+
+impl fmt::Display for CuboidsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedDevice {
+                required,
+                available,
+            } => write!(
+                f,
+                "Cuboids need {required} storage buffers in the vertex stage, but the device only \
+                 supports {available}"
+            ),
+            Self::PipelineCompileFailed { pipeline, message } => {
+                write!(
+                    f,
+                    "Failed to compile cuboids pipeline {pipeline:?}: {message}"
+                )
+            }
+            Self::BufferTooLarge {
+                label,
+                size,
+                max_size,
+            } => write!(
+                f,
+                "Buffer {label} needs {size} bytes, but the device allows at most {max_size}"
+            ),
+            Self::EmptyMaterialMap => write!(f, "Cannot draw Cuboids with empty CuboidMaterialMap"),
+            Self::InvalidMaterialId { entity, id } => {
+                write!(f, "Cuboids {entity:?} has unknown CuboidMaterialId({id})")
+            }
+            Self::TooManyClippingPlanes { count, max } => write!(
+                f,
+                "Too many ClippingPlaneRange entities ({count}), at most {max} are supported"
+            ),
+            Self::TooManyClippingVolumes { count } => write!(
+                f,
+                "Too many ClippingBox and ClippingSphere entities ({count}), at most {} are \
+                 supported",
+                crate::MAX_CLIPPING_VOLUMES
+            ),
+            Self::InvalidCuboid { entity, invalid } => write!(
+                f,
+                "Cuboids {entity:?} has invalid bounds at instance {}: minimum {}, maximum {}",
+                invalid.index, invalid.minimum, invalid.maximum
+            ),
+            Self::UnsupportedComputeSource { entity } => write!(
+                f,
+                "Cuboids {entity:?} has a CuboidsComputeSource, but its instances are not in \
+                 full-precision storage buffers"
+            ),
+            Self::BuffersNotReady { entity } => write!(
+                f,
+                "Cuboids {entity:?} was about to be drawn before its instance buffers were \
+                 written"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CuboidsError {}
+
+/// Collects [`CuboidsError`]s from both the main and render worlds.
+///
+/// The same queue is shared by both worlds. Its contents are sent as
+/// [`CuboidsError`] events in the main world every frame.
+#[derive(Clone, Default, Resource)]
+pub struct CuboidsErrors {
+    queue: Arc<Mutex<Vec<CuboidsError>>>,
+}
+
+impl CuboidsErrors {
+    /// Logs `error` and queues it to be sent as an event.
+    pub fn send(&self, error: CuboidsError) {
+        warn!("{error}");
+        self.queue.lock().unwrap().push(error);
+    }
+
+    fn drain(&self) -> Vec<CuboidsError> {
+        std::mem::take(&mut *self.queue.lock().unwrap())
+    }
+}
+
+pub(crate) fn send_cuboids_errors(
+    errors: Res<CuboidsErrors>,
+    mut events: EventWriter<CuboidsError>,
+) {
+    events.send_batch(errors.drain());
+}
@@ -0,0 +1,278 @@
+use crate::{ClippingPlaneRange, Cuboid, Cuboids};
+
+use bevy::prelude::{Color, GlobalTransform, Mat4, Quat, Vec3};
+use std::io::{self, Write};
+
+/// Corner indices of each face, wound counter-clockwise when viewed from
+/// outside. Corner `i` has coordinates `(i & 1, (i >> 1) & 1, (i >> 2) & 1)`.
+const FACE_CORNERS: [[usize; 4]; 6] = [
+    [0, 4, 6, 2], // -X
+    [1, 3, 7, 5], // +X
+    [0, 1, 5, 4], // -Y
+    [2, 6, 7, 3], // +Y
+    [0, 2, 3, 1], // -Z
+    [4, 5, 7, 6], // +Z
+];
+
+fn corners(cuboid: &Cuboid, rotation: Quat) -> [Vec3; 8] {
+    let center = 0.5 * (cuboid.minimum + cuboid.maximum);
+    let mut corners = [Vec3::ZERO; 8];
+    for (i, corner) in corners.iter_mut().enumerate() {
+        let select = Vec3::new((i & 1) as f32, ((i >> 1) & 1) as f32, ((i >> 2) & 1) as f32);
+        let unrotated = select * cuboid.maximum + (1.0 - select) * cuboid.minimum;
+        *corner = center + rotation * (unrotated - center);
+    }
+    corners
+}
+
+/// Decodes `cuboid.color` as RGB, see [`COLOR_MODE_RGB`](crate::COLOR_MODE_RGB).
+fn rgb_bytes(cuboid: &Cuboid) -> [u8; 3] {
+    let [r, g, b, _] = cuboid.color.to_le_bytes();
+    [r, g, b]
+}
+
+impl Cuboids {
+    /// Writes all visible instances to `writer` as a Wavefront OBJ mesh.
+    ///
+    /// Each cuboid emits 8 vertices and 12 triangles. Vertex colors are written
+    /// with the common `v x y z r g b` extension, assuming RGB-encoded colors.
+    /// Positions are in the local space of the [`Cuboids`] entity, including
+    /// any [`Cuboids::rotations`].
+    ///
+    /// Output is streamed one cuboid at a time, so wrap `writer` in a
+    /// [`BufWriter`](std::io::BufWriter) for large batches.
+    pub fn export_obj(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut num_vertices = 0;
+        for (index, cuboid) in self.visible_instances() {
+            let [r, g, b] = rgb_bytes(cuboid).map(|c| c as f32 / 255.0);
+            for p in corners(cuboid, self.rotation(index)) {
+                writeln!(writer, "v {} {} {} {r} {g} {b}", p.x, p.y, p.z)?;
+            }
+            for [i0, i1, i2, i3] in FACE_CORNERS.map(|f| f.map(|i| num_vertices + i + 1)) {
+                writeln!(writer, "f {i0} {i1} {i2}")?;
+                writeln!(writer, "f {i0} {i2} {i3}")?;
+            }
+            num_vertices += 8;
+        }
+        Ok(())
+    }
+
+    /// Writes all visible instances to `writer` as an ASCII PLY mesh with
+    /// per-vertex colors.
+    ///
+    /// Like [`Cuboids::export_obj`], this emits 8 vertices and 12 triangles per
+    /// cuboid, assumes RGB-encoded colors, and uses local-space positions.
+    pub fn export_ply(&self, writer: &mut impl Write) -> io::Result<()> {
+        let num_visible = self.visible_instances().count();
+
+        writeln!(writer, "ply")?;
+        writeln!(writer, "format ascii 1.0")?;
+        writeln!(writer, "element vertex {}", 8 * num_visible)?;
+        writeln!(writer, "property float x")?;
+        writeln!(writer, "property float y")?;
+        writeln!(writer, "property float z")?;
+        writeln!(writer, "property uchar red")?;
+        writeln!(writer, "property uchar green")?;
+        writeln!(writer, "property uchar blue")?;
+        writeln!(writer, "element face {}", 12 * num_visible)?;
+        writeln!(writer, "property list uchar uint vertex_indices")?;
+        writeln!(writer, "end_header")?;
+
+        for (index, cuboid) in self.visible_instances() {
+            let [r, g, b] = rgb_bytes(cuboid);
+            for p in corners(cuboid, self.rotation(index)) {
+                writeln!(writer, "{} {} {} {r} {g} {b}", p.x, p.y, p.z)?;
+            }
+        }
+        for i in 0..num_visible {
+            for [i0, i1, i2, i3] in FACE_CORNERS.map(|f| f.map(|c| 8 * i + c)) {
+                writeln!(writer, "3 {i0} {i1} {i2}")?;
+                writeln!(writer, "3 {i0} {i2} {i3}")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn visible_instances(&self) -> impl Iterator<Item = (usize, &Cuboid)> {
+        self.instances
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.is_visible())
+    }
+}
+
+/// Merges the instances of several [`Cuboids`] batches into a single
+/// world-space triangle mesh, to write as OBJ or binary glTF for tools that
+/// can't run the instanced renderer.
+///
+/// Like [`Cuboids::export_obj`], each cuboid becomes 8 vertices and 12
+/// triangles, with colors decoded as RGB. Instances hidden by
+/// [`Cuboid::make_invisible`] or [`Cuboids::set_visible`] are skipped unless
+/// [`Self::include_hidden`] is called, and so are instances whose centroid is
+/// cut away by one of the clipping planes added with [`Self::clip`]. The mesh
+/// is kept in memory until it's written, at 120 bytes per cuboid.
+#[derive(Clone, Debug, Default)]
+pub struct CuboidsMeshExport {
+    include_hidden: bool,
+    clipping_planes: Vec<(Vec3, Vec3, ClippingPlaneRange)>,
+    positions: Vec<Vec3>,
+    /// Nonlinear sRGB, as encoded in the instances.
+    colors: Vec<[u8; 3]>,
+}
+
+impl CuboidsMeshExport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps hidden instances in batches added after this call.
+    pub fn include_hidden(&mut self) -> &mut Self {
+        self.include_hidden = true;
+        self
+    }
+
+    /// Skips instances of batches added after this call whose centroid is
+    /// clipped by `range` of a clipping plane at `transform`, like the
+    /// renderer does with a [`ClippingPlaneBundle`](crate::ClippingPlaneBundle)
+    /// and [`ClippingCombine::All`](crate::ClippingCombine::All).
+    pub fn clip(&mut self, range: &ClippingPlaneRange, transform: &GlobalTransform) -> &mut Self {
+        let (_, rotation, translation) = transform.to_scale_rotation_translation();
+        self.clipping_planes
+            .push((translation, rotation * Vec3::X, range.clone()));
+        self
+    }
+
+    /// Adds the instances of `cuboids`, placed by the [`GlobalTransform`] of
+    /// its entity.
+    pub fn add(&mut self, cuboids: &Cuboids, transform: &GlobalTransform) -> &mut Self {
+        let matrix: Mat4 = transform.compute_matrix();
+        for (index, cuboid) in cuboids.instances.iter().enumerate() {
+            if !self.include_hidden && !cuboids.is_visible(index) {
+                continue;
+            }
+            let centroid = matrix.transform_point3(0.5 * (cuboid.minimum + cuboid.maximum));
+            let clipped = self
+                .clipping_planes
+                .iter()
+                .any(|(origin, normal, range)| !range.keeps((centroid - *origin).dot(*normal)));
+            if clipped {
+                continue;
+            }
+            let color = rgb_bytes(cuboid);
+            for corner in corners(cuboid, cuboids.rotation(index)) {
+                self.positions.push(matrix.transform_point3(corner));
+                self.colors.push(color);
+            }
+        }
+        self
+    }
+
+    /// The number of cuboids in the mesh.
+    pub fn len(&self) -> usize {
+        self.positions.len() / 8
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Writes the mesh as a Wavefront OBJ, with the vertex colors of
+    /// [`Cuboids::export_obj`].
+    pub fn write_obj(&self, writer: &mut impl Write) -> io::Result<()> {
+        for (p, color) in self.positions.iter().zip(&self.colors) {
+            let [r, g, b] = color.map(|c| c as f32 / 255.0);
+            writeln!(writer, "v {} {} {} {r} {g} {b}", p.x, p.y, p.z)?;
+        }
+        for i in 0..self.len() {
+            for [i0, i1, i2, i3] in FACE_CORNERS.map(|f| f.map(|c| 8 * i + c + 1)) {
+                writeln!(writer, "f {i0} {i1} {i2}")?;
+                writeln!(writer, "f {i0} {i2} {i3}")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the mesh as a binary glTF 2.0 (`.glb`) file with a single node.
+    ///
+    /// Vertex colors are converted to linear `COLOR_0`, as glTF requires.
+    /// There are no normals, so viewers shade the faces flat.
+    pub fn write_glb(&self, writer: &mut impl Write) -> io::Result<()> {
+        let num_vertices = self.positions.len();
+        let num_indices = 36 * self.len();
+        let positions_size = 12 * num_vertices;
+        let colors_size = 12 * num_vertices;
+
+        let mut bin = Vec::with_capacity(positions_size + colors_size + 4 * num_indices);
+        for p in &self.positions {
+            for component in p.to_array() {
+                bin.extend(component.to_le_bytes());
+            }
+        }
+        for &[r, g, b] in &self.colors {
+            let [r, g, b, _] = Color::rgb_u8(r, g, b).as_linear_rgba_f32();
+            for component in [r, g, b] {
+                bin.extend(component.to_le_bytes());
+            }
+        }
+        for i in 0..self.len() as u32 {
+            for [i0, i1, i2, i3] in FACE_CORNERS.map(|f| f.map(|c| 8 * i + c as u32)) {
+                for index in [i0, i1, i2, i0, i2, i3] {
+                    bin.extend(index.to_le_bytes());
+                }
+            }
+        }
+
+        let asset = r#""asset":{"version":"2.0","generator":"bevy_aabb_instancing"}"#;
+        let mut json = if self.is_empty() {
+            format!(r#"{{{asset},"scene":0,"scenes":[{{}}]}}"#)
+        } else {
+            let min = self.positions.iter().copied().fold(Vec3::MAX, Vec3::min);
+            let max = self.positions.iter().copied().fold(Vec3::MIN, Vec3::max);
+            format!(
+                concat!(
+                    r#"{{{asset},"scene":0,"scenes":[{{"nodes":[0]}}],"nodes":[{{"mesh":0}}],"#,
+                    r#""meshes":[{{"primitives":[{{"attributes":{{"POSITION":0,"COLOR_0":1}},"indices":2}}]}}],"#,
+                    r#""buffers":[{{"byteLength":{bin_size}}}],"#,
+                    r#""bufferViews":["#,
+                    r#"{{"buffer":0,"byteOffset":0,"byteLength":{positions_size},"target":34962}},"#,
+                    r#"{{"buffer":0,"byteOffset":{positions_size},"byteLength":{colors_size},"target":34962}},"#,
+                    r#"{{"buffer":0,"byteOffset":{indices_offset},"byteLength":{indices_size},"target":34963}}],"#,
+                    r#""accessors":["#,
+                    r#"{{"bufferView":0,"componentType":5126,"count":{num_vertices},"type":"VEC3","min":{min:?},"max":{max:?}}},"#,
+                    r#"{{"bufferView":1,"componentType":5126,"count":{num_vertices},"type":"VEC3"}},"#,
+                    r#"{{"bufferView":2,"componentType":5125,"count":{num_indices},"type":"SCALAR"}}]}}"#,
+                ),
+                asset = asset,
+                bin_size = bin.len(),
+                positions_size = positions_size,
+                colors_size = colors_size,
+                indices_offset = positions_size + colors_size,
+                indices_size = 4 * num_indices,
+                num_vertices = num_vertices,
+                num_indices = num_indices,
+                min = min.to_array(),
+                max = max.to_array(),
+            )
+        };
+        // Chunks are 4-byte aligned, JSON with spaces and binary with zeros.
+        while json.len() % 4 != 0 {
+            json.push(' ');
+        }
+        bin.resize((bin.len() + 3) / 4 * 4, 0);
+
+        let bin_chunk_size = if bin.is_empty() { 0 } else { 8 + bin.len() };
+        let total_size = 12 + 8 + json.len() + bin_chunk_size;
+        writer.write_all(b"glTF")?;
+        writer.write_all(&2u32.to_le_bytes())?;
+        writer.write_all(&(total_size as u32).to_le_bytes())?;
+        writer.write_all(&(json.len() as u32).to_le_bytes())?;
+        writer.write_all(b"JSON")?;
+        writer.write_all(json.as_bytes())?;
+        if !bin.is_empty() {
+            writer.write_all(&(bin.len() as u32).to_le_bytes())?;
+            writer.write_all(b"BIN\0")?;
+            writer.write_all(&bin)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+use bevy::{
+    pbr::{FogFalloff, FogSettings},
+    prelude::*,
+    render::render_resource::ShaderType,
+};
+
+// Zero, the default, disables fog.
+const FOG_MODE_LINEAR: u32 = 1;
+const FOG_MODE_EXPONENTIAL: u32 = 2;
+const FOG_MODE_EXPONENTIAL_SQUARED: u32 = 3;
+const FOG_MODE_ATMOSPHERIC: u32 = 4;
+
+/// Bevy's [`FogSettings`] for one camera, as seen by the cuboids shader.
+///
+/// Directional light inscattering is not supported, so
+/// [`FogSettings::directional_light_color`] is ignored.
+#[derive(Clone, Component, Debug, Default, ShaderType)]
+pub(crate) struct GpuCuboidsFog {
+    /// Linear RGB, with the alpha scaling the amount of fog.
+    pub color: Vec4,
+    /// `(start, end, _)` for linear fog, `(density, _, _)` for exponential
+    /// fog, or the extinction of atmospheric fog.
+    pub falloff: Vec3,
+    pub mode: u32,
+    /// The inscattering of atmospheric fog.
+    pub inscattering: Vec3,
+}
+
+impl GpuCuboidsFog {
+    pub fn new(fog: &FogSettings) -> Self {
+        let (mode, falloff, inscattering) = match fog.falloff {
+            FogFalloff::Linear { start, end } => {
+                (FOG_MODE_LINEAR, Vec3::new(start, end, 0.0), Vec3::ZERO)
+            }
+            FogFalloff::Exponential { density } => (
+                FOG_MODE_EXPONENTIAL,
+                Vec3::new(density, 0.0, 0.0),
+                Vec3::ZERO,
+            ),
+            FogFalloff::ExponentialSquared { density } => (
+                FOG_MODE_EXPONENTIAL_SQUARED,
+                Vec3::new(density, 0.0, 0.0),
+                Vec3::ZERO,
+            ),
+            FogFalloff::Atmospheric {
+                extinction,
+                inscattering,
+            } => (FOG_MODE_ATMOSPHERIC, extinction, inscattering),
+        };
+        Self {
+            color: Vec4::from(fog.color.as_linear_rgba_f32()),
+            falloff,
+            mode,
+            inscattering,
+        }
+    }
+}
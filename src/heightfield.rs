@@ -0,0 +1,160 @@
+use crate::{Color, Cuboid, Cuboids};
+
+use bevy::{
+    math::{UVec2, Vec3},
+    render::{render_resource::TextureFormat, texture::Image},
+};
+
+/// A grid of heights, e.g. a terrain heightmap, to be built into a column of
+/// cuboids per cell with [`CuboidsHeightfield::build`].
+///
+/// Cell `(x, z)` covers `x * cell_size..(x + 1) * cell_size` along X, and
+/// likewise along Z, and its column extends from `base` up to
+/// `base + height_scale * height`. Columns of non-positive height are left
+/// out.
+#[derive(Clone, Debug)]
+pub struct CuboidsHeightfield {
+    /// Cells along X and Z.
+    pub size: UVec2,
+    /// Height of each cell, row by row along X, one row per Z.
+    pub heights: Vec<f32>,
+    pub cell_size: f32,
+    pub height_scale: f32,
+    pub base: f32,
+    /// Color below the first stop of `color_ramp`, or of every column
+    /// without one.
+    pub color: Color,
+    /// Elevations in increasing order, each with the color of everything
+    /// above it up to the next stop.
+    ///
+    /// Columns are cut at the stops they cross, so that e.g. rock shows
+    /// through below a snowy top. Each band of a column is a single cuboid.
+    pub color_ramp: Vec<(f32, Color)>,
+    /// Rounds columns to whole layers of this height, for a voxel look.
+    ///
+    /// Bands are then cut between layers, each layer having the color of the
+    /// elevation of its middle, and the layers of a band are merged into one
+    /// cuboid.
+    pub layer_height: Option<f32>,
+    /// Sets the [`Cuboids::scalars`] entry of each cuboid to the elevation of
+    /// its top, so that materials in
+    /// [`COLOR_MODE_SCALAR_HUE`](crate::COLOR_MODE_SCALAR_HUE) are colored by
+    /// elevation.
+    pub elevation_scalars: bool,
+}
+
+impl Default for CuboidsHeightfield {
+    fn default() -> Self {
+        Self {
+            size: UVec2::ZERO,
+            heights: Vec::new(),
+            cell_size: 1.0,
+            height_scale: 1.0,
+            base: 0.0,
+            color: 0xFFFFFFFF,
+            color_ramp: Vec::new(),
+            layer_height: None,
+            elevation_scalars: false,
+        }
+    }
+}
+
+impl CuboidsHeightfield {
+    pub fn new(size: UVec2, heights: Vec<f32>) -> Self {
+        assert_eq!(heights.len(), (size.x * size.y) as usize);
+        Self {
+            size,
+            heights,
+            ..Self::default()
+        }
+    }
+
+    /// A heightfield with a cell per pixel of `image`, and heights from zero
+    /// to one in its first channel, with the top row of the image at Z zero.
+    ///
+    /// Returns `None` for formats other than `R8Unorm`, `Rgba8Unorm`,
+    /// `Rgba8UnormSrgb`, `R16Unorm` and `R32Float`.
+    pub fn from_image(image: &Image) -> Option<Self> {
+        let size = image.size().as_uvec2();
+        let data = &image.data;
+        let heights: Vec<f32> = match image.texture_descriptor.format {
+            TextureFormat::R8Unorm => data.iter().map(|&v| f32::from(v) / 255.0).collect(),
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => data
+                .chunks_exact(4)
+                .map(|p| f32::from(p[0]) / 255.0)
+                .collect(),
+            TextureFormat::R16Unorm => data
+                .chunks_exact(2)
+                .map(|p| f32::from(u16::from_le_bytes([p[0], p[1]])) / 65535.0)
+                .collect(),
+            TextureFormat::R32Float => data
+                .chunks_exact(4)
+                .map(|p| f32::from_le_bytes([p[0], p[1], p[2], p[3]]))
+                .collect(),
+            _ => return None,
+        };
+        (heights.len() == (size.x * size.y) as usize).then(|| Self::new(size, heights))
+    }
+
+    /// The color of elevation `y`.
+    pub fn color_at(&self, y: f32) -> Color {
+        self.color_ramp
+            .iter()
+            .take_while(|&&(stop, _)| stop <= y)
+            .last()
+            .map_or(self.color, |&(_, color)| color)
+    }
+
+    pub fn build(&self) -> Cuboids {
+        let mut instances = Vec::new();
+        let mut scalars = Vec::new();
+        let mut cuts = Vec::new();
+        for z in 0..self.size.y {
+            for x in 0..self.size.x {
+                let height = self.height_scale * self.heights[(x + z * self.size.x) as usize];
+                let height = match self.layer_height {
+                    Some(layer) if layer > 0.0 => (height / layer).round() * layer,
+                    _ => height,
+                };
+                if height.is_nan() || height <= 0.0 {
+                    continue;
+                }
+                let top = self.base + height;
+
+                // Band boundaries inside of the column, between layers if any.
+                cuts.clear();
+                cuts.push(self.base);
+                for &(stop, _) in &self.color_ramp {
+                    let cut = match self.layer_height {
+                        Some(layer) if layer > 0.0 => {
+                            self.base + ((stop - self.base) / layer - 0.5).ceil() * layer
+                        }
+                        _ => stop,
+                    };
+                    if cut > *cuts.last().unwrap() && cut < top {
+                        cuts.push(cut);
+                    }
+                }
+                cuts.push(top);
+
+                let min_xz = self.cell_size * Vec3::new(x as f32, 0.0, z as f32);
+                let max_xz = min_xz + Vec3::new(self.cell_size, 0.0, self.cell_size);
+                for band in cuts.windows(2) {
+                    let (bottom, top) = (band[0], band[1]);
+                    let color = self.color_at(0.5 * (bottom + top));
+                    instances.push(Cuboid::new(
+                        Vec3::new(min_xz.x, bottom, min_xz.z),
+                        Vec3::new(max_xz.x, top, max_xz.z),
+                        color,
+                    ));
+                    if self.elevation_scalars {
+                        scalars.push(top);
+                    }
+                }
+            }
+        }
+        let mut cuboids = Cuboids::new(instances);
+        cuboids.scalars = scalars;
+        cuboids
+    }
+}
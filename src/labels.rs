@@ -0,0 +1,301 @@
+use crate::{CuboidSelection, Cuboids};
+
+use bevy::{
+    prelude::*, render::camera::NormalizedRenderTarget, ui::UiSystem, utils::HashMap,
+    window::PrimaryWindow,
+};
+
+/// Draws text labels over [`Cuboids`] instances, e.g. the grade of picked
+/// blocks (`labels` feature).
+///
+/// Each label is a Bevy UI text node that faces the screen, centered on the
+/// projected center of its instance in the viewport of
+/// [`CuboidLabelSettings::camera`]. Crowded labels are decluttered: nearer
+/// instances are labeled first, and a label is only shown if its anchor is at
+/// least [`CuboidLabelSettings::min_spacing`] away from every label already
+/// shown. Labels follow their instances a frame late, since they're placed
+/// before UI layout, with the transforms of the previous frame. Labels aren't
+/// hidden by other geometry or clipping planes.
+pub struct CuboidLabelsPlugin;
+
+impl Plugin for CuboidLabelsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CuboidLabelSettings>()
+            .init_resource::<CuboidLabelNodes>()
+            .add_system(
+                update_cuboid_labels
+                    .in_base_set(CoreSet::PostUpdate)
+                    .before(UiSystem::Flex),
+            );
+    }
+}
+
+/// The labels of the instances of a [`Cuboids`] entity, by index into
+/// [`Cuboids::instances`].
+#[derive(Clone, Component, Debug, Default)]
+pub struct CuboidLabels {
+    labels: HashMap<usize, CuboidLabel>,
+}
+
+impl CuboidLabels {
+    pub fn set(&mut self, index: usize, label: CuboidLabel) {
+        self.labels.insert(index, label);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<CuboidLabel> {
+        self.labels.remove(&index)
+    }
+
+    pub fn get(&self, index: usize) -> Option<&CuboidLabel> {
+        self.labels.get(&index)
+    }
+
+    pub fn clear(&mut self) {
+        self.labels.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &CuboidLabel)> + '_ {
+        self.labels.iter().map(|(&index, label)| (index, label))
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+}
+
+/// What a label shows.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CuboidLabel {
+    Text(String),
+    /// The [`Cuboids::scalars`] entry of the instance with this many decimals,
+    /// followed by `unit`, e.g. `" g/t"`. Follows changes to the scalar.
+    /// Instances of batches without scalars aren't labeled.
+    Scalar {
+        decimals: usize,
+        unit: String,
+    },
+    /// The [`Cuboids::user_data`] of the instance.
+    UserData,
+}
+
+impl CuboidLabel {
+    /// The text of this label for the instance at `index` of `cuboids`.
+    pub fn text(&self, cuboids: &Cuboids, index: usize) -> Option<String> {
+        match self {
+            Self::Text(text) => Some(text.clone()),
+            Self::Scalar { decimals, unit } => {
+                let scalar = cuboids.instance_scalar(index)?;
+                Some(format!("{scalar:.decimals$}{unit}"))
+            }
+            Self::UserData => Some(cuboids.instance_user_data(index).to_string()),
+        }
+    }
+}
+
+/// Controls how [`CuboidLabelsPlugin`] draws labels.
+#[derive(Clone, Debug, Resource)]
+pub struct CuboidLabelSettings {
+    /// The camera whose viewport labels are placed in, or the highest-order
+    /// active camera that renders to the primary window if `None`.
+    pub camera: Option<Entity>,
+    /// Bevy's default font if left at the default handle, which needs the
+    /// `bevy/default_font` feature.
+    pub font: Handle<Font>,
+    pub font_size: f32,
+    pub color: Color,
+    /// Smallest distance between the anchors of two shown labels, in logical
+    /// pixels.
+    pub min_spacing: f32,
+    /// Upper bound on the labels shown at once.
+    pub max_labels: usize,
+    /// Also labels each instance of the [`CuboidSelection`] that has no
+    /// [`CuboidLabels`] entry with this, e.g. a
+    /// [`CuboidLabel::Scalar`] to show the value of picked blocks.
+    pub selection_label: Option<CuboidLabel>,
+}
+
+impl Default for CuboidLabelSettings {
+    fn default() -> Self {
+        Self {
+            camera: None,
+            font: default(),
+            font_size: 14.0,
+            color: Color::WHITE,
+            min_spacing: 48.0,
+            max_labels: 256,
+            selection_label: None,
+        }
+    }
+}
+
+/// Text nodes reused for the labels shown each frame.
+#[derive(Default, Resource)]
+pub(crate) struct CuboidLabelNodes {
+    nodes: Vec<Entity>,
+}
+
+/// A label that may be shown, at its anchor in window coordinates.
+struct LabelCandidate {
+    distance_sq: f32,
+    position: Vec2,
+    text: String,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn update_cuboid_labels(
+    mut commands: Commands,
+    settings: Res<CuboidLabelSettings>,
+    selection: Res<CuboidSelection>,
+    mut label_nodes: ResMut<CuboidLabelNodes>,
+    windows: Query<Entity, With<PrimaryWindow>>,
+    cameras: Query<(Entity, &Camera, &GlobalTransform)>,
+    batches: Query<(
+        Entity,
+        &Cuboids,
+        &GlobalTransform,
+        &ComputedVisibility,
+        Option<&CuboidLabels>,
+    )>,
+    mut nodes: Query<(&mut Text, &mut Style, &mut Visibility, &Node)>,
+) {
+    let primary_window = windows.get_single().ok();
+    let camera = match settings.camera {
+        Some(entity) => cameras.get(entity).ok(),
+        None => cameras
+            .iter()
+            .filter(|(_, camera, _)| {
+                let target = camera.target.normalize(primary_window);
+                camera.is_active && matches!(target, Some(NormalizedRenderTarget::Window(_)))
+            })
+            .max_by_key(|(_, camera, _)| camera.order),
+    };
+
+    let mut candidates = Vec::new();
+    if let Some((_, camera, camera_transform)) = camera {
+        if let Some((viewport_min, viewport_max)) = camera.logical_viewport_rect() {
+            let viewport_size = viewport_max - viewport_min;
+            let camera_position = camera_transform.translation();
+            let mut add_candidate = |cuboids: &Cuboids,
+                                     transform: &GlobalTransform,
+                                     index: usize,
+                                     label: &CuboidLabel| {
+                if index >= cuboids.instances.len() || !cuboids.is_visible(index) {
+                    return;
+                }
+                let cuboid = &cuboids.instances[index];
+                let center = transform.transform_point(0.5 * (cuboid.minimum + cuboid.maximum));
+                let Some(p) = camera.world_to_viewport(camera_transform, center) else {
+                    return;
+                };
+                if p.cmplt(Vec2::ZERO).any() || p.cmpgt(viewport_size).any() {
+                    return;
+                }
+                let Some(text) = label.text(cuboids, index) else {
+                    return;
+                };
+                // Viewport coordinates start at the bottom, UI ones at the top.
+                candidates.push(LabelCandidate {
+                    distance_sq: center.distance_squared(camera_position),
+                    position: viewport_min + Vec2::new(p.x, viewport_size.y - p.y),
+                    text,
+                });
+            };
+
+            for (_, cuboids, transform, visibility, labels) in batches.iter() {
+                if !visibility.is_visible_in_hierarchy() {
+                    continue;
+                }
+                for (index, label) in labels.into_iter().flat_map(CuboidLabels::iter) {
+                    add_candidate(cuboids, transform, index, label);
+                }
+            }
+            if let Some(label) = &settings.selection_label {
+                for (entity, index) in selection.iter() {
+                    let Ok((_, cuboids, transform, visibility, labels)) = batches.get(entity)
+                    else {
+                        continue;
+                    };
+                    let labeled = labels.map_or(false, |l| l.get(index).is_some());
+                    if visibility.is_visible_in_hierarchy() && !labeled {
+                        add_candidate(cuboids, transform, index, label);
+                    }
+                }
+            }
+        }
+    }
+
+    // Nearest first, each only if no shown label is too close, found in a
+    // grid with cells the size of the spacing.
+    candidates.sort_by(|a, b| a.distance_sq.total_cmp(&b.distance_sq));
+    let spacing = settings.min_spacing.max(1.0);
+    let mut grid: HashMap<IVec2, Vec<Vec2>> = HashMap::default();
+    let mut shown = Vec::new();
+    for candidate in candidates {
+        if shown.len() >= settings.max_labels {
+            break;
+        }
+        let cell = (candidate.position / spacing).floor().as_ivec2();
+        let crowded = (-1..=1).any(|y| {
+            (-1..=1).any(|x| {
+                grid.get(&(cell + IVec2::new(x, y)))
+                    .map_or(false, |anchors| {
+                        anchors
+                            .iter()
+                            .any(|a| a.distance_squared(candidate.position) < spacing * spacing)
+                    })
+            })
+        });
+        if !crowded {
+            grid.entry(cell).or_default().push(candidate.position);
+            shown.push(candidate);
+        }
+    }
+
+    // New nodes show up from the next frame.
+    let text_style = TextStyle {
+        font: settings.font.clone(),
+        font_size: settings.font_size,
+        color: settings.color,
+    };
+    while label_nodes.nodes.len() < shown.len() {
+        let node = commands
+            .spawn(
+                TextBundle::from_section("", text_style.clone()).with_style(Style {
+                    position_type: PositionType::Absolute,
+                    ..default()
+                }),
+            )
+            .insert(Visibility::Hidden)
+            .id();
+        label_nodes.nodes.push(node);
+    }
+    for (i, &node) in label_nodes.nodes.iter().enumerate() {
+        let Ok((mut text, mut style, mut visibility, ui_node)) = nodes.get_mut(node) else {
+            continue;
+        };
+        let Some(label) = shown.get(i) else {
+            if *visibility != Visibility::Hidden {
+                *visibility = Visibility::Hidden;
+            }
+            continue;
+        };
+        if settings.is_changed() {
+            text.sections[0].style = text_style.clone();
+        }
+        if text.sections[0].value != label.text {
+            text.sections[0].value = label.text.clone();
+        }
+        // Centered with the size of the last layout.
+        let corner = label.position - 0.5 * ui_node.size();
+        style.position = UiRect {
+            left: Val::Px(corner.x),
+            top: Val::Px(corner.y),
+            ..default()
+        };
+        *visibility = Visibility::Inherited;
+    }
+}
@@ -14,11 +14,74 @@
 //! # Features
 //!
 //! - vertex pulling renderer
+//! - per-batch frustum culling on the CPU, with automatic bounds
+//! - optional GPU frustum culling with indirect draws
+//! - optional Hi-Z occlusion culling on top of GPU culling
+//! - optional per-instance rotations for oriented boxes
+//! - voxel grids built into fewer, larger cuboids by greedy merging
+//! - heightmaps and images built into columns of cuboids, banded by an elevation color ramp
+//! - splitting of huge batches into grid chunks of their own entities, culled and streamed separately
+//! - LOD groups that draw coarser representations of a region per camera as its projected size shrinks
+//! - ray traced sphere and capped cylinder instances, e.g. for drill-holes, drawn and clipped alongside cuboids
+//! - instancing of small template meshes, e.g. arrow glyphs, stretched onto each instance's box
+//! - WebGL2 support, reading instances from data textures when storage buffers are unavailable
+//! - stereo rendering with a camera per eye, including side-by-side viewports of a double-wide target (no multiview)
 //! - cuboid edge shading
+//! - per-material shading of top, side and bottom faces, and a hemispherical sky and ground ambient
+//! - per-material fake bevels that shade face rims as rounded edges, for depth cues between same-colored blocks
+//! - optional per-face colors
+//! - packed colors declared linear or sRGB per material, converted in the shader, and `Color` setters that pack them
+//! - texture atlas tiles on cuboid faces, e.g. icons or hazard stripes
+//! - per-instance scalars for scalar-hue coloring, kept apart from the color and returned by picking
 //! - edge-only wireframes
-//! - clipping planes
-//! - multiple color modes: RGB and Linear-Range Scalar
-//! - depth jitter to counteract z-fighting of coplanar cuboids
+//! - clipping planes and slabs that keep either side, combined as an intersection or union, boxes and spheres, with optional translucent gizmos, caps, per-camera and per-material toggles, per-camera planes, tweens and looping sweeps
+//! - multiple color modes: RGB and Linear-Range Scalar, with an HSL hue ramp or colormaps like viridis and turbo
+//! - automatic scalar ranges, and legend colors that match the shader
+//! - up to 16 materials per batch, selected per instance
+//! - color keyframe playback for time series (`color_keyframes` feature)
+//! - GPU interpolation between two snapshots of a batch, for smooth playback of simulation steps
+//! - per-instance and per-material depth bias to layer coplanar cuboids or counteract z-fighting
+//! - reverse-Z depth like Bevy's projections, or forward-Z depth for custom projections
+//! - depth-only occluders
+//! - materials without depth writes, always on top, or x-ray through other geometry
+//! - per-material render phases, including an overlay pass after the main pass, with sort key offsets to order them against other plugins
+//! - per-material color tints and scalar offsets, to dim or re-range whole batches without re-uploading them
+//! - per-instance pulse and flash effects animated in the shader, for alerts that blink without recoloring
+//! - spawn and despawn animations that grow instances in and shrink them out in the shader, with events as they finish
+//! - optional streaming of large batches to the GPU over several frames, and a GPU memory budget that evicts batches out of view
+//! - an optional per-frame upload budget in bytes or CPU time that spreads the first uploads of many new batches over several frames, visible batches first
+//! - events as batches are uploaded, evicted or fail to draw, with their GPU sizes
+//! - optional half-precision or 16-bit quantized instance bounds on the GPU, for half the instance memory
+//! - optional spare room in instance buffers, so growing batches and appends don't reallocate
+//! - double or N-buffered instance uploads for dynamic batches, and optionally for any batch uploaded every frame
+//! - packing and uploading of changed batches and chunks on all cores
+//! - batches moved by their transforms and parents without uploading instances, with optional interpolation between fixed timesteps
+//! - building of batches on background threads, swapped into their entity once done
+//! - generation of instances by a user compute shader from GPU-resident buffers, e.g. simulation output, without a CPU round trip
+//! - validation of instance bounds in debug builds, reporting the first inverted or NaN instance by index
+//! - removal of instances by swapping in the last ones, with stable handles that follow instances as they move
+//! - per-instance outline overrides, and instances drawn in front of everything regardless of depth
+//! - optional multi-draw indirect batching of many small static batches into one draw per material
+//! - alpha-blended transparent materials, sorted or order-independent
+//! - exploded views that spread instances away from a pivot plane in the vertex shader
+//! - dithered screen-door transparency that needs no sorting and keeps depth writes
+//! - cutout grid and hatch patterns in screen or object space, to show overlapping volumes through each other
+//! - per-material WGSL hooks that modify the fragment color
+//! - a user-supplied replacement for the cuboid shader, and hot reloading of the built-in shaders (`shader_hot_reload` feature)
+//! - shadow casting into Bevy lights (`shadows` feature)
+//! - opaque cuboids in Bevy's depth and normal prepasses, for effects that read them
+//! - directional and ambient lighting from Bevy lights (`lighting` feature)
+//! - distance fog from Bevy's `FogSettings` (`fog` feature)
+//! - CPU raycasting, and mouse picking on the CPU or GPU, with a word of user data per instance
+//! - per-instance lookups and box queries, optionally backed by a lazily built BVH per batch
+//! - hover enter, over and exit events for tooltips
+//! - box and lasso selection of instances in screen space
+//! - decluttered text labels at instance centers, e.g. the scalars of selected instances (`labels` feature)
+//! - a `bevy_mod_picking` backend that reports the instance under each pointer (`mod_picking` feature)
+//! - click-to-select, with a tint, outline or screen-space contour highlight of the selected instances
+//! - binary snapshots of batches for caching on disk, loadable as hot-reloadable `.cuboids` assets, and `serde` support (`serialize` feature)
+//! - export of batches to OBJ, PLY or merged world-space glTF meshes
+//! - draw statistics, optional GPU pass timings and GPU culling counts, also recorded as Bevy diagnostics
 //!
 //! # License
 //!
@@ -34,12 +97,87 @@
 //! src="https://user-images.githubusercontent.com/2632925/151242316-db3455d1-4934-4374-8369-1818daf512dd.png"
 //! alt="Foresight Mining Software Corporation" width="480">
 
+mod atlas;
+mod bvh;
 mod clipping_planes;
+#[cfg(feature = "color_keyframes")]
+mod color_keyframes;
+mod colormap;
+mod compute_source;
+mod cuboid_chunks;
+mod cuboid_handles;
 mod cuboids;
+mod cuboids_animation;
+mod cuboids_asset;
+mod cuboids_builder;
+mod cuboids_commands;
+mod cylinders;
+mod draw_stats;
+mod effects;
+mod error;
+mod export;
+#[cfg(feature = "fog")]
+mod fog;
+mod heightfield;
+#[cfg(feature = "labels")]
+mod labels;
+#[cfg(feature = "lighting")]
+mod lighting;
+mod lod;
 mod material;
+mod mesh_instances;
+#[cfg(feature = "mod_picking")]
+mod mod_picking;
+mod picking;
+mod scalar_range;
+mod screen_select;
+mod selection;
+mod shader_hook;
+mod snapshot;
+mod spheres;
+mod transform_interpolation;
 mod vertex_pulling;
 
+pub use atlas::*;
 pub use clipping_planes::*;
+#[cfg(feature = "color_keyframes")]
+pub use color_keyframes::*;
+pub use colormap::*;
+pub use compute_source::CuboidsComputeSource;
+pub use cuboid_chunks::CuboidChunks;
+pub use cuboid_handles::CuboidHandle;
 pub use cuboids::*;
+pub use cuboids_animation::*;
+pub use cuboids_asset::{CuboidsAsset, CuboidsAssetBundle, CuboidsAssetLoader};
+pub use cuboids_builder::{CuboidsBuilder, CuboidsSender, PendingCuboids};
+pub use cuboids_commands::CuboidsCommands;
+pub use cylinders::*;
+pub use draw_stats::{CuboidsCullingCounts, CuboidsDrawStats, CuboidsGpuCulling, CuboidsTimings};
+pub use effects::{CuboidEffects, CuboidsSpawnEvent};
+pub use error::*;
+pub use export::CuboidsMeshExport;
+pub use heightfield::CuboidsHeightfield;
+#[cfg(feature = "labels")]
+pub use labels::{CuboidLabel, CuboidLabelSettings, CuboidLabels, CuboidLabelsPlugin};
+#[cfg(feature = "lighting")]
+pub use lighting::MAX_CUBOID_DIRECTIONAL_LIGHTS;
+pub use lod::*;
 pub use material::*;
+pub use mesh_instances::*;
+#[cfg(feature = "mod_picking")]
+pub use mod_picking::*;
+pub use picking::*;
+pub use scalar_range::*;
+pub use screen_select::*;
+pub use selection::{CuboidHighlightStyle, CuboidSelection};
+pub use shader_hook::CuboidShaderHook;
+pub use snapshot::*;
+pub use spheres::*;
+pub use transform_interpolation::CuboidsTransformInterpolation;
+pub use vertex_pulling::index_buffer::{
+    CuboidsIndexBuffer, GpuCuboidsIndexBuffer, CUBE_INDICES, CUBE_INDICES_HANDLE,
+    TRANSFORM_INDEX_SHIFT,
+};
+pub use vertex_pulling::occlusion::OcclusionCullingSettings;
+pub use vertex_pulling::overlay::CUBOIDS_OVERLAY_NODE;
 pub use vertex_pulling::plugin::*;
@@ -0,0 +1,48 @@
+use bevy::{prelude::*, render::render_resource::ShaderType};
+
+/// The most directional lights that shade
+/// [`CuboidMaterial::lit`](crate::CuboidMaterial::lit) cuboids. Extra lights
+/// are ignored.
+pub const MAX_CUBOID_DIRECTIONAL_LIGHTS: usize = 4;
+
+/// Illuminance that lights a face at full brightness, the default of
+/// [`DirectionalLight::illuminance`].
+const REFERENCE_ILLUMINANCE: f32 = 100_000.0;
+
+#[derive(Clone, Default, ShaderType)]
+pub(crate) struct GpuCuboidDirectionalLight {
+    pub color: Vec3,
+    pub direction_to_light: Vec3,
+}
+
+#[derive(Clone, Default, ShaderType)]
+pub(crate) struct GpuCuboidLights {
+    pub directional: [GpuCuboidDirectionalLight; MAX_CUBOID_DIRECTIONAL_LIGHTS],
+    pub ambient: Vec3,
+    pub num_directional: u32,
+}
+
+impl GpuCuboidLights {
+    pub fn new<'a>(
+        ambient: Option<&AmbientLight>,
+        directional: impl Iterator<Item = (&'a DirectionalLight, &'a GlobalTransform)>,
+    ) -> Self {
+        let mut lights = Self::default();
+        if let Some(ambient) = ambient {
+            lights.ambient = linear_rgb(ambient.color) * ambient.brightness;
+        }
+        for (light, transform) in directional.take(MAX_CUBOID_DIRECTIONAL_LIGHTS) {
+            lights.directional[lights.num_directional as usize] = GpuCuboidDirectionalLight {
+                color: linear_rgb(light.color) * (light.illuminance / REFERENCE_ILLUMINANCE),
+                // Lights shine along their forward direction.
+                direction_to_light: transform.back(),
+            };
+            lights.num_directional += 1;
+        }
+        lights
+    }
+}
+
+fn linear_rgb(color: Color) -> Vec3 {
+    Vec4::from(color.as_linear_rgba_f32()).truncate()
+}
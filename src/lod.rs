@@ -0,0 +1,145 @@
+use crate::{CuboidMaterialId, Cuboids, CuboidsBundle};
+
+use bevy::{
+    prelude::*,
+    render::{extract_resource::ExtractResource, primitives::Aabb, view::VisibleEntities},
+    utils::HashSet,
+};
+
+/// Global threshold for manually assigned cuboid LOD levels.
+///
+/// Only cuboids with [`Cuboid::lod_level`](crate::Cuboid::lod_level) less than
+/// or equal to `current_level` are drawn. Levels range from 0 to
+/// [`MAX_LOD_LEVEL`], so the default draws everything.
+#[derive(Clone, Debug, ExtractResource, Resource)]
+pub struct CuboidsLod {
+    pub current_level: u8,
+}
+
+impl Default for CuboidsLod {
+    fn default() -> Self {
+        Self {
+            current_level: MAX_LOD_LEVEL,
+        }
+    }
+}
+
+/// The highest LOD level that fits in [`MetaBits`](crate::MetaBits).
+pub const MAX_LOD_LEVEL: u8 = 3;
+
+/// One representation of the region of a [`CuboidsLodGroup`].
+#[derive(Clone, Debug)]
+pub struct CuboidsLodLevel {
+    pub cuboids: Cuboids,
+    pub material_id: CuboidMaterialId,
+    /// The smallest fraction of the viewport height that the bounds of the
+    /// group must cover for this level to be drawn, if no finer level is.
+    pub min_screen_size: f32,
+}
+
+/// Switches between representations of one region at decreasing detail, e.g.
+/// the blocks of a chunk aggregated 1×, 8× and 64×, so distant regions don't
+/// draw at full resolution.
+///
+/// This component lives on the parent of one [`Cuboids`] entity per level.
+/// Each camera only draws the finest level whose
+/// [`CuboidsLodLevel::min_screen_size`] the bounds of the finest level exceed
+/// on its screen, or the coarsest level when none do. Levels are still
+/// checked for visibility, raycast and cast shadows on their own, so coarser
+/// levels that shouldn't cast shadows need a material that doesn't.
+#[derive(Clone, Component, Debug)]
+pub struct CuboidsLodGroup {
+    /// The entity and minimum screen size of each level, finest first.
+    levels: Vec<(Entity, f32)>,
+}
+
+impl CuboidsLodGroup {
+    /// Spawns a parent entity with a [`CuboidsLodGroup`] of `levels`, ordered
+    /// finest first, and a child for each. Returns the parent.
+    pub fn spawn(commands: &mut Commands, levels: Vec<CuboidsLodLevel>) -> Entity {
+        let levels: Vec<_> = levels
+            .into_iter()
+            .map(|level| {
+                let entity = commands
+                    .spawn(CuboidsBundle {
+                        material_id: level.material_id,
+                        cuboids: level.cuboids,
+                        spatial: default(),
+                    })
+                    .id();
+                (entity, level.min_screen_size)
+            })
+            .collect();
+        let children: Vec<_> = levels.iter().map(|&(entity, _)| entity).collect();
+        commands
+            .spawn((SpatialBundle::default(), Self { levels }))
+            .push_children(&children)
+            .id()
+    }
+
+    /// The entity of each level, finest first.
+    pub fn levels(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.levels.iter().map(|&(entity, _)| entity)
+    }
+
+    /// The level to draw when the group covers `screen_size` of the viewport
+    /// height.
+    fn select(&self, screen_size: f32) -> Option<Entity> {
+        self.levels
+            .iter()
+            .find(|&&(_, min_screen_size)| screen_size >= min_screen_size)
+            .or(self.levels.last())
+            .map(|&(entity, _)| entity)
+    }
+}
+
+/// Drops the levels of each [`CuboidsLodGroup`] that a camera shouldn't draw
+/// from its visible entities, after Bevy checked visibility.
+pub(crate) fn select_cuboids_lod_levels(
+    groups: Query<&CuboidsLodGroup>,
+    bounds: Query<(&Aabb, &GlobalTransform)>,
+    mut cameras: Query<(&Camera, &GlobalTransform, &mut VisibleEntities)>,
+) {
+    if groups.is_empty() {
+        return;
+    }
+    let mut hidden = HashSet::default();
+    for (camera, camera_transform, mut visible_entities) in cameras.iter_mut() {
+        if !camera.is_active {
+            continue;
+        }
+        let projection = camera.projection_matrix();
+        // Perspective projections have no constant term in `w`.
+        let perspective = projection.w_axis.w == 0.0;
+        let camera_position = camera_transform.translation();
+        let camera_forward = camera_transform.forward();
+
+        hidden.clear();
+        for group in groups.iter() {
+            let Some(&(finest, _)) = group.levels.first() else {
+                continue;
+            };
+            let Ok((aabb, transform)) = bounds.get(finest) else {
+                continue;
+            };
+            let center = transform.transform_point(aabb.center.into());
+            let radius = transform
+                .affine()
+                .transform_vector3(aabb.half_extents.into())
+                .length();
+            let depth = (center - camera_position).dot(camera_forward);
+            let screen_size = if perspective {
+                radius * projection.y_axis.y / depth.max(f32::EPSILON)
+            } else {
+                radius * projection.y_axis.y
+            };
+            let selected = group.select(screen_size);
+            hidden.extend(group.levels().filter(|&entity| Some(entity) != selected));
+        }
+        if !hidden.is_empty() {
+            visible_entities
+                .entities
+                .retain(|entity| !hidden.contains(entity));
+        }
+    }
+}
@@ -1,5 +1,8 @@
 use bevy::prelude::*;
 use bevy::render::render_resource::{DynamicUniformBuffer, ShaderType};
+use bevy::utils::{HashMap, HashSet};
+
+use crate::CuboidShaderHook;
 
 /// Bare enum for toggling shader behavior for [`Color`].
 ///
@@ -10,36 +13,383 @@ pub type ColorMode = u32;
 
 /// "Manual" coloring based on RGB-valued `cuboid.color`.
 ///
-/// Encode with `Color::as_rgba_u32`.
+/// Encode with [`pack_color`] in the [`ColorSpace`] of the material, e.g.
+/// with [`Cuboid::set_color`](crate::Cuboid::set_color).
 pub const COLOR_MODE_RGB: ColorMode = 0;
 
 /// "Automatic" coloring based on scalar-valued `cuboid.color`. See [`ScalarHueOptions`].
 ///
-/// Encode with `u32::from_le_bytes(f32::to_le_bytes(x))`.
+/// Encode with `u32::from_le_bytes(f32::to_le_bytes(x))`, or keep scalars in
+/// [`Cuboids::scalars`](crate::Cuboids::scalars) instead.
 pub const COLOR_MODE_SCALAR_HUE: ColorMode = 1;
 
+/// Bare enum for how a [`CuboidMaterial`] uses the depth buffer.
+///
+/// One of:
+/// - [`DEPTH_MODE_DEFAULT`]
+/// - [`DEPTH_MODE_NO_WRITE`]
+/// - [`DEPTH_MODE_ALWAYS_ON_TOP`]
+/// - [`DEPTH_MODE_XRAY`]
+pub type DepthMode = u32;
+
+/// Cuboids are depth tested, and hide the geometry behind them.
+pub const DEPTH_MODE_DEFAULT: DepthMode = 0;
+
+/// Cuboids are depth tested, but don't write depth, so they don't hide
+/// geometry that is drawn after them. They are left out of the prepasses.
+pub const DEPTH_MODE_NO_WRITE: DepthMode = 1;
+
+/// Cuboids are neither depth tested nor write depth, and are drawn after all
+/// other geometry of the main pass, so they are always on top. They are left
+/// out of the prepasses.
+pub const DEPTH_MODE_ALWAYS_ON_TOP: DepthMode = 2;
+
+/// Cuboids are drawn like [`DEPTH_MODE_DEFAULT`], and then once more where
+/// other geometry hides them, dimmed and blended over it. E.g. for debug
+/// colliders inside of level geometry.
+pub const DEPTH_MODE_XRAY: DepthMode = 3;
+
+/// Bare enum for how the packed colors of a [`CuboidMaterial`] in
+/// [`COLOR_MODE_RGB`] are encoded.
+///
+/// One of:
+/// - [`COLOR_SPACE_LINEAR`]
+/// - [`COLOR_SPACE_SRGB`]
+pub type ColorSpace = u32;
+
+/// Packed colors are linear RGB, and written as they are. Colors packed with
+/// `Color::as_rgba_u32`, which is sRGB, come out washed out.
+pub const COLOR_SPACE_LINEAR: ColorSpace = 0;
+
+/// Packed colors are nonlinear sRGB, like those of `Color::as_rgba_u32`,
+/// color pickers and most image formats, and are converted to linear RGB in
+/// the shader. Colors are blended between keyframes after the conversion.
+pub const COLOR_SPACE_SRGB: ColorSpace = 1;
+
+/// Packs `color` for materials in `color_space`, four bytes of RGBA from the
+/// lowest byte up.
+pub fn pack_color(color: Color, color_space: ColorSpace) -> crate::Color {
+    match color_space {
+        COLOR_SPACE_SRGB => color.as_rgba_u32(),
+        _ => color.as_linear_rgba_u32(),
+    }
+}
+
+/// Bare enum for the pattern that a [`CuboidMaterial`] cuts out of its
+/// cuboids.
+///
+/// One of:
+/// - [`CUTOUT_PATTERN_NONE`]
+/// - [`CUTOUT_PATTERN_GRID`]
+/// - [`CUTOUT_PATTERN_HATCH`]
+pub type CutoutPattern = u32;
+
+pub const CUTOUT_PATTERN_NONE: CutoutPattern = 0;
+
+/// Only lines along both axes of each face are kept, like a wire mesh.
+pub const CUTOUT_PATTERN_GRID: CutoutPattern = 1;
+
+/// Only diagonal stripes are kept.
+pub const CUTOUT_PATTERN_HATCH: CutoutPattern = 2;
+
+/// Bare enum for what the cutout pattern of a [`CuboidMaterial`] is anchored
+/// to.
+///
+/// One of:
+/// - [`CUTOUT_SPACE_SCREEN`]
+/// - [`CUTOUT_SPACE_OBJECT`]
+pub type CutoutSpace = u32;
+
+/// The pattern is laid out in pixels, and stays put on the screen as cuboids
+/// move.
+pub const CUTOUT_SPACE_SCREEN: CutoutSpace = 0;
+
+/// The pattern is laid out on each face in the local units of the batch,
+/// along the axes of the instance, and moves with it.
+pub const CUTOUT_SPACE_OBJECT: CutoutSpace = 1;
+
+/// Bare enum for the render phase that the batches of a [`CuboidMaterial`]
+/// are queued into.
+///
+/// One of:
+/// - [`DRAW_PHASE_AUTO`]
+/// - [`DRAW_PHASE_OPAQUE`]
+/// - [`DRAW_PHASE_ALPHA_MASK`]
+/// - [`DRAW_PHASE_TRANSPARENT`]
+/// - [`DRAW_PHASE_OVERLAY`]
+pub type DrawPhase = u32;
+
+/// Bevy's `Transparent3d` for `alpha_blend` materials and
+/// [`DEPTH_MODE_ALWAYS_ON_TOP`], `Opaque3d` otherwise.
+pub const DRAW_PHASE_AUTO: DrawPhase = 0;
+
+/// Bevy's `Opaque3d`, sorted front-to-back.
+pub const DRAW_PHASE_OPAQUE: DrawPhase = 1;
+
+/// Bevy's `AlphaMask3d`, drawn after `Opaque3d` and sorted front-to-back.
+pub const DRAW_PHASE_ALPHA_MASK: DrawPhase = 2;
+
+/// Bevy's `Transparent3d`, drawn after the other phases of the main pass and
+/// sorted back-to-front.
+pub const DRAW_PHASE_TRANSPARENT: DrawPhase = 3;
+
+/// A phase of this crate, drawn after the main pass and before tonemapping,
+/// and sorted back-to-front, see
+/// [`CUBOIDS_OVERLAY_NODE`](crate::CUBOIDS_OVERLAY_NODE). E.g. for debug
+/// overlays that other render plugins order their own passes against.
+pub const DRAW_PHASE_OVERLAY: DrawPhase = 4;
+
 /// Denotes which [`CuboidMaterial`] to use when rendering
 /// [`Cuboids`](crate::Cuboids).
 ///
 /// When a material is modified, _all_ entities with the corresponding
 /// [`CuboidMaterialId`] will be affected.
-#[derive(Clone, Component, Copy, Eq, Hash, PartialEq)]
+#[derive(Clone, Component, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct CuboidMaterialId(pub usize);
 
+/// The highest slot that fits in [`MetaBits`](crate::MetaBits), see
+/// [`Cuboid::set_material_slot`](crate::Cuboid::set_material_slot).
+pub const MAX_MATERIAL_SLOT: u8 = 15;
+
+/// Only materials with ids below this can be put in [`CuboidMaterialSlots`].
+pub const MAX_SLOTTED_MATERIALS: usize = 64;
+
+/// Materials that instances of a [`Cuboids`](crate::Cuboids) batch select
+/// with [`Cuboid::set_material_slot`](crate::Cuboid::set_material_slot), so one
+/// batch can mix several materials.
+///
+/// Instances in slot `i` use the material `self.0[i]`. Slots past the end, and
+/// batches without this component, use the batch's [`CuboidMaterialId`].
+///
+/// Only the shading varies per instance: color mode, wireframe, scalar hue
+/// options, emissive gain and lighting. Transparency, shadow casting and
+/// [`CuboidShaderHook`]s are decided by the batch's [`CuboidMaterialId`]. Every
+/// slotted id must be below [`MAX_SLOTTED_MATERIALS`].
+#[derive(Clone, Component, Debug, Default)]
+pub struct CuboidMaterialSlots(pub Vec<CuboidMaterialId>);
+
+impl CuboidMaterialSlots {
+    /// Packs one byte per slot, 0 for the batch material and otherwise the
+    /// material id + 1, or returns the first id that can't be slotted.
+    pub(crate) fn pack(&self, materials: &CuboidMaterialMap) -> Result<UVec4, usize> {
+        let mut packed = UVec4::ZERO;
+        for (slot, id) in self
+            .0
+            .iter()
+            .take(MAX_MATERIAL_SLOT as usize + 1)
+            .enumerate()
+        {
+            if id.0 >= MAX_SLOTTED_MATERIALS || id.0 >= materials.materials.len() {
+                return Err(id.0);
+            }
+            packed[slot / 4] |= (id.0 as u32 + 1) << (8 * (slot % 4));
+        }
+        Ok(packed)
+    }
+}
+
 /// Shading options, constant for each draw call.
 #[derive(Clone, Debug, ShaderType)]
+#[cfg_attr(
+    feature = "serialize",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 pub struct CuboidMaterial {
     pub color_mode: ColorMode,
-    /// Nonzero values imply that _only_ cuboid edges will be shaded.
-    /// [`VertexPullingRenderPlugin::edges`](crate::VertexPullingRenderPlugin)
-    /// must be `true` for this to take effect.
+    /// Nonzero values draw only the 12 edges of each cuboid, about two pixels
+    /// wide, and discard the rest of its faces. Works with or without
+    /// [`VertexPullingRenderPlugin::outlines`](crate::VertexPullingRenderPlugin::outlines);
+    /// wireframe edges are never darkened.
     pub wireframe: u32,
+    /// Nonzero values exempt cuboids from every
+    /// [`ClippingPlaneRange`](crate::ClippingPlaneRange),
+    /// [`ClippingBox`](crate::ClippingBox) and
+    /// [`ClippingSphere`](crate::ClippingSphere), e.g. to keep reference
+    /// geometry like pit outlines visible while an ore body is cut away.
+    ///
+    /// This applies to every camera and to shadows, unlike
+    /// [`ViewClipping`](crate::ViewClipping), which exempts whole cameras.
+    pub ignore_clipping: u32,
     #[align(16)]
     pub scalar_hue: ScalarHueOptions,
 
     /// An extra factor that multiplies a cuboid's color when the "emissive" bit
     /// on [`MetaBits`](crate::cuboids::MetaBits) is set.
+    ///
+    /// Colors are only written unclamped for cameras with `Camera::hdr`, where
+    /// gains above one make emissive cuboids glow with Bevy's `BloomSettings`.
+    /// See [`Cuboids::set_emissive`](crate::Cuboids::set_emissive).
     pub emissive_gain: Vec3,
+
+    /// Nonzero values draw cuboids with alpha blending, after all opaque
+    /// geometry. In [`COLOR_MODE_RGB`], the alpha is read from the high byte of
+    /// `cuboid.color`; scalar colors are always opaque.
+    ///
+    /// Batches are sorted back-to-front, but instances within a batch are drawn
+    /// in order, see [`Cuboids::sort_back_to_front`](crate::Cuboids::sort_back_to_front).
+    /// Transparent cuboids don't write depth. With
+    /// [`VertexPullingRenderPlugin::order_independent_transparency`](crate::VertexPullingRenderPlugin::order_independent_transparency),
+    /// nothing is sorted, and the draw order doesn't matter.
+    pub alpha_blend: u32,
+
+    /// Nonzero values make cuboids cast shadows from Bevy's directional, point
+    /// and spot lights. Requires the `shadows` feature.
+    ///
+    /// Shadows are cast by the solid boxes, even for wireframe and transparent
+    /// materials. Shadow passes draw every instance, even with
+    /// [`VertexPullingRenderPlugin::gpu_culling`](crate::VertexPullingRenderPlugin::gpu_culling).
+    pub cast_shadows: u32,
+
+    /// Nonzero values shade each face with Bevy's `AmbientLight`, or
+    /// `hemisphere_ambient`, and up to `MAX_CUBOID_DIRECTIONAL_LIGHTS`
+    /// `DirectionalLight`s. Requires the `lighting` feature.
+    ///
+    /// A light with the default illuminance lights faces that point at it with
+    /// the full cuboid color. Emissive cuboids and interior faces are unlit.
+    pub lit: u32,
+
+    /// In [`COLOR_MODE_SCALAR_HUE`], nonzero values color scalars with a
+    /// [`Colormap`](crate::Colormap) returned by
+    /// [`CuboidColormaps::push`](crate::CuboidColormaps::push), instead of the
+    /// HSL hue ramp of `scalar_hue`.
+    pub colormap: u32,
+
+    /// How cuboids are depth tested, see [`DepthMode`].
+    ///
+    /// Like `alpha_blend`, this is decided by the batch's [`CuboidMaterialId`].
+    /// Shadows are cast the same way in every mode. Instances of
+    /// [`DEPTH_MODE_ALWAYS_ON_TOP`] and [`DEPTH_MODE_XRAY`] batches are never
+    /// occlusion culled, since they are meant to be seen through geometry.
+    pub depth_mode: DepthMode,
+
+    /// Added to the [`Cuboid::set_depth_bias`](crate::Cuboid::set_depth_bias)
+    /// of every instance with this material, in the same units.
+    ///
+    /// Giving batches that share a plane increasing biases draws them in a
+    /// fixed order, the lowest on top.
+    pub depth_bias: u32,
+
+    /// Multiplies the RGBA color of every instance with this material,
+    /// including scalar colors, before edge shading, lighting and fog.
+    ///
+    /// Changing it only rewrites the material uniform, so whole batches can be
+    /// dimmed, faded or brightened without uploading their instances again.
+    /// Alpha only matters with `alpha_blend`.
+    pub tint: Vec4,
+
+    /// In [`COLOR_MODE_SCALAR_HUE`], added to every scalar before the visible
+    /// and clamp ranges of `scalar_hue` apply, like `tint` without touching
+    /// the instances.
+    ///
+    /// Automatic scalar ranges still fit the scalars without the offset, so it
+    /// shifts them along the color range.
+    pub scalar_offset: f32,
+
+    /// Nonzero values draw cuboids with screen-door transparency: in
+    /// [`COLOR_MODE_RGB`], each fragment is kept or discarded by comparing the
+    /// alpha of `cuboid.color` to an ordered 4x4 dither pattern, so that it
+    /// covers about that fraction of the pixels.
+    ///
+    /// Unlike `alpha_blend`, nothing is sorted or blended. Batches are drawn
+    /// with the opaque ones and keep writing depth and the prepasses, so they
+    /// are hidden by and hide opaque geometry correctly, at the cost of a
+    /// visible pattern. This suits ghosting whole batches, e.g. with the alpha
+    /// of `tint`. Ignored with `alpha_blend`. Shadows are still cast by the
+    /// solid boxes.
+    pub dither_alpha: u32,
+
+    /// Moves every instance away from `explode_plane` by this factor of the
+    /// signed distance from its center to the plane, e.g. to pull apart the
+    /// benches of a block model for inspection. Zero turns it off.
+    ///
+    /// Instances keep their size, and move in the vertex shader, so like
+    /// `tint` the factor can be animated without uploading anything. Bounds,
+    /// culling and CPU picking use the instances where they are in
+    /// [`Cuboids`](crate::Cuboids), so batches that are exploded far should
+    /// turn off frustum culling with Bevy's `NoFrustumCulling`, and are best
+    /// drawn without
+    /// [`VertexPullingRenderPlugin::gpu_culling`](crate::VertexPullingRenderPlugin::gpu_culling).
+    pub explode_factor: f32,
+
+    /// The pivot plane of `explode_factor`, in the local space of each batch:
+    /// `xyz` is its unit normal, and `w` its signed distance from the origin
+    /// along the normal. Defaults to the `XZ` plane, which spreads out stacked
+    /// levels vertically.
+    pub explode_plane: Vec4,
+
+    /// Shades the rims of each face as if its edges were rounded off over this
+    /// width, in the local units of the batch, so that dense blocks of the
+    /// same color stand apart without any extra geometry. Zero turns it off.
+    ///
+    /// The normal of the face is tilted towards each edge within the rim, up to
+    /// 45 degrees where it meets the next face, and the rim is lit from the
+    /// camera: rims that face it brighten, the others darken. This is only
+    /// shading, on top of `lit` and before edge darkening, so silhouettes,
+    /// depth, picking and the normal prepass keep the sharp box. Rims are at
+    /// most half of each face wide, and wireframes and interior faces have
+    /// none.
+    pub bevel_width: f32,
+
+    /// Multiplies the color of faces whose normal points up, sideways and down
+    /// in world space, in that order, blending between them for rotated
+    /// cuboids, e.g. `Vec3::new(1.0, 0.8, 0.6)` for the look of a voxel game.
+    /// The default of ones leaves every face the same. Like `lit`, this leaves
+    /// emissive cuboids, interior faces, and the other primitives alone.
+    pub face_shading: Vec3,
+    /// Nonzero values add a hemispherical ambient term: faces are lit by
+    /// `sky_color` when they point up and `ground_color` when they point down,
+    /// blended in between. With `lit`, this replaces Bevy's `AmbientLight`;
+    /// otherwise it multiplies the color like `face_shading`.
+    pub hemisphere_ambient: u32,
+    pub sky_color: Vec3,
+    pub ground_color: Vec3,
+
+    /// How packed colors in [`COLOR_MODE_RGB`] are encoded, see
+    /// [`ColorSpace`]. Also applies to [`Cuboids::face_colors`](crate::Cuboids::face_colors),
+    /// [`CuboidsInteriorColor`](crate::CuboidsInteriorColor) and color
+    /// keyframes. Scalar colors are never converted.
+    pub color_space: ColorSpace,
+
+    /// Discards the fragments of every face outside of a [`CutoutPattern`],
+    /// so that a batch overlapping another in the same space, e.g. the planned
+    /// blocks over the mined ones, shows the other through its gaps.
+    ///
+    /// Like `dither_alpha`, batches are drawn with the opaque ones and keep
+    /// writing depth, and GPU picking clicks through the gaps. Shadows are
+    /// still cast by the solid boxes, and CPU picking hits them.
+    pub cutout_pattern: CutoutPattern,
+    pub cutout_space: CutoutSpace,
+    /// Period of the pattern, in pixels or in local units depending on
+    /// `cutout_space`.
+    pub cutout_spacing: f32,
+    /// Fraction of each period covered by the lines of the pattern, from zero
+    /// to one.
+    pub cutout_coverage: f32,
+
+    /// The render phase that batches with this material are queued into, see
+    /// [`DrawPhase`].
+    ///
+    /// Only the phase changes: blending and depth testing still follow
+    /// `alpha_blend` and `depth_mode`. Transparent and overlay batches are
+    /// left out of the prepasses, and only the [`DRAW_PHASE_AUTO`]
+    /// transparent batches are drawn with
+    /// [`VertexPullingRenderPlugin::order_independent_transparency`](crate::VertexPullingRenderPlugin::order_independent_transparency).
+    /// Only applies to [`Cuboids`](crate::Cuboids) batches.
+    pub draw_phase: DrawPhase,
+
+    /// Added to the distance that batches with this material are sorted by in
+    /// their phase, their view-space depth, which is negative in front of the
+    /// camera. Opaque and alpha mask phases draw the highest distance first,
+    /// transparent and overlay phases draw it last.
+    ///
+    /// Giving overlays distinct offsets, larger than the depth range of the
+    /// scene, draws them in a fixed order relative to each other and to the
+    /// items other plugins queue. Batches drawn on top are sorted after all
+    /// others by the offset alone. Batches with an offset, or outside of
+    /// [`DRAW_PHASE_AUTO`] and [`DRAW_PHASE_OPAQUE`], are never merged into
+    /// multi-draws.
+    pub sort_key_offset: f32,
 }
 
 impl Default for CuboidMaterial {
@@ -47,8 +397,32 @@ impl Default for CuboidMaterial {
         Self {
             color_mode: COLOR_MODE_RGB,
             wireframe: default(),
+            ignore_clipping: default(),
             scalar_hue: default(),
             emissive_gain: Vec3::splat(30.0),
+            alpha_blend: default(),
+            cast_shadows: default(),
+            lit: default(),
+            colormap: default(),
+            depth_mode: DEPTH_MODE_DEFAULT,
+            depth_bias: 0,
+            tint: Vec4::ONE,
+            scalar_offset: 0.0,
+            dither_alpha: 0,
+            explode_factor: 0.0,
+            explode_plane: Vec4::new(0.0, 1.0, 0.0, 0.0),
+            bevel_width: 0.0,
+            face_shading: Vec3::ONE,
+            hemisphere_ambient: 0,
+            sky_color: Vec3::ONE,
+            ground_color: Vec3::splat(0.4),
+            color_space: COLOR_SPACE_LINEAR,
+            cutout_pattern: CUTOUT_PATTERN_NONE,
+            cutout_space: CUTOUT_SPACE_SCREEN,
+            cutout_spacing: 8.0,
+            cutout_coverage: 0.25,
+            draw_phase: DRAW_PHASE_AUTO,
+            sort_key_offset: 0.0,
         }
     }
 }
@@ -66,8 +440,19 @@ impl Default for CuboidMaterial {
 ///
 /// These options are only available in [`COLOR_MODE_SCALAR_HUE`].
 #[derive(Clone, Debug, ShaderType)]
+#[cfg_attr(
+    feature = "serialize",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 pub struct ScalarHueOptions {
     /// Cuboids with `cuboid.color < min_visible` will be clipped.
+    ///
+    /// Together with `max_visible`, this is a filter on the scalars that is
+    /// applied in the vertex shader: clipped instances are degenerated before
+    /// rasterization, and also skipped by CPU picking. Changing it only
+    /// rewrites the material uniform, so a cutoff can be slid over millions of
+    /// instances without uploading any of them.
     pub min_visible: f32,
     /// Cuboids with `cuboid.color > max_visible` will be clipped.
     pub max_visible: f32,
@@ -102,12 +487,16 @@ impl Default for ScalarHueOptions {
 pub struct CuboidMaterialMap {
     // Consumed every frame during GPU buffering.
     materials: Vec<CuboidMaterial>,
+    hooks: HashMap<usize, CuboidShaderHook>,
+    auto_scalar_ranges: HashSet<usize>,
 }
 
 impl Default for CuboidMaterialMap {
     fn default() -> Self {
         Self {
             materials: vec![default()],
+            hooks: default(),
+            auto_scalar_ranges: default(),
         }
     }
 }
@@ -117,8 +506,21 @@ impl CuboidMaterialMap {
         self.materials.is_empty()
     }
 
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+
     pub fn clear(&mut self) {
         self.materials.clear();
+        self.hooks.clear();
+        self.auto_scalar_ranges.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (CuboidMaterialId, &CuboidMaterial)> {
+        self.materials
+            .iter()
+            .enumerate()
+            .map(|(id, material)| (CuboidMaterialId(id), material))
     }
 
     pub fn get(&self, id: CuboidMaterialId) -> &CuboidMaterial {
@@ -135,6 +537,56 @@ impl CuboidMaterialMap {
         id
     }
 
+    /// Replaces the shader hook of the material, or removes it with `None`.
+    pub fn set_shader_hook(&mut self, id: CuboidMaterialId, hook: Option<CuboidShaderHook>) {
+        match hook {
+            Some(hook) => self.hooks.insert(id.0, hook),
+            None => self.hooks.remove(&id.0),
+        };
+    }
+
+    pub fn shader_hook(&self, id: CuboidMaterialId) -> Option<&CuboidShaderHook> {
+        self.hooks.get(&id.0)
+    }
+
+    /// Makes the material fit `clamp_min` and `clamp_max` of its
+    /// [`ScalarHueOptions`] to the smallest and largest scalars of the
+    /// [`Cuboids`](crate::Cuboids) batches that use it, whenever they change.
+    ///
+    /// Only visible instances and their face colors count, in batches whose
+    /// [`CuboidMaterialId`] is this material; give a batch its own material to
+    /// fit the range to that batch alone. See
+    /// [`CuboidColorLegends`](crate::CuboidColorLegends) for the resulting
+    /// colors.
+    pub fn set_auto_scalar_range(&mut self, id: CuboidMaterialId, enabled: bool) {
+        if enabled {
+            self.auto_scalar_ranges.insert(id.0);
+        } else {
+            self.auto_scalar_ranges.remove(&id.0);
+        }
+    }
+
+    pub fn auto_scalar_range(&self, id: CuboidMaterialId) -> bool {
+        self.auto_scalar_ranges.contains(&id.0)
+    }
+
+    /// The first [`MAX_SLOTTED_MATERIALS`], for [`CuboidMaterialSlots`].
+    pub(crate) fn slotted_materials(&self) -> GpuSlottedMaterials {
+        let mut slotted = GpuSlottedMaterials::default();
+        for (gpu, material) in slotted.materials.iter_mut().zip(self.materials.iter()) {
+            *gpu = material.clone();
+        }
+        slotted
+    }
+
+    pub(crate) fn shader_hooks(
+        &self,
+    ) -> impl Iterator<Item = (CuboidMaterialId, &CuboidShaderHook)> {
+        self.hooks
+            .iter()
+            .map(|(&id, hook)| (CuboidMaterialId(id), hook))
+    }
+
     pub(crate) fn write_uniforms(
         &self,
         uniforms: &mut DynamicUniformBuffer<CuboidMaterial>,
@@ -148,5 +600,55 @@ impl CuboidMaterialMap {
     }
 }
 
+/// The first [`CuboidMaterialId`] of [`InternalCuboidMaterials`].
+pub(crate) const FIRST_INTERNAL_MATERIAL_ID: usize = 1 << 30;
+
+/// Materials of the entities that the plugin spawns itself, e.g. clipping
+/// plane gizmos. They are kept out of the [`CuboidMaterialMap`], so that they
+/// don't show up in it and survive it being cleared or replaced, and their ids
+/// start at [`FIRST_INTERNAL_MATERIAL_ID`].
+#[derive(Clone, Debug, Default, Resource)]
+pub(crate) struct InternalCuboidMaterials {
+    materials: Vec<CuboidMaterial>,
+}
+
+impl InternalCuboidMaterials {
+    pub fn push(&mut self, material: CuboidMaterial) -> CuboidMaterialId {
+        let id = CuboidMaterialId(FIRST_INTERNAL_MATERIAL_ID + self.materials.len());
+        self.materials.push(material);
+        id
+    }
+
+    pub fn get(&self, id: CuboidMaterialId) -> Option<&CuboidMaterial> {
+        let index = id.0.checked_sub(FIRST_INTERNAL_MATERIAL_ID)?;
+        self.materials.get(index)
+    }
+
+    /// Appends the materials to `uniforms`, after those of
+    /// [`CuboidMaterialMap::write_uniforms`].
+    pub fn write_uniforms(
+        &self,
+        uniforms: &mut DynamicUniformBuffer<CuboidMaterial>,
+    ) -> Vec<CuboidMaterialUniformIndex> {
+        self.materials
+            .iter()
+            .map(|material| CuboidMaterialUniformIndex(uniforms.push(material.clone())))
+            .collect()
+    }
+}
+
 #[derive(Clone, Copy, Debug, Component)]
 pub(crate) struct CuboidMaterialUniformIndex(pub u32);
+
+#[derive(Clone, ShaderType)]
+pub(crate) struct GpuSlottedMaterials {
+    pub materials: [CuboidMaterial; MAX_SLOTTED_MATERIALS],
+}
+
+impl Default for GpuSlottedMaterials {
+    fn default() -> Self {
+        Self {
+            materials: std::array::from_fn(|_| default()),
+        }
+    }
+}
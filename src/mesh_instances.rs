@@ -0,0 +1,176 @@
+use bevy::{
+    prelude::*,
+    render::{
+        mesh::{PrimitiveTopology, VertexAttributeValues},
+        primitives::Aabb,
+        render_resource::ShaderType,
+    },
+};
+use std::sync::Arc;
+
+use crate::{Cuboid, CuboidMaterialId};
+
+/// A vertex of a [`TemplateMesh`].
+#[derive(Clone, Copy, Debug, PartialEq, ShaderType)]
+pub struct TemplateVertex {
+    pub position: Vec3,
+    pub normal: Vec3,
+}
+
+/// A small triangle list that is drawn once per instance of a
+/// [`MeshInstances`] batch, e.g. an arrow glyph or a cross.
+///
+/// Templates are modeled in the box from `-1` to `1` on every axis, which is
+/// stretched onto the box of each instance. Vertices are pulled from a storage
+/// buffer, without an index buffer.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TemplateMesh {
+    pub vertices: Vec<TemplateVertex>,
+}
+
+impl TemplateMesh {
+    /// The triangles are `vertices` taken by 3.
+    pub fn new(vertices: Vec<TemplateVertex>) -> Self {
+        assert_eq!(vertices.len() % 3, 0);
+        Self { vertices }
+    }
+
+    /// Copies the triangles of a [`PrimitiveTopology::TriangleList`] `mesh`,
+    /// resolving its indices. Faces are flat shaded when the mesh has no
+    /// normals.
+    ///
+    /// Returns `None` for other topologies, or without `Float32x3` positions.
+    pub fn from_mesh(mesh: &Mesh) -> Option<Self> {
+        if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+            return None;
+        }
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            return None;
+        };
+        let normals = match mesh.attribute(Mesh::ATTRIBUTE_NORMAL) {
+            Some(VertexAttributeValues::Float32x3(normals)) => Some(normals),
+            _ => None,
+        };
+        let indices: Vec<usize> = match mesh.indices() {
+            Some(indices) => indices.iter().collect(),
+            None => (0..positions.len()).collect(),
+        };
+
+        let mut vertices = Vec::with_capacity(indices.len());
+        for triangle in indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| Vec3::from(positions[triangle[i]]));
+            let flat_normal = (b - a).cross(c - a).normalize_or_zero();
+            for (&index, position) in triangle.iter().zip([a, b, c]) {
+                let normal = normals.map_or(flat_normal, |n| Vec3::from(n[index]));
+                vertices.push(TemplateVertex { position, normal });
+            }
+        }
+        Some(Self { vertices })
+    }
+
+    /// Three bars through the center along each axis, `thickness` wide
+    /// relative to the whole box.
+    pub fn cross(thickness: f32) -> Self {
+        let t = thickness.clamp(0.0, 1.0);
+        let mut vertices = Vec::with_capacity(3 * 36);
+        for axis in 0..3 {
+            let mut half_extents = Vec3::splat(t);
+            half_extents[axis] = 1.0;
+            push_box_triangles(&mut vertices, half_extents);
+        }
+        Self { vertices }
+    }
+}
+
+/// Pushes the 12 triangles of a box centered on the origin.
+fn push_box_triangles(vertices: &mut Vec<TemplateVertex>, half_extents: Vec3) {
+    for axis in 0..3 {
+        for sign in [-1.0, 1.0] {
+            let mut normal = Vec3::ZERO;
+            normal[axis] = sign;
+            let u = Vec3::AXES[(axis + 1) % 3] * sign;
+            let v = Vec3::AXES[(axis + 2) % 3];
+            let corner = |a: f32, b: f32| TemplateVertex {
+                position: (normal + a * u + b * v) * half_extents,
+                normal,
+            };
+            vertices.extend([
+                corner(-1.0, -1.0),
+                corner(1.0, -1.0),
+                corner(1.0, 1.0),
+                corner(-1.0, -1.0),
+                corner(1.0, 1.0),
+                corner(-1.0, 1.0),
+            ]);
+        }
+    }
+}
+
+/// A set of [`TemplateMesh`] instances to be extracted for rendering,
+/// alongside any [`Cuboids`](crate::Cuboids).
+///
+/// Each instance is given by a [`Cuboid`], whose box the template is stretched
+/// onto, and whose color and meta bits apply to the whole template. Instances
+/// are not rotated, per-batch orientation comes from the [`GlobalTransform`].
+///
+/// Like [`Spheres`](crate::Spheres), mesh instances use the same materials,
+/// transforms, buffer chunking and clipping as cuboids. Each instance is
+/// clipped as a whole, depending on its center. Wireframes, picking and
+/// shadows are not supported, and nothing is drawn on devices without storage
+/// buffers.
+#[derive(Clone, Component, Debug, Default)]
+pub struct MeshInstances {
+    /// Shared by every instance of the batch, and uploaded again whenever the
+    /// batch changes.
+    pub template: Arc<TemplateMesh>,
+    /// Instances to be rendered.
+    pub instances: Vec<Cuboid>,
+}
+
+impl MeshInstances {
+    pub fn new(template: Arc<TemplateMesh>, instances: Vec<Cuboid>) -> Self {
+        Self {
+            template,
+            instances,
+        }
+    }
+
+    pub fn aabb(&self) -> Aabb {
+        let mut min = Vec3::splat(f32::MAX);
+        let mut max = Vec3::splat(f32::MIN);
+        for i in self.instances.iter() {
+            min = min.min(i.minimum);
+            max = max.max(i.maximum);
+        }
+        Aabb::from_min_max(min, max)
+    }
+}
+
+#[derive(Bundle)]
+pub struct MeshInstancesBundle {
+    pub material_id: CuboidMaterialId,
+    pub mesh_instances: MeshInstances,
+    #[bundle]
+    pub spatial: SpatialBundle,
+}
+
+/// Recomputes the [`Aabb`] of every changed batch before Bevy checks
+/// visibility.
+pub(crate) fn update_mesh_instances_aabbs(
+    mut commands: Commands,
+    mut batches: Query<(Entity, &MeshInstances, Option<&mut Aabb>), Changed<MeshInstances>>,
+) {
+    for (entity, mesh_instances, maybe_aabb) in batches.iter_mut() {
+        if mesh_instances.instances.is_empty() {
+            continue;
+        }
+        match maybe_aabb {
+            Some(mut aabb) => *aabb = mesh_instances.aabb(),
+            None => {
+                commands.entity(entity).insert(mesh_instances.aabb());
+            }
+        }
+    }
+}
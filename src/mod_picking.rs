@@ -0,0 +1,110 @@
+use crate::clipping_planes::ClippingPlaneGizmo;
+use crate::selection::CuboidSelectionHighlight;
+use crate::{Cuboids, CuboidsLod, CuboidsOccluder};
+
+use bevy::{
+    math::Ray, prelude::*, render::view::RenderLayers, utils::HashMap, window::PrimaryWindow,
+};
+use bevy_picking_core::{
+    backend::{HitData, PointerHits},
+    pointer::{PointerId, PointerLocation},
+    PickSet,
+};
+
+/// A `bevy_mod_picking` backend that hit tests [`Cuboids`] batches on the CPU
+/// (`mod_picking` feature).
+///
+/// Each batch under a pointer is reported as a hit at its nearest visible
+/// instance, so batches get the same hover, click and drag events as meshes
+/// once they have a `PickableBundle`. The index of the instance that was hit
+/// is kept in [`CuboidPointerHits`], since `bevy_mod_picking` only reports
+/// entities.
+///
+/// Instances are tested like [`Cuboids::raycast`], every frame and for every
+/// pointer, so very large batches are better picked with
+/// [`VertexPullingRenderPlugin::gpu_picking`](crate::VertexPullingRenderPlugin::gpu_picking).
+pub struct CuboidsPickingBackend;
+
+impl Plugin for CuboidsPickingBackend {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CuboidPointerHits>()
+            .add_system(update_cuboid_pointer_hits.in_set(PickSet::Backend));
+    }
+}
+
+/// The instances hit by each pointer in the last update of
+/// [`CuboidsPickingBackend`].
+#[derive(Clone, Debug, Default, Resource)]
+pub struct CuboidPointerHits {
+    hits: HashMap<(PointerId, Entity), usize>,
+}
+
+impl CuboidPointerHits {
+    /// The index of the instance of `entity` under `pointer`, e.g. for the
+    /// `pointer_id` and `target` of a `bevy_mod_picking` event.
+    pub fn instance(&self, pointer: PointerId, entity: Entity) -> Option<usize> {
+        self.hits.get(&(pointer, entity)).copied()
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[allow(clippy::type_complexity)]
+fn update_cuboid_pointer_hits(
+    lod: Res<CuboidsLod>,
+    pointers: Query<(&PointerId, &PointerLocation)>,
+    primary_window: Query<Entity, With<PrimaryWindow>>,
+    cameras: Query<(Entity, &Camera, &GlobalTransform)>,
+    render_layers: Query<&RenderLayers>,
+    batches: Query<
+        (Entity, &Cuboids, &GlobalTransform, &ComputedVisibility),
+        (
+            Without<CuboidsOccluder>,
+            Without<ClippingPlaneGizmo>,
+            Without<CuboidSelectionHighlight>,
+        ),
+    >,
+    mut pointer_hits: ResMut<CuboidPointerHits>,
+    mut output: EventWriter<PointerHits>,
+) {
+    pointer_hits.hits.clear();
+    let layers = |entity| render_layers.get(entity).copied().unwrap_or_default();
+
+    for (&pointer, location) in pointers.iter() {
+        let Some(location) = location.location() else {
+            continue;
+        };
+        for (camera_entity, camera, camera_transform) in cameras.iter() {
+            if !camera.is_active || !location.is_in_viewport(camera, &primary_window) {
+                continue;
+            }
+            let Some(ray) = camera.viewport_to_world(camera_transform, location.position) else {
+                continue;
+            };
+            let camera_layers = layers(camera_entity);
+
+            let mut picks = Vec::new();
+            for (entity, cuboids, transform, visibility) in batches.iter() {
+                if !visibility.is_visible() || !camera_layers.intersects(&layers(entity)) {
+                    continue;
+                }
+                // The direction is not normalized, so that `t` is the distance
+                // along the world space ray.
+                let inv_matrix = transform.compute_matrix().inverse();
+                let local_ray = Ray {
+                    origin: inv_matrix.transform_point3(ray.origin),
+                    direction: inv_matrix.transform_vector3(ray.direction),
+                };
+                let Some((index, t)) = cuboids.raycast_up_to_lod(local_ray, lod.current_level)
+                else {
+                    continue;
+                };
+                let position = ray.origin + t * ray.direction;
+                picks.push((entity, HitData::new(camera_entity, t, Some(position), None)));
+                pointer_hits.hits.insert((pointer, entity), index);
+            }
+            if !picks.is_empty() {
+                output.send(PointerHits::new(pointer, picks, camera.order as f32));
+            }
+        }
+    }
+}
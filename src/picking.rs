@@ -0,0 +1,425 @@
+use crate::clipping_planes::ClippingPlaneGizmo;
+use crate::selection::CuboidSelectionHighlight;
+use crate::{
+    CuboidMaterialId, CuboidMaterialMap, Cuboids, CuboidsLod, CuboidsOccluder,
+    COLOR_MODE_SCALAR_HUE, MAX_LOD_LEVEL,
+};
+
+use bevy::{
+    math::Ray,
+    prelude::*,
+    render::{camera::NormalizedRenderTarget, view::RenderLayers},
+    window::PrimaryWindow,
+};
+
+/// Sent when the left mouse button is pressed over a [`Cuboids`] instance.
+///
+/// Only the nearest instance under the cursor of the primary window is picked,
+/// as seen from the highest-order active camera that renders to it. Hidden
+/// instances, [`CuboidsOccluder`] batches, batches on other [`RenderLayers`]
+/// than the camera, instances hidden by [`CuboidsLod`] and instances outside
+/// the scalar filter of their batch's material are ignored.
+/// Clipping planes are not taken into account.
+///
+/// With [`VertexPullingRenderPlugin::gpu_picking`](crate::VertexPullingRenderPlugin),
+/// clicks are resolved on the GPU instead, where clipping planes and occluders
+/// do apply, and the event arrives a few frames after the click. Hits for
+/// [`GpuPickingRequests`] are sent the same way.
+#[derive(Clone, Copy, Debug)]
+pub struct CuboidPickedEvent {
+    pub entity: Entity,
+    /// Index into [`Cuboids::instances`].
+    pub index: usize,
+    /// [`Cuboids::user_data`] of the instance, or zero.
+    pub user_data: u32,
+    /// [`Cuboids::scalars`] entry of the instance, if its batch has scalars.
+    pub scalar: Option<f32>,
+}
+
+impl Cuboids {
+    /// Finds the nearest visible instance hit by `ray`, given in the local
+    /// space of this entity.
+    ///
+    /// Returns the index of the instance and the ray parameter `t` of the hit,
+    /// i.e. the hit point is `ray.origin + t * ray.direction`. Rays starting
+    /// inside of an instance hit it at `t = 0`.
+    pub fn raycast(&self, ray: Ray) -> Option<(usize, f32)> {
+        self.raycast_up_to_lod(ray, MAX_LOD_LEVEL, None)
+    }
+
+    /// Like [`Cuboids::raycast`], skipping instances above `max_lod_level` and
+    /// scalars (with the material's offset already added) outside of
+    /// `visible_scalars`.
+    pub(crate) fn raycast_up_to_lod(
+        &self,
+        ray: Ray,
+        max_lod_level: u8,
+        visible_scalars: Option<(f32, f32)>,
+    ) -> Option<(usize, f32)> {
+        let mut nearest: Option<(usize, f32)> = None;
+        for (index, cuboid) in self.instances.iter().enumerate() {
+            if !self.is_visible(index) || cuboid.lod_level() > max_lod_level {
+                continue;
+            }
+            if let Some((min, max)) = visible_scalars {
+                let scalar = f32::from_bits(self.gpu_color(index));
+                if !(min..=max).contains(&scalar) {
+                    continue;
+                }
+            }
+            let center = 0.5 * (cuboid.minimum + cuboid.maximum);
+            let half_extents = 0.5 * (cuboid.maximum - cuboid.minimum);
+
+            // Slab test in the frame of the (possibly rotated) box.
+            let inv_rotation = self.rotation(index).inverse();
+            let origin = inv_rotation * (ray.origin - center);
+            let inv_direction = (inv_rotation * ray.direction).recip();
+            let t0 = (-half_extents - origin) * inv_direction;
+            let t1 = (half_extents - origin) * inv_direction;
+            let t_enter = t0.min(t1).max_element().max(0.0);
+            let t_exit = t0.max(t1).min_element();
+
+            if t_enter <= t_exit && nearest.map_or(true, |(_, t)| t_enter < t) {
+                nearest = Some((index, t_enter));
+            }
+        }
+        nearest
+    }
+}
+
+/// Reads back the [`Cuboids`] instance drawn at a pixel, without testing any
+/// instances on the CPU.
+///
+/// Requires [`VertexPullingRenderPlugin::gpu_picking`](crate::VertexPullingRenderPlugin),
+/// which inserts this resource. Each request renders instance IDs for a single
+/// pixel of the camera's view and copies the result back asynchronously. Hits
+/// arrive as [`CuboidPickedEvent`]s a few frames later; misses send nothing.
+#[derive(Clone, Debug, Default, ExtractResource, Resource)]
+pub struct GpuPickingRequests {
+    requests: Vec<GpuPickingRequest>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct GpuPickingRequest {
+    pub camera: Entity,
+    pub pixel: UVec2,
+}
+
+impl GpuPickingRequests {
+    /// Picks the instance that `camera` draws at `pixel`, in physical pixels
+    /// from the top-left corner of its viewport.
+    pub fn request(&mut self, camera: Entity, pixel: UVec2) {
+        self.requests.push(GpuPickingRequest { camera, pixel });
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &GpuPickingRequest> {
+        self.requests.iter()
+    }
+}
+
+/// Results of the GPU picking pass, shared by the main and render worlds like
+/// [`CuboidsErrors`](crate::CuboidsErrors).
+#[derive(Clone, Default, Resource)]
+pub(crate) struct GpuPickingResults {
+    queue: Arc<Mutex<Vec<CuboidPickedEvent>>>,
+}
+
+impl GpuPickingResults {
+    pub fn send(&self, event: CuboidPickedEvent) {
+        self.queue.lock().unwrap().push(event);
+    }
+
+    fn drain(&self) -> Vec<CuboidPickedEvent> {
+        std::mem::take(&mut *self.queue.lock().unwrap())
+    }
+}
+
+fn left_clicked(mouse_buttons: Option<Res<Input<MouseButton>>>) -> bool {
+    mouse_buttons.map_or(false, |b| b.just_pressed(MouseButton::Left))
+}
+
+/// The cursor position in the primary window, and the highest-order active
+/// camera that renders to it.
+#[allow(clippy::type_complexity)]
+fn cursor_camera<'a>(
+    windows: &Query<(Entity, &Window), With<PrimaryWindow>>,
+    cameras: &'a Query<(Entity, &Camera, &GlobalTransform)>,
+) -> Option<(Entity, &'a Camera, &'a GlobalTransform, Vec2)> {
+    let (window_entity, window) = windows.get_single().ok()?;
+    let cursor = window.cursor_position()?;
+    let (entity, camera, transform) = cameras
+        .iter()
+        .filter(|(_, camera, _)| {
+            let target = camera.target.normalize(Some(window_entity));
+            let renders_to_window = matches!(
+                target,
+                Some(NormalizedRenderTarget::Window(w)) if w.entity() == window_entity
+            );
+            camera.is_active && renders_to_window
+        })
+        .max_by_key(|(_, camera, _)| camera.order)?;
+    Some((entity, camera, transform, cursor))
+}
+
+type PickableBatches<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static Cuboids,
+        &'static CuboidMaterialId,
+        &'static GlobalTransform,
+        &'static ComputedVisibility,
+    ),
+    (
+        Without<CuboidsOccluder>,
+        Without<ClippingPlaneGizmo>,
+        Without<CuboidSelectionHighlight>,
+    ),
+>;
+
+/// The nearest instance under the cursor, and where the cursor ray hits it in
+/// world space.
+fn pick_under_cursor(
+    lod: &CuboidsLod,
+    materials: &CuboidMaterialMap,
+    windows: &Query<(Entity, &Window), With<PrimaryWindow>>,
+    cameras: &Query<(Entity, &Camera, &GlobalTransform)>,
+    render_layers: &Query<&RenderLayers>,
+    batches: &PickableBatches,
+) -> Option<(CuboidPickedEvent, Vec3)> {
+    let (camera_entity, camera, camera_transform, cursor) = cursor_camera(windows, cameras)?;
+    let layers = |entity| render_layers.get(entity).copied().unwrap_or_default();
+    let camera_layers = layers(camera_entity);
+    let ray = camera.viewport_to_world(camera_transform, cursor)?;
+
+    let mut nearest: Option<(CuboidPickedEvent, f32)> = None;
+    for (entity, cuboids, &material_id, transform, visibility) in batches.iter() {
+        if !visibility.is_visible() || !camera_layers.intersects(&layers(entity)) {
+            continue;
+        }
+        // The direction is not normalized, so that `t` is the same in both
+        // spaces.
+        let inv_matrix = transform.compute_matrix().inverse();
+        let local_ray = Ray {
+            origin: inv_matrix.transform_point3(ray.origin),
+            direction: inv_matrix.transform_vector3(ray.direction),
+        };
+        // Match the scalar filter of the vertex shader, so that filtered out
+        // instances can't be picked.
+        let material = materials.get(material_id);
+        let visible_scalars = (material.color_mode == COLOR_MODE_SCALAR_HUE).then(|| {
+            let options = &material.scalar_hue;
+            (
+                options.min_visible - material.scalar_offset,
+                options.max_visible - material.scalar_offset,
+            )
+        });
+        let Some((index, t)) =
+            cuboids.raycast_up_to_lod(local_ray, lod.current_level, visible_scalars)
+        else {
+            continue;
+        };
+        if nearest.map_or(true, |(_, nearest_t)| t < nearest_t) {
+            nearest = Some((
+                CuboidPickedEvent {
+                    entity,
+                    index,
+                    user_data: cuboids.instance_user_data(index),
+                    scalar: cuboids.instance_scalar(index),
+                },
+                t,
+            ));
+        }
+    }
+    nearest.map(|(event, t)| (event, ray.get_point(t)))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn pick_cuboids(
+    mouse_buttons: Option<Res<Input<MouseButton>>>,
+    lod: Res<CuboidsLod>,
+    materials: Res<CuboidMaterialMap>,
+    windows: Query<(Entity, &Window), With<PrimaryWindow>>,
+    cameras: Query<(Entity, &Camera, &GlobalTransform)>,
+    render_layers: Query<&RenderLayers>,
+    batches: PickableBatches,
+    mut events: EventWriter<CuboidPickedEvent>,
+) {
+    if !left_clicked(mouse_buttons) {
+        return;
+    }
+    if let Some((event, _)) = pick_under_cursor(
+        &lod,
+        &materials,
+        &windows,
+        &cameras,
+        &render_layers,
+        &batches,
+    ) {
+        events.send(event);
+    }
+}
+
+/// Whether a [`CuboidHoverEvent`] starts, continues or ends hovering over an
+/// instance.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CuboidHoverPhase {
+    /// The cursor moved onto the instance.
+    Enter,
+    /// The cursor is still over the instance, sent on every test after
+    /// `Enter`.
+    Over,
+    /// The cursor left the instance, or its batch is gone. `world_pos` is
+    /// where it was last hit.
+    Exit,
+}
+
+/// Sent for the [`Cuboids`] instance under the cursor of the primary window,
+/// so that UI layers can show tooltips without their own hit testing.
+///
+/// Instances are found like the clicks of [`CuboidPickedEvent`], always on
+/// the CPU, at most once per [`CuboidHoverSettings::interval`]. Each test sends
+/// `Over` for the hovered instance, or `Exit` and then `Enter` when it changed.
+#[derive(Clone, Copy, Debug)]
+pub struct CuboidHoverEvent {
+    pub entity: Entity,
+    /// Index into [`Cuboids::instances`].
+    pub index: usize,
+    /// Where the cursor ray hits the instance, in world space.
+    pub world_pos: Vec3,
+    pub phase: CuboidHoverPhase,
+}
+
+/// How often [`CuboidHoverEvent`]s test the instance under the cursor.
+#[derive(Clone, Debug, Resource)]
+pub struct CuboidHoverSettings {
+    /// Seconds between tests, to bound the cost of raycasting large batches
+    /// while the cursor moves. Zero tests every frame.
+    pub interval: f32,
+}
+
+impl Default for CuboidHoverSettings {
+    fn default() -> Self {
+        Self { interval: 0.05 }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct CuboidHoverState {
+    last_test: Option<f32>,
+    hovered: Option<CuboidHoverEvent>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn send_cuboid_hovers(
+    settings: Res<CuboidHoverSettings>,
+    time: Res<Time>,
+    lod: Res<CuboidsLod>,
+    materials: Res<CuboidMaterialMap>,
+    windows: Query<(Entity, &Window), With<PrimaryWindow>>,
+    cameras: Query<(Entity, &Camera, &GlobalTransform)>,
+    render_layers: Query<&RenderLayers>,
+    batches: PickableBatches,
+    mut state: Local<CuboidHoverState>,
+    mut events: EventWriter<CuboidHoverEvent>,
+) {
+    let now = time.elapsed_seconds();
+    if state
+        .last_test
+        .map_or(false, |last| now - last < settings.interval)
+    {
+        return;
+    }
+    state.last_test = Some(now);
+
+    let hit = pick_under_cursor(
+        &lod,
+        &materials,
+        &windows,
+        &cameras,
+        &render_layers,
+        &batches,
+    );
+    let hovered = hit.map(|(pick, world_pos)| CuboidHoverEvent {
+        entity: pick.entity,
+        index: pick.index,
+        world_pos,
+        phase: CuboidHoverPhase::Over,
+    });
+    let same =
+        |a: &CuboidHoverEvent, b: &CuboidHoverEvent| a.entity == b.entity && a.index == b.index;
+    match (state.hovered, hovered) {
+        (Some(previous), Some(current)) if same(&previous, &current) => {
+            events.send(current);
+        }
+        (previous, current) => {
+            if let Some(previous) = previous {
+                events.send(CuboidHoverEvent {
+                    phase: CuboidHoverPhase::Exit,
+                    ..previous
+                });
+            }
+            if let Some(current) = current {
+                events.send(CuboidHoverEvent {
+                    phase: CuboidHoverPhase::Enter,
+                    ..current
+                });
+            }
+        }
+    }
+    state.hovered = hovered;
+}
+
+/// Like [`pick_cuboids`], but reads the clicked pixel back from the GPU.
+pub(crate) fn request_gpu_pick_on_click(
+    mouse_buttons: Option<Res<Input<MouseButton>>>,
+    windows: Query<(Entity, &Window), With<PrimaryWindow>>,
+    cameras: Query<(Entity, &Camera, &GlobalTransform)>,
+    mut requests: ResMut<GpuPickingRequests>,
+) {
+    if !left_clicked(mouse_buttons) {
+        return;
+    }
+    let Some((entity, camera, _, cursor)) = cursor_camera(&windows, &cameras) else {
+        return;
+    };
+    let (Some(logical_size), Some(physical_size)) = (
+        camera.logical_viewport_size(),
+        camera.physical_viewport_size(),
+    ) else {
+        return;
+    };
+    // The cursor starts at the bottom-left, pixels start at the top-left.
+    let scale = physical_size.as_vec2() / logical_size;
+    let pixel = Vec2::new(cursor.x, logical_size.y - cursor.y) * scale;
+    let pixel = pixel.as_uvec2().min(physical_size.max(UVec2::ONE) - 1);
+    requests.request(entity, pixel);
+}
+
+/// Requests are extracted once, at the end of the frame they were made in.
+pub(crate) fn clear_gpu_picking_requests(mut requests: ResMut<GpuPickingRequests>) {
+    requests.requests.clear();
+}
+
+pub(crate) fn send_gpu_picks(
+    results: Res<GpuPickingResults>,
+    batches: Query<&Cuboids>,
+    highlights: Query<&CuboidSelectionHighlight>,
+    mut events: EventWriter<CuboidPickedEvent>,
+) {
+    events.send_batch(results.drain().into_iter().map(|mut event| {
+        // Highlights are drawn over the instance they highlight.
+        if let Ok(highlight) = highlights.get(event.entity) {
+            if let Some(&index) = highlight.indices.get(event.index) {
+                event.entity = highlight.source;
+                event.index = index;
+            }
+        }
+        if let Ok(cuboids) = batches.get(event.entity) {
+            event.user_data = cuboids.instance_user_data(event.index);
+            event.scalar = cuboids.instance_scalar(event.index);
+        }
+        event
+    }));
+}
@@ -0,0 +1,177 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::{
+    Colormap, CuboidColormaps, CuboidMaterialId, CuboidMaterialMap, Cuboids, ScalarHueOptions,
+    COLOR_MODE_SCALAR_HUE,
+};
+
+/// The colors that a [`CuboidMaterial`](crate::CuboidMaterial) in
+/// [`COLOR_MODE_SCALAR_HUE`] gives to scalars, as the shader computes them,
+/// e.g. to draw a legend in a UI.
+#[derive(Clone, Debug)]
+pub struct ColorLegend {
+    /// The scalar at the first color, `clamp_min` of the material minus its
+    /// `scalar_offset`.
+    pub min: f32,
+    /// The scalar at the last color, `clamp_max` of the material minus its
+    /// `scalar_offset`.
+    pub max: f32,
+    options: ScalarHueOptions,
+    tint: Vec4,
+    colormap: Option<Colormap>,
+}
+
+impl ColorLegend {
+    /// The color of cuboids with `scalar`, before edge shading, lighting and
+    /// fog.
+    pub fn color(&self, scalar: f32) -> Color {
+        let s = (scalar.max(self.min).min(self.max) - self.min) / (self.max - self.min);
+        let color = match &self.colormap {
+            Some(colormap) => colormap.sample(s),
+            None => {
+                let o = &self.options;
+                let hue = (360.0 + (o.hue_zero + s * o.hue_slope)) % 360.0;
+                Color::hsl(hue, o.saturation, o.lightness)
+            }
+        };
+        let [r, g, b, a] = (Vec4::from(color.as_rgba_f32()) * self.tint).to_array();
+        Color::rgba(r, g, b, a)
+    }
+
+    /// `n` colors of scalars evenly spaced from `min` to `max`.
+    pub fn colors(&self, n: usize) -> Vec<Color> {
+        let step = (self.max - self.min) / n.saturating_sub(1).max(1) as f32;
+        (0..n)
+            .map(|i| self.color(self.min + i as f32 * step))
+            .collect()
+    }
+}
+
+/// A [`ColorLegend`] for every material in [`COLOR_MODE_SCALAR_HUE`].
+///
+/// Updated in `PostUpdate` whenever the [`CuboidMaterialMap`] or
+/// [`CuboidColormaps`] change, including by
+/// [`CuboidMaterialMap::set_auto_scalar_range`], so a legend always matches
+/// the colors being drawn.
+#[derive(Clone, Debug, Default, Resource)]
+pub struct CuboidColorLegends {
+    legends: HashMap<CuboidMaterialId, ColorLegend>,
+}
+
+impl CuboidColorLegends {
+    pub fn get(&self, id: CuboidMaterialId) -> Option<&ColorLegend> {
+        self.legends.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (CuboidMaterialId, &ColorLegend)> {
+        self.legends.iter().map(|(&id, legend)| (id, legend))
+    }
+}
+
+/// The smallest and largest finite scalar of the visible instances, including
+/// their face colors.
+fn scalar_range(cuboids: &Cuboids) -> Option<Vec2> {
+    let mut range: Option<Vec2> = None;
+    for i in 0..cuboids.instances.len() {
+        if !cuboids.is_visible(i) {
+            continue;
+        }
+        let face_colors = cuboids.face_colors.get(i).into_iter().flatten();
+        for color in std::iter::once(cuboids.gpu_color(i)).chain(face_colors.copied()) {
+            let scalar = f32::from_bits(color);
+            if !scalar.is_finite() {
+                continue;
+            }
+            range = Some(range.map_or(Vec2::splat(scalar), |r| {
+                Vec2::new(r.x.min(scalar), r.y.max(scalar))
+            }));
+        }
+    }
+    range
+}
+
+/// Fits `clamp_min` and `clamp_max` of every material with an automatic scalar
+/// range to the [`Cuboids`] that use it.
+///
+/// Only batches that changed are scanned again.
+pub(crate) fn update_auto_scalar_ranges(
+    mut materials: ResMut<CuboidMaterialMap>,
+    batches: Query<
+        (Entity, &CuboidMaterialId, &Cuboids),
+        Or<(Changed<Cuboids>, Changed<CuboidMaterialId>)>,
+    >,
+    mut removed: RemovedComponents<Cuboids>,
+    mut batch_ranges: Local<HashMap<Entity, (CuboidMaterialId, Option<Vec2>)>>,
+) {
+    let mut changed = materials.is_changed();
+    for entity in removed.iter() {
+        changed |= batch_ranges.remove(&entity).is_some();
+    }
+    for (entity, &material_id, cuboids) in batches.iter() {
+        batch_ranges.insert(entity, (material_id, scalar_range(cuboids)));
+        changed = true;
+    }
+    if !changed {
+        return;
+    }
+
+    let mut material_ranges: HashMap<CuboidMaterialId, Vec2> = default();
+    for &(id, range) in batch_ranges.values() {
+        if let Some(range) = range {
+            material_ranges
+                .entry(id)
+                .and_modify(|r| *r = Vec2::new(r.x.min(range.x), r.y.max(range.y)))
+                .or_insert(range);
+        }
+    }
+    for (id, range) in material_ranges {
+        if id.0 >= materials.len() || !materials.auto_scalar_range(id) {
+            continue;
+        }
+        // A single scalar still needs a range to be normalized by.
+        let max = if range.y > range.x {
+            range.y
+        } else {
+            range.x + 1.0
+        };
+        let options = &materials.get(id).scalar_hue;
+        // Only written when different, so materials aren't changed every frame.
+        if options.clamp_min != range.x || options.clamp_max != max {
+            let options = &mut materials.get_mut(id).scalar_hue;
+            options.clamp_min = range.x;
+            options.clamp_max = max;
+        }
+    }
+}
+
+pub(crate) fn update_cuboid_color_legends(
+    materials: Res<CuboidMaterialMap>,
+    colormaps: Res<CuboidColormaps>,
+    mut legends: ResMut<CuboidColorLegends>,
+) {
+    if !materials.is_changed() && !colormaps.is_changed() {
+        return;
+    }
+    legends.legends = materials
+        .iter()
+        .filter(|(_, material)| material.color_mode == COLOR_MODE_SCALAR_HUE)
+        .map(|(id, material)| {
+            // Unknown colormaps are white on the GPU.
+            let colormap = (material.colormap != 0).then(|| {
+                colormaps
+                    .get(material.colormap)
+                    .cloned()
+                    .unwrap_or_else(|| Colormap::from_colors(&[Color::WHITE]))
+            });
+            let legend = ColorLegend {
+                min: material.scalar_hue.clamp_min - material.scalar_offset,
+                max: material.scalar_hue.clamp_max - material.scalar_offset,
+                options: material.scalar_hue.clone(),
+                tint: material.tint,
+                colormap,
+            };
+            (id, legend)
+        })
+        .collect();
+}
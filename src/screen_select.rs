@@ -0,0 +1,142 @@
+use crate::Cuboids;
+
+use bevy::{math::Rect, prelude::*, render::primitives::Aabb};
+
+/// A region of a camera's viewport, in logical pixels from its bottom-left
+/// corner like `Window::cursor_position`, e.g. a rubber band or a lasso drawn
+/// with the mouse.
+#[derive(Clone, Debug)]
+pub enum ScreenRegion {
+    Rect(Rect),
+    /// A closed polygon, which may be concave or self-intersecting. Points
+    /// inside are found with the even-odd rule.
+    Polygon(Vec<Vec2>),
+}
+
+/// Which instances [`cuboids_in_screen_region`] returns.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ScreenSelectMode {
+    /// Instances whose projected center is in the region.
+    #[default]
+    Center,
+    /// Instances whose eight projected corners are all in the region. For
+    /// concave polygons, an edge may still leave the region between corners.
+    Contained,
+}
+
+impl ScreenRegion {
+    pub fn contains(&self, point: Vec2) -> bool {
+        match self {
+            Self::Rect(rect) => rect.contains(point),
+            Self::Polygon(points) => {
+                let mut inside = false;
+                for (i, &a) in points.iter().enumerate() {
+                    let b = points[(i + 1) % points.len()];
+                    if (a.y > point.y) != (b.y > point.y)
+                        && point.x < a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x)
+                    {
+                        inside = !inside;
+                    }
+                }
+                inside
+            }
+        }
+    }
+
+    /// The smallest rectangle around the region.
+    pub fn bounds(&self) -> Rect {
+        match self {
+            Self::Rect(rect) => *rect,
+            Self::Polygon(points) => rect_around(points),
+        }
+    }
+}
+
+/// Finds every visible instance of `batches` that `camera` projects into
+/// `region`, as `(entity, index)` pairs, e.g. to fill a
+/// [`CuboidSelection`](crate::CuboidSelection) from a box or lasso select.
+///
+/// Each batch is given with its [`GlobalTransform`] and, to skip batches
+/// whose bounds project outside of the region, its [`Aabb`], which
+/// [`VertexPullingRenderPlugin`](crate::VertexPullingRenderPlugin) keeps up
+/// to date for every [`Cuboids`] entity and every chunk of a
+/// [`CuboidChunks`](crate::CuboidChunks). Instances behind the camera are
+/// never selected. Like CPU picking, this ignores clipping planes and the
+/// vertex shader's scalar filter, and doesn't test occlusion. Leave out
+/// [`ClippingPlaneGizmo`](crate::ClippingPlaneGizmo) entities, which CPU
+/// picking skips as well.
+pub fn cuboids_in_screen_region<'a>(
+    camera: &Camera,
+    camera_transform: &GlobalTransform,
+    region: &ScreenRegion,
+    mode: ScreenSelectMode,
+    batches: impl IntoIterator<Item = (Entity, &'a Cuboids, &'a GlobalTransform, Option<&'a Aabb>)>,
+) -> Vec<(Entity, usize)> {
+    let Some(viewport_size) = camera.logical_viewport_size() else {
+        return Vec::new();
+    };
+    let view_proj = camera.projection_matrix() * camera_transform.compute_matrix().inverse();
+    let bounds = region.bounds();
+
+    let mut selected = Vec::new();
+    for (entity, cuboids, transform, aabb) in batches {
+        let clip_from_local = view_proj * transform.compute_matrix();
+        let project = |point: Vec3| {
+            let clip = clip_from_local * point.extend(1.0);
+            (clip.w > 0.0)
+                .then(|| (clip.truncate().truncate() / clip.w + 1.0) / 2.0 * viewport_size)
+        };
+        if let Some(aabb) = aabb {
+            let corners = box_corners(aabb.center.into(), aabb.half_extents.into(), Quat::IDENTITY);
+            // Batches that reach behind the camera can't be pruned this way.
+            if let Some(points) = corners.map(project).into_iter().collect::<Option<Vec<_>>>() {
+                if rect_around(&points).intersect(bounds).is_empty() {
+                    continue;
+                }
+            }
+        }
+
+        for (index, cuboid) in cuboids.instances.iter().enumerate() {
+            if !cuboids.is_visible(index) {
+                continue;
+            }
+            let center = 0.5 * (cuboid.minimum + cuboid.maximum);
+            let inside = match mode {
+                ScreenSelectMode::Center => project(center).map_or(false, |p| region.contains(p)),
+                ScreenSelectMode::Contained => {
+                    let half_extents = 0.5 * (cuboid.maximum - cuboid.minimum);
+                    box_corners(center, half_extents, cuboids.rotation(index))
+                        .into_iter()
+                        .all(|corner| project(corner).map_or(false, |p| region.contains(p)))
+                }
+            };
+            if inside {
+                selected.push((entity, index));
+            }
+        }
+    }
+    selected
+}
+
+fn rect_around(points: &[Vec2]) -> Rect {
+    let min = points
+        .iter()
+        .copied()
+        .fold(Vec2::splat(f32::MAX), Vec2::min);
+    let max = points
+        .iter()
+        .copied()
+        .fold(Vec2::splat(f32::MIN), Vec2::max);
+    Rect::from_corners(min, max)
+}
+
+fn box_corners(center: Vec3, half_extents: Vec3, rotation: Quat) -> [Vec3; 8] {
+    std::array::from_fn(|i| {
+        let signs = Vec3::new(
+            if i & 1 == 0 { -1.0 } else { 1.0 },
+            if i & 2 == 0 { -1.0 } else { 1.0 },
+            if i & 4 == 0 { -1.0 } else { 1.0 },
+        );
+        center + rotation * (signs * half_extents)
+    })
+}
@@ -0,0 +1,261 @@
+use crate::{
+    ClippingPlaneGizmo, Color, Cuboid, CuboidMaterial, CuboidMaterialId, CuboidMaterialMap,
+    CuboidPickedEvent, Cuboids, CuboidsBundle,
+};
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+/// Selected instances of [`Cuboids`] entities, drawn with a highlight.
+///
+/// Highlights are rendered as regular cuboids in a child of each batch with a
+/// selection, slightly larger than the selected instances, or into a mask for
+/// [`CuboidHighlightStyle::Contour`]. Clicking such a highlight picks the
+/// instance under it.
+#[derive(Clone, Debug, Resource)]
+pub struct CuboidSelection {
+    selected: HashSet<(Entity, usize)>,
+    pub style: CuboidHighlightStyle,
+    /// RGBA color of the highlight. With [`CuboidHighlightStyle::Tint`], the
+    /// alpha is how strongly it covers the instance color.
+    pub color: Color,
+    /// Highlights extend past each side of their instance by this fraction of
+    /// its size.
+    pub margin: f32,
+    /// Width of [`CuboidHighlightStyle::Contour`] in physical pixels, at most
+    /// 16.
+    pub contour_width: f32,
+    /// Selects the instance of every [`CuboidPickedEvent`], replacing the
+    /// selection, or toggling it while shift is held. Clicks that miss every
+    /// instance keep the selection.
+    pub select_on_pick: bool,
+}
+
+/// How [`CuboidSelection`] highlights selected instances.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CuboidHighlightStyle {
+    /// Covers each instance with a translucent box.
+    #[default]
+    Tint,
+    /// Draws the edges of a box around each instance, like
+    /// [`CuboidMaterial::wireframe`].
+    Outline,
+    /// Draws a crisp contour of constant screen-space width around the
+    /// silhouette of the selection, like object outlines in editors, over
+    /// everything in front of it.
+    ///
+    /// Highlights are drawn into a mask in a pass of their own, whose
+    /// outside edge is drawn over the view target after the main pass,
+    /// independently of the edge shading of materials. Instances that are
+    /// selected together share one contour.
+    Contour,
+}
+
+impl Default for CuboidSelection {
+    fn default() -> Self {
+        Self {
+            selected: default(),
+            style: default(),
+            // Translucent gold, as `Color::as_rgba_u32` packs it.
+            color: 0x8000D7FF,
+            margin: 0.02,
+            contour_width: 2.0,
+            select_on_pick: false,
+        }
+    }
+}
+
+impl CuboidSelection {
+    pub fn select(&mut self, entity: Entity, index: usize) {
+        self.selected.insert((entity, index));
+    }
+
+    pub fn deselect(&mut self, entity: Entity, index: usize) {
+        self.selected.remove(&(entity, index));
+    }
+
+    /// Selects the instance if it isn't, and deselects it otherwise.
+    pub fn toggle(&mut self, entity: Entity, index: usize) {
+        if !self.selected.remove(&(entity, index)) {
+            self.selected.insert((entity, index));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.selected.clear();
+    }
+
+    pub fn is_selected(&self, entity: Entity, index: usize) -> bool {
+        self.selected.contains(&(entity, index))
+    }
+
+    /// The selected instances, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (Entity, usize)> + '_ {
+        self.selected.iter().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.selected.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.selected.is_empty()
+    }
+}
+
+/// Marks the [`Cuboids`] entity that highlights the selected instances of
+/// `source`.
+#[derive(Component)]
+pub(crate) struct CuboidSelectionHighlight {
+    pub source: Entity,
+    /// The instance of `source` under each highlight instance.
+    pub indices: Vec<usize>,
+}
+
+pub(crate) fn select_picked_cuboids(
+    keys: Option<Res<Input<KeyCode>>>,
+    mut selection: ResMut<CuboidSelection>,
+    mut events: EventReader<CuboidPickedEvent>,
+) {
+    if !selection.select_on_pick {
+        events.clear();
+        return;
+    }
+    let toggle = keys.map_or(false, |k| k.any_pressed([KeyCode::LShift, KeyCode::RShift]));
+    for event in events.iter() {
+        if toggle {
+            selection.toggle(event.entity, event.index);
+        } else {
+            selection.clear();
+            selection.select(event.entity, event.index);
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub(crate) fn update_cuboid_selection_highlights(
+    mut commands: Commands,
+    selection: Res<CuboidSelection>,
+    mut highlight_materials: Local<Option<[CuboidMaterialId; 2]>>,
+    mut material_map: ResMut<CuboidMaterialMap>,
+    batches: Query<
+        &Cuboids,
+        (
+            Without<CuboidSelectionHighlight>,
+            Without<ClippingPlaneGizmo>,
+        ),
+    >,
+    mut highlights: Query<(
+        Entity,
+        &mut CuboidSelectionHighlight,
+        &mut Cuboids,
+        &mut CuboidMaterialId,
+    )>,
+) {
+    let mut selected: HashMap<Entity, Vec<usize>> = HashMap::default();
+    for (entity, index) in selection.iter() {
+        selected.entry(entity).or_default().push(index);
+    }
+    if selected.is_empty() && highlights.is_empty() {
+        return;
+    }
+
+    let [tint, outline] = *highlight_materials.get_or_insert_with(|| {
+        [
+            material_map.push(CuboidMaterial {
+                alpha_blend: 1,
+                ..default()
+            }),
+            material_map.push(CuboidMaterial {
+                wireframe: 1,
+                ..default()
+            }),
+        ]
+    });
+    // Contour masks skip every pass that uses the material.
+    let material_id = match selection.style {
+        CuboidHighlightStyle::Tint | CuboidHighlightStyle::Contour => tint,
+        CuboidHighlightStyle::Outline => outline,
+    };
+
+    for (entity, mut highlight, mut cuboids, mut highlight_material) in highlights.iter_mut() {
+        let source = highlight.source;
+        let built = selected
+            .remove(&source)
+            .zip(batches.get(source).ok())
+            .map(|(indices, batch)| highlight_instances(&selection, batch, indices));
+        let Some((indices, instances, rotations)) = built.filter(|(i, ..)| !i.is_empty()) else {
+            commands.entity(entity).despawn_recursive();
+            continue;
+        };
+        // Avoid triggering change detection when nothing changed.
+        if highlight.indices != indices {
+            highlight.indices = indices;
+        }
+        if cuboids.instances != instances || cuboids.rotations != rotations {
+            cuboids.instances = instances;
+            cuboids.rotations = rotations;
+        }
+        if *highlight_material != material_id {
+            *highlight_material = material_id;
+        }
+    }
+
+    for (source, indices) in selected {
+        let Ok(batch) = batches.get(source) else {
+            continue;
+        };
+        let (indices, instances, rotations) = highlight_instances(&selection, batch, indices);
+        if indices.is_empty() {
+            continue;
+        }
+        let cuboids = if rotations.is_empty() {
+            Cuboids::new(instances)
+        } else {
+            Cuboids::with_rotations(instances, rotations)
+        };
+        let highlight = commands
+            .spawn((
+                CuboidsBundle {
+                    material_id,
+                    cuboids,
+                    spatial: default(),
+                },
+                CuboidSelectionHighlight { source, indices },
+            ))
+            .id();
+        commands.entity(source).add_child(highlight);
+    }
+}
+
+/// Builds the highlight of the visible instances at `indices` of `batch`, in
+/// its local space. Returns the indices that are highlighted, in order.
+fn highlight_instances(
+    selection: &CuboidSelection,
+    batch: &Cuboids,
+    mut indices: Vec<usize>,
+) -> (Vec<usize>, Vec<Cuboid>, Vec<Quat>) {
+    indices.sort_unstable();
+    indices.retain(|&i| i < batch.instances.len() && batch.is_visible(i));
+    let instances = indices
+        .iter()
+        .map(|&i| {
+            let cuboid = &batch.instances[i];
+            let margin = selection.margin * (cuboid.maximum - cuboid.minimum);
+            Cuboid::new(
+                cuboid.minimum - margin,
+                cuboid.maximum + margin,
+                selection.color,
+            )
+        })
+        .collect();
+    // Rotations are about the center, which the margin doesn't move.
+    let rotations = if batch.rotations.is_empty() {
+        Vec::new()
+    } else {
+        indices.iter().map(|&i| batch.rotation(i)).collect()
+    };
+    (indices, instances, rotations)
+}
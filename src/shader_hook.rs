@@ -0,0 +1,65 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::vertex_pulling::pipeline::VERTEX_PULLING_SHADER_SOURCE;
+use crate::CuboidMaterialMap;
+
+/// WGSL that is injected into the cuboid fragment shader of a
+/// [`CuboidMaterial`](crate::CuboidMaterial), set with
+/// [`CuboidMaterialMap::set_shader_hook`].
+///
+/// `fragment_modify` must define
+/// ```wgsl
+/// fn cuboid_fragment_modify(color: vec4<f32>, in: CuboidHookInput) -> vec4<f32>
+/// ```
+/// which is given the final color of each fragment, after edges, lighting and
+/// fog, and returns the color to be written. See `CuboidHookInput` in
+/// `vertex_pulling.wgsl` for what it can read. The hook is appended to the
+/// cuboid shader, so it can also call any of its functions and read its
+/// bindings.
+///
+/// Each distinct hook specializes its own set of pipelines, compiled once when
+/// the hook is first used. Errors in the WGSL are reported as
+/// [`CuboidsError::PipelineCompileFailed`](crate::CuboidsError::PipelineCompileFailed).
+///
+/// Hooks only apply to [`Cuboids`](crate::Cuboids) in the main opaque and
+/// sorted transparent passes. Order-independent transparency, picking,
+/// shadows, and the other primitives ignore them.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct CuboidShaderHook {
+    pub fragment_modify: String,
+}
+
+impl CuboidShaderHook {
+    pub fn new(fragment_modify: impl Into<String>) -> Self {
+        Self {
+            fragment_modify: fragment_modify.into(),
+        }
+    }
+}
+
+/// The cuboid shader with each hook appended that was ever set on a material.
+///
+/// Shaders are kept after their hook is removed, so toggling a hook doesn't
+/// compile it again.
+#[derive(Default, Resource)]
+pub(crate) struct CuboidShaderHookShaders {
+    pub handles: HashMap<CuboidShaderHook, Handle<Shader>>,
+}
+
+pub(crate) fn add_cuboid_shader_hook_shaders(
+    materials: Res<CuboidMaterialMap>,
+    mut shaders: ResMut<Assets<Shader>>,
+    mut hook_shaders: ResMut<CuboidShaderHookShaders>,
+) {
+    if !materials.is_changed() {
+        return;
+    }
+    for (_, hook) in materials.shader_hooks() {
+        if !hook_shaders.handles.contains_key(hook) {
+            let source = format!("{VERTEX_PULLING_SHADER_SOURCE}\n{}\n", hook.fragment_modify);
+            let handle = shaders.add(Shader::from_wgsl(source));
+            hook_shaders.handles.insert(hook.clone(), handle);
+        }
+    }
+}
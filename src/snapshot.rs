@@ -0,0 +1,350 @@
+use crate::{Color, Cuboid, Cuboids};
+
+use bevy::prelude::{Quat, Vec3};
+use std::io::{self, Write};
+use std::ops::Range;
+
+/// The first bytes of every snapshot.
+pub const CUBOIDS_SNAPSHOT_MAGIC: [u8; 8] = *b"CUBOIDS\0";
+/// The snapshot version written by [`Cuboids::write_snapshot`].
+pub const CUBOIDS_SNAPSHOT_VERSION: u32 = 1;
+
+const HEADER_SIZE: usize = 32;
+/// Sections start at multiples of this, so they can be read in place from a
+/// memory-mapped file with any alignment up to 16.
+const SECTION_ALIGN: usize = 16;
+
+const FLAG_ROTATIONS: u32 = 1;
+const FLAG_USER_DATA: u32 = 1 << 1;
+const FLAG_FACE_COLORS: u32 = 1 << 2;
+const FLAG_ATLAS_TILES: u32 = 1 << 3;
+const FLAG_HIDDEN_MASK: u32 = 1 << 4;
+const FLAG_DYNAMIC: u32 = 1 << 5;
+const FLAG_SCALARS: u32 = 1 << 6;
+
+/// Bytes per element of each optional section, in the order they're written.
+const SECTIONS: [(u32, usize); 6] = [
+    (FLAG_ROTATIONS, 16),
+    (FLAG_USER_DATA, 4),
+    (FLAG_FACE_COLORS, 24),
+    (FLAG_ATLAS_TILES, 4),
+    (FLAG_HIDDEN_MASK, 4),
+    (FLAG_SCALARS, 4),
+];
+const CUBOID_SIZE: usize = 32;
+
+/// A snapshot of a [`Cuboids`] batch in a byte slice, e.g. a memory-mapped
+/// file written by [`Cuboids::write_snapshot`].
+///
+/// The format is little-endian throughout:
+///
+/// - a 32 byte header: [`CUBOIDS_SNAPSHOT_MAGIC`], the version (u32), flags
+///   of the optional sections (u32), the number of instances (u64) and 8
+///   reserved bytes
+/// - the instances, laid out like [`Cuboid`]: minimum (3 × f32), meta bits
+///   (u32), maximum (3 × f32) and color (u32)
+/// - if present, in order: rotations (4 × f32, `xyzw`), user data (u32),
+///   face colors (6 × u32), atlas tiles (u32), the bits of
+///   [`Cuboids::set_visible`] (one u32 per 32 instances) and scalars (f32)
+///
+/// Each section starts at a multiple of 16 bytes, padded with zeros. Parsing
+/// only checks the header and the size; instances are decoded when they're
+/// read.
+#[derive(Clone, Copy, Debug)]
+pub struct CuboidsSnapshot<'a> {
+    bytes: &'a [u8],
+    flags: u32,
+    len: usize,
+}
+
+impl<'a> CuboidsSnapshot<'a> {
+    /// Checks the header of `bytes`, and that they're long enough for every
+    /// section it lists. Trailing bytes are ignored.
+    pub fn parse(bytes: &'a [u8]) -> io::Result<Self> {
+        if bytes.len() < HEADER_SIZE || bytes[..8] != CUBOIDS_SNAPSHOT_MAGIC {
+            return Err(invalid_data("not a cuboids snapshot"));
+        }
+        let version = read_u32(bytes, 8);
+        if version != CUBOIDS_SNAPSHOT_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported cuboids snapshot version {version}"
+            )));
+        }
+        let len = usize::try_from(u64::from_le_bytes(bytes[16..24].try_into().unwrap()))
+            .map_err(|_| invalid_data("too many instances"))?;
+        let snapshot = Self {
+            bytes,
+            flags: read_u32(bytes, 12),
+            len,
+        };
+        let end = snapshot
+            .section_end()
+            .ok_or_else(|| invalid_data("too many instances"))?;
+        if bytes.len() < end {
+            return Err(invalid_data("truncated cuboids snapshot"));
+        }
+        Ok(snapshot)
+    }
+
+    /// The number of instances.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the batch was [`Cuboids::dynamic`].
+    pub fn is_dynamic(&self) -> bool {
+        self.flags & FLAG_DYNAMIC != 0
+    }
+
+    /// The instance at `index`.
+    ///
+    /// # Panics
+    ///
+    /// If `index` is out of bounds.
+    pub fn instance(&self, index: usize) -> Cuboid {
+        assert!(index < self.len);
+        let offset = HEADER_SIZE + index * CUBOID_SIZE;
+        Cuboid {
+            minimum: read_vec3(self.bytes, offset),
+            meta_bits: read_u32(self.bytes, offset + 12),
+            maximum: read_vec3(self.bytes, offset + 16),
+            color: read_u32(self.bytes, offset + 28),
+        }
+    }
+
+    pub fn instances(&self) -> impl ExactSizeIterator<Item = Cuboid> + '_ {
+        (0..self.len).map(|i| self.instance(i))
+    }
+
+    /// Decodes the whole batch.
+    pub fn to_cuboids(&self) -> Cuboids {
+        let mut cuboids = Cuboids::new(self.instances().collect());
+        cuboids.dynamic = self.is_dynamic();
+        if let Some(range) = self.section(FLAG_ROTATIONS) {
+            cuboids.rotations = self.bytes[range]
+                .chunks_exact(16)
+                .map(|r| {
+                    let [x, y, z] = read_vec3(r, 0).to_array();
+                    Quat::from_xyzw(x, y, z, read_f32(r, 12))
+                })
+                .collect();
+        }
+        if let Some(range) = self.section(FLAG_USER_DATA) {
+            cuboids.user_data = read_u32s(&self.bytes[range]).collect();
+        }
+        if let Some(range) = self.section(FLAG_FACE_COLORS) {
+            cuboids.face_colors = self.bytes[range]
+                .chunks_exact(24)
+                .map(|faces| {
+                    let mut colors: [Color; 6] = [0; 6];
+                    for (color, value) in colors.iter_mut().zip(read_u32s(faces)) {
+                        *color = value;
+                    }
+                    colors
+                })
+                .collect();
+        }
+        if let Some(range) = self.section(FLAG_ATLAS_TILES) {
+            cuboids.atlas_tiles = read_u32s(&self.bytes[range]).collect();
+        }
+        if let Some(range) = self.section(FLAG_HIDDEN_MASK) {
+            for (word_index, word) in read_u32s(&self.bytes[range]).enumerate() {
+                for bit in (0..32).filter(|bit| word & (1 << bit) != 0) {
+                    let index = 32 * word_index + bit;
+                    if index < self.len {
+                        cuboids.set_visible(index..index + 1, false);
+                    }
+                }
+            }
+        }
+        if let Some(range) = self.section(FLAG_SCALARS) {
+            cuboids.scalars = read_u32s(&self.bytes[range]).map(f32::from_bits).collect();
+        }
+        cuboids
+    }
+
+    fn num_elements(&self, flag: u32) -> usize {
+        if flag == FLAG_HIDDEN_MASK {
+            (self.len + 31) / 32
+        } else {
+            self.len
+        }
+    }
+
+    /// The byte range of the section of `flag`, if the snapshot has it.
+    fn section(&self, flag: u32) -> Option<Range<usize>> {
+        if self.flags & flag == 0 {
+            return None;
+        }
+        let mut start = align(HEADER_SIZE + self.len * CUBOID_SIZE);
+        for (section_flag, size) in SECTIONS {
+            if self.flags & section_flag == 0 {
+                continue;
+            }
+            let end = start + self.num_elements(section_flag) * size;
+            if section_flag == flag {
+                return Some(start..end);
+            }
+            start = align(end);
+        }
+        None
+    }
+
+    /// The end of the last section, or `None` if it overflows.
+    fn section_end(&self) -> Option<usize> {
+        let mut end = self
+            .len
+            .checked_mul(CUBOID_SIZE)?
+            .checked_add(HEADER_SIZE)?;
+        for (flag, size) in SECTIONS {
+            if self.flags & flag != 0 {
+                let size = self.num_elements(flag).checked_mul(size)?;
+                end = align(end).checked_add(size)?;
+            }
+        }
+        Some(end)
+    }
+}
+
+impl Cuboids {
+    /// Writes this batch to `writer` in the format of [`CuboidsSnapshot`].
+    ///
+    /// Snapshots are meant for caching batches on disk, so they're written
+    /// as they're laid out in memory, without compression. Read them back
+    /// with [`Cuboids::read_snapshot`], or [`CuboidsSnapshot::parse`] to read
+    /// instances from a memory-mapped file without decoding all of them.
+    ///
+    /// Output is streamed one instance at a time, so wrap `writer` in a
+    /// [`BufWriter`](std::io::BufWriter) for large batches.
+    pub fn write_snapshot(&self, writer: &mut impl Write) -> io::Result<()> {
+        let len = self.instances.len();
+        let has = |section_len: usize, expected: usize| section_len != 0 && section_len == expected;
+        let mut flags = 0;
+        for (flag, present) in [
+            (FLAG_ROTATIONS, has(self.rotations.len(), len)),
+            (FLAG_USER_DATA, has(self.user_data.len(), len)),
+            (FLAG_FACE_COLORS, has(self.face_colors.len(), len)),
+            (FLAG_ATLAS_TILES, has(self.atlas_tiles.len(), len)),
+            (FLAG_HIDDEN_MASK, !self.hidden_mask().is_empty()),
+            (FLAG_DYNAMIC, self.dynamic),
+            (FLAG_SCALARS, has(self.scalars.len(), len)),
+        ] {
+            if present {
+                flags |= flag;
+            }
+        }
+
+        writer.write_all(&CUBOIDS_SNAPSHOT_MAGIC)?;
+        writer.write_all(&CUBOIDS_SNAPSHOT_VERSION.to_le_bytes())?;
+        writer.write_all(&flags.to_le_bytes())?;
+        writer.write_all(&(len as u64).to_le_bytes())?;
+        writer.write_all(&[0; 8])?;
+        let mut written = HEADER_SIZE;
+
+        for cuboid in &self.instances {
+            write_vec3(writer, cuboid.minimum)?;
+            writer.write_all(&cuboid.meta_bits.to_le_bytes())?;
+            write_vec3(writer, cuboid.maximum)?;
+            writer.write_all(&cuboid.color.to_le_bytes())?;
+        }
+        written += len * CUBOID_SIZE;
+
+        if flags & FLAG_ROTATIONS != 0 {
+            let words = self
+                .rotations
+                .iter()
+                .flat_map(|r| r.to_array().map(f32::to_bits));
+            write_section(writer, &mut written, words)?;
+        }
+        if flags & FLAG_USER_DATA != 0 {
+            write_section(writer, &mut written, self.user_data.iter().copied())?;
+        }
+        if flags & FLAG_FACE_COLORS != 0 {
+            let words = self.face_colors.iter().flatten().copied();
+            write_section(writer, &mut written, words)?;
+        }
+        if flags & FLAG_ATLAS_TILES != 0 {
+            write_section(writer, &mut written, self.atlas_tiles.iter().copied())?;
+        }
+        if flags & FLAG_HIDDEN_MASK != 0 {
+            let words = self.snapshot_hidden_mask().into_iter();
+            write_section(writer, &mut written, words)?;
+        }
+        if flags & FLAG_SCALARS != 0 {
+            let words = self.scalars.iter().map(|s| s.to_bits());
+            write_section(writer, &mut written, words)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a batch written by [`Cuboids::write_snapshot`] from `bytes`.
+    pub fn read_snapshot(bytes: &[u8]) -> io::Result<Self> {
+        CuboidsSnapshot::parse(bytes).map(|snapshot| snapshot.to_cuboids())
+    }
+
+    /// The bits of [`Cuboids::set_visible`], one word per 32 instances.
+    fn snapshot_hidden_mask(&self) -> Vec<u32> {
+        let mut mask = self.hidden_mask().to_vec();
+        if !mask.is_empty() {
+            mask.resize((self.instances.len() + 31) / 32, 0);
+        }
+        mask
+    }
+}
+
+fn align(offset: usize) -> usize {
+    (offset + SECTION_ALIGN - 1) / SECTION_ALIGN * SECTION_ALIGN
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_f32(bytes: &[u8], offset: usize) -> f32 {
+    f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_vec3(bytes: &[u8], offset: usize) -> Vec3 {
+    Vec3::new(
+        read_f32(bytes, offset),
+        read_f32(bytes, offset + 4),
+        read_f32(bytes, offset + 8),
+    )
+}
+
+fn read_u32s(bytes: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    bytes
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+}
+
+/// Pads the output to the next section, then writes `words` to it.
+fn write_section(
+    writer: &mut impl Write,
+    written: &mut usize,
+    words: impl Iterator<Item = u32>,
+) -> io::Result<()> {
+    let padding = align(*written) - *written;
+    writer.write_all(&[0; SECTION_ALIGN][..padding])?;
+    *written += padding;
+    for word in words {
+        writer.write_all(&word.to_le_bytes())?;
+        *written += 4;
+    }
+    Ok(())
+}
+
+fn write_vec3(writer: &mut impl Write, v: Vec3) -> io::Result<()> {
+    for component in v.to_array() {
+        writer.write_all(&component.to_le_bytes())?;
+    }
+    Ok(())
+}
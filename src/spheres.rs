@@ -0,0 +1,138 @@
+use bevy::{
+    prelude::*,
+    render::{primitives::Aabb, render_resource::ShaderType},
+};
+
+use crate::{Color, CuboidMaterialId, MetaBits, MAX_LOD_LEVEL};
+
+/// A sphere of `radius` around `center`.
+///
+/// Spheres are drawn as camera-facing quads, and ray traced per fragment, so
+/// they are perfectly round at any distance.
+#[derive(Clone, Copy, Debug, PartialEq, ShaderType)]
+#[repr(C)]
+pub struct Sphere {
+    pub center: Vec3,
+    pub radius: f32,
+    /// The same bits as [`Cuboid::meta_bits`](crate::Cuboid::meta_bits). The
+    /// depth bias is ignored.
+    pub meta_bits: MetaBits,
+    pub color: Color,
+}
+
+impl Sphere {
+    pub fn new(center: Vec3, radius: f32, color: Color) -> Self {
+        Self {
+            center,
+            radius,
+            meta_bits: 0,
+            color,
+        }
+    }
+
+    #[inline]
+    pub fn is_visible(&self) -> bool {
+        self.meta_bits & 1 == 0
+    }
+
+    #[inline]
+    pub fn make_visible(&mut self) -> &mut Self {
+        self.meta_bits &= !1;
+        self
+    }
+
+    #[inline]
+    pub fn make_invisible(&mut self) -> &mut Self {
+        self.meta_bits |= 1;
+        self
+    }
+
+    #[inline]
+    pub fn make_emissive(&mut self) -> &mut Self {
+        self.meta_bits |= 0b10;
+        self
+    }
+
+    #[inline]
+    pub fn make_non_emissive(&mut self) -> &mut Self {
+        self.meta_bits &= !0b10;
+        self
+    }
+
+    /// Sets the LOD level in `0..=MAX_LOD_LEVEL`, see
+    /// [`Cuboid::set_lod_level`](crate::Cuboid::set_lod_level).
+    #[inline]
+    pub fn set_lod_level(&mut self, level: u8) -> &mut Self {
+        debug_assert!(level <= MAX_LOD_LEVEL);
+        self.meta_bits &= !0b1100; // clear
+        self.meta_bits |= ((level & 0b11) as u32) << 2; // set
+        self
+    }
+}
+
+/// A set of spheres to be extracted for rendering, alongside any
+/// [`Cuboids`](crate::Cuboids).
+///
+/// Spheres use the same [`CuboidMaterial`](crate::CuboidMaterial)s, batch
+/// transforms, clipping planes and volumes as cuboids, so both can be mixed in
+/// one scene. Each sphere is clipped as a whole, depending on its center, and
+/// is shaded by its normal. Wireframes, picking and shadows are not supported,
+/// and nothing is drawn on devices without storage buffers.
+#[derive(Clone, Component, Debug, Default)]
+pub struct Spheres {
+    /// Instances to be rendered.
+    pub instances: Vec<Sphere>,
+}
+
+impl Spheres {
+    pub fn new(instances: Vec<Sphere>) -> Self {
+        Self { instances }
+    }
+
+    /// Creates a sphere of `radius` centered on each of `points`.
+    pub fn from_points(points: &[Vec3], radius: f32, color: Color) -> Self {
+        Self::new(
+            points
+                .iter()
+                .map(|&p| Sphere::new(p, radius, color))
+                .collect(),
+        )
+    }
+
+    pub fn aabb(&self) -> Aabb {
+        let mut min = Vec3::splat(f32::MAX);
+        let mut max = Vec3::splat(f32::MIN);
+        for s in self.instances.iter() {
+            min = min.min(s.center - Vec3::splat(s.radius));
+            max = max.max(s.center + Vec3::splat(s.radius));
+        }
+        Aabb::from_min_max(min, max)
+    }
+}
+
+#[derive(Bundle)]
+pub struct SpheresBundle {
+    pub material_id: CuboidMaterialId,
+    pub spheres: Spheres,
+    #[bundle]
+    pub spatial: SpatialBundle,
+}
+
+/// Recomputes the [`Aabb`] of every changed batch before Bevy checks
+/// visibility.
+pub(crate) fn update_spheres_aabbs(
+    mut commands: Commands,
+    mut batches: Query<(Entity, &Spheres, Option<&mut Aabb>), Changed<Spheres>>,
+) {
+    for (entity, spheres, maybe_aabb) in batches.iter_mut() {
+        if spheres.instances.is_empty() {
+            continue;
+        }
+        match maybe_aabb {
+            Some(mut aabb) => *aabb = spheres.aabb(),
+            None => {
+                commands.entity(entity).insert(spheres.aabb());
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use bevy::{prelude::*, time::fixed_timestep::FixedTime};
+
+/// Draws a [`Cuboids`](crate::Cuboids) entity that is moved in
+/// `CoreSchedule::FixedUpdate` at a transform interpolated between its last
+/// two fixed steps, so that it moves smoothly whatever the frame rate.
+///
+/// Moving a batch, directly or through its parents, only rewrites its entry in
+/// the transform buffer, never its instances, so this costs no uploads either.
+/// The [`GlobalTransform`] is split into scale, rotation and translation, which
+/// are interpolated separately, so shears from non-uniformly scaled parents
+/// aren't kept. The drawn batch lags the fixed steps by up to one step, while
+/// culling, picking and the other CPU-side features use the latest
+/// `GlobalTransform`. When several steps run in one frame, the batch moves
+/// from where it was at the end of the previous frame. Call
+/// [`CuboidsTransformInterpolation::reset`] after teleporting a batch.
+#[derive(Clone, Component, Debug, Default)]
+pub struct CuboidsTransformInterpolation {
+    previous: Option<GlobalTransform>,
+    current: Option<GlobalTransform>,
+    /// The transform drawn this frame.
+    pub(crate) matrix: Option<Mat4>,
+}
+
+impl CuboidsTransformInterpolation {
+    /// Jumps to the current transform, rather than moving there from the
+    /// previous one.
+    pub fn reset(&mut self) {
+        self.previous = None;
+        self.current = None;
+    }
+}
+
+/// Fixed steps run so far.
+#[derive(Default, Resource)]
+pub(crate) struct CuboidsFixedSteps(u64);
+
+pub(crate) fn count_cuboids_fixed_steps(mut steps: ResMut<CuboidsFixedSteps>) {
+    steps.0 += 1;
+}
+
+pub(crate) fn interpolate_cuboids_transforms(
+    mut last_steps: Local<u64>,
+    steps: Res<CuboidsFixedSteps>,
+    fixed_time: Res<FixedTime>,
+    mut batches: Query<(&GlobalTransform, &mut CuboidsTransformInterpolation)>,
+) {
+    let stepped = steps.0 != *last_steps;
+    *last_steps = steps.0;
+    let period = fixed_time.period.as_secs_f32();
+    let t = if period > 0.0 {
+        (fixed_time.accumulated().as_secs_f32() / period).clamp(0.0, 1.0)
+    } else {
+        1.0
+    };
+
+    for (transform, mut interpolation) in batches.iter_mut() {
+        if stepped || interpolation.current.is_none() {
+            interpolation.previous = interpolation.current.or(Some(*transform));
+            interpolation.current = Some(*transform);
+        }
+        let (Some(previous), Some(current)) = (interpolation.previous, interpolation.current)
+        else {
+            continue;
+        };
+        let (scale_a, rotation_a, translation_a) = previous.to_scale_rotation_translation();
+        let (scale_b, rotation_b, translation_b) = current.to_scale_rotation_translation();
+        interpolation.matrix = Some(Mat4::from_scale_rotation_translation(
+            scale_a.lerp(scale_b, t),
+            rotation_a.slerp(rotation_b, t),
+            translation_a.lerp(translation_b, t),
+        ));
+    }
+}
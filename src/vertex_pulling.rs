@@ -1,12 +1,25 @@
 // Original copyright: robswain, bevy-vertex-pulling, MIT OR Apache-2.0
 
 mod buffers;
+mod compute_source;
+mod contour;
 mod cuboid_cache;
+mod culling;
+mod culling_stats;
+mod data_texture;
 mod draw;
 mod extract;
-mod index_buffer;
-mod pipeline;
+pub(crate) mod index_buffer;
+mod multi_draw;
+pub(crate) mod occlusion;
+mod oit;
+pub(crate) mod overlay;
+mod picking;
+pub(crate) mod pipeline;
 mod prepare;
+mod primitives;
 mod queue;
+mod shader_hook;
+mod timestamps;
 
 pub mod plugin;
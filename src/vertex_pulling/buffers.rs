@@ -1,18 +1,137 @@
-use crate::clipping_planes::GpuClippingPlaneRanges;
+use crate::clipping_planes::{GpuClippingPlaneRange, GpuClippingPlaneRanges, GpuClippingVolumes};
+use crate::colormap::GpuColormaps;
 use crate::cuboids::CuboidsTransform;
+use crate::material::{CuboidMaterialUniformIndex, GpuSlottedMaterials};
 use crate::CuboidMaterial;
-use bevy::prelude::{Deref, DerefMut, Resource};
-use bevy::render::render_resource::{DynamicUniformBuffer, UniformBuffer};
+use bevy::prelude::{default, Component, Deref, DerefMut, Entity, Resource, UVec2};
+use bevy::render::render_resource::{
+    BindingResource, Buffer, BufferInitDescriptor, BufferUsages, DynamicUniformBuffer, ShaderType,
+    StorageBuffer, UniformBuffer,
+};
+use bevy::render::renderer::{RenderDevice, RenderQueue};
+use bevy::utils::HashMap;
 
 #[derive(Resource, Default, Deref, DerefMut)]
 pub(crate) struct DynamicUniformBufferOfCuboidMaterial(pub(crate) DynamicUniformBuffer<CuboidMaterial>);
 
+/// The dynamic offset of each material in [`DynamicUniformBufferOfCuboidMaterial`],
+/// by [`CuboidMaterialId`](crate::CuboidMaterialId), as of the last extraction.
 #[derive(Resource, Default, Deref, DerefMut)]
-pub(crate) struct DynamicUniformBufferOfCuboidTransforms(
-    pub(crate) DynamicUniformBuffer<CuboidsTransform>,
+pub(crate) struct CuboidMaterialIndices(pub(crate) Vec<CuboidMaterialUniformIndex>);
+
+/// All batch transforms in a single binding, indexed by
+/// [`CachedCuboidBuffers::transform_index`](super::cuboid_cache::CachedCuboidBuffers).
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct StorageBufferOfCuboidTransforms(pub(crate) StorageBuffer<Vec<CuboidsTransform>>);
+
+/// Holds [`GpuClippingPlaneRanges`] in a uniform sized for `capacity` planes.
+pub(crate) struct UniformBufferOfGpuClippingPlaneRanges {
+    pub capacity: usize,
+    pub planes: GpuClippingPlaneRanges,
+    buffer: Option<Buffer>,
+}
+
+impl UniformBufferOfGpuClippingPlaneRanges {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            planes: default(),
+            buffer: None,
+        }
+    }
+
+    pub fn write_buffer(&mut self, render_device: &RenderDevice, render_queue: &RenderQueue) {
+        let bytes = self.planes.encode(self.capacity);
+        if let Some(buffer) = &self.buffer {
+            render_queue.write_buffer(buffer, 0, &bytes);
+        } else {
+            self.buffer = Some(
+                render_device.create_buffer_with_data(&BufferInitDescriptor {
+                    label: Some("clipping_planes_uniform"),
+                    contents: &bytes,
+                    usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+                }),
+            );
+        }
+    }
+
+    pub fn binding(&self) -> Option<BindingResource> {
+        self.buffer.as_ref().map(Buffer::as_entire_binding)
+    }
+}
+
+/// The clipping planes of every view, each in its own uniform.
+#[derive(Resource)]
+pub(crate) struct ViewClippingPlanes {
+    pub capacity: usize,
+    /// Planes without a [`ClippingPlaneTarget`](crate::ClippingPlaneTarget).
+    pub shared: Vec<GpuClippingPlaneRange>,
+    /// Planes with a [`ClippingPlaneTarget`](crate::ClippingPlaneTarget), by
+    /// camera.
+    pub targeted: HashMap<Entity, Vec<GpuClippingPlaneRange>>,
+    pub uniforms: HashMap<Entity, UniformBufferOfGpuClippingPlaneRanges>,
+}
+
+impl ViewClippingPlanes {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            shared: default(),
+            targeted: default(),
+            uniforms: default(),
+        }
+    }
+
+    /// All planes that apply to `view`.
+    pub fn planes(&self, view: Entity) -> impl Iterator<Item = &GpuClippingPlaneRange> {
+        self.shared
+            .iter()
+            .chain(self.targeted.get(&view).into_iter().flatten())
+    }
+}
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct UniformBufferOfGpuClippingVolumes(pub(crate) UniformBuffer<GpuClippingVolumes>);
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct UniformBufferOfGpuColormaps(pub(crate) UniformBuffer<GpuColormaps>);
+
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct UniformBufferOfGpuSlottedMaterials(pub(crate) UniformBuffer<GpuSlottedMaterials>);
+
+#[cfg(feature = "lighting")]
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct UniformBufferOfGpuCuboidLights(
+    pub(crate) UniformBuffer<crate::lighting::GpuCuboidLights>,
 );
 
+/// Per-view shader constants that aren't covered by Bevy's `ViewUniform`.
+#[derive(Clone, Debug, Default, ShaderType)]
+pub(crate) struct GpuCuboidsView {
+    pub clipping_enabled: u32,
+    pub lod_level: u32,
+    /// Columns and rows of the [`CuboidsAtlas`](crate::CuboidsAtlas), or zero
+    /// without one.
+    pub atlas_grid: UVec2,
+    pub effects: crate::effects::GpuCuboidEffects,
+    #[cfg(feature = "fog")]
+    pub fog: crate::fog::GpuCuboidsFog,
+}
+
 #[derive(Resource, Default, Deref, DerefMut)]
-pub(crate) struct UniformBufferOfGpuClippingPlaneRanges(
-    pub(crate) UniformBuffer<GpuClippingPlaneRanges>,
+pub(crate) struct DynamicUniformBufferOfGpuCuboidsView(
+    pub(crate) DynamicUniformBuffer<GpuCuboidsView>,
 );
+
+#[derive(Component)]
+pub(crate) struct CuboidsViewUniformOffset(pub u32);
+
+/// GPU copy of the [`ColorKeyframes`](crate::ColorKeyframes) table.
+#[cfg(feature = "color_keyframes")]
+#[derive(Resource, Default)]
+pub(crate) struct ColorKeyframeBuffers {
+    pub header: UniformBuffer<crate::color_keyframes::GpuColorKeyframesHeader>,
+    pub table: StorageBuffer<Vec<u32>>,
+    pub table_revision: Option<u64>,
+    pub table_dirty: bool,
+}
@@ -0,0 +1,250 @@
+use super::cuboid_cache::CuboidBufferCache;
+use crate::{CuboidsComputeSource, CuboidsError, CuboidsErrors, CuboidsInstanceFormat};
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_graph::{Node, NodeRunError, RenderGraphContext},
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType, BufferBindingType,
+            BufferId, BufferSize, CachedComputePipelineId, ComputePassDescriptor,
+            ComputePipelineDescriptor, PipelineCache, ShaderStages, ShaderType, UniformBuffer,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+    },
+    utils::HashMap,
+};
+use std::borrow::Cow;
+
+/// Main render graph node that runs every [`CuboidsComputeSource`] before the
+/// cameras draw.
+pub(crate) const CUBOIDS_COMPUTE_SOURCES_NODE: &str = "cuboids_compute_sources";
+
+#[derive(Clone, Copy, Default, ShaderType)]
+struct GpuComputeChunkParams {
+    first_instance: u32,
+    num_instances: u32,
+}
+
+/// The bind group layout shared by all compute sources, and a pipeline for
+/// each distinct shader and entry point.
+#[derive(Resource)]
+pub(crate) struct CuboidsComputeSourcePipelines {
+    layout: BindGroupLayout,
+    pipelines: HashMap<(Handle<Shader>, Cow<'static, str>), CachedComputePipelineId>,
+}
+
+impl FromWorld for CuboidsComputeSourcePipelines {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let storage_entry = |binding, read_only| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: BufferSize::new(0),
+            },
+            count: None,
+        };
+        let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("cuboids_compute_source_layout"),
+            entries: &[
+                // Application data
+                storage_entry(0, true),
+                // Instances
+                storage_entry(1, false),
+                // Colors
+                storage_entry(2, false),
+                // Chunk parameters
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(GpuComputeChunkParams::min_size()),
+                    },
+                    count: None,
+                },
+            ],
+        });
+        Self {
+            layout,
+            pipelines: HashMap::default(),
+        }
+    }
+}
+
+/// A dispatch of a compute source into one instance chunk.
+pub(crate) struct ComputeSourceChunk {
+    pipeline_id: CachedComputePipelineId,
+    workgroups: u32,
+    params: UniformBuffer<GpuComputeChunkParams>,
+    /// The source, instance and color buffers that the bind group refers to.
+    buffers: (BufferId, BufferId, BufferId),
+    bind_group: BindGroup,
+}
+
+/// The chunks dispatched this frame, keyed by the batch entity and chunk
+/// index.
+#[derive(Default, Resource)]
+pub(crate) struct CuboidsComputeSourceChunks {
+    chunks: HashMap<(Entity, usize), ComputeSourceChunk>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn prepare_cuboid_compute_sources(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    pipeline_cache: Res<PipelineCache>,
+    errors: Res<CuboidsErrors>,
+    buffer_cache: Res<CuboidBufferCache>,
+    mut pipelines: ResMut<CuboidsComputeSourcePipelines>,
+    mut dispatches: ResMut<CuboidsComputeSourceChunks>,
+    sources: Query<(Entity, &CuboidsComputeSource)>,
+) {
+    let pipelines = pipelines.as_mut();
+    let dispatches = &mut dispatches.chunks;
+    // Evicted batches, and batches whose instances weren't uploaded yet, have
+    // no buffers to write into.
+    let current_chunks = |entity: &Entity| {
+        buffer_cache
+            .entries
+            .get(entity)
+            .filter(|entry| !entry.evicted)
+            .and_then(|entry| entry.instance_buffers.get(entry.current_buffer))
+            .map(|buffer| &buffer.chunks)
+    };
+    dispatches.retain(|(entity, i), _| {
+        sources.contains(*entity) && current_chunks(entity).map_or(false, |c| *i < c.len())
+    });
+
+    for (entity, source) in sources.iter() {
+        let Some(chunks) = current_chunks(&entity) else {
+            continue;
+        };
+        let supported = chunks.iter().all(|chunk| {
+            chunk.format == CuboidsInstanceFormat::Full && chunk.data_texture.is_none()
+        });
+        if !supported {
+            errors.send(CuboidsError::UnsupportedComputeSource { entity });
+            dispatches.retain(|(e, _), _| *e != entity);
+            continue;
+        }
+
+        let layout = &pipelines.layout;
+        let pipeline_id = *pipelines
+            .pipelines
+            .entry((source.shader.clone_weak(), source.entry_point.clone()))
+            .or_insert_with(|| {
+                pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                    label: Some("cuboids_compute_source_pipeline".into()),
+                    layout: vec![layout.clone()],
+                    push_constant_ranges: Vec::new(),
+                    shader: source.shader.clone(),
+                    shader_defs: Vec::new(),
+                    entry_point: source.entry_point.clone(),
+                })
+            });
+
+        let mut first_instance = 0;
+        for (i, chunk) in chunks.iter().enumerate() {
+            let num_instances = chunk.len as u32;
+            let params = GpuComputeChunkParams {
+                first_instance,
+                num_instances,
+            };
+            first_instance += num_instances;
+
+            let (Some(instances), Some(colors)) = (chunk.buffer.buffer(), chunk.colors.buffer())
+            else {
+                continue;
+            };
+            let buffers = (source.source.id(), instances.id(), colors.id());
+            let workgroup_size = source.workgroup_size.max(1);
+            let workgroups = (num_instances + workgroup_size - 1) / workgroup_size;
+            if let Some(dispatch) = dispatches.get_mut(&(entity, i)) {
+                if dispatch.buffers == buffers {
+                    dispatch.pipeline_id = pipeline_id;
+                    dispatch.workgroups = workgroups;
+                    dispatch.params.set(params);
+                    dispatch.params.write_buffer(&render_device, &render_queue);
+                    continue;
+                }
+            }
+
+            let mut params_buffer = UniformBuffer::from(params);
+            params_buffer.write_buffer(&render_device, &render_queue);
+            let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+                label: Some("cuboids_compute_source_bind_group"),
+                layout: &pipelines.layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: source.source.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: chunk.buffer.binding().unwrap(),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: chunk.colors.binding().unwrap(),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: params_buffer.binding().unwrap(),
+                    },
+                ],
+            });
+            dispatches.insert(
+                (entity, i),
+                ComputeSourceChunk {
+                    pipeline_id,
+                    workgroups,
+                    params: params_buffer,
+                    buffers,
+                    bind_group,
+                },
+            );
+        }
+    }
+}
+
+/// Dispatches every compute source chunk whose pipeline is ready.
+pub(crate) struct CuboidsComputeSourcesNode;
+
+impl Node for CuboidsComputeSourcesNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let dispatches = &world.resource::<CuboidsComputeSourceChunks>().chunks;
+        if dispatches.is_empty() {
+            return Ok(());
+        }
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("cuboids_compute_sources_pass"),
+                });
+        for dispatch in dispatches.values() {
+            if dispatch.workgroups == 0 {
+                continue;
+            }
+            let Some(pipeline) = pipeline_cache.get_compute_pipeline(dispatch.pipeline_id) else {
+                continue;
+            };
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &dispatch.bind_group, &[]);
+            pass.dispatch_workgroups(dispatch.workgroups, 1, 1);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,472 @@
+use super::cuboid_cache::CuboidBufferCache;
+use super::draw::DrawCuboids;
+use super::pipeline::{
+    CuboidsPipelines, CuboidsShaderDefs, TrackedSpecializedPipelines, VERTEX_PULLING_SHADER_HANDLE,
+};
+use crate::{CuboidHighlightStyle, CuboidSelection};
+
+use bevy::{
+    core_pipeline::{core_3d::Camera3d, fullscreen_vertex_shader::fullscreen_shader_vertex_state},
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        camera::ExtractedCamera,
+        mesh::PrimitiveTopology,
+        render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+        render_phase::{
+            CachedRenderPipelinePhaseItem, DrawFunctionId, DrawFunctions, PhaseItem, RenderPhase,
+        },
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType,
+            BlendState, BufferBindingType, CachedRenderPipelineId, ColorTargetState, ColorWrites,
+            Extent3d, FragmentState, FrontFace, LoadOp, MultisampleState, Operations,
+            PipelineCache, PolygonMode, PrimitiveState, RenderPassColorAttachment,
+            RenderPassDescriptor, RenderPipelineDescriptor, ShaderStages, ShaderType,
+            SpecializedRenderPipeline, TextureDescriptor, TextureDimension, TextureFormat,
+            TextureSampleType, TextureUsages, TextureViewDimension, UniformBuffer, VertexState,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        texture::{BevyDefault, CachedTexture, TextureCache},
+        view::{ExtractedView, ViewTarget, VisibleEntities},
+        Extract,
+    },
+};
+
+pub(crate) const CONTOUR_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 11946527803437712469);
+
+/// Render graph node that draws the contour of the selection after the main
+/// 3D pass, see [`CuboidHighlightStyle::Contour`].
+pub(crate) const CUBOIDS_CONTOUR_NODE: &str = "cuboids_contour";
+
+/// Nonzero where a selected cuboid covers the pixel.
+const MASK_FORMAT: TextureFormat = TextureFormat::R8Unorm;
+
+/// Contours are searched for in a square of this many pixels on each side of
+/// a pixel, which bounds their width.
+const MAX_CONTOUR_WIDTH: f32 = 16.0;
+
+/// A selection highlight, drawn into the contour mask of a view.
+pub(crate) struct CuboidsContourMask {
+    pub entity: Entity,
+    pub pipeline: CachedRenderPipelineId,
+    pub draw_function: DrawFunctionId,
+}
+
+impl PhaseItem for CuboidsContourMask {
+    // The mask is the same in any order.
+    type SortKey = ();
+
+    #[inline]
+    fn entity(&self) -> Entity {
+        self.entity
+    }
+
+    #[inline]
+    fn sort_key(&self) -> Self::SortKey {}
+
+    #[inline]
+    fn draw_function(&self) -> DrawFunctionId {
+        self.draw_function
+    }
+}
+
+impl CachedRenderPipelinePhaseItem for CuboidsContourMask {
+    #[inline]
+    fn cached_pipeline(&self) -> CachedRenderPipelineId {
+        self.pipeline
+    }
+}
+
+#[derive(Clone, Default, ShaderType)]
+pub(crate) struct GpuCuboidsContour {
+    pub color: Vec4,
+    /// In physical pixels.
+    pub width: f32,
+}
+
+/// The contour settings of [`CuboidSelection`], for all views.
+#[derive(Default, Resource)]
+pub(crate) struct CuboidsContourSettings {
+    pub contour: GpuCuboidsContour,
+    pub uniform: UniformBuffer<GpuCuboidsContour>,
+}
+
+/// Specializes the mask and composite pipelines for each
+/// [`CuboidsContourPipelineKey`].
+#[derive(Resource)]
+pub(crate) struct CuboidsContourPipelines {
+    /// The composite pipeline for LDR views without MSAA.
+    mask_descriptor: RenderPipelineDescriptor,
+    composite_descriptor: RenderPipelineDescriptor,
+    pub composite_layout: BindGroupLayout,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum CuboidsContourPipelineKey {
+    Mask,
+    Composite { hdr: bool, samples: u32 },
+}
+
+impl SpecializedRenderPipeline for CuboidsContourPipelines {
+    type Key = CuboidsContourPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        match key {
+            CuboidsContourPipelineKey::Mask => self.mask_descriptor.clone(),
+            CuboidsContourPipelineKey::Composite { hdr, samples } => {
+                let mut descriptor = self.composite_descriptor.clone();
+                if hdr {
+                    descriptor.label = Some("cuboids_contour_hdr_composite_pipeline".into());
+                    descriptor.fragment.as_mut().unwrap().targets[0]
+                        .as_mut()
+                        .unwrap()
+                        .format = TextureFormat::Rgba16Float;
+                }
+                descriptor.multisample.count = samples;
+                descriptor
+            }
+        }
+    }
+}
+
+impl FromWorld for CuboidsContourPipelines {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let composite_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("cuboids_contour_composite_layout"),
+            entries: &[
+                // Mask
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: false },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                // Color and width
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(GpuCuboidsContour::min_size()),
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let shader_defs = world.resource::<CuboidsShaderDefs>();
+        let vertex_defs = shader_defs.vertex.clone();
+        let mut fragment_defs = shader_defs.fragment.clone();
+        fragment_defs.push("CONTOUR_MASK".into());
+
+        let multisample = MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        };
+
+        // Highlights are drawn without depth testing, so the contour of the
+        // whole selection shows through anything in front of it.
+        let cuboids_pipelines = world.resource::<CuboidsPipelines>();
+        let mask_descriptor = RenderPipelineDescriptor {
+            label: Some("cuboids_contour_mask_pipeline".into()),
+            layout: vec![
+                cuboids_pipelines.view_layout.clone(),
+                cuboids_pipelines.aux_layout.clone(),
+                cuboids_pipelines.transforms_layout.clone(),
+                cuboids_pipelines.cuboids_layout.clone(),
+            ],
+            vertex: VertexState {
+                shader: VERTEX_PULLING_SHADER_HANDLE.typed(),
+                shader_defs: vertex_defs,
+                entry_point: "vertex".into(),
+                buffers: vec![],
+            },
+            fragment: Some(FragmentState {
+                shader: VERTEX_PULLING_SHADER_HANDLE.typed(),
+                shader_defs: fragment_defs,
+                entry_point: "fragment_contour_mask".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: MASK_FORMAT,
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState {
+                front_face: FrontFace::Ccw,
+                cull_mode: None,
+                unclipped_depth: false,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+                topology: PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+            },
+            depth_stencil: None,
+            multisample,
+            push_constant_ranges: Vec::new(),
+        };
+
+        let composite_descriptor = RenderPipelineDescriptor {
+            label: Some("cuboids_contour_composite_pipeline".into()),
+            layout: vec![composite_layout.clone()],
+            vertex: fullscreen_shader_vertex_state(),
+            fragment: Some(FragmentState {
+                shader: CONTOUR_SHADER_HANDLE.typed(),
+                shader_defs: vec![],
+                entry_point: "composite".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: TextureFormat::bevy_default(),
+                    blend: Some(BlendState::ALPHA_BLENDING),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: default(),
+            depth_stencil: None,
+            multisample,
+            push_constant_ranges: Vec::new(),
+        };
+
+        Self {
+            mask_descriptor,
+            composite_descriptor,
+            composite_layout,
+        }
+    }
+}
+
+/// Adds a mask phase to each active camera while the selection is drawn with
+/// [`CuboidHighlightStyle::Contour`], so that no other frame pays for it.
+pub(crate) fn extract_cuboids_contour(
+    mut commands: Commands,
+    selection: Extract<Res<CuboidSelection>>,
+    cameras: Extract<Query<(Entity, &Camera), With<Camera3d>>>,
+    mut settings: ResMut<CuboidsContourSettings>,
+) {
+    if selection.style != CuboidHighlightStyle::Contour || selection.is_empty() {
+        return;
+    }
+    settings.contour = GpuCuboidsContour {
+        color: Vec4::from_array(selection.color.to_le_bytes().map(|c| f32::from(c) / 255.0)),
+        width: selection.contour_width.clamp(0.0, MAX_CONTOUR_WIDTH),
+    };
+    for (entity, camera) in cameras.iter() {
+        if camera.is_active {
+            commands
+                .get_or_spawn(entity)
+                .insert(RenderPhase::<CuboidsContourMask>::default());
+        }
+    }
+}
+
+/// Queues the selection highlights, which every other pass skips while they
+/// are drawn as a contour.
+pub(crate) fn queue_cuboids_contour(
+    pipelines: Res<CuboidsContourPipelines>,
+    mut specialized_pipelines: ResMut<TrackedSpecializedPipelines<CuboidsContourPipelines>>,
+    pipeline_cache: Res<PipelineCache>,
+    draw_functions: Res<DrawFunctions<CuboidsContourMask>>,
+    buffer_cache: Res<CuboidBufferCache>,
+    mut views: Query<(&VisibleEntities, &mut RenderPhase<CuboidsContourMask>)>,
+) {
+    let draw_cuboids = draw_functions.read().get_id::<DrawCuboids>().unwrap();
+    let pipeline = specialized_pipelines.specialize(
+        &pipeline_cache,
+        &pipelines,
+        CuboidsContourPipelineKey::Mask,
+    );
+    for (visible_entities, mut phase) in views.iter_mut() {
+        for &entity in &visible_entities.entities {
+            let Some(entry) = buffer_cache.entries.get(&entity) else {
+                continue;
+            };
+            if !entry.enabled || !entry.contour_mask {
+                continue;
+            }
+            phase.add(CuboidsContourMask {
+                entity,
+                pipeline,
+                draw_function: draw_cuboids,
+            });
+        }
+    }
+}
+
+/// The contour mask of a view.
+#[derive(Component)]
+pub(crate) struct ViewContourTextures {
+    mask: CachedTexture,
+    composite_bind_group: BindGroup,
+    composite_pipeline_id: CachedRenderPipelineId,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn prepare_cuboids_contour_textures(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut texture_cache: ResMut<TextureCache>,
+    msaa: Res<Msaa>,
+    mut settings: ResMut<CuboidsContourSettings>,
+    pipelines: Res<CuboidsContourPipelines>,
+    mut specialized_pipelines: ResMut<TrackedSpecializedPipelines<CuboidsContourPipelines>>,
+    pipeline_cache: Res<PipelineCache>,
+    views: Query<(Entity, &ExtractedCamera, &ExtractedView), With<RenderPhase<CuboidsContourMask>>>,
+) {
+    if views.is_empty() {
+        return;
+    }
+    let settings = &mut *settings;
+    settings.uniform.set(settings.contour.clone());
+    settings.uniform.write_buffer(&render_device, &render_queue);
+    let Some(uniform) = settings.uniform.binding() else {
+        return;
+    };
+
+    for (entity, camera, view) in views.iter() {
+        let Some(size) = camera.physical_target_size else {
+            continue;
+        };
+        let mask = texture_cache.get(
+            &render_device,
+            TextureDescriptor {
+                label: Some("cuboids_contour_mask_texture"),
+                size: Extent3d {
+                    width: size.x,
+                    height: size.y,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: MASK_FORMAT,
+                usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+        );
+        let composite_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("cuboids_contour_composite_bind_group"),
+            layout: &pipelines.composite_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&mask.default_view),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: uniform.clone(),
+                },
+            ],
+        });
+
+        let composite_pipeline_id = specialized_pipelines.specialize(
+            &pipeline_cache,
+            &pipelines,
+            CuboidsContourPipelineKey::Composite {
+                hdr: view.hdr,
+                samples: msaa.samples(),
+            },
+        );
+        commands.entity(entity).insert(ViewContourTextures {
+            mask,
+            composite_bind_group,
+            composite_pipeline_id,
+        });
+    }
+}
+
+/// Draws the selection highlights of a view into its mask, then draws the
+/// contour of the mask over the view target.
+pub(crate) struct CuboidsContourNode {
+    view_query: QueryState<(
+        &'static ExtractedCamera,
+        &'static RenderPhase<CuboidsContourMask>,
+        &'static ViewTarget,
+        &'static ViewContourTextures,
+    )>,
+}
+
+impl CuboidsContourNode {
+    pub const IN_VIEW: &'static str = "view";
+
+    pub fn new(world: &mut World) -> Self {
+        Self {
+            view_query: QueryState::new(world),
+        }
+    }
+}
+
+impl Node for CuboidsContourNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(Self::IN_VIEW, SlotType::Entity)]
+    }
+
+    fn update(&mut self, world: &mut World) {
+        self.view_query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+        let Ok((camera, phase, target, textures)) = self.view_query.get_manual(world, view_entity)
+        else {
+            return Ok(());
+        };
+        if phase.items.is_empty() {
+            return Ok(());
+        }
+        let Some(composite_pipeline) = world
+            .resource::<PipelineCache>()
+            .get_render_pipeline(textures.composite_pipeline_id)
+        else {
+            return Ok(());
+        };
+
+        {
+            let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+                label: Some("cuboids_contour_mask_pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &textures.mask.default_view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(default()),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+            if let Some(viewport) = camera.viewport.as_ref() {
+                pass.set_camera_viewport(viewport);
+            }
+            phase.render(&mut pass, world, view_entity);
+        }
+
+        let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("cuboids_contour_composite_pass"),
+            color_attachments: &[Some(target.get_color_attachment(Operations {
+                load: LoadOp::Load,
+                store: true,
+            }))],
+            depth_stencil_attachment: None,
+        });
+        if let Some(viewport) = camera.viewport.as_ref() {
+            pass.set_camera_viewport(viewport);
+        }
+        pass.set_render_pipeline(composite_pipeline);
+        pass.set_bind_group(0, &textures.composite_bind_group, &[]);
+        pass.draw(0..3, 0..1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+// Draws the contour of the selection mask over the view target, see
+// `fragment_contour_mask` in the cuboids shader.
+
+struct Contour {
+    color: vec4<f32>,
+    // In pixels, outside of the mask.
+    width: f32,
+}
+
+@group(0) @binding(0)
+var mask_texture: texture_2d<f32>;
+
+@group(0) @binding(1)
+var<uniform> contour: Contour;
+
+@fragment
+fn composite(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    let texel = vec2<i32>(position.xy);
+    // The selection itself is left as is.
+    if (textureLoad(mask_texture, texel, 0).r > 0.0) {
+        discard;
+    }
+    let max_texel = vec2<i32>(textureDimensions(mask_texture)) - vec2<i32>(1);
+    let radius = i32(ceil(contour.width));
+    var nearest = contour.width + 1.0;
+    for (var y = -radius; y <= radius; y += 1) {
+        for (var x = -radius; x <= radius; x += 1) {
+            let offset = vec2<i32>(x, y);
+            let neighbor = clamp(texel + offset, vec2<i32>(0), max_texel);
+            if (textureLoad(mask_texture, neighbor, 0).r > 0.0) {
+                nearest = min(nearest, length(vec2<f32>(offset)));
+            }
+        }
+    }
+    // Antialiased over the outermost pixel.
+    let coverage = clamp(contour.width + 0.5 - nearest, 0.0, 1.0);
+    if (coverage <= 0.0) {
+        discard;
+    }
+    return vec4<f32>(contour.color.rgb, contour.color.a * coverage);
+}
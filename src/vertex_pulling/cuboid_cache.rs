@@ -1,29 +1,1062 @@
-use crate::Cuboid;
+use super::data_texture::DataTexture;
+use crate::{Cuboid, Cuboids, CuboidsInstanceFormat, DepthMode, DrawPhase};
 
 use bevy::{
     prelude::*,
-    render::render_resource::{BindGroup, StorageBuffer},
+    render::{
+        render_resource::{
+            encase::{self, internal::WriteInto, ShaderSize},
+            BindGroup, BindingResource, Buffer, StorageBuffer,
+        },
+        renderer::{RenderDevice, RenderQueue},
+    },
+    tasks::ComputeTaskPool,
     utils::HashMap,
 };
+use std::ops::Range;
+use std::time::Duration;
+
+/// Number of instance buffers cycled through by [`Cuboids::dynamic`](crate::Cuboids::dynamic)
+/// batches, unless [`VertexPullingRenderPlugin::dynamic_instance_buffers`](crate::VertexPullingRenderPlugin::dynamic_instance_buffers)
+/// is set.
+pub(crate) const DYNAMIC_INSTANCE_BUFFER_COUNT: usize = 3;
+
+/// How many instance buffers a batch cycles through on full uploads.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct InstanceBuffering {
+    /// Buffers of dynamic batches.
+    pub dynamic_buffers: usize,
+    /// Batches that are fully uploaded this many frames in a row are buffered
+    /// like dynamic batches.
+    pub auto_dynamic_frames: Option<u32>,
+}
+
+impl Default for InstanceBuffering {
+    fn default() -> Self {
+        Self {
+            dynamic_buffers: DYNAMIC_INSTANCE_BUFFER_COUNT,
+            auto_dynamic_frames: None,
+        }
+    }
+}
+
+/// The most instances in a chunk of storage buffers, for a device whose
+/// bindings and buffers are at most `max_chunk_size` bytes, and at most
+/// `max_instances`. Sized for the largest instance format.
+pub(crate) fn max_chunk_instances(max_chunk_size: u64, max_instances: usize) -> usize {
+    ((max_chunk_size / <Cuboid as ShaderSize>::SHADER_SIZE.get()) as usize)
+        .min(max_instances)
+        .max(1)
+}
 
 #[derive(Default, Resource)]
 pub(crate) struct CuboidBufferCache {
     pub entries: HashMap<Entity, CachedCuboidBuffers>,
+    /// Buffers allocated at startup, waiting to be claimed by new entries.
+    pub prewarmed: Vec<PrewarmedBuffer>,
+    /// Batches larger than this are split into multiple chunks, each with its
+    /// own buffer, bind group, and draw call.
+    pub max_chunk_instances: usize,
+    /// Whether full uploads of static batches with more than one chunk are
+    /// streamed, one chunk per frame.
+    pub streaming: bool,
+    /// Bytes of instance buffers to keep on the GPU, see
+    /// [`CuboidBufferCache::evict_to_budget`].
+    pub memory_budget: Option<u64>,
+    /// Bytes and CPU time of uploads per frame before the first uploads of
+    /// further batches are deferred, see `prepare_cuboids`.
+    pub upload_budget_bytes: Option<u64>,
+    pub upload_budget_time: Option<Duration>,
+    /// Incremented once per extraction.
+    pub frame: u64,
+    /// Chunks are uploaded into [`InstanceChunk::data_texture`] instead of
+    /// storage buffers, for devices without them.
+    pub data_textures: bool,
+    /// Bytes of instance data written by the last `prepare_cuboids`.
+    pub uploaded_bytes: u64,
+    /// CPU time of the last `prepare_cuboids`.
+    pub prepare_time: Duration,
+    /// Chunks that grow are allocated with room for this many times their
+    /// instances, so that later growth and appends fit, see
+    /// [`InstanceChunk::len`]. Disabled at 1 or less.
+    pub growth_factor: f32,
+    /// The layout of instances on the GPU, always
+    /// [`CuboidsInstanceFormat::Full`] with data textures.
+    pub instance_format: CuboidsInstanceFormat,
+    pub buffering: InstanceBuffering,
+}
+
+pub(crate) struct PrewarmedBuffer {
+    pub capacity: usize,
+    pub buffer: InstanceBuffer,
 }
 
 #[derive(Default)]
 pub(crate) struct CachedCuboidBuffers {
     pub material_index: u32,
+    /// The [`CuboidMaterialId`](crate::CuboidMaterialId), for shader hooks.
+    pub material_id: usize,
     pub dirty: bool,
+    /// Only the visibility mask of the current buffer changed.
+    pub visibility_dirty: bool,
+    /// Ranges of the current buffer to upload in place, by chunk index.
+    pub dirty_ranges: Vec<(usize, Range<usize>)>,
+    /// Like `dirty_ranges`, for ranges of which only the colors changed.
+    pub dirty_color_ranges: Vec<(usize, Range<usize>)>,
     pub enabled: bool,
+    pub occluder: bool,
+    /// A [`ClippingPlaneGizmo`](crate::ClippingPlaneGizmo), which is never
+    /// picked or counted in the draw stats.
+    pub gizmo: bool,
+    /// A selection highlight, drawn only into the contour mask of each view.
+    pub contour_mask: bool,
+    pub transparent: bool,
+    pub casts_shadows: bool,
+    pub depth_mode: DepthMode,
+    pub draw_phase: DrawPhase,
+    pub sort_key_offset: f32,
     pub keep_alive: bool,
-    pub instance_buffer: StorageBuffer<Vec<Cuboid>>,
-    pub instance_buffer_bind_group: Option<BindGroup>,
+    /// A single buffer for static batches, or
+    /// [`InstanceBuffering::dynamic_buffers`] for dynamic batches.
+    pub instance_buffers: Vec<InstanceBuffer>,
+    pub current_buffer: usize,
+    /// The [`CuboidBufferCache::frame`] of the last full upload.
+    pub last_upload_frame: u64,
+    /// Full uploads in consecutive frames, up to the last one.
+    pub consecutive_uploads: u32,
+    /// The batch is buffered like a dynamic batch, because of
+    /// [`InstanceBuffering::auto_dynamic_frames`].
+    pub auto_dynamic: bool,
+    /// The current buffer is being uploaded one chunk per frame, and isn't
+    /// drawn until all of its chunks are.
+    pub streaming: bool,
+    /// Number of chunks of the current buffer uploaded while streaming.
+    pub streamed_chunks: usize,
+    /// A full upload was written since the batch was created or last evicted,
+    /// so that it can be drawn.
+    pub on_gpu: bool,
+    /// The full upload of the current buffer was deferred by the upload
+    /// budget, and the batch isn't drawn until it is written.
+    pub upload_deferred: bool,
+    /// The instance buffers were freed to stay within the memory budget, and
+    /// are uploaded again once the batch is visible.
+    pub evicted: bool,
+    /// The last [`CuboidBufferCache::frame`] this batch was enabled in.
+    pub last_drawn_frame: u64,
+    /// Instances of the batch, even while evicted.
+    pub num_instances: usize,
+    /// The batch is visible, but outside of every view.
+    pub frustum_culled: bool,
     pub position: Vec3,
     pub transform_index: u32,
 }
 
+/// All instances of a batch, split into chunks that each fit in a single
+/// storage buffer binding.
+#[derive(Default)]
+pub(crate) struct InstanceBuffer {
+    pub chunks: Vec<InstanceChunk>,
+}
+
+#[derive(Default)]
+pub(crate) struct InstanceChunk {
+    /// Instances of the batch in this chunk. With
+    /// [`CuboidBufferCache::growth_factor`], the buffers below are padded to
+    /// [`Self::capacity`] with invisible instances.
+    pub len: usize,
+    /// The color of each instance is read from `colors` instead.
+    pub buffer: StorageBuffer<Vec<Cuboid>>,
+    pub format: CuboidsInstanceFormat,
+    /// With a compressed [`Self::format`], the bounds and meta bits of each
+    /// instance in `buffer`, which the shaders read instead. `buffer` then
+    /// only holds the CPU-side copy.
+    pub packed: StorageBuffer<Vec<UVec4>>,
+    /// With [`CuboidsInstanceFormat::Quantized`], the bounds of the whole
+    /// batch, the same for every chunk.
+    pub quantization: InstanceQuantization,
+    /// [`Cuboid::color`] of each instance, or its
+    /// [`Cuboids::scalars`](crate::Cuboids::scalars) entry, so that colors can be rewritten
+    /// without the bounds, followed by six
+    /// [`Cuboids::face_colors`](crate::Cuboids::face_colors), then one of
+    /// the [`Cuboids::atlas_tiles`](crate::Cuboids::atlas_tiles) and then the
+    /// two [`Cuboids::spawn_times`](crate::Cuboids::spawn_times) per instance,
+    /// for batches that have them.
+    pub colors: StorageBuffer<Vec<u32>>,
+    /// Quaternions as `xyzw`, or a single identity rotation for axis-aligned
+    /// batches, since empty bindings are invalid.
+    pub rotations: StorageBuffer<Vec<Vec4>>,
+    /// [`Cuboids::user_data`](crate::Cuboids::user_data), or a single zero for
+    /// batches without any.
+    pub user_data: StorageBuffer<Vec<u32>>,
+    /// One bit per instance of this chunk, set for hidden instances. Always
+    /// holds a word for every 32 instances, so that it can be rewritten in
+    /// place.
+    pub hidden_mask: StorageBuffer<Vec<u32>>,
+    /// All of the above, with [`CuboidBufferCache::data_textures`]. The
+    /// storage buffers then only hold the CPU-side copy.
+    pub data_texture: Option<DataTexture>,
+    pub bind_group: Option<BindGroup>,
+}
+
+/// A word of [`InstanceChunk::colors`] for a spawn or despawn time that isn't
+/// set. It's a NaN, so it's never the bits of a time.
+const NO_SPAWN_TIME: u32 = u32::MAX;
+
+/// Ranges that are closer than this many instances are uploaded together.
+const MIN_RANGE_GAP: usize = 64;
+
+impl InstanceChunk {
+    /// Instances that fit in the buffers: the real ones and the padding.
+    pub fn capacity(&self) -> usize {
+        self.buffer.get().len()
+    }
+
+    /// The GPU buffer that the shaders read instances from.
+    pub fn instances_buffer(&self) -> Option<&Buffer> {
+        match self.format {
+            CuboidsInstanceFormat::Full => self.buffer.buffer(),
+            _ => self.packed.buffer(),
+        }
+    }
+
+    pub fn instances_binding(&self) -> BindingResource {
+        match self.format {
+            CuboidsInstanceFormat::Full => self.buffer.binding().unwrap(),
+            _ => self.packed.binding().unwrap(),
+        }
+    }
+
+    /// Uploads all instances to the buffer that the shaders read.
+    pub fn write_instances(&mut self, render_device: &RenderDevice, render_queue: &RenderQueue) {
+        match self.format {
+            CuboidsInstanceFormat::Full => self.buffer.write_buffer(render_device, render_queue),
+            _ => self.packed.write_buffer(render_device, render_queue),
+        }
+    }
+
+    /// Packs the instances in `range` of `buffer` again, after they changed.
+    fn repack(&mut self, range: Range<usize>) {
+        if self.format == CuboidsInstanceFormat::Full {
+            return;
+        }
+        let (format, quantization) = (self.format, self.quantization);
+        for (dst, src) in self.packed.get_mut()[range.clone()]
+            .iter_mut()
+            .zip(&self.buffer.get()[range])
+        {
+            *dst = format.pack(src, &quantization);
+        }
+    }
+
+    /// Uploads the instances, and any per-instance rotations and user data, in
+    /// `range` of a chunk that is already on the GPU. Returns the bytes written.
+    pub fn write_range(&self, render_queue: &RenderQueue, range: Range<usize>) -> u64 {
+        let mut bytes = match self.format {
+            CuboidsInstanceFormat::Full => write_slice(
+                render_queue,
+                self.buffer.buffer().unwrap(),
+                &self.buffer.get()[range.clone()],
+                range.start,
+            ),
+            _ => write_slice(
+                render_queue,
+                self.packed.buffer().unwrap(),
+                &self.packed.get()[range.clone()],
+                range.start,
+            ),
+        };
+        bytes += self.write_color_range(render_queue, range.clone());
+        if self.rotations.get().len() == self.capacity() {
+            bytes += write_slice(
+                render_queue,
+                self.rotations.buffer().unwrap(),
+                &self.rotations.get()[range.clone()],
+                range.start,
+            );
+        }
+        if self.user_data.get().len() == self.capacity() {
+            bytes += write_slice(
+                render_queue,
+                self.user_data.buffer().unwrap(),
+                &self.user_data.get()[range.clone()],
+                range.start,
+            );
+        }
+        bytes
+    }
+
+    /// Uploads only the colors in `range` of a chunk that is already on the
+    /// GPU. Returns the bytes written.
+    pub fn write_color_range(&self, render_queue: &RenderQueue, range: Range<usize>) -> u64 {
+        write_slice(
+            render_queue,
+            self.colors.buffer().unwrap(),
+            &self.colors.get()[range.clone()],
+            range.start,
+        )
+    }
+
+    /// Bytes allocated on the GPU for this chunk.
+    pub fn gpu_size(&self) -> u64 {
+        let size = |buffer: Option<&Buffer>| buffer.map_or(0, |b| b.size());
+        size(self.buffer.buffer())
+            + size(self.packed.buffer())
+            + size(self.colors.buffer())
+            + size(self.rotations.buffer())
+            + size(self.user_data.buffer())
+            + size(self.hidden_mask.buffer())
+            + self.data_texture.as_ref().map_or(0, DataTexture::size)
+    }
+}
+
+impl CuboidsInstanceFormat {
+    /// Bytes per instance in the buffer that the shaders read.
+    fn instance_size(self) -> u64 {
+        match self {
+            Self::Full => <Cuboid as ShaderSize>::SHADER_SIZE.get(),
+            Self::Half | Self::Quantized => 16,
+        }
+    }
+
+    /// The instance as the shaders read it in a compressed format. Must match
+    /// `load_cuboid` in the shaders.
+    fn pack(self, cuboid: &Cuboid, quantization: &InstanceQuantization) -> UVec4 {
+        let ([x0, y0, z0], [x1, y1, z1]) = match self {
+            Self::Full => unreachable!("full instances aren't packed"),
+            Self::Half => (
+                cuboid.minimum.to_array().map(f32_to_f16_bits),
+                cuboid.maximum.to_array().map(f32_to_f16_bits),
+            ),
+            Self::Quantized => (
+                quantization.quantize(cuboid.minimum),
+                quantization.quantize(cuboid.maximum),
+            ),
+        };
+        UVec4::new(
+            x0 | (y0 << 16),
+            z0 | (x1 << 16),
+            y1 | (z1 << 16),
+            cuboid.meta_bits,
+        )
+    }
+}
+
+/// The bounds that [`CuboidsInstanceFormat::Quantized`] instances are stored
+/// in, as 65535 steps along each axis.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct InstanceQuantization {
+    pub minimum: Vec3,
+    pub maximum: Vec3,
+}
+
+impl InstanceQuantization {
+    fn new(instances: &[Cuboid]) -> Self {
+        let (minimum, maximum) = instances.iter().fold(
+            (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+            |(min, max), c| (min.min(c.minimum), max.max(c.maximum)),
+        );
+        Self { minimum, maximum }
+    }
+
+    pub fn step(&self) -> Vec3 {
+        (self.maximum - self.minimum) / 65535.0
+    }
+
+    fn contains(&self, cuboid: &Cuboid) -> bool {
+        cuboid.minimum.cmpge(self.minimum).all() && cuboid.maximum.cmple(self.maximum).all()
+    }
+
+    fn quantize(&self, p: Vec3) -> [u32; 3] {
+        let step = self.step();
+        // Flat batches have no steps along some axes.
+        let steps = Vec3::select(
+            step.cmpgt(Vec3::ZERO),
+            (p - self.minimum) / step,
+            Vec3::ZERO,
+        );
+        steps
+            .round()
+            .clamp(Vec3::ZERO, Vec3::splat(65535.0))
+            .to_array()
+            .map(|s| s as u32)
+    }
+}
+
+fn f32_to_f16_bits(x: f32) -> u32 {
+    u32::from(half::f16::from_f32(x).to_bits())
+}
+
+fn write_slice<T: ShaderSize + WriteInto + Clone>(
+    render_queue: &RenderQueue,
+    buffer: &Buffer,
+    items: &[T],
+    first_index: usize,
+) -> u64 {
+    let mut bytes = encase::StorageBuffer::new(Vec::new());
+    bytes.write(&items.to_vec()).unwrap();
+    let offset = first_index as u64 * T::min_size().get();
+    render_queue.write_buffer(buffer, offset, bytes.as_ref());
+    bytes.as_ref().len() as u64
+}
+
+/// The per-instance data of a batch, as uploaded by [`InstanceBuffer::set`].
+/// Each slice but `instances` and `hidden_mask` is either empty or holds an
+/// entry per instance, except that missing rotations and user data of the
+/// last instances are filled in with identity rotations and zeros.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct InstanceData<'a> {
+    pub instances: &'a [Cuboid],
+    pub rotations: &'a [Quat],
+    pub user_data: &'a [u32],
+    pub face_colors: &'a [[u32; 6]],
+    pub atlas_tiles: &'a [u32],
+    pub spawn_times: &'a [[f32; 2]],
+    pub scalars: &'a [f32],
+    pub hidden_mask: &'a [u32],
+}
+
+impl<'a> InstanceData<'a> {
+    pub fn new(cuboids: &'a Cuboids) -> Self {
+        Self {
+            instances: &cuboids.instances,
+            rotations: &cuboids.rotations,
+            user_data: &cuboids.user_data,
+            face_colors: &cuboids.face_colors,
+            atlas_tiles: &cuboids.atlas_tiles,
+            spawn_times: &cuboids.spawn_times,
+            scalars: &cuboids.scalars,
+            hidden_mask: cuboids.hidden_mask(),
+        }
+    }
+}
+
+impl InstanceBuffer {
+    pub fn is_ready(&self) -> bool {
+        self.chunks.iter().all(|c| c.bind_group.is_some())
+    }
+
+    fn set(
+        &mut self,
+        data: InstanceData,
+        max_chunk_instances: usize,
+        growth_factor: f32,
+        format: CuboidsInstanceFormat,
+    ) {
+        let InstanceData {
+            instances,
+            rotations,
+            user_data,
+            face_colors,
+            atlas_tiles,
+            spawn_times,
+            scalars,
+            hidden_mask,
+        } = data;
+        debug_assert!(face_colors.is_empty() || face_colors.len() == instances.len());
+        debug_assert!(atlas_tiles.is_empty() || atlas_tiles.len() == instances.len());
+        debug_assert!(spawn_times.is_empty() || spawn_times.len() == instances.len());
+        debug_assert!(scalars.is_empty() || scalars.len() == instances.len());
+        let max_chunk_instances = max_chunk_instances.max(1);
+        // Face colors, atlas tiles and spawn times follow the colors of all
+        // instances, so they can't be padded in place.
+        let grows = growth_factor > 1.0
+            && face_colors.is_empty()
+            && atlas_tiles.is_empty()
+            && spawn_times.is_empty();
+        let num_chunks = (instances.len() + max_chunk_instances - 1) / max_chunk_instances;
+        let quantization = if format == CuboidsInstanceFormat::Quantized {
+            InstanceQuantization::new(instances)
+        } else {
+            default()
+        };
+        // Existing chunks keep their GPU buffers, so they can be rewritten
+        // without reallocating.
+        self.chunks.resize_with(num_chunks, Default::default);
+        let pack_chunk = |i: usize, chunk: &mut InstanceChunk, instances: &[Cuboid]| {
+            // Chunks keep the capacity of their GPU buffer, which survives the
+            // CPU-side copy being cleared.
+            chunk.format = format;
+            chunk.quantization = quantization;
+            let gpu_capacity = chunk
+                .instances_buffer()
+                .map_or(0, |b| (b.size() / format.instance_size()) as usize);
+            let capacity = if !grows {
+                instances.len()
+            } else if instances.len() <= gpu_capacity {
+                gpu_capacity
+            } else {
+                ((instances.len() as f32 * growth_factor).ceil() as usize)
+                    .clamp(instances.len(), max_chunk_instances)
+            };
+            let padding = capacity - instances.len();
+            chunk.len = instances.len();
+            let mut chunk_instances = instances.to_vec();
+            chunk_instances.resize(
+                capacity,
+                *Cuboid::new(Vec3::ZERO, Vec3::ZERO, 0).make_invisible(),
+            );
+            if format != CuboidsInstanceFormat::Full {
+                chunk.packed.set(
+                    chunk_instances
+                        .iter()
+                        .map(|c| format.pack(c, &quantization))
+                        .collect(),
+                );
+            }
+            chunk.buffer.set(chunk_instances);
+            let mut colors: Vec<u32> = match scalars.chunks(max_chunk_instances).nth(i) {
+                Some(chunk_scalars) => chunk_scalars.iter().map(|s| s.to_bits()).collect(),
+                None => instances.iter().map(|c| c.color).collect(),
+            };
+            colors.resize(colors.len() + padding, 0);
+            if let Some(chunk_face_colors) = face_colors.chunks(max_chunk_instances).nth(i) {
+                colors.extend(chunk_face_colors.iter().flatten());
+            }
+            if let Some(chunk_atlas_tiles) = atlas_tiles.chunks(max_chunk_instances).nth(i) {
+                colors.extend(chunk_atlas_tiles);
+            }
+            if let Some(chunk_spawn_times) = spawn_times.chunks(max_chunk_instances).nth(i) {
+                colors.extend(chunk_spawn_times.iter().flatten().map(|&time| {
+                    if time.is_finite() {
+                        time.to_bits()
+                    } else {
+                        NO_SPAWN_TIME
+                    }
+                }));
+            }
+            chunk.colors.set(colors);
+            // Only as many as the chunk has instances, padded to the capacity.
+            let first = i * max_chunk_instances;
+            let chunk_rotations = if rotations.is_empty() {
+                vec![Vec4::from(Quat::IDENTITY)]
+            } else {
+                let mut chunk_rotations: Vec<Vec4> = rotations
+                    .iter()
+                    .skip(first)
+                    .take(instances.len())
+                    .map(|&q| Vec4::from(q))
+                    .collect();
+                chunk_rotations.resize(capacity, Vec4::from(Quat::IDENTITY));
+                chunk_rotations
+            };
+            chunk.rotations.set(chunk_rotations);
+            let chunk_user_data = if user_data.is_empty() {
+                vec![0]
+            } else {
+                let mut chunk_user_data: Vec<u32> = user_data
+                    .iter()
+                    .skip(first)
+                    .take(instances.len())
+                    .copied()
+                    .collect();
+                chunk_user_data.resize(capacity, 0);
+                chunk_user_data
+            };
+            chunk.user_data.set(chunk_user_data);
+        };
+        let chunks = self
+            .chunks
+            .iter_mut()
+            .zip(instances.chunks(max_chunk_instances))
+            .enumerate();
+        // Chunks are packed on all cores, since this dominates the upload of
+        // large batches.
+        if num_chunks > 1 {
+            ComputeTaskPool::get().scope(|scope| {
+                for (i, (chunk, instances)) in chunks {
+                    let pack_chunk = &pack_chunk;
+                    scope.spawn(async move { pack_chunk(i, chunk, instances) });
+                }
+            });
+        } else {
+            for (i, (chunk, instances)) in chunks {
+                pack_chunk(i, chunk, instances);
+            }
+        }
+        self.set_hidden_mask(hidden_mask, max_chunk_instances);
+    }
+
+    /// Splits the mask of the whole batch between the chunks. Padding is
+    /// hidden as well.
+    fn set_hidden_mask(&mut self, hidden_mask: &[u32], max_chunk_instances: usize) {
+        let max_chunk_instances = max_chunk_instances.max(1);
+        let is_hidden = |i: usize| {
+            hidden_mask
+                .get(i / 32)
+                .map_or(false, |word| word & (1 << (i % 32)) != 0)
+        };
+        for (i, chunk) in self.chunks.iter_mut().enumerate() {
+            let first = i * max_chunk_instances;
+            let capacity = chunk.capacity();
+            let mut words = vec![0; (capacity + 31) / 32];
+            for j in 0..capacity {
+                if j >= chunk.len || is_hidden(first + j) {
+                    words[j / 32] |= 1 << (j % 32);
+                }
+            }
+            chunk.hidden_mask.set(words);
+        }
+    }
+
+    /// Bytes allocated on the GPU for all chunks.
+    fn gpu_size(&self) -> u64 {
+        self.chunks.iter().map(InstanceChunk::gpu_size).sum()
+    }
+
+    fn clear(&mut self) {
+        for chunk in self.chunks.iter_mut() {
+            chunk.buffer.set(Vec::new());
+            chunk.packed.set(Vec::new());
+            chunk.colors.set(Vec::new());
+            chunk.rotations.set(Vec::new());
+            chunk.user_data.set(Vec::new());
+            chunk.hidden_mask.set(Vec::new());
+        }
+    }
+}
+
+impl CachedCuboidBuffers {
+    /// The buffer that was most recently written and should be drawn.
+    pub fn current(&self) -> &InstanceBuffer {
+        &self.instance_buffers[self.current_buffer]
+    }
+
+    pub fn current_mut(&mut self) -> &mut InstanceBuffer {
+        &mut self.instance_buffers[self.current_buffer]
+    }
+
+    /// Whether the batch was never staged for upload, e.g. because its
+    /// material was invalid when the entry was created, so that it needs a
+    /// full upload even if [`Cuboids`] didn't change since.
+    pub fn needs_first_upload(&self) -> bool {
+        !self.on_gpu && !self.dirty && !self.streaming && !self.upload_deferred && !self.evicted
+    }
+
+    /// Stages the instances of `cuboids` for upload.
+    ///
+    /// Dynamic batches rotate to the least recently written buffer, so we never
+    /// write into a buffer that the GPU might still be reading from the
+    /// previous frames. So do batches that are uploaded every frame with
+    /// [`InstanceBuffering::auto_dynamic_frames`], until they haven't been for
+    /// as many frames.
+    pub fn set_instances(
+        &mut self,
+        cuboids: &Cuboids,
+        max_chunk_instances: usize,
+        growth_factor: f32,
+        format: CuboidsInstanceFormat,
+        buffering: InstanceBuffering,
+        frame: u64,
+    ) {
+        self.consecutive_uploads = if frame == self.last_upload_frame + 1 {
+            self.consecutive_uploads.saturating_add(1)
+        } else {
+            1
+        };
+        self.auto_dynamic = buffering.auto_dynamic_frames.map_or(false, |frames| {
+            self.consecutive_uploads >= frames
+                || (self.auto_dynamic && frame - self.last_upload_frame <= u64::from(frames))
+        });
+        self.last_upload_frame = frame;
+        let num_buffers = if cuboids.dynamic || self.auto_dynamic {
+            buffering.dynamic_buffers.max(1)
+        } else {
+            1
+        };
+        self.instance_buffers
+            .resize_with(num_buffers, Default::default);
+
+        // Only the current buffer needs to hold on to its CPU-side copy.
+        let prev_buffer = self.current_buffer.min(num_buffers - 1);
+        self.instance_buffers[prev_buffer].clear();
+
+        self.current_buffer = (self.current_buffer + 1) % num_buffers;
+        self.evicted = false;
+        self.dirty_ranges.clear();
+        self.dirty_color_ranges.clear();
+        self.current_mut().set(
+            InstanceData::new(cuboids),
+            max_chunk_instances,
+            growth_factor,
+            format,
+        );
+    }
+
+    /// Stages the instances that were added with [`Cuboids::append`] for
+    /// upload, into the padding of the last chunk of the current buffer.
+    ///
+    /// Returns `false` if they don't fit, and all instances must be uploaded
+    /// instead.
+    pub fn append_instances(&mut self, cuboids: &Cuboids, max_chunk_instances: usize) -> bool {
+        let Some(first) = cuboids.edits.appended_from else {
+            return true;
+        };
+        if !cuboids.face_colors.is_empty()
+            || !cuboids.atlas_tiles.is_empty()
+            || !cuboids.spawn_times.is_empty()
+        {
+            return false;
+        }
+        let buffer = &mut self.instance_buffers[self.current_buffer];
+        let Some(chunk_index) = buffer.chunks.len().checked_sub(1) else {
+            return false;
+        };
+        let chunk = &mut buffer.chunks[chunk_index];
+        let local = chunk.len..chunk.len + cuboids.instances.len() - first;
+        if local.end > chunk.capacity() {
+            return false;
+        }
+        // Chunks without rotations or user data hold a single entry, and a
+        // chunk with room left holds at least two instances.
+        let has_rotations = chunk.rotations.get().len() == chunk.capacity();
+        let has_user_data = chunk.user_data.get().len() == chunk.capacity();
+        if has_rotations != !cuboids.rotations.is_empty()
+            || has_user_data != !cuboids.user_data.is_empty()
+        {
+            return false;
+        }
+        let instances = first..cuboids.instances.len();
+        chunk.buffer.get_mut()[local.clone()]
+            .copy_from_slice(&cuboids.instances[instances.clone()]);
+        chunk.repack(local.clone());
+        for (dst, index) in chunk.colors.get_mut()[local.clone()]
+            .iter_mut()
+            .zip(instances.clone())
+        {
+            *dst = cuboids.gpu_color(index);
+        }
+        if has_rotations {
+            for (dst, &src) in chunk.rotations.get_mut()[local.clone()]
+                .iter_mut()
+                .zip(&cuboids.rotations[instances.clone()])
+            {
+                *dst = Vec4::from(src);
+            }
+        }
+        if has_user_data {
+            chunk.user_data.get_mut()[local.clone()].copy_from_slice(&cuboids.user_data[instances]);
+        }
+        chunk.len = local.end;
+        self.dirty_ranges.push((chunk_index, local));
+        // The new instances are no longer hidden as padding.
+        self.set_hidden_mask(cuboids, max_chunk_instances);
+        self.visibility_dirty = true;
+        true
+    }
+
+    /// Stages only the visibility mask of `cuboids` for upload, into the
+    /// current buffer. The mask keeps its size, so it's rewritten in place.
+    pub fn set_hidden_mask(&mut self, cuboids: &Cuboids, max_chunk_instances: usize) {
+        self.current_mut()
+            .set_hidden_mask(cuboids.hidden_mask(), max_chunk_instances);
+    }
+
+    /// Stages the ranges of `cuboids` that were changed with
+    /// [`Cuboids::update_range`] for upload, into the current buffer.
+    pub fn set_instance_ranges(&mut self, cuboids: &Cuboids, max_chunk_instances: usize) {
+        let buffer = &mut self.instance_buffers[self.current_buffer];
+        for (chunk_index, local, first) in chunk_ranges(&cuboids.edits.ranges, max_chunk_instances)
+        {
+            let instances = first..first + local.len();
+            let chunk = &mut buffer.chunks[chunk_index];
+            chunk.buffer.get_mut()[local.clone()]
+                .copy_from_slice(&cuboids.instances[instances.clone()]);
+            chunk.repack(local.clone());
+            for (dst, index) in chunk.colors.get_mut()[local.clone()]
+                .iter_mut()
+                .zip(instances.clone())
+            {
+                *dst = cuboids.gpu_color(index);
+            }
+            if !cuboids.rotations.is_empty() {
+                for (dst, &src) in chunk.rotations.get_mut()[local.clone()]
+                    .iter_mut()
+                    .zip(&cuboids.rotations[instances.clone()])
+                {
+                    *dst = Vec4::from(src);
+                }
+            }
+            if !cuboids.user_data.is_empty() {
+                chunk.user_data.get_mut()[local.clone()]
+                    .copy_from_slice(&cuboids.user_data[instances]);
+            }
+            self.dirty_ranges.push((chunk_index, local));
+        }
+    }
+
+    /// Stages the colors of `cuboids` that were changed with
+    /// [`Cuboids::recolor`] or [`Cuboids::set_scalars`] for upload, into the
+    /// current buffer.
+    pub fn set_color_ranges(&mut self, cuboids: &Cuboids, max_chunk_instances: usize) {
+        let buffer = &mut self.instance_buffers[self.current_buffer];
+        for (chunk_index, local, first) in chunk_ranges(&cuboids.edits.colors, max_chunk_instances)
+        {
+            let chunk = &mut buffer.chunks[chunk_index];
+            let instances = &cuboids.instances[first..first + local.len()];
+            for (((dst, cuboid), src), index) in chunk.colors.get_mut()[local.clone()]
+                .iter_mut()
+                .zip(&mut chunk.buffer.get_mut()[local.clone()])
+                .zip(instances)
+                .zip(first..)
+            {
+                *dst = cuboids.gpu_color(index);
+                cuboid.color = src.color;
+            }
+            self.dirty_color_ranges.push((chunk_index, local));
+        }
+    }
+
+    /// Bytes allocated on the GPU for all instance buffers.
+    pub fn gpu_size(&self) -> u64 {
+        self.instance_buffers
+            .iter()
+            .map(InstanceBuffer::gpu_size)
+            .sum()
+    }
+
+    /// Frees all instance buffers, until the next [`Self::set_instances`].
+    fn evict(&mut self) {
+        self.instance_buffers.clear();
+        self.current_buffer = 0;
+        self.dirty = false;
+        self.visibility_dirty = false;
+        self.dirty_ranges.clear();
+        self.dirty_color_ranges.clear();
+        self.streaming = false;
+        self.on_gpu = false;
+        self.upload_deferred = false;
+        self.evicted = true;
+    }
+
+    /// Whether the current buffer holds as many instances, rotations, user
+    /// data, face colors, atlas tiles and spawn times as `cuboids` before any
+    /// [`Cuboids::append`], so that parts of it can be rewritten in place.
+    pub fn matches_layout(&self, cuboids: &Cuboids) -> bool {
+        let Some(buffer) = self.instance_buffers.get(self.current_buffer) else {
+            return false;
+        };
+        let len: usize = buffer.chunks.iter().map(|c| c.len).sum();
+        let expected_len = cuboids
+            .edits
+            .appended_from
+            .unwrap_or(cuboids.instances.len());
+        // Batches without rotations or user data upload a single entry per
+        // chunk.
+        let expected_entries =
+            |c: &InstanceChunk, empty: bool| if empty { 1 } else { c.capacity() };
+        let rotations_match = buffer
+            .chunks
+            .iter()
+            .all(|c| c.rotations.get().len() == expected_entries(c, cuboids.rotations.is_empty()));
+        let user_data_match = buffer
+            .chunks
+            .iter()
+            .all(|c| c.user_data.get().len() == expected_entries(c, cuboids.user_data.is_empty()));
+        // Edits index rotations and user data by instance, so those with
+        // missing entries are uploaded in full, which fills them in.
+        let complete = |entries: usize| entries == 0 || entries == cuboids.instances.len();
+        let rotations_match = rotations_match && complete(cuboids.rotations.len());
+        let user_data_match = user_data_match && complete(cuboids.user_data.len());
+        // Chunks with face colors, atlas tiles or spawn times are never padded.
+        let colors_match = buffer.chunks.iter().all(|c| {
+            c.colors.get().len()
+                == c.capacity()
+                    * (1 + 6 * !cuboids.face_colors.is_empty() as usize
+                        + !cuboids.atlas_tiles.is_empty() as usize
+                        + 2 * !cuboids.spawn_times.is_empty() as usize)
+        });
+        len == expected_len && rotations_match && user_data_match && colors_match
+    }
+
+    /// Whether the instances of `cuboids` that were edited or appended since
+    /// the last upload still fit within the bounds that the current buffer is
+    /// quantized in. Otherwise the whole batch must be quantized again.
+    pub fn fits_quantization(&self, cuboids: &Cuboids) -> bool {
+        let Some(chunk) = self.current().chunks.first() else {
+            return true;
+        };
+        if chunk.format != CuboidsInstanceFormat::Quantized {
+            return true;
+        }
+        let appended = cuboids
+            .edits
+            .appended_from
+            .map(|first| first..cuboids.instances.len());
+        cuboids
+            .edits
+            .ranges
+            .iter()
+            .cloned()
+            .chain(appended)
+            .flat_map(|range| &cuboids.instances[range])
+            .all(|c| chunk.quantization.contains(c))
+    }
+
+    /// The bounds that the current buffer is quantized in, if it holds
+    /// [`CuboidsInstanceFormat::Quantized`] instances.
+    pub fn quantization(&self) -> Option<InstanceQuantization> {
+        let chunk = self
+            .instance_buffers
+            .get(self.current_buffer)?
+            .chunks
+            .first()?;
+        (chunk.format == CuboidsInstanceFormat::Quantized).then_some(chunk.quantization)
+    }
+}
+
+/// Merges nearby `ranges` of instances and splits them between chunks.
+///
+/// Returns the index of each chunk, the range within it, and the index of its
+/// first instance in the whole batch.
+fn chunk_ranges(
+    ranges: &[Range<usize>],
+    max_chunk_instances: usize,
+) -> Vec<(usize, Range<usize>, usize)> {
+    let max_chunk_instances = max_chunk_instances.max(1);
+
+    let mut sorted = ranges.to_vec();
+    sorted.sort_unstable_by_key(|r| r.start);
+    let mut merged: Vec<Range<usize>> = Vec::new();
+    for range in sorted {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end + MIN_RANGE_GAP => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+
+    let mut chunk_ranges = Vec::new();
+    for range in merged {
+        let mut start = range.start;
+        while start < range.end {
+            let chunk_index = start / max_chunk_instances;
+            let chunk_start = chunk_index * max_chunk_instances;
+            let end = range.end.min(chunk_start + max_chunk_instances);
+            chunk_ranges.push((chunk_index, start - chunk_start..end - chunk_start, start));
+            start = end;
+        }
+    }
+    chunk_ranges
+}
+
 impl CuboidBufferCache {
+    /// Allocates GPU instance buffers with room for `num_cuboids` ahead of
+    /// time, so the first batch that fits doesn't have to.
+    pub fn prewarm(
+        &mut self,
+        num_cuboids: usize,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+    ) {
+        let mut buffer = InstanceBuffer::default();
+        let instances = vec![Cuboid::new(Vec3::ZERO, Vec3::ZERO, 0); num_cuboids];
+        buffer.set(
+            InstanceData {
+                instances: &instances,
+                ..default()
+            },
+            self.max_chunk_instances,
+            1.0,
+            self.instance_format,
+        );
+        for chunk in buffer.chunks.iter_mut() {
+            if self.data_textures {
+                chunk.write_data_texture(render_device, render_queue);
+                continue;
+            }
+            chunk.write_instances(render_device, render_queue);
+            chunk.colors.write_buffer(render_device, render_queue);
+            chunk.rotations.write_buffer(render_device, render_queue);
+            chunk.user_data.write_buffer(render_device, render_queue);
+            chunk.hidden_mask.write_buffer(render_device, render_queue);
+        }
+        buffer.clear();
+        self.prewarmed.push(PrewarmedBuffer {
+            capacity: num_cuboids,
+            buffer,
+        });
+    }
+
+    /// Gets the entry for `entity`, creating it if necessary.
+    ///
+    /// New entries claim the smallest prewarmed buffer that can hold
+    /// `num_instances`.
+    pub fn get_or_insert(
+        &mut self,
+        entity: Entity,
+        num_instances: usize,
+    ) -> &mut CachedCuboidBuffers {
+        let Self {
+            entries, prewarmed, ..
+        } = self;
+        entries.entry(entity).or_insert_with(|| {
+            let mut entry = CachedCuboidBuffers::default();
+            let best_fit = prewarmed
+                .iter()
+                .enumerate()
+                .filter(|(_, b)| b.capacity >= num_instances)
+                .min_by_key(|(_, b)| b.capacity)
+                .map(|(i, _)| i);
+            if let Some(i) = best_fit {
+                entry.instance_buffers.push(prewarmed.swap_remove(i).buffer);
+            }
+            entry
+        })
+    }
+
+    /// Frees the instance buffers of the least recently drawn batches, while
+    /// the cache holds more than [`Self::memory_budget`] bytes on the GPU.
+    ///
+    /// Prewarmed buffers that were never claimed are freed first. Batches that
+    /// are enabled this frame are never evicted, so the budget can still be
+    /// exceeded by the batches in view. Returns the evicted batches, with the
+    /// bytes freed for each.
+    pub fn evict_to_budget(&mut self) -> Vec<(Entity, u64)> {
+        let mut evicted = Vec::new();
+        let Some(budget) = self.memory_budget else {
+            return evicted;
+        };
+        let prewarmed_size = |p: &PrewarmedBuffer| p.buffer.gpu_size();
+        let mut total = self.gpu_size();
+        while total > budget {
+            let Some(prewarmed) = self.prewarmed.pop() else {
+                break;
+            };
+            total -= prewarmed_size(&prewarmed);
+        }
+        if total <= budget {
+            return evicted;
+        }
+
+        let mut candidates: Vec<(&Entity, &mut CachedCuboidBuffers)> = self
+            .entries
+            .iter_mut()
+            .filter(|(_, e)| !e.enabled && !e.evicted)
+            .collect();
+        candidates.sort_unstable_by_key(|(_, e)| e.last_drawn_frame);
+        for (&entity, entry) in candidates {
+            if total <= budget {
+                break;
+            }
+            let size = entry.gpu_size();
+            total -= size;
+            entry.evict();
+            evicted.push((entity, size));
+        }
+        evicted
+    }
+
+    /// Bytes allocated on the GPU for all instance buffers, including
+    /// prewarmed ones.
+    pub fn gpu_size(&self) -> u64 {
+        self.prewarmed
+            .iter()
+            .map(|p| p.buffer.gpu_size())
+            .sum::<u64>()
+            + self.entries.values().map(|e| e.gpu_size()).sum::<u64>()
+    }
+
     pub fn cull_entities(&mut self) {
         let mut to_remove = Vec::new();
         for (entity, entry) in self.entries.iter_mut() {
@@ -37,3 +1070,82 @@ impl CuboidBufferCache {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::tasks::TaskPool;
+
+    #[test]
+    fn set_splits_instances_into_chunks() {
+        ComputeTaskPool::init(TaskPool::new);
+        // A device whose bindings fit 3 instances and a few spare bytes.
+        let max_chunk_size = 3 * <Cuboid as ShaderSize>::SHADER_SIZE.get() + 5;
+        assert_eq!(max_chunk_instances(max_chunk_size, 2), 2);
+        let max_chunk_instances = max_chunk_instances(max_chunk_size, usize::MAX);
+        assert_eq!(max_chunk_instances, 3);
+        let instances: Vec<Cuboid> = (0..10)
+            .map(|i| Cuboid::new(Vec3::splat(i as f32), Vec3::splat(i as f32 + 1.0), i))
+            .collect();
+        let face_colors: Vec<[u32; 6]> = (0..10).map(|i| [1000 + i; 6]).collect();
+        let atlas_tiles: Vec<u32> = (100..110).collect();
+        let mut buffer = InstanceBuffer::default();
+        buffer.set(
+            InstanceData {
+                instances: &instances,
+                face_colors: &face_colors,
+                atlas_tiles: &atlas_tiles,
+                ..default()
+            },
+            max_chunk_instances,
+            2.0,
+            CuboidsInstanceFormat::Full,
+        );
+
+        assert_eq!(buffer.chunks.len(), 4);
+        for (chunk, range) in buffer.chunks.iter().zip([0..3, 3..6, 6..9, 9..10]) {
+            // Chunks with face colors and atlas tiles are never padded.
+            assert_eq!(chunk.len, range.len());
+            assert_eq!(chunk.capacity(), range.len());
+            assert_eq!(chunk.buffer.get().as_slice(), &instances[range.clone()]);
+            let colors: Vec<u32> = range
+                .clone()
+                .map(|i| i as u32)
+                .chain(range.clone().flat_map(|i| face_colors[i]))
+                .chain(range.clone().map(|i| atlas_tiles[i]))
+                .collect();
+            assert_eq!(chunk.colors.get(), &colors);
+            assert_eq!(chunk.rotations.get().len(), 1);
+            assert_eq!(chunk.hidden_mask.get(), &vec![0]);
+        }
+    }
+
+    #[test]
+    fn first_upload_after_invalid_material() {
+        // No entry is created while the material id is invalid, so the batch
+        // only gets one once the id is fixed, without `Cuboids` changing.
+        let entity = Entity::from_raw(0);
+        let mut cache = CuboidBufferCache::default();
+        let entry = cache.get_or_insert(entity, 10);
+        assert!(entry.instance_buffers.is_empty());
+        assert!(entry.needs_first_upload());
+
+        let instances = vec![Cuboid::new(Vec3::ZERO, Vec3::ONE, 0); 10];
+        entry.set_instances(
+            &Cuboids::new(instances),
+            10,
+            1.0,
+            CuboidsInstanceFormat::default(),
+            InstanceBuffering::default(),
+            1,
+        );
+        entry.dirty = true;
+        assert!(!entry.needs_first_upload());
+        entry.dirty = false;
+        entry.on_gpu = true;
+        assert!(!entry.needs_first_upload());
+
+        entry.evict();
+        assert!(!entry.needs_first_upload());
+    }
+}
@@ -0,0 +1,408 @@
+use super::buffers::CuboidsViewUniformOffset;
+use super::cuboid_cache::CuboidBufferCache;
+use super::draw::{TransformsMeta, ViewMeta};
+use super::index_buffer::{CUBE_INDICES, TRANSFORM_INDEX_SHIFT};
+use super::occlusion::{DepthPyramidPipelines, ViewOcclusion};
+use super::pipeline::{CuboidsPipelines, CuboidsShaderDefs};
+use super::timestamps::{CuboidsTimestamps, TimedPass};
+
+use bevy::{
+    core_pipeline::core_3d::Opaque3d,
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+        render_phase::RenderPhase,
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType, Buffer,
+            BufferBindingType, BufferDescriptor, BufferId, BufferSize, BufferUsages,
+            CachedComputePipelineId, ComputePassDescriptor, ComputePipelineDescriptor,
+            PipelineCache, ShaderStages, ShaderType, StorageBuffer,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        settings::WgpuLimits,
+        view::{ViewUniformOffset, VisibleEntities},
+    },
+    utils::{HashMap, HashSet},
+};
+
+pub(crate) const CULLING_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 9481771739432342956);
+
+/// Render graph node that runs the culling pass before the main 3D pass.
+pub(crate) const CUBOIDS_CULLING_NODE: &str = "cuboids_culling";
+
+const WORKGROUP_SIZE: u32 = 256;
+
+/// The most instances that a single dispatch of `cull` covers, so chunks
+/// culled on the GPU must not be larger.
+pub(crate) fn max_culled_chunk_instances(limits: &WgpuLimits) -> usize {
+    limits.max_compute_workgroups_per_dimension as usize * WORKGROUP_SIZE as usize
+}
+
+/// Matches `wgpu::util::DrawIndexedIndirect`.
+#[derive(Clone, Copy, Default, ShaderType)]
+pub(crate) struct GpuDrawIndexedIndirect {
+    pub index_count: u32,
+    pub instance_count: u32,
+    pub first_index: u32,
+    pub base_vertex: i32,
+    pub first_instance: u32,
+}
+
+#[derive(Resource)]
+pub(crate) struct CuboidsCullingPipeline {
+    pub layout: BindGroupLayout,
+    pub pipeline_id: CachedComputePipelineId,
+}
+
+impl FromWorld for CuboidsCullingPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let storage_entry = |binding, read_only| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: BufferSize::new(0),
+            },
+            count: None,
+        };
+        let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("cuboids_culling_layout"),
+            entries: &[
+                // Instances
+                storage_entry(0, true),
+                // Rotations
+                storage_entry(1, true),
+                // Indirect draw arguments
+                storage_entry(2, false),
+                // Visible instance indices
+                storage_entry(3, false),
+                // Hidden instance mask
+                storage_entry(4, true),
+            ],
+        });
+
+        let cuboids_pipelines = world.resource::<CuboidsPipelines>();
+        let descriptor = ComputePipelineDescriptor {
+            label: Some("cuboids_culling_pipeline".into()),
+            layout: vec![
+                cuboids_pipelines.view_layout.clone(),
+                cuboids_pipelines.transforms_layout.clone(),
+                layout.clone(),
+                world
+                    .resource::<DepthPyramidPipelines>()
+                    .occlusion_layout
+                    .clone(),
+            ],
+            push_constant_ranges: Vec::new(),
+            shader: CULLING_SHADER_HANDLE.typed(),
+            shader_defs: world.resource::<CuboidsShaderDefs>().culling(),
+            entry_point: "cull".into(),
+        };
+        let pipeline_id = world
+            .resource_mut::<PipelineCache>()
+            .queue_compute_pipeline(descriptor);
+
+        Self {
+            layout,
+            pipeline_id,
+        }
+    }
+}
+
+/// Culling results for every instance chunk, per view.
+#[derive(Default, Resource)]
+pub(crate) struct CuboidsCullingCache {
+    pub views: HashMap<Entity, ViewCulling>,
+}
+
+#[derive(Default)]
+pub(crate) struct ViewCulling {
+    /// Keyed by the batch entity and chunk index.
+    pub chunks: HashMap<(Entity, usize), CulledChunk>,
+    pub occlusion: ViewOcclusion,
+}
+
+pub(crate) struct CulledChunk {
+    pub num_instances: u32,
+    pub visible_indices: Buffer,
+    pub capacity: usize,
+    pub indirect: StorageBuffer<GpuDrawIndexedIndirect>,
+    /// The instance, rotation, hidden mask, and color buffers that the bind
+    /// groups refer to.
+    pub source_buffers: Option<(BufferId, BufferId, BufferId, BufferId, BufferId)>,
+    /// Bound for the culling pass.
+    pub cull_bind_group: Option<BindGroup>,
+    /// Bound for drawing, in place of the chunk's own bind group.
+    pub draw_bind_group: Option<BindGroup>,
+}
+
+fn create_visible_indices(render_device: &RenderDevice, capacity: usize) -> Buffer {
+    render_device.create_buffer(&BufferDescriptor {
+        label: Some("cuboids_visible_indices"),
+        size: (capacity.max(1) * std::mem::size_of::<u32>()) as u64,
+        usage: BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    })
+}
+
+impl CulledChunk {
+    fn new(render_device: &RenderDevice, capacity: usize) -> Self {
+        let mut indirect = StorageBuffer::default();
+        // Read back by the culling stats.
+        indirect.add_usages(BufferUsages::INDIRECT | BufferUsages::COPY_SRC);
+        Self {
+            num_instances: 0,
+            visible_indices: create_visible_indices(render_device, capacity),
+            capacity,
+            indirect,
+            source_buffers: None,
+            cull_bind_group: None,
+            draw_bind_group: None,
+        }
+    }
+}
+
+pub(crate) fn prepare_cuboids_culling(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    cuboids_pipelines: Res<CuboidsPipelines>,
+    culling_pipeline: Res<CuboidsCullingPipeline>,
+    buffer_cache: Res<CuboidBufferCache>,
+    mut culling_cache: ResMut<CuboidsCullingCache>,
+    views: Query<(Entity, &VisibleEntities), With<RenderPhase<Opaque3d>>>,
+) {
+    let culling_cache = culling_cache.as_mut();
+    culling_cache.views.retain(|view, _| views.contains(*view));
+
+    for (view, visible_entities) in views.iter() {
+        // Batches this view doesn't draw, e.g. because of `RenderLayers`, are
+        // not culled either. Every chunk that is kept is dispatched, so stale
+        // ones must go.
+        let visible: HashSet<Entity> = visible_entities.entities.iter().copied().collect();
+        let view_culling = culling_cache.views.entry(view).or_default();
+        view_culling.chunks.retain(|(entity, i), _| {
+            visible.contains(entity)
+                && buffer_cache
+                    .entries
+                    .get(entity)
+                    .map(|entry| entry.enabled && *i < entry.current().chunks.len())
+                    .unwrap_or(false)
+        });
+
+        for &entity in &visible_entities.entities {
+            let Some(entry) = buffer_cache.entries.get(&entity) else {
+                continue;
+            };
+            if !entry.enabled {
+                continue;
+            }
+            for (i, chunk) in entry.current().chunks.iter().enumerate() {
+                let num_instances = chunk.len;
+                let culled = view_culling
+                    .chunks
+                    .entry((entity, i))
+                    .or_insert_with(|| CulledChunk::new(&render_device, num_instances));
+                if culled.capacity < num_instances {
+                    culled.visible_indices = create_visible_indices(&render_device, num_instances);
+                    culled.capacity = num_instances;
+                    culled.cull_bind_group = None;
+                }
+                // Instances are rewritten into a different buffer on change.
+                let source_buffers = (
+                    chunk.instances_buffer().unwrap().id(),
+                    chunk.rotations.buffer().unwrap().id(),
+                    chunk.hidden_mask.buffer().unwrap().id(),
+                    chunk.colors.buffer().unwrap().id(),
+                    chunk.user_data.buffer().unwrap().id(),
+                );
+                if culled.source_buffers != Some(source_buffers) {
+                    culled.source_buffers = Some(source_buffers);
+                    culled.cull_bind_group = None;
+                }
+                culled.num_instances = num_instances as u32;
+
+                // Reset the instance count before every culling pass.
+                culled.indirect.set(GpuDrawIndexedIndirect {
+                    index_count: CUBE_INDICES.len() as u32,
+                    instance_count: 0,
+                    first_index: 0,
+                    base_vertex: (entry.transform_index << TRANSFORM_INDEX_SHIFT) as i32,
+                    first_instance: 0,
+                });
+                culled.indirect.write_buffer(&render_device, &render_queue);
+
+                if culled.cull_bind_group.is_some() {
+                    continue;
+                }
+                let indirect_binding = culled.indirect.binding().unwrap();
+                let visible_indices_binding = culled.visible_indices.as_entire_binding();
+                culled.cull_bind_group =
+                    Some(render_device.create_bind_group(&BindGroupDescriptor {
+                        label: Some("cuboids_culling_bind_group"),
+                        layout: &culling_pipeline.layout,
+                        entries: &[
+                            BindGroupEntry {
+                                binding: 0,
+                                resource: chunk.instances_binding(),
+                            },
+                            BindGroupEntry {
+                                binding: 1,
+                                resource: chunk.rotations.binding().unwrap(),
+                            },
+                            BindGroupEntry {
+                                binding: 2,
+                                resource: indirect_binding,
+                            },
+                            BindGroupEntry {
+                                binding: 3,
+                                resource: visible_indices_binding.clone(),
+                            },
+                            BindGroupEntry {
+                                binding: 4,
+                                resource: chunk.hidden_mask.binding().unwrap(),
+                            },
+                        ],
+                    }));
+                culled.draw_bind_group =
+                    Some(render_device.create_bind_group(&BindGroupDescriptor {
+                        label: Some("cuboids_culled_instance_buffer_bind_group"),
+                        layout: &cuboids_pipelines.cuboids_layout,
+                        entries: &[
+                            BindGroupEntry {
+                                binding: 0,
+                                resource: chunk.instances_binding(),
+                            },
+                            BindGroupEntry {
+                                binding: 1,
+                                resource: chunk.rotations.binding().unwrap(),
+                            },
+                            BindGroupEntry {
+                                binding: 2,
+                                resource: visible_indices_binding,
+                            },
+                            BindGroupEntry {
+                                binding: 3,
+                                resource: chunk.hidden_mask.binding().unwrap(),
+                            },
+                            BindGroupEntry {
+                                binding: 4,
+                                resource: chunk.colors.binding().unwrap(),
+                            },
+                            BindGroupEntry {
+                                binding: 5,
+                                resource: chunk.user_data.binding().unwrap(),
+                            },
+                        ],
+                    }));
+            }
+        }
+    }
+}
+
+/// Culls the instances of every enabled chunk against the view frustum, and
+/// the depth pyramid of the previous frame.
+pub(crate) struct CuboidsCullingNode {
+    view_query: QueryState<(
+        &'static ViewUniformOffset,
+        &'static CuboidsViewUniformOffset,
+    )>,
+}
+
+impl CuboidsCullingNode {
+    pub const IN_VIEW: &'static str = "view";
+
+    pub fn new(world: &mut World) -> Self {
+        Self {
+            view_query: QueryState::new(world),
+        }
+    }
+}
+
+impl Node for CuboidsCullingNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(Self::IN_VIEW, SlotType::Entity)]
+    }
+
+    fn update(&mut self, world: &mut World) {
+        self.view_query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+        let Ok((view_uniform_offset, cuboids_view_uniform_offset)) =
+            self.view_query.get_manual(world, view_entity)
+        else {
+            return Ok(());
+        };
+        let Some(view_culling) = world
+            .resource::<CuboidsCullingCache>()
+            .views
+            .get(&view_entity)
+        else {
+            return Ok(());
+        };
+        let (Some(view_bind_group), Some(transforms_bind_group)) = (
+            world
+                .resource::<ViewMeta>()
+                .view_bind_groups
+                .get(&view_entity),
+            world
+                .resource::<TransformsMeta>()
+                .transform_buffer_bind_group
+                .as_ref(),
+        ) else {
+            return Ok(());
+        };
+        let Some(occlusion_bind_group) = view_culling.occlusion.bind_group.as_ref() else {
+            return Ok(());
+        };
+        let Some(pipeline) = world
+            .resource::<PipelineCache>()
+            .get_compute_pipeline(world.resource::<CuboidsCullingPipeline>().pipeline_id)
+        else {
+            return Ok(());
+        };
+
+        let timestamps = world.get_resource::<CuboidsTimestamps>();
+        let query =
+            timestamps.and_then(|t| t.begin(render_context.command_encoder(), TimedPass::Cull));
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("cuboids_culling_pass"),
+                });
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(
+            0,
+            view_bind_group,
+            &[view_uniform_offset.offset, cuboids_view_uniform_offset.0],
+        );
+        pass.set_bind_group(1, transforms_bind_group, &[]);
+        pass.set_bind_group(3, occlusion_bind_group, &[]);
+        for culled in view_culling.chunks.values() {
+            let Some(bind_group) = culled.cull_bind_group.as_ref() else {
+                continue;
+            };
+            pass.set_bind_group(2, bind_group, &[]);
+            let num_workgroups = (culled.num_instances + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE;
+            pass.dispatch_workgroups(num_workgroups, 1, 1);
+        }
+        drop(pass);
+        if let (Some(timestamps), Some(query)) = (timestamps, query) {
+            timestamps.end(render_context.command_encoder(), query);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,263 @@
+// Frustum and occlusion culling for cuboid instances. Visible instance indices are compacted
+// into a buffer that is consumed by an indirect draw.
+
+struct View {
+    view_proj: mat4x4<f32>,
+    inverse_view_proj: mat4x4<f32>,
+    view: mat4x4<f32>,
+    inverse_view: mat4x4<f32>,
+    projection: mat4x4<f32>,
+    inverse_projection: mat4x4<f32>,
+    world_position: vec3<f32>,
+    width: f32,
+    height: f32,
+}
+
+struct CuboidsView {
+    clipping_enabled: u32,
+    lod_level: u32,
+}
+
+struct Cuboid {
+    min: vec3<f32>,
+    meta_bits: u32,
+    max: vec3<f32>,
+    color: u32,
+}
+
+#ifdef PACKED_INSTANCES
+// Packed like `load_cuboid` in the vertex shader reads them.
+struct Cuboids {
+    data: array<vec4<u32>>,
+}
+#else
+struct Cuboids {
+    data: array<Cuboid>,
+}
+#endif
+
+struct Rotations {
+    data: array<vec4<f32>>,
+}
+
+struct Transform {
+    m: mat4x4<f32>,
+    m_inv: mat4x4<f32>,
+    interior_color: u32,
+    has_interior_color: u32,
+    animation_t: f32,
+    color_layout: u32,
+    material_slots: vec4<u32>,
+    quantization_min: vec3<f32>,
+    quantization_step: vec3<f32>,
+}
+
+struct Transforms {
+    data: array<Transform>,
+}
+
+struct DrawIndexedIndirect {
+    index_count: u32,
+    instance_count: atomic<u32>,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+}
+
+struct VisibleIndices {
+    data: array<u32>,
+}
+
+@group(0) @binding(0)
+var<uniform> view: View;
+
+@group(0) @binding(1)
+var<uniform> cuboids_view: CuboidsView;
+
+@group(1) @binding(0)
+var<storage> transforms: Transforms;
+
+@group(2) @binding(0)
+var<storage> cuboids: Cuboids;
+
+@group(2) @binding(1)
+var<storage> rotations: Rotations;
+
+#ifdef HALF_INSTANCES
+fn unpack_bounds(word: u32) -> vec2<f32> {
+    return unpack2x16float(word);
+}
+#endif
+
+#ifdef QUANTIZED_INSTANCES
+fn unpack_bounds(word: u32) -> vec2<f32> {
+    return vec2<f32>(f32(word & 0xFFFFu), f32(word >> 16u));
+}
+#endif
+
+// The color isn't needed for culling. Quantized bounds are in steps of the
+// batch bounds.
+fn load_cuboid(i: u32) -> Cuboid {
+    #ifdef PACKED_INSTANCES
+    let packed = cuboids.data[i];
+    let min_xy = unpack_bounds(packed.x);
+    let min_z_max_x = unpack_bounds(packed.y);
+    let max_yz = unpack_bounds(packed.z);
+    return Cuboid(vec3<f32>(min_xy, min_z_max_x.x), packed.w, vec3<f32>(min_z_max_x.y, max_yz), 0u);
+    #else
+    return cuboids.data[i];
+    #endif
+}
+
+@group(2) @binding(2)
+var<storage, read_write> indirect: DrawIndexedIndirect;
+
+@group(2) @binding(3)
+var<storage, read_write> visible_indices: VisibleIndices;
+
+struct HiddenMask {
+    data: array<u32>,
+}
+
+@group(2) @binding(4)
+var<storage> hidden_mask: HiddenMask;
+
+struct OcclusionCulling {
+    view_proj: mat4x4<f32>,
+    pyramid_size: vec2<u32>,
+    num_mips: u32,
+    enabled: u32,
+    viewport: vec4<f32>,
+}
+
+@group(3) @binding(0)
+var depth_pyramid: texture_2d<f32>;
+
+@group(3) @binding(1)
+var<uniform> occlusion: OcclusionCulling;
+
+fn quat_rotate(q: vec4<f32>, v: vec3<f32>) -> vec3<f32> {
+    let t = 2.0 * cross(q.xyz, v);
+    return v + q.w * t + cross(q.xyz, t);
+}
+
+// Bits set for each clip plane that `p` is outside of.
+fn outside_mask(p: vec4<f32>) -> u32 {
+    return u32(p.x < -p.w) |
+        u32(p.x > p.w) << 1u |
+        u32(p.y < -p.w) << 2u |
+        u32(p.y > p.w) << 3u |
+        u32(p.z < 0.0) << 4u |
+        u32(p.z > p.w) << 5u;
+}
+
+fn corner_sign(c: u32) -> vec3<f32> {
+    return vec3<f32>(
+        f32(c & 0x1u),
+        f32((c & 0x2u) >> 1u),
+        f32((c & 0x4u) >> 2u),
+    ) * 2.0 - 1.0;
+}
+
+// Tests the screen-space bounds of the cuboid against the depth pyramid, which
+// was rendered with `clip_from_model`.
+fn is_occluded(
+    clip_from_model: mat4x4<f32>,
+    center: vec3<f32>,
+    half_extents: vec3<f32>,
+    rotation: vec4<f32>,
+) -> bool {
+    var ndc_min = vec2<f32>(1.0);
+    var ndc_max = vec2<f32>(-1.0);
+    var nearest_depth = 0.0;
+    for (var c = 0u; c < 8u; c++) {
+        let corner = center + quat_rotate(rotation, corner_sign(c) * half_extents);
+        let p = clip_from_model * vec4<f32>(corner, 1.0);
+        if (p.w <= 0.0) {
+            // Crosses the camera plane.
+            return false;
+        }
+        let ndc = p.xyz / p.w;
+        ndc_min = min(ndc_min, ndc.xy);
+        ndc_max = max(ndc_max, ndc.xy);
+        // Reverse Z, so larger is nearer.
+        nearest_depth = max(nearest_depth, ndc.z);
+    }
+
+    // NDC y points up, texel rows point down.
+    let view_uv_min = clamp(vec2<f32>(ndc_min.x, -ndc_max.y) * 0.5 + 0.5, vec2<f32>(0.0), vec2<f32>(1.0));
+    let view_uv_max = clamp(vec2<f32>(ndc_max.x, -ndc_min.y) * 0.5 + 0.5, vec2<f32>(0.0), vec2<f32>(1.0));
+    // The view only covers its viewport of the pyramid.
+    let uv_min = occlusion.viewport.xy + view_uv_min * occlusion.viewport.zw;
+    let uv_max = occlusion.viewport.xy + view_uv_max * occlusion.viewport.zw;
+
+    // Pick the mip where the bounds cover at most 2x2 texels.
+    let size_px = (uv_max - uv_min) * vec2<f32>(occlusion.pyramid_size);
+    let mip = min(
+        u32(ceil(log2(max(max(size_px.x, size_px.y), 1.0)))),
+        occlusion.num_mips - 1u,
+    );
+    let mip_size = vec2<f32>(textureDimensions(depth_pyramid, i32(mip)));
+    let texel_min = vec2<i32>(uv_min * mip_size);
+    let texel_max = min(vec2<i32>(uv_max * mip_size), vec2<i32>(mip_size) - 1);
+
+    var farthest_depth = 1.0;
+    for (var y = texel_min.y; y <= texel_max.y; y++) {
+        for (var x = texel_min.x; x <= texel_max.x; x++) {
+            farthest_depth = min(farthest_depth, textureLoad(depth_pyramid, vec2<i32>(x, y), i32(mip)).x);
+        }
+    }
+    return nearest_depth < farthest_depth;
+}
+
+@compute @workgroup_size(256)
+fn cull(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.x;
+    if (i >= arrayLength(&cuboids.data)) {
+        return;
+    }
+    var cuboid = load_cuboid(i);
+
+    // Invisible, or hidden by the current LOD.
+    let hidden = (hidden_mask.data[i >> 5u] >> (i & 31u)) & 1u;
+    if ((cuboid.meta_bits & 0x01u) != 0u || hidden != 0u ||
+        ((cuboid.meta_bits >> 2u) & 0x3u) > cuboids_view.lod_level)
+    {
+        return;
+    }
+
+    // The CPU encodes the transform index in the base vertex, see the vertex
+    // shader.
+    let transform = transforms.data[u32(indirect.base_vertex) >> 5u];
+    #ifdef QUANTIZED_INSTANCES
+    cuboid.min = transform.quantization_min + cuboid.min * transform.quantization_step;
+    cuboid.max = transform.quantization_min + cuboid.max * transform.quantization_step;
+    #endif
+    let rotation = rotations.data[min(i, arrayLength(&rotations.data) - 1u)];
+    let clip_from_model = view.view_proj * transform.m;
+    let center = (cuboid.min + cuboid.max) / 2.0;
+    let half_extents = (cuboid.max - cuboid.min) / 2.0;
+
+    // The cuboid is culled when all of its corners are outside of the same
+    // clip plane.
+    var outside = 0x3Fu;
+    for (var c = 0u; c < 8u; c++) {
+        let corner = center + quat_rotate(rotation, corner_sign(c) * half_extents);
+        outside &= outside_mask(clip_from_model * vec4<f32>(corner, 1.0));
+    }
+    if (outside != 0u) {
+        return;
+    }
+
+    // Bit 2 of the color layout opts the batch out of occlusion culling, and
+    // bit 31 of `meta_bits` the instance, since it's drawn over occluders.
+    if (occlusion.enabled != 0u && (transform.color_layout & 4u) == 0u &&
+        (cuboid.meta_bits & 0x80000000u) == 0u &&
+        is_occluded(occlusion.view_proj * transform.m, center, half_extents, rotation))
+    {
+        return;
+    }
+
+    let slot = atomicAdd(&indirect.instance_count, 1u);
+    visible_indices.data[slot] = i;
+}
@@ -0,0 +1,204 @@
+use super::culling::CuboidsCullingCache;
+use crate::draw_stats::RenderedCuboidsDrawStats;
+use crate::CuboidsGpuCulling;
+
+use bevy::{
+    core::cast_slice,
+    prelude::*,
+    render::{
+        render_graph::{Node, NodeRunError, RenderGraphContext},
+        render_resource::{Buffer, BufferDescriptor, BufferUsages, Maintain, MapMode},
+        renderer::{RenderContext, RenderDevice},
+    },
+};
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Arc,
+};
+
+/// Main render graph node that copies the instance count of every culled
+/// chunk, once every camera is drawn.
+pub(crate) const CUBOIDS_CULLING_STATS_NODE: &str = "cuboids_culling_stats";
+
+/// Frames that can wait to be read back at once. Later frames aren't counted
+/// until one is.
+const MAX_PENDING_READBACKS: usize = 3;
+const COUNT_SIZE: u64 = std::mem::size_of::<u32>() as u64;
+/// Offset of `instance_count` in `GpuDrawIndexedIndirect`.
+const INSTANCE_COUNT_OFFSET: u64 = COUNT_SIZE;
+
+/// Surviving instance counts, copied out of the indirect draw arguments of the
+/// culling pass, and read back into [`CuboidsGpuCulling`] a few frames later.
+#[derive(Default, Resource)]
+pub(crate) struct CuboidsCullingStats {
+    /// The readback of this frame, `None` while too many are pending.
+    frame: Option<CullingReadback>,
+    pending: Vec<CullingReadback>,
+    free_buffers: Vec<(Buffer, usize)>,
+}
+
+struct CullingReadback {
+    buffer: Buffer,
+    capacity: usize,
+    /// The view, batch entity, chunk index and instances tested of each count.
+    chunks: Vec<(Entity, Entity, usize, u32)>,
+    state: Arc<AtomicU8>,
+}
+
+const READBACK_PENDING: u8 = 0;
+const READBACK_MAPPED: u8 = 1;
+const READBACK_FAILED: u8 = 2;
+
+impl CullingReadback {
+    fn map(&self) {
+        let state = self.state.clone();
+        self.buffer
+            .slice(..)
+            .map_async(MapMode::Read, move |result| {
+                let new_state = match result {
+                    Ok(()) => READBACK_MAPPED,
+                    Err(err) => {
+                        warn!("Failed to read back cuboid culling counts: {err}");
+                        READBACK_FAILED
+                    }
+                };
+                state.store(new_state, Ordering::Release);
+            });
+    }
+
+    fn read(&self) -> CuboidsGpuCulling {
+        let visible: Vec<u32> = {
+            let data = self.buffer.slice(..).get_mapped_range();
+            cast_slice(&data).to_vec()
+        };
+        self.buffer.unmap();
+        let mut culling = CuboidsGpuCulling::default();
+        for (&(_, entity, _, tested), &visible) in self.chunks.iter().zip(&visible) {
+            let counts = culling.batches.entry(entity).or_default();
+            counts.chunks += 1;
+            counts.instances_tested += tested;
+            counts.instances_visible += visible;
+        }
+        culling
+    }
+}
+
+/// Records the chunks that are culled this frame, unless too many frames are
+/// still being read back.
+pub(crate) fn prepare_cuboids_culling_stats(
+    render_device: Res<RenderDevice>,
+    culling_cache: Res<CuboidsCullingCache>,
+    mut stats: ResMut<CuboidsCullingStats>,
+) {
+    if stats.pending.len() >= MAX_PENDING_READBACKS {
+        return;
+    }
+    let mut chunks = Vec::new();
+    for (&view, view_culling) in &culling_cache.views {
+        for (&(entity, i), culled) in &view_culling.chunks {
+            if culled.cull_bind_group.is_some() {
+                chunks.push((view, entity, i, culled.num_instances));
+            }
+        }
+    }
+    if chunks.is_empty() {
+        return;
+    }
+
+    let (buffer, capacity) = match stats
+        .free_buffers
+        .iter()
+        .position(|&(_, capacity)| capacity >= chunks.len())
+    {
+        Some(i) => stats.free_buffers.swap_remove(i),
+        None => {
+            // Room to grow, so that buffers aren't reallocated every frame.
+            let capacity = chunks.len().next_power_of_two();
+            let buffer = render_device.create_buffer(&BufferDescriptor {
+                label: Some("cuboids_culling_stats_readback_buffer"),
+                size: capacity as u64 * COUNT_SIZE,
+                usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            });
+            (buffer, capacity)
+        }
+    };
+    stats.frame = Some(CullingReadback {
+        buffer,
+        capacity,
+        chunks,
+        state: default(),
+    });
+}
+
+/// Maps the counts of this frame, and reports the newest of the earlier ones
+/// that are mapped.
+///
+/// Runs after the render graph, like
+/// [`read_back_cuboids_timestamps`](super::timestamps::read_back_cuboids_timestamps).
+pub(crate) fn read_back_cuboids_culling_stats(
+    render_device: Res<RenderDevice>,
+    draw_stats: Res<RenderedCuboidsDrawStats>,
+    mut stats: ResMut<CuboidsCullingStats>,
+) {
+    let CuboidsCullingStats {
+        frame,
+        pending,
+        free_buffers,
+    } = &mut *stats;
+    if let Some(frame) = frame.take() {
+        frame.map();
+        pending.push(frame);
+    }
+
+    render_device.poll(Maintain::Poll);
+    let mut gpu_culling = None;
+    pending.retain(|readback| match readback.state.load(Ordering::Acquire) {
+        READBACK_PENDING => true,
+        READBACK_MAPPED => {
+            gpu_culling = Some(readback.read());
+            free_buffers.push((readback.buffer.clone(), readback.capacity));
+            false
+        }
+        _ => false,
+    });
+    if let Some(gpu_culling) = gpu_culling {
+        draw_stats.set_gpu_culling(gpu_culling);
+    }
+}
+
+/// Copies the instance count of every recorded chunk into the readback buffer
+/// of the frame.
+pub(crate) struct CuboidsCullingStatsNode;
+
+impl Node for CuboidsCullingStatsNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let Some(frame) = world.resource::<CuboidsCullingStats>().frame.as_ref() else {
+            return Ok(());
+        };
+        let culling_cache = world.resource::<CuboidsCullingCache>();
+        let encoder = render_context.command_encoder();
+        for (slot, &(view, entity, i, _)) in frame.chunks.iter().enumerate() {
+            let Some(culled) = culling_cache
+                .views
+                .get(&view)
+                .and_then(|v| v.chunks.get(&(entity, i)))
+            else {
+                continue;
+            };
+            encoder.copy_buffer_to_buffer(
+                culled.indirect.buffer().unwrap(),
+                INSTANCE_COUNT_OFFSET,
+                &frame.buffer,
+                slot as u64 * COUNT_SIZE,
+                COUNT_SIZE,
+            );
+        }
+        Ok(())
+    }
+}
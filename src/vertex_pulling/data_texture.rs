@@ -0,0 +1,176 @@
+use super::cuboid_cache::InstanceChunk;
+use crate::cuboids::CuboidsTransform;
+
+use bevy::{
+    core::cast_slice,
+    prelude::*,
+    render::{
+        render_resource::{
+            Extent3d, ImageCopyTexture, ImageDataLayout, Origin3d, Texture, TextureAspect,
+            TextureDescriptor, TextureDimension, TextureFormat, TextureUsages, TextureView,
+            TextureViewDescriptor,
+        },
+        renderer::{RenderDevice, RenderQueue},
+    },
+};
+use std::num::NonZeroU32;
+
+/// Width of every data texture, in texels. This is the smallest
+/// `max_texture_dimension_2d` that WebGL2 guarantees.
+pub(crate) const DATA_TEXTURE_WIDTH: u32 = 2048;
+
+pub(crate) const DATA_TEXTURE_FORMAT: TextureFormat = TextureFormat::Rgba32Uint;
+
+/// Texels per instance: the minimum and meta bits, the maximum and color, the
+/// rotation, and the hidden bit and user data.
+pub(crate) const INSTANCE_TEXELS: u32 = 4;
+
+/// Texels per batch transform: both matrices by columns, the interior color
+/// and animation parameter, then the material slots.
+pub(crate) const TRANSFORM_TEXELS: u32 = 10;
+
+type Texel = [u32; 4];
+
+/// Per-instance data in a texture of [`DATA_TEXTURE_FORMAT`], for devices
+/// without storage buffers.
+///
+/// Texels are laid out row by row, [`DATA_TEXTURE_WIDTH`] per row.
+pub(crate) struct DataTexture {
+    texture: Texture,
+    pub view: TextureView,
+    rows: u32,
+}
+
+impl DataTexture {
+    /// The most texels that fit in a single texture on this device.
+    pub fn max_texels(render_device: &RenderDevice) -> u64 {
+        let max_rows = render_device.limits().max_texture_dimension_2d;
+        u64::from(DATA_TEXTURE_WIDTH) * u64::from(max_rows)
+    }
+
+    /// Bytes allocated on the GPU.
+    pub fn size(&self) -> u64 {
+        u64::from(DATA_TEXTURE_WIDTH) * u64::from(self.rows) * 16
+    }
+
+    /// Uploads all of `texels` into `slot`, reusing its texture if it has the
+    /// same number of rows.
+    fn write(
+        slot: &mut Option<Self>,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+        label: &'static str,
+        mut texels: Vec<Texel>,
+    ) {
+        // Empty textures are invalid.
+        let rows = ((texels.len() as u32 + DATA_TEXTURE_WIDTH - 1) / DATA_TEXTURE_WIDTH).max(1);
+        texels.resize((rows * DATA_TEXTURE_WIDTH) as usize, [0; 4]);
+        let size = Extent3d {
+            width: DATA_TEXTURE_WIDTH,
+            height: rows,
+            depth_or_array_layers: 1,
+        };
+
+        if slot.as_ref().map_or(true, |t| t.rows != rows) {
+            let texture = render_device.create_texture(&TextureDescriptor {
+                label: Some(label),
+                size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: DATA_TEXTURE_FORMAT,
+                usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+                view_formats: &[],
+            });
+            *slot = Some(Self {
+                view: texture.create_view(&TextureViewDescriptor::default()),
+                texture,
+                rows,
+            });
+        }
+
+        let texture = &slot.as_ref().unwrap().texture;
+        render_queue.write_texture(
+            ImageCopyTexture {
+                texture,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
+            },
+            cast_slice(&texels),
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(DATA_TEXTURE_WIDTH * 16),
+                rows_per_image: None,
+            },
+            size,
+        );
+    }
+}
+
+impl InstanceChunk {
+    /// Uploads the whole chunk into [`InstanceChunk::data_texture`].
+    ///
+    /// There are no partial writes, rewriting a whole chunk is cheap next to
+    /// the rest of a WebGL2 frame.
+    pub fn write_data_texture(&mut self, render_device: &RenderDevice, render_queue: &RenderQueue) {
+        let cuboids = self.buffer.get();
+        let colors = self.colors.get();
+        let rotations = self.rotations.get();
+        let hidden_mask = self.hidden_mask.get();
+        let user_data = self.user_data.get();
+        let mut texels = Vec::with_capacity(cuboids.len() * INSTANCE_TEXELS as usize);
+        for (i, cuboid) in cuboids.iter().enumerate() {
+            let min = cuboid.minimum.to_array().map(f32::to_bits);
+            let max = cuboid.maximum.to_array().map(f32::to_bits);
+            let rotation = rotations[i.min(rotations.len() - 1)];
+            let hidden = (hidden_mask[i / 32] >> (i % 32)) & 1;
+            let user_data = user_data[i.min(user_data.len() - 1)];
+            texels.push([min[0], min[1], min[2], cuboid.meta_bits]);
+            texels.push([max[0], max[1], max[2], colors[i]]);
+            texels.push(rotation.to_array().map(f32::to_bits));
+            texels.push([hidden, user_data, 0, 0]);
+        }
+        DataTexture::write(
+            &mut self.data_texture,
+            render_device,
+            render_queue,
+            "cuboids_instance_data_texture",
+            texels,
+        );
+    }
+}
+
+/// Uploads all batch transforms into `slot`.
+pub(crate) fn write_transforms_data_texture(
+    slot: &mut Option<DataTexture>,
+    render_device: &RenderDevice,
+    render_queue: &RenderQueue,
+    transforms: &[CuboidsTransform],
+) {
+    let mut texels = Vec::with_capacity(transforms.len() * TRANSFORM_TEXELS as usize);
+    for transform in transforms {
+        for column in transform
+            .matrix
+            .to_cols_array_2d()
+            .into_iter()
+            .chain(transform.inv_matrix.to_cols_array_2d())
+        {
+            texels.push(column.map(f32::to_bits));
+        }
+        texels.push([
+            transform.interior_color,
+            transform.has_interior_color,
+            transform.animation_t.to_bits(),
+            transform.color_layout,
+        ]);
+        texels.push(transform.material_slots.to_array());
+    }
+    DataTexture::write(
+        slot,
+        render_device,
+        render_queue,
+        "cuboids_transforms_data_texture",
+        texels,
+    );
+}
@@ -0,0 +1,59 @@
+// Builds a hierarchical depth buffer. Each texel holds the farthest depth of
+// the texels it covers in the previous level. With reverse Z, that's the
+// minimum.
+
+#ifdef COPY_DEPTH
+#ifdef MULTISAMPLED
+@group(0) @binding(0)
+var source: texture_depth_multisampled_2d;
+#else
+@group(0) @binding(0)
+var source: texture_depth_2d;
+#endif
+#else
+@group(0) @binding(0)
+var source: texture_2d<f32>;
+#endif
+
+@group(0) @binding(1)
+var destination: texture_storage_2d<r32float, write>;
+
+@compute @workgroup_size(8, 8)
+fn downsample(@builtin(global_invocation_id) id: vec3<u32>) {
+    let out_size = vec2<i32>(textureDimensions(destination));
+    let coords = vec2<i32>(id.xy);
+    if (any(coords >= out_size)) {
+        return;
+    }
+
+    #ifdef COPY_DEPTH
+
+    #ifdef MULTISAMPLED
+    var depth = 1.0;
+    for (var s = 0; s < i32(textureNumSamples(source)); s++) {
+        depth = min(depth, textureLoad(source, coords, s));
+    }
+    #else
+    let depth = textureLoad(source, coords, 0);
+    #endif
+
+    #else
+
+    let in_size = vec2<i32>(textureDimensions(source));
+    // Odd sizes need an extra row or column at the edge, so that no texel of
+    // the previous level is skipped.
+    let last = coords == out_size - 1;
+    let odd = (in_size & vec2<i32>(1)) == vec2<i32>(1);
+    let extent = vec2<i32>(1) + vec2<i32>(last & odd);
+    var depth = 1.0;
+    for (var y = 0; y <= extent.y; y++) {
+        for (var x = 0; x <= extent.x; x++) {
+            let c = min(2 * coords + vec2<i32>(x, y), in_size - 1);
+            depth = min(depth, textureLoad(source, c, 0).x);
+        }
+    }
+
+    #endif
+
+    textureStore(destination, coords, vec4<f32>(depth, 0.0, 0.0, 0.0));
+}
@@ -1,4 +1,11 @@
-use super::{cuboid_cache::CuboidBufferCache, index_buffer::CuboidsIndexBuffer};
+use super::{
+    buffers::CuboidsViewUniformOffset,
+    cuboid_cache::{CachedCuboidBuffers, CuboidBufferCache},
+    culling::CuboidsCullingCache,
+    data_texture::DataTexture,
+    index_buffer::{CuboidsIndexBuffer, CUBE_INDICES, CUBE_INDICES_HANDLE, TRANSFORM_INDEX_SHIFT},
+    multi_draw::{DrawCuboidsIndirect, SetMultiDrawAuxBindGroup},
+};
 use bevy::{
     ecs::system::{lifetimeless::*, SystemParamItem},
     prelude::*,
@@ -7,9 +14,10 @@ use bevy::{
         render_phase::{
             PhaseItem, RenderCommand, RenderCommandResult, SetItemPipeline, TrackedRenderPass,
         },
-        render_resource::{BindGroup, IndexFormat},
+        render_resource::BindGroup,
         view::ViewUniformOffset,
     },
+    utils::HashMap,
 };
 
 pub(crate) type DrawCuboids = (
@@ -17,13 +25,40 @@ pub(crate) type DrawCuboids = (
     SetCuboidsViewBindGroup<0>,
     SetAuxBindGroup<1>,
     SetGpuTransformBufferBindGroup<2>,
-    SetGpuCuboidBuffersBindGroup<3>,
-    DrawVertexPulledCuboids,
+    DrawVertexPulledCuboids<3>,
+);
+
+/// Draws the members of a [`CuboidsMultiDrawItem`](super::multi_draw::CuboidsMultiDrawItem)
+/// at once.
+pub(crate) type DrawCuboidsMultiDraw = (
+    SetItemPipeline,
+    SetCuboidsViewBindGroup<0>,
+    SetMultiDrawAuxBindGroup<1>,
+    SetGpuTransformBufferBindGroup<2>,
+    DrawCuboidsIndirect<3>,
+);
+
+pub(crate) type DrawCuboidsPrepass = (
+    SetItemPipeline,
+    SetCuboidsViewBindGroup<0>,
+    SetAuxBindGroup<1>,
+    SetGpuTransformBufferBindGroup<2>,
+    DrawUnculledCuboids<3>,
 );
 
+#[cfg(feature = "shadows")]
+pub(crate) type DrawCuboidShadows = (
+    SetItemPipeline,
+    SetCuboidsViewBindGroup<0>,
+    SetAuxBindGroup<1>,
+    SetGpuTransformBufferBindGroup<2>,
+    DrawUnculledCuboids<3>,
+);
+
+/// Holds the bind group of each view, keyed by the view entity.
 #[derive(Default, Resource)]
 pub struct ViewMeta {
-    pub cuboids_view_bind_group: Option<BindGroup>,
+    pub view_bind_groups: HashMap<Entity, BindGroup>,
 }
 
 pub(crate) struct SetCuboidsViewBindGroup<const I: usize>;
@@ -31,23 +66,30 @@ pub(crate) struct SetCuboidsViewBindGroup<const I: usize>;
 impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetCuboidsViewBindGroup<I> {
     type Param = SRes<ViewMeta>;
     type ItemWorldQuery = ();
-    type ViewWorldQuery = Read<ViewUniformOffset>;
+    type ViewWorldQuery = (
+        Entity,
+        Read<ViewUniformOffset>,
+        Read<CuboidsViewUniformOffset>,
+    );
     #[inline]
     fn render<'w>(
         _item: &P,
-        view_uniform_offset: &'_ ViewUniformOffset,
+        (view, view_uniform_offset, cuboids_view_uniform_offset): (
+            Entity,
+            &'_ ViewUniformOffset,
+            &'_ CuboidsViewUniformOffset,
+        ),
         _entity: (),
         view_meta: SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
+        let Some(bind_group) = view_meta.into_inner().view_bind_groups.get(&view) else {
+            return RenderCommandResult::Failure;
+        };
         pass.set_bind_group(
             I,
-            view_meta
-                .into_inner()
-                .cuboids_view_bind_group
-                .as_ref()
-                .unwrap(),
-            &[view_uniform_offset.offset],
+            bind_group,
+            &[view_uniform_offset.offset, cuboids_view_uniform_offset.0],
         );
         RenderCommandResult::Success
     }
@@ -89,61 +131,98 @@ impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetAuxBindGroup<I> {
 #[derive(Default, Resource)]
 pub struct TransformsMeta {
     pub transform_buffer_bind_group: Option<BindGroup>,
+    /// The transforms on devices without storage buffers, see
+    /// [`CuboidsShaderDefs::data_textures`](super::pipeline::CuboidsShaderDefs).
+    pub(crate) data_texture: Option<DataTexture>,
 }
 
 pub(crate) struct SetGpuTransformBufferBindGroup<const I: usize>;
 
 impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetGpuTransformBufferBindGroup<I> {
-    type Param = (SRes<CuboidBufferCache>, SRes<TransformsMeta>);
-    type ItemWorldQuery = Entity;
+    type Param = SRes<TransformsMeta>;
+    type ItemWorldQuery = ();
     type ViewWorldQuery = ();
 
     #[inline]
     fn render<'w>(
         _item: &P,
         _view: (),
-        entity: Entity,
-        (buffer_cache, transforms_meta): SystemParamItem<'w, '_, Self::Param>,
+        _entity: (),
+        transforms_meta: SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        let transforms_meta = transforms_meta.into_inner();
-        let entry = buffer_cache.into_inner().entries.get(&entity).unwrap();
-        pass.set_bind_group(
-            I,
-            transforms_meta
-                .transform_buffer_bind_group
-                .as_ref()
-                .unwrap(),
-            &[entry.transform_index],
-        );
+        // Missing when the transforms don't fit on the device, see
+        // `CuboidsError::BufferTooLarge`.
+        let Some(bind_group) = transforms_meta
+            .into_inner()
+            .transform_buffer_bind_group
+            .as_ref()
+        else {
+            return RenderCommandResult::Failure;
+        };
+        // The transform is selected per draw with the base vertex, so this bind
+        // group is the same for every batch. The render pass skips redundant
+        // calls.
+        pass.set_bind_group(I, bind_group, &[]);
         RenderCommandResult::Success
     }
 }
 
-pub(crate) struct SetGpuCuboidBuffersBindGroup<const I: usize>;
+/// Binds each instance chunk of the batch at group `I` and draws it.
+///
+/// With GPU culling, each chunk is drawn indirectly with the instances that
+/// passed the culling pass for this view.
+pub(crate) struct DrawVertexPulledCuboids<const I: usize>;
 
-impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetGpuCuboidBuffersBindGroup<I> {
-    type Param = SRes<CuboidBufferCache>;
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for DrawVertexPulledCuboids<I> {
+    type Param = (
+        SRes<CuboidBufferCache>,
+        SRes<RenderAssets<CuboidsIndexBuffer>>,
+        Option<SRes<CuboidsCullingCache>>,
+    );
     type ItemWorldQuery = Entity;
-    type ViewWorldQuery = ();
+    type ViewWorldQuery = Entity;
 
     #[inline]
     fn render<'w>(
         _item: &P,
-        _view: (),
+        view: Entity,
         entity: Entity,
-        buffer_cache: SystemParamItem<'w, '_, Self::Param>,
+        (buffer_cache, index_buffers, culling_cache): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
         let entry = buffer_cache.into_inner().entries.get(&entity).unwrap();
-        pass.set_bind_group(I, entry.instance_buffer_bind_group.as_ref().unwrap(), &[]);
+        let index_buffer = index_buffers
+            .into_inner()
+            .get(&CUBE_INDICES_HANDLE.typed())
+            .unwrap();
+        pass.set_index_buffer(index_buffer.buffer.slice(..), 0, index_buffer.index_format);
+
+        if let Some(culling_cache) = culling_cache {
+            let Some(view_culling) = culling_cache.into_inner().views.get(&view) else {
+                return RenderCommandResult::Failure;
+            };
+            for i in 0..entry.current().chunks.len() {
+                let Some(culled) = view_culling.chunks.get(&(entity, i)) else {
+                    return RenderCommandResult::Failure;
+                };
+                // The base vertex is part of the indirect arguments.
+                pass.set_bind_group(I, culled.draw_bind_group.as_ref().unwrap(), &[]);
+                pass.draw_indexed_indirect(culled.indirect.buffer().unwrap(), 0);
+            }
+            return RenderCommandResult::Success;
+        }
+
+        draw_unculled::<I>(entry, pass);
         RenderCommandResult::Success
     }
 }
 
-pub(crate) struct DrawVertexPulledCuboids;
+/// Like [`DrawVertexPulledCuboids`], but always draws every instance, for
+/// passes that don't run the culling pass.
+pub(crate) struct DrawUnculledCuboids<const I: usize>;
 
-impl<P: PhaseItem> RenderCommand<P> for DrawVertexPulledCuboids {
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for DrawUnculledCuboids<I> {
     type Param = (
         SRes<CuboidBufferCache>,
         SRes<RenderAssets<CuboidsIndexBuffer>>,
@@ -159,15 +238,27 @@ impl<P: PhaseItem> RenderCommand<P> for DrawVertexPulledCuboids {
         (buffer_cache, index_buffers): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        use super::index_buffer::{CUBE_INDICES, CUBE_INDICES_HANDLE};
         let entry = buffer_cache.into_inner().entries.get(&entity).unwrap();
-        let num_cuboids = entry.instance_buffer.get().len().try_into().unwrap();
         let index_buffer = index_buffers
             .into_inner()
             .get(&CUBE_INDICES_HANDLE.typed())
             .unwrap();
-        pass.set_index_buffer(index_buffer.slice(..), 0, IndexFormat::Uint32);
-        pass.draw_indexed(0..(CUBE_INDICES.len() as u32), 0, 0..num_cuboids);
+        pass.set_index_buffer(index_buffer.buffer.slice(..), 0, index_buffer.index_format);
+        draw_unculled::<I>(entry, pass);
         RenderCommandResult::Success
     }
 }
+
+fn draw_unculled<'w, const I: usize>(
+    entry: &'w CachedCuboidBuffers,
+    pass: &mut TrackedRenderPass<'w>,
+) {
+    // Cube indices only use the low 5 bits of the vertex index, so we pass
+    // the transform index in the remaining bits.
+    let base_vertex = (entry.transform_index << TRANSFORM_INDEX_SHIFT) as i32;
+    for chunk in entry.current().chunks.iter() {
+        let num_cuboids = chunk.len.try_into().unwrap();
+        pass.set_bind_group(I, chunk.bind_group.as_ref().unwrap(), &[]);
+        pass.draw_indexed(0..(CUBE_INDICES.len() as u32), base_vertex, 0..num_cuboids);
+    }
+}
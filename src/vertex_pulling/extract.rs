@@ -2,48 +2,93 @@ use super::buffers::*;
 use super::cuboid_cache::CuboidBufferCache;
 use crate::clipping_planes::*;
 use crate::cuboids::*;
+use crate::material::{InternalCuboidMaterials, FIRST_INTERNAL_MATERIAL_ID};
+use crate::selection::CuboidSelectionHighlight;
+use crate::transform_interpolation::CuboidsTransformInterpolation;
 use crate::CuboidMaterialId;
 use crate::CuboidMaterialMap;
+use crate::CuboidMaterialSlots;
+use crate::{CuboidHighlightStyle, CuboidSelection};
+use crate::{CuboidsError, CuboidsErrors};
+use crate::{DEPTH_MODE_ALWAYS_ON_TOP, DEPTH_MODE_XRAY};
 
-use bevy::{prelude::*, render::Extract};
+use bevy::{prelude::*, render::Extract, tasks::ComputeTaskPool, utils::HashMap};
 
+#[allow(clippy::too_many_arguments)]
 #[allow(clippy::type_complexity)]
 pub(crate) fn extract_cuboids(
     mut prev_extracted_entities_size: Local<usize>,
     mut commands: Commands,
     cuboids: Extract<
-        Query<(
-            Entity,
-            &Cuboids,
-            &GlobalTransform,
-            &CuboidMaterialId,
-            Option<&ComputedVisibility>,
-            Or<(Added<Cuboids>, Changed<Cuboids>)>,
-        )>,
+        Query<
+            (
+                Entity,
+                &Cuboids,
+                &GlobalTransform,
+                &CuboidMaterialId,
+                Option<&ComputedVisibility>,
+                Option<&CuboidsOccluder>,
+                Option<&CuboidsInteriorColor>,
+                Option<&CuboidMaterialSlots>,
+                Option<&CuboidsDirty>,
+                Option<&CuboidSelectionHighlight>,
+                Option<&CuboidsTransformInterpolation>,
+                Option<&ClippingPlaneGizmo>,
+                Or<(Added<Cuboids>, Changed<Cuboids>)>,
+            ),
+            Without<CuboidsInvalid>,
+        >,
     >,
     materials: Extract<Res<CuboidMaterialMap>>,
+    internal_materials: Extract<Res<InternalCuboidMaterials>>,
+    selection: Extract<Res<CuboidSelection>>,
     mut materials_uniforms: ResMut<DynamicUniformBufferOfCuboidMaterial>,
+    mut material_indices: ResMut<CuboidMaterialIndices>,
+    mut slotted_materials: ResMut<UniformBufferOfGpuSlottedMaterials>,
     mut cuboid_buffers: ResMut<CuboidBufferCache>,
-    mut transform_uniforms: ResMut<DynamicUniformBufferOfCuboidTransforms>,
+    mut transforms: ResMut<StorageBufferOfCuboidTransforms>,
+    errors: Res<CuboidsErrors>,
+    uploads: Res<CuboidsUploads>,
 ) {
-    transform_uniforms.clear();
+    transforms.get_mut().clear();
+    cuboid_buffers.frame += 1;
 
     if materials.is_empty() {
-        warn!("Cannot draw Cuboids with empty CuboidMaterialMap");
+        errors.send(CuboidsError::EmptyMaterialMap);
         return;
     }
 
     // First extract material so we can assign dynamic uniform indices to
     // cuboids.
-    let materials_indices = materials.write_uniforms(&mut materials_uniforms);
+    material_indices.0 = materials.write_uniforms(&mut materials_uniforms);
+    let materials_indices = &material_indices.0;
+    let internal_indices = internal_materials.write_uniforms(&mut materials_uniforms);
+    let material_of = |id: CuboidMaterialId| match internal_materials.get(id) {
+        Some(material) => Some((
+            material,
+            internal_indices[id.0 - FIRST_INTERNAL_MATERIAL_ID],
+        )),
+        None => materials_indices
+            .get(id.0)
+            .map(|&index| (materials.get(id), index)),
+    };
+    slotted_materials.set(materials.slotted_materials());
 
     let mut extracted_entities = Vec::with_capacity(*prev_extracted_entities_size);
+    let mut full_uploads: HashMap<Entity, &Cuboids> = HashMap::default();
     for (
         entity,
         cuboids,
         transform,
         materials_id,
         maybe_visibility,
+        maybe_occluder,
+        maybe_interior_color,
+        maybe_material_slots,
+        maybe_dirty,
+        maybe_highlight,
+        maybe_interpolation,
+        maybe_gizmo,
         instance_buffer_needs_update,
     ) in cuboids.iter()
     {
@@ -53,56 +98,295 @@ pub(crate) fn extract_cuboids(
         if cuboids.instances.is_empty() {
             continue;
         }
+        let Some((material, material_index)) = material_of(*materials_id) else {
+            errors.send(CuboidsError::InvalidMaterialId {
+                entity,
+                id: materials_id.0,
+            });
+            uploads.send_buffer_event(CuboidsBufferEvent::Failed {
+                entity,
+                reason: CuboidsBufferFailure::InvalidMaterialId { id: materials_id.0 },
+            });
+            continue;
+        };
+
+        let material_slots = match maybe_material_slots.map(|s| s.pack(&materials)) {
+            Some(Ok(packed)) => packed,
+            Some(Err(id)) => {
+                errors.send(CuboidsError::InvalidMaterialId { entity, id });
+                uploads.send_buffer_event(CuboidsBufferEvent::Failed {
+                    entity,
+                    reason: CuboidsBufferFailure::InvalidMaterialId { id },
+                });
+                continue;
+            }
+            None => UVec4::ZERO,
+        };
 
         extracted_entities.push((entity, ()));
 
-        let transform = CuboidsTransform::from_matrix(transform.compute_matrix());
+        // Transform changes alone never upload the instances again.
+        let matrix = maybe_interpolation
+            .and_then(|i| i.matrix)
+            .unwrap_or_else(|| transform.compute_matrix());
+        let mut transform =
+            CuboidsTransform::from_matrix(matrix).with_interior_color(maybe_interior_color);
+        transform.material_slots = material_slots;
+        transform.color_layout = !cuboids.face_colors.is_empty() as u32
+            | (!cuboids.atlas_tiles.is_empty() as u32) << 1
+            | (!cuboids.spawn_times.is_empty() as u32) << 3;
 
         let is_visible = maybe_visibility
             .map(ComputedVisibility::is_visible)
             .unwrap_or(true);
 
-        let entry = cuboid_buffers.entries.entry(entity).or_default();
-        if instance_buffer_needs_update {
-            entry.instance_buffer.set(cuboids.instances.clone());
+        let max_chunk_instances = cuboid_buffers.max_chunk_instances;
+        let frame = cuboid_buffers.frame;
+        let entry = cuboid_buffers.get_or_insert(entity, cuboids.instances.len());
+        // Evicted batches are uploaded again from scratch, but only once they
+        // are visible.
+        let instance_buffer_needs_update = if entry.evicted {
+            is_visible
+        } else {
+            instance_buffer_needs_update || maybe_dirty.is_some() || entry.needs_first_upload()
+        };
+        // Edits that were tracked by `Cuboids` are rewritten in place, and
+        // appended instances are written into spare room if they fit. Changes
+        // behind `CuboidsDirty` weren't tracked.
+        let partial_update = instance_buffer_needs_update
+            && maybe_dirty.is_none()
+            && cuboids.edits.is_partial()
+            && !entry.streaming
+            && !entry.upload_deferred
+            && entry.on_gpu
+            && entry.matches_layout(cuboids)
+            && entry.current().is_ready()
+            && entry.fits_quantization(cuboids)
+            && entry.append_instances(cuboids, max_chunk_instances);
+        if partial_update {
+            if cuboids.edits.visibility {
+                entry.set_hidden_mask(cuboids, max_chunk_instances);
+                entry.visibility_dirty = true;
+            }
+            entry.set_instance_ranges(cuboids, max_chunk_instances);
+            entry.set_color_ranges(cuboids, max_chunk_instances);
+        }
+        let full_update = instance_buffer_needs_update && !partial_update;
+        if full_update {
+            // Packed after all batches, on all cores.
+            full_uploads.insert(entity, cuboids);
+        }
+        entry.material_index = material_index.0;
+        entry.material_id = materials_id.0;
+        entry.dirty = full_update;
+        // Evicted batches are uploaded again below.
+        entry.enabled = is_visible && (!entry.evicted || full_update);
+        entry.num_instances = cuboids.instances.len();
+        entry.frustum_culled = !is_visible
+            && maybe_visibility.map_or(false, ComputedVisibility::is_visible_in_hierarchy);
+        if entry.enabled {
+            entry.last_drawn_frame = frame;
+        }
+        entry.occluder = maybe_occluder.is_some();
+        entry.contour_mask =
+            maybe_highlight.is_some() && selection.style == CuboidHighlightStyle::Contour;
+        entry.gizmo = maybe_gizmo.is_some();
+        entry.transparent = !entry.occluder && material.alpha_blend != 0;
+        entry.casts_shadows = !entry.occluder && !entry.contour_mask && material.cast_shadows != 0;
+        entry.depth_mode = material.depth_mode;
+        entry.draw_phase = material.draw_phase;
+        entry.sort_key_offset = material.sort_key_offset;
+        if matches!(
+            material.depth_mode,
+            DEPTH_MODE_ALWAYS_ON_TOP | DEPTH_MODE_XRAY
+        ) {
+            transform.color_layout |= 1 << 2;
         }
-        entry.material_index = materials_indices[materials_id.0].0;
-        entry.dirty = instance_buffer_needs_update;
-        entry.enabled = is_visible;
         entry.keep_alive = true;
         entry.position = transform.position();
-        entry.transform_index = transform_uniforms.push(transform);
+        entry.transform_index = transforms.get().len().try_into().unwrap();
+        if !full_update {
+            if let Some(quantization) = entry.quantization() {
+                transform.quantization_min = quantization.minimum;
+                transform.quantization_step = quantization.step();
+            }
+        }
+        transforms.get_mut().push(transform);
+    }
+
+    // Packing dominates the extraction of large batches that changed, so
+    // batches are packed in parallel, and so are the chunks of each batch.
+    let max_chunk_instances = cuboid_buffers.max_chunk_instances;
+    let streaming = cuboid_buffers.streaming;
+    let growth_factor = cuboid_buffers.growth_factor;
+    let instance_format = cuboid_buffers.instance_format;
+    let buffering = cuboid_buffers.buffering;
+    let frame = cuboid_buffers.frame;
+    ComputeTaskPool::get().scope(|scope| {
+        for (entity, entry) in cuboid_buffers.entries.iter_mut() {
+            let Some(&cuboids) = full_uploads.get(entity) else {
+                continue;
+            };
+            scope.spawn(async move {
+                entry.set_instances(
+                    cuboids,
+                    max_chunk_instances,
+                    growth_factor,
+                    instance_format,
+                    buffering,
+                    frame,
+                );
+                // Dynamic batches would never finish streaming.
+                entry.streaming = streaming
+                    && entry.instance_buffers.len() == 1
+                    && entry.current().chunks.len() > 1;
+                entry.streamed_chunks = 0;
+            });
+        }
+    });
+    for entity in full_uploads.keys() {
+        let entry = &cuboid_buffers.entries[entity];
+        if let Some(quantization) = entry.quantization() {
+            let transform = &mut transforms.get_mut()[entry.transform_index as usize];
+            transform.quantization_min = quantization.minimum;
+            transform.quantization_step = quantization.step();
+        }
     }
 
     *prev_extracted_entities_size = extracted_entities.len();
     commands.insert_or_spawn_batch(extracted_entities);
 
     cuboid_buffers.cull_entities();
+    for (entity, bytes) in cuboid_buffers.evict_to_budget() {
+        uploads.send_buffer_event(CuboidsBufferEvent::Evicted { entity, bytes });
+    }
 }
 
 pub(crate) fn extract_clipping_planes(
-    clipping_planes: Extract<Query<(&ClippingPlaneRange, &GlobalTransform)>>,
-    mut clipping_plane_uniform: ResMut<UniformBufferOfGpuClippingPlaneRanges>,
+    clipping_planes: Extract<
+        Query<(
+            &ClippingPlaneRange,
+            &GlobalTransform,
+            Option<&ClippingPlaneTarget>,
+        )>,
+    >,
+    mut view_clipping_planes: ResMut<ViewClippingPlanes>,
 ) {
-    let mut iter = clipping_planes.iter();
-    let mut gpu_planes = GpuClippingPlaneRanges::default();
-    for (range, transform) in iter.by_ref() {
+    let view_clipping_planes = view_clipping_planes.as_mut();
+    view_clipping_planes.shared.clear();
+    view_clipping_planes.targeted.clear();
+    for (range, transform, maybe_target) in clipping_planes.iter() {
         let (_, rotation, translation) = transform.to_scale_rotation_translation();
-        gpu_planes.ranges[gpu_planes.num_ranges as usize] = GpuClippingPlaneRange {
+        let gpu_plane = GpuClippingPlaneRange {
             origin: translation,
             unit_normal: rotation * Vec3::X,
             min_sdist: range.min_sdist,
             max_sdist: range.max_sdist,
+            keep_outside: range.keep_outside as u32,
         };
-        gpu_planes.num_ranges += 1;
-        if gpu_planes.num_ranges as usize == MAX_CLIPPING_PLANES {
-            break;
+        match maybe_target {
+            Some(target) => view_clipping_planes
+                .targeted
+                .entry(target.0)
+                .or_default()
+                .push(gpu_plane),
+            None => view_clipping_planes.shared.push(gpu_plane),
         }
     }
-    if iter.next().is_some() {
-        warn!(
-            "Too many GpuClippingPlaneRanges entities, at most {MAX_CLIPPING_PLANES} are supported"
+}
+
+pub(crate) fn extract_clipping_volumes(
+    boxes: Extract<Query<(&ClippingBox, &GlobalTransform)>>,
+    spheres: Extract<Query<(&ClippingSphere, &GlobalTransform)>>,
+    mut clipping_volume_uniform: ResMut<UniformBufferOfGpuClippingVolumes>,
+    errors: Res<CuboidsErrors>,
+) {
+    let mut iter = boxes
+        .iter()
+        .map(|(b, t)| GpuClippingVolume::new_box(b, t))
+        .chain(
+            spheres
+                .iter()
+                .map(|(s, t)| GpuClippingVolume::new_sphere(s, t)),
         );
+    let mut gpu_volumes = GpuClippingVolumes::default();
+    for volume in iter.by_ref().take(MAX_CLIPPING_VOLUMES) {
+        gpu_volumes.volumes[gpu_volumes.num_volumes as usize] = volume;
+        gpu_volumes.num_volumes += 1;
     }
-    clipping_plane_uniform.set(gpu_planes);
+    if iter.next().is_some() {
+        errors.send(CuboidsError::TooManyClippingVolumes {
+            count: boxes.iter().count() + spheres.iter().count(),
+        });
+    }
+    clipping_volume_uniform.set(gpu_volumes);
+}
+
+pub(crate) fn extract_view_clipping(
+    mut commands: Commands,
+    views: Extract<Query<(Entity, &ViewClipping), With<Camera>>>,
+) {
+    let mut extracted = Vec::new();
+    for (entity, clipping) in views.iter() {
+        extracted.push((entity, clipping.clone()));
+    }
+    commands.insert_or_spawn_batch(extracted);
+}
+
+#[cfg(feature = "fog")]
+pub(crate) fn extract_cuboids_fog(
+    mut commands: Commands,
+    views: Extract<Query<(Entity, &bevy::pbr::FogSettings), With<Camera>>>,
+) {
+    let mut extracted = Vec::new();
+    for (entity, fog) in views.iter() {
+        extracted.push((entity, crate::fog::GpuCuboidsFog::new(fog)));
+    }
+    commands.insert_or_spawn_batch(extracted);
+}
+
+#[cfg(feature = "color_keyframes")]
+pub(crate) fn extract_color_keyframes(
+    keyframes: Extract<Res<crate::ColorKeyframes>>,
+    mut buffers: ResMut<ColorKeyframeBuffers>,
+) {
+    buffers
+        .header
+        .set(crate::color_keyframes::GpuColorKeyframesHeader {
+            num_keyframes: keyframes.num_keyframes() as u32,
+            time: keyframes.time,
+        });
+
+    // Only copy the table when it changes, so scrubbing `time` stays cheap.
+    if buffers.table_revision != Some(keyframes.revision()) {
+        let mut colors = keyframes.colors().to_vec();
+        if colors.is_empty() {
+            // Storage bindings can't be empty.
+            colors.push(0);
+        }
+        buffers.table.set(colors);
+        buffers.table_revision = Some(keyframes.revision());
+        buffers.table_dirty = true;
+    }
+}
+
+#[cfg(feature = "lighting")]
+pub(crate) fn extract_cuboid_lights(
+    ambient_light: Extract<Option<Res<bevy::pbr::AmbientLight>>>,
+    directional_lights: Extract<
+        Query<(
+            &bevy::pbr::DirectionalLight,
+            &GlobalTransform,
+            &ComputedVisibility,
+        )>,
+    >,
+    mut lights_uniform: ResMut<UniformBufferOfGpuCuboidLights>,
+) {
+    use crate::lighting::GpuCuboidLights;
+
+    let directional = directional_lights
+        .iter()
+        .filter(|(_, _, visibility)| visibility.is_visible())
+        .map(|(light, transform, _)| (light, transform));
+    lights_uniform.set(GpuCuboidLights::new(ambient_light.as_deref(), directional));
 }
@@ -1,3 +1,5 @@
+use std::ops::{Deref, Range};
+
 use bevy::{
     core::cast_slice,
     ecs::system::lifetimeless::SRes,
@@ -5,21 +7,102 @@ use bevy::{
     reflect::TypeUuid,
     render::{
         render_asset::RenderAsset,
-        render_resource::{Buffer, BufferInitDescriptor, BufferUsages},
+        render_resource::{Buffer, BufferInitDescriptor, BufferUsages, IndexFormat},
         renderer::RenderDevice,
     },
 };
 
-#[derive(Default, TypeUuid)]
+/// The index buffer shared by all cuboid draws.
+///
+/// It always starts with the cuboid indices, but custom primitives can append
+/// their own index templates with [`CuboidsIndexBuffer::register_template`].
+/// The asset lives at [`CUBE_INDICES_HANDLE`] for the lifetime of the app.
+///
+/// The buffer only holds index templates, never per-instance indices: every
+/// instance reuses the same indices, and the batch transform is passed in the
+/// base vertex. Its size doesn't depend on the batches, so there is nothing to
+/// shrink, and instance counts are limited by the chunks of each batch instead.
+/// While every index fits, it is uploaded with 16-bit indices, see
+/// [`CuboidsIndexBuffer::index_format`].
+#[derive(Clone, TypeUuid)]
 #[uuid = "8f6d78a6-fffe-4e54-81db-08b0739a947a"]
-pub struct CuboidsIndexBuffer;
+pub struct CuboidsIndexBuffer {
+    indices: Vec<u32>,
+}
+
+impl Default for CuboidsIndexBuffer {
+    fn default() -> Self {
+        Self {
+            indices: CUBE_INDICES.to_vec(),
+        }
+    }
+}
+
+impl CuboidsIndexBuffer {
+    /// Appends `indices` to the shared buffer and returns the range they
+    /// occupy, suitable for `TrackedRenderPass::draw_indexed`.
+    ///
+    /// Templates are never removed, so returned ranges stay valid forever.
+    /// However, each registration causes the entire GPU buffer to be
+    /// reallocated and re-uploaded, so register all templates up front.
+    ///
+    /// The cuboid shader reserves vertex index bits starting at
+    /// [`TRANSFORM_INDEX_SHIFT`] for the batch transform, so templates drawn
+    /// with that convention must keep their indices below `1 <<
+    /// TRANSFORM_INDEX_SHIFT`.
+    pub fn register_template(&mut self, indices: &[u32]) -> Range<u32> {
+        let start = self.indices.len() as u32;
+        self.indices.extend_from_slice(indices);
+        start..self.indices.len() as u32
+    }
+
+    /// All indices in the buffer, starting with [`CUBE_INDICES`].
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
+    }
 
-pub(crate) const CUBE_INDICES_HANDLE: HandleUntyped =
+    /// [`IndexFormat::Uint16`] unless a registered template has an index above
+    /// `u16::MAX`. The base vertex is added after the index is read, so the
+    /// transform index of each draw isn't limited by the format.
+    pub fn index_format(&self) -> IndexFormat {
+        if self.indices.iter().all(|&i| i <= u16::MAX as u32) {
+            IndexFormat::Uint16
+        } else {
+            IndexFormat::Uint32
+        }
+    }
+}
+
+/// The prepared [`CuboidsIndexBuffer`].
+///
+/// This used to be a bare [`Buffer`], which it still derefs to, but custom
+/// draws must now bind it with `index_format` rather than
+/// [`IndexFormat::Uint32`].
+pub struct GpuCuboidsIndexBuffer {
+    pub buffer: Buffer,
+    /// [`CuboidsIndexBuffer::index_format`], needed to bind `buffer`.
+    pub index_format: IndexFormat,
+}
+
+impl Deref for GpuCuboidsIndexBuffer {
+    type Target = Buffer;
+
+    fn deref(&self) -> &Buffer {
+        &self.buffer
+    }
+}
+
+/// Handle of the [`CuboidsIndexBuffer`] asset. The prepared
+/// [`GpuCuboidsIndexBuffer`] holds the format to bind it with.
+pub const CUBE_INDICES_HANDLE: HandleUntyped =
     HandleUntyped::weak_from_u64(CuboidsIndexBuffer::TYPE_UUID, 17343092250772987267);
 
 // Only 3 faces are actually drawn.
 const NUM_CUBE_INDICES_USIZE: usize = 3 * 3 * 2;
 
+/// Bits of the vertex index above this shift hold the batch's transform index.
+pub const TRANSFORM_INDEX_SHIFT: u32 = 5;
+
 /// The indices for all triangles in a cuboid mesh (given 8 corner
 /// vertices).
 ///
@@ -27,7 +110,7 @@ const NUM_CUBE_INDICES_USIZE: usize = 3 * 3 * 2;
 /// to indicate which of the 3 faces is being rendered.
 #[rustfmt::skip]
 #[allow(clippy::unusual_byte_groupings)]
-pub(crate) const CUBE_INDICES: [u32; NUM_CUBE_INDICES_USIZE] = [
+pub const CUBE_INDICES: [u32; NUM_CUBE_INDICES_USIZE] = [
     0b00_000, 0b00_010, 0b00_001, 0b00_010, 0b00_011, 0b00_001, // face XY (0)
     0b01_101, 0b01_100, 0b01_001, 0b01_001, 0b01_100, 0b01_000, // face XZ (1)
     0b10_000, 0b10_100, 0b10_110, 0b10_000, 0b10_110, 0b10_010, // face YZ (2)
@@ -36,26 +119,38 @@ pub(crate) const CUBE_INDICES: [u32; NUM_CUBE_INDICES_USIZE] = [
 impl RenderAsset for CuboidsIndexBuffer {
     type ExtractedAsset = Self;
 
-    type PreparedAsset = Buffer;
+    type PreparedAsset = GpuCuboidsIndexBuffer;
 
     type Param = SRes<RenderDevice>;
 
     fn extract_asset(&self) -> Self::ExtractedAsset {
-        Self
+        self.clone()
     }
 
     fn prepare_asset(
-        _extracted_asset: Self::ExtractedAsset,
+        extracted_asset: Self::ExtractedAsset,
         render_device: &mut bevy::ecs::system::SystemParamItem<Self::Param>,
     ) -> Result<
         Self::PreparedAsset,
         bevy::render::render_asset::PrepareAssetError<Self::ExtractedAsset>,
     > {
+        let index_format = extracted_asset.index_format();
+        let indices_u16: Vec<u16>;
+        let contents = match index_format {
+            IndexFormat::Uint16 => {
+                indices_u16 = extracted_asset.indices.iter().map(|&i| i as u16).collect();
+                cast_slice(indices_u16.as_slice())
+            }
+            IndexFormat::Uint32 => cast_slice(extracted_asset.indices.as_slice()),
+        };
         let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
             usage: BufferUsages::INDEX,
             label: Some("Cuboid Index Buffer"),
-            contents: cast_slice(CUBE_INDICES.as_slice()),
+            contents,
         });
-        Ok(buffer)
+        Ok(GpuCuboidsIndexBuffer {
+            buffer,
+            index_format,
+        })
     }
 }
@@ -0,0 +1,395 @@
+use super::cuboid_cache::{CachedCuboidBuffers, CuboidBufferCache};
+use super::culling::GpuDrawIndexedIndirect;
+use super::draw::AuxiliaryMeta;
+use super::index_buffer::{
+    CuboidsIndexBuffer, CUBE_INDICES, CUBE_INDICES_HANDLE, TRANSFORM_INDEX_SHIFT,
+};
+use super::pipeline::CuboidsPipelines;
+use crate::{
+    Cuboid, CuboidsInstanceFormat, DEPTH_MODE_DEFAULT, DRAW_PHASE_AUTO, DRAW_PHASE_OPAQUE,
+};
+
+use bevy::{
+    ecs::system::{lifetimeless::*, SystemParamItem},
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_phase::{PhaseItem, RenderCommand, RenderCommandResult, TrackedRenderPass},
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, Buffer, BufferUsages, ShaderType,
+            StorageBuffer,
+        },
+        renderer::{RenderDevice, RenderQueue},
+    },
+    utils::HashMap,
+};
+
+/// Instances of static opaque batches that fit in a single chunk, copied into
+/// shared buffers, so that all of them are drawn with one indirect draw per
+/// pipeline and material in each view.
+///
+/// The copies are rebuilt whenever a member changes or members are added or
+/// removed, so this only pays off for batches that rarely change. The batches
+/// keep their own buffers for the passes that still draw them one by one.
+#[derive(Resource)]
+pub(crate) struct CuboidsMultiDraw {
+    /// The first instance of each member in the shared buffers, a multiple of
+    /// 32 so that hidden masks can be copied word by word.
+    pub members: HashMap<Entity, u32>,
+    /// The members in the order they were copied.
+    order: Vec<Entity>,
+    instances: StorageBuffer<Vec<Cuboid>>,
+    packed: StorageBuffer<Vec<UVec4>>,
+    colors: StorageBuffer<Vec<u32>>,
+    rotations: StorageBuffer<Vec<Vec4>>,
+    user_data: StorageBuffer<Vec<u32>>,
+    hidden_mask: StorageBuffer<Vec<u32>>,
+    bind_group: Option<BindGroup>,
+    /// The draw arguments of every member drawn by any view this frame.
+    indirect: StorageBuffer<Vec<GpuDrawIndexedIndirect>>,
+}
+
+impl Default for CuboidsMultiDraw {
+    fn default() -> Self {
+        let mut indirect = StorageBuffer::default();
+        indirect.add_usages(BufferUsages::INDIRECT);
+        Self {
+            members: default(),
+            order: Vec::new(),
+            instances: default(),
+            packed: default(),
+            colors: default(),
+            rotations: default(),
+            user_data: default(),
+            hidden_mask: default(),
+            bind_group: None,
+            indirect,
+        }
+    }
+}
+
+/// One merged draw of a view, queued in place of its members.
+#[derive(Component)]
+pub(crate) struct CuboidsMultiDrawItem {
+    pub material_index: u32,
+    /// The range of [`CuboidsMultiDraw::indirect`] to draw.
+    pub first_draw: u32,
+    pub draw_count: u32,
+}
+
+impl CuboidsMultiDraw {
+    /// Starts collecting the draws of this frame.
+    pub fn clear_draws(&mut self) {
+        self.indirect.get_mut().clear();
+    }
+
+    /// The draw arguments of a member, or `None` for batches that are drawn on
+    /// their own.
+    pub fn draw(
+        &self,
+        entity: Entity,
+        entry: &CachedCuboidBuffers,
+    ) -> Option<GpuDrawIndexedIndirect> {
+        let &first_instance = self.members.get(&entity)?;
+        Some(GpuDrawIndexedIndirect {
+            index_count: CUBE_INDICES.len() as u32,
+            instance_count: entry.current().chunks[0].len as u32,
+            first_index: 0,
+            base_vertex: (entry.transform_index << TRANSFORM_INDEX_SHIFT) as i32,
+            first_instance,
+        })
+    }
+
+    /// Adds the draws of one merged draw, drawn with `material_index`.
+    pub fn push_draws(
+        &mut self,
+        material_index: u32,
+        draws: impl IntoIterator<Item = GpuDrawIndexedIndirect>,
+    ) -> CuboidsMultiDrawItem {
+        let indirect = self.indirect.get_mut();
+        let first_draw = indirect.len();
+        indirect.extend(draws);
+        CuboidsMultiDrawItem {
+            material_index,
+            first_draw: first_draw as u32,
+            draw_count: (indirect.len() - first_draw) as u32,
+        }
+    }
+
+    pub fn write_draws(&mut self, render_device: &RenderDevice, render_queue: &RenderQueue) {
+        if !self.indirect.get().is_empty() {
+            self.indirect.write_buffer(render_device, render_queue);
+        }
+    }
+
+    /// Bytes allocated on the GPU for the shared buffers.
+    pub fn gpu_size(&self) -> u64 {
+        let size = |buffer: Option<&Buffer>| buffer.map_or(0, |b| b.size());
+        size(self.instances.buffer())
+            + size(self.packed.buffer())
+            + size(self.colors.buffer())
+            + size(self.rotations.buffer())
+            + size(self.user_data.buffer())
+            + size(self.hidden_mask.buffer())
+    }
+
+    fn rebuild(&mut self, buffer_cache: &CuboidBufferCache, order: Vec<Entity>) {
+        let format = buffer_cache.instance_format;
+        let chunks: Vec<_> = order
+            .iter()
+            .map(|entity| &buffer_cache.entries[entity].current().chunks[0])
+            .collect();
+        let any_rotations = chunks
+            .iter()
+            .any(|c| c.rotations.get().len() == c.capacity());
+        let any_user_data = chunks
+            .iter()
+            .any(|c| c.user_data.get().len() == c.capacity());
+
+        self.members.clear();
+        let mut instances = Vec::new();
+        let mut packed = Vec::new();
+        let mut colors = Vec::new();
+        let mut rotations = Vec::new();
+        let mut user_data = Vec::new();
+        let mut hidden_mask = Vec::new();
+        for (&entity, chunk) in order.iter().zip(&chunks) {
+            self.members.insert(entity, instances.len() as u32);
+            let capacity = chunk.capacity();
+            let aligned = pooled_capacity(capacity);
+            instances.extend_from_slice(chunk.buffer.get());
+            instances.resize(
+                instances.len() + aligned - capacity,
+                *Cuboid::new(Vec3::ZERO, Vec3::ZERO, 0).make_invisible(),
+            );
+            if format != CuboidsInstanceFormat::Full {
+                packed.extend_from_slice(chunk.packed.get());
+                packed.resize(packed.len() + aligned - capacity, UVec4::ZERO);
+            }
+            colors.extend_from_slice(chunk.colors.get());
+            colors.resize(colors.len() + aligned - capacity, 0);
+            if any_rotations {
+                match chunk.rotations.get() {
+                    r if r.len() == capacity => rotations.extend_from_slice(r),
+                    _ => rotations.resize(rotations.len() + capacity, Vec4::W),
+                }
+                rotations.resize(rotations.len() + aligned - capacity, Vec4::W);
+            }
+            if any_user_data {
+                match chunk.user_data.get() {
+                    u if u.len() == capacity => user_data.extend_from_slice(u),
+                    _ => user_data.resize(user_data.len() + capacity, 0),
+                }
+                user_data.resize(user_data.len() + aligned - capacity, 0);
+            }
+            hidden_mask.extend_from_slice(chunk.hidden_mask.get());
+            hidden_mask.resize(instances.len() / 32, 0);
+        }
+        // Empty bindings are invalid.
+        if rotations.is_empty() {
+            rotations.push(Vec4::W);
+        }
+        if user_data.is_empty() {
+            user_data.push(0);
+        }
+        if packed.is_empty() {
+            packed.push(UVec4::ZERO);
+        }
+        self.instances.set(instances);
+        self.packed.set(packed);
+        self.colors.set(colors);
+        self.rotations.set(rotations);
+        self.user_data.set(user_data);
+        self.hidden_mask.set(hidden_mask);
+        self.order = order;
+    }
+}
+
+/// Whether a batch can be drawn from the shared buffers of
+/// [`CuboidsMultiDraw`]: a static, opaque batch with a single chunk and
+/// without face colors or atlas tiles, whose instances are on the GPU, and
+/// that is sorted like the other opaque batches.
+fn is_multi_drawn(entry: &CachedCuboidBuffers) -> bool {
+    if entry.evicted
+        || entry.streaming
+        || entry.upload_deferred
+        || entry.transparent
+        || entry.occluder
+        || entry.contour_mask
+        || entry.depth_mode != DEPTH_MODE_DEFAULT
+        || !matches!(entry.draw_phase, DRAW_PHASE_AUTO | DRAW_PHASE_OPAQUE)
+        || entry.sort_key_offset != 0.0
+        || entry.instance_buffers.len() != 1
+    {
+        return false;
+    }
+    match entry.current().chunks.as_slice() {
+        [chunk] => chunk.len > 0 && chunk.colors.get().len() == chunk.capacity(),
+        _ => false,
+    }
+}
+
+fn pooled_capacity(capacity: usize) -> usize {
+    (capacity + 31) / 32 * 32
+}
+
+/// Copies the members of [`CuboidsMultiDraw`] into its shared buffers when
+/// they changed. Runs before `prepare_cuboids`, which clears the changes.
+pub(crate) fn prepare_cuboids_multi_draw(
+    pipeline: Res<CuboidsPipelines>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    buffer_cache: Res<CuboidBufferCache>,
+    mut multi_draw: ResMut<CuboidsMultiDraw>,
+) {
+    let mut order: Vec<Entity> = buffer_cache
+        .entries
+        .iter()
+        .filter(|(_, entry)| is_multi_drawn(entry))
+        .map(|(&entity, _)| entity)
+        .collect();
+    order.sort_unstable();
+    // The shared buffers are a single binding, like a chunk. Batches that
+    // don't fit are drawn on their own.
+    let mut total = 0;
+    order.retain(|entity| {
+        let capacity = pooled_capacity(buffer_cache.entries[entity].current().chunks[0].capacity());
+        let fits = total + capacity <= buffer_cache.max_chunk_instances;
+        total += fits as usize * capacity;
+        fits
+    });
+    let changed = order.iter().any(|entity| {
+        let entry = &buffer_cache.entries[entity];
+        entry.dirty
+            || entry.visibility_dirty
+            || !entry.dirty_ranges.is_empty()
+            || !entry.dirty_color_ranges.is_empty()
+    });
+    if !changed && order == multi_draw.order {
+        return;
+    }
+
+    multi_draw.rebuild(&buffer_cache, order);
+    if multi_draw.members.is_empty() {
+        multi_draw.bind_group = None;
+        return;
+    }
+    let multi_draw = &mut *multi_draw;
+    multi_draw
+        .instances
+        .write_buffer(&render_device, &render_queue);
+    if buffer_cache.instance_format != CuboidsInstanceFormat::Full {
+        multi_draw
+            .packed
+            .write_buffer(&render_device, &render_queue);
+    }
+    multi_draw
+        .colors
+        .write_buffer(&render_device, &render_queue);
+    multi_draw
+        .rotations
+        .write_buffer(&render_device, &render_queue);
+    multi_draw
+        .user_data
+        .write_buffer(&render_device, &render_queue);
+    multi_draw
+        .hidden_mask
+        .write_buffer(&render_device, &render_queue);
+    let instances_binding = match buffer_cache.instance_format {
+        CuboidsInstanceFormat::Full => multi_draw.instances.binding().unwrap(),
+        _ => multi_draw.packed.binding().unwrap(),
+    };
+    multi_draw.bind_group = Some(render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("cuboids_multi_draw_bind_group"),
+        layout: &pipeline.unculled_cuboids_layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: instances_binding,
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: multi_draw.rotations.binding().unwrap(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: multi_draw.hidden_mask.binding().unwrap(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: multi_draw.colors.binding().unwrap(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: multi_draw.user_data.binding().unwrap(),
+            },
+        ],
+    }));
+}
+
+/// Like [`SetAuxBindGroup`](super::draw::SetAuxBindGroup), with the material of
+/// a [`CuboidsMultiDrawItem`].
+pub(crate) struct SetMultiDrawAuxBindGroup<const I: usize>;
+
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetMultiDrawAuxBindGroup<I> {
+    type Param = SRes<AuxiliaryMeta>;
+    type ItemWorldQuery = Read<CuboidsMultiDrawItem>;
+    type ViewWorldQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        _item: &P,
+        _view: (),
+        item: &'w CuboidsMultiDrawItem,
+        aux_meta: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        pass.set_bind_group(
+            I,
+            aux_meta.into_inner().bind_group.as_ref().unwrap(),
+            &[item.material_index],
+        );
+        RenderCommandResult::Success
+    }
+}
+
+/// Binds the shared buffers of [`CuboidsMultiDraw`] at group `I`, and draws
+/// every member of a [`CuboidsMultiDrawItem`] with a single indirect draw.
+pub(crate) struct DrawCuboidsIndirect<const I: usize>;
+
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for DrawCuboidsIndirect<I> {
+    type Param = (
+        SRes<CuboidsMultiDraw>,
+        SRes<RenderAssets<CuboidsIndexBuffer>>,
+    );
+    type ItemWorldQuery = Read<CuboidsMultiDrawItem>;
+    type ViewWorldQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        _item: &P,
+        _view: (),
+        item: &'w CuboidsMultiDrawItem,
+        (multi_draw, index_buffers): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let multi_draw = multi_draw.into_inner();
+        let (Some(bind_group), Some(indirect)) =
+            (multi_draw.bind_group.as_ref(), multi_draw.indirect.buffer())
+        else {
+            return RenderCommandResult::Failure;
+        };
+        let index_buffer = index_buffers
+            .into_inner()
+            .get(&CUBE_INDICES_HANDLE.typed())
+            .unwrap();
+        pass.set_index_buffer(index_buffer.buffer.slice(..), 0, index_buffer.index_format);
+        pass.set_bind_group(I, bind_group, &[]);
+        pass.multi_draw_indexed_indirect(
+            indirect,
+            u64::from(item.first_draw) * GpuDrawIndexedIndirect::min_size().get(),
+            item.draw_count,
+        );
+        RenderCommandResult::Success
+    }
+}
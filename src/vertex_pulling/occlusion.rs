@@ -0,0 +1,487 @@
+use super::culling::CuboidsCullingCache;
+use super::pipeline::CuboidsShaderDefs;
+use super::timestamps::{CuboidsTimestamps, TimedPass};
+
+use bevy::{
+    core_pipeline::core_3d::{Camera3d, Opaque3d},
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        camera::ExtractedCamera,
+        extract_resource::ExtractResource,
+        render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+        render_phase::RenderPhase,
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType,
+            BufferBindingType, CachedComputePipelineId, ComputePassDescriptor,
+            ComputePipelineDescriptor, Extent3d, PipelineCache, ShaderStages, ShaderType,
+            StorageTextureAccess, Texture, TextureDescriptor, TextureDimension, TextureFormat,
+            TextureSampleType, TextureUsages, TextureView, TextureViewDescriptor,
+            TextureViewDimension, UniformBuffer,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        view::{ExtractedView, ViewDepthTexture},
+    },
+};
+use std::num::NonZeroU32;
+
+pub(crate) const DEPTH_PYRAMID_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 4263612942777289154);
+
+/// Render graph node that builds the depth pyramid after the main 3D pass.
+pub(crate) const CUBOIDS_DEPTH_PYRAMID_NODE: &str = "cuboids_depth_pyramid";
+
+const WORKGROUP_SIZE: u32 = 8;
+
+/// Controls occlusion culling, which hides cuboids behind the depth of the
+/// previous frame.
+///
+/// Requires [`VertexPullingRenderPlugin::gpu_culling`](crate::VertexPullingRenderPlugin),
+/// which inserts this resource. While enabled, the depth texture of every
+/// [`Camera3d`] is made readable, and a depth pyramid is built for each view
+/// after the main pass. Cuboids that move into view from behind an occluder
+/// can appear one frame late. Ignored with
+/// [`DepthConvention::ForwardZ`](crate::DepthConvention::ForwardZ).
+#[derive(Clone, Debug, Default, ExtractResource, Resource)]
+pub struct OcclusionCullingSettings {
+    pub enabled: bool,
+}
+
+#[derive(Clone, Default, ShaderType)]
+pub(crate) struct GpuOcclusionCulling {
+    /// The view projection that the depth pyramid was rendered with.
+    pub view_proj: Mat4,
+    pub pyramid_size: UVec2,
+    pub num_mips: u32,
+    pub enabled: u32,
+    /// The offset and size of the view's viewport in the pyramid, as
+    /// fractions of its size, e.g. one half of a side-by-side stereo target.
+    pub viewport: Vec4,
+}
+
+#[derive(Resource)]
+pub(crate) struct DepthPyramidPipelines {
+    /// Culling pass group with the pyramid of the previous frame.
+    pub occlusion_layout: BindGroupLayout,
+    /// Indexed by whether the depth texture is multisampled, since MSAA can
+    /// change at runtime.
+    pub copy_depth_layouts: [BindGroupLayout; 2],
+    pub downsample_layout: BindGroupLayout,
+    pub copy_depth_pipeline_ids: [CachedComputePipelineId; 2],
+    pub downsample_pipeline_id: CachedComputePipelineId,
+    /// Bound when there is no pyramid yet; its depth never occludes anything.
+    pub fallback_view: TextureView,
+}
+
+fn storage_texture_entry(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::StorageTexture {
+            access: StorageTextureAccess::WriteOnly,
+            format: TextureFormat::R32Float,
+            view_dimension: TextureViewDimension::D2,
+        },
+        count: None,
+    }
+}
+
+fn texture_entry(
+    binding: u32,
+    sample_type: TextureSampleType,
+    multisampled: bool,
+) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Texture {
+            sample_type,
+            view_dimension: TextureViewDimension::D2,
+            multisampled,
+        },
+        count: None,
+    }
+}
+
+fn create_pyramid_texture(render_device: &RenderDevice, size: UVec2, num_mips: u32) -> Texture {
+    render_device.create_texture(&TextureDescriptor {
+        label: Some("cuboids_depth_pyramid"),
+        size: Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: num_mips,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format: TextureFormat::R32Float,
+        usage: TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    })
+}
+
+impl FromWorld for DepthPyramidPipelines {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let unfilterable = TextureSampleType::Float { filterable: false };
+
+        let occlusion_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("cuboids_occlusion_layout"),
+            entries: &[
+                texture_entry(0, unfilterable, false),
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(GpuOcclusionCulling::min_size()),
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let copy_depth_layouts = [false, true].map(|multisampled| {
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("cuboids_copy_depth_layout"),
+                entries: &[
+                    texture_entry(0, TextureSampleType::Depth, multisampled),
+                    storage_texture_entry(1),
+                ],
+            })
+        });
+        let downsample_layout =
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("cuboids_downsample_depth_layout"),
+                entries: &[
+                    texture_entry(0, unfilterable, false),
+                    storage_texture_entry(1),
+                ],
+            });
+
+        // Textures are zero-initialized, and zero is the far plane with reverse Z.
+        let fallback_texture = create_pyramid_texture(render_device, UVec2::ONE, 1);
+        let fallback_view = fallback_texture.create_view(&TextureViewDescriptor::default());
+
+        let copy_depth_descriptors = [false, true].map(|multisampled| {
+            let mut shader_defs = vec!["COPY_DEPTH".into()];
+            if multisampled {
+                shader_defs.push("MULTISAMPLED".into());
+            }
+            ComputePipelineDescriptor {
+                label: Some("cuboids_copy_depth_pipeline".into()),
+                layout: vec![copy_depth_layouts[multisampled as usize].clone()],
+                push_constant_ranges: Vec::new(),
+                shader: DEPTH_PYRAMID_SHADER_HANDLE.typed(),
+                shader_defs,
+                entry_point: "downsample".into(),
+            }
+        });
+        let downsample_descriptor = ComputePipelineDescriptor {
+            label: Some("cuboids_downsample_depth_pipeline".into()),
+            layout: vec![downsample_layout.clone()],
+            shader_defs: Vec::new(),
+            ..copy_depth_descriptors[0].clone()
+        };
+
+        let pipeline_cache = world.resource_mut::<PipelineCache>();
+        let copy_depth_pipeline_ids = copy_depth_descriptors
+            .map(|descriptor| pipeline_cache.queue_compute_pipeline(descriptor));
+        let downsample_pipeline_id = pipeline_cache.queue_compute_pipeline(downsample_descriptor);
+
+        Self {
+            occlusion_layout,
+            copy_depth_layouts,
+            downsample_layout,
+            copy_depth_pipeline_ids,
+            downsample_pipeline_id,
+            fallback_view,
+        }
+    }
+}
+
+/// Occlusion culling state of a single view.
+#[derive(Default)]
+pub(crate) struct ViewOcclusion {
+    pub pyramid: Option<DepthPyramid>,
+    pub uniform: UniformBuffer<GpuOcclusionCulling>,
+    /// Bound for the culling pass.
+    pub bind_group: Option<BindGroup>,
+    /// The view projection of the previous frame.
+    prev_view_proj: Mat4,
+    /// [`GpuOcclusionCulling::viewport`] of the previous frame.
+    prev_viewport: Vec4,
+}
+
+pub(crate) struct DepthPyramid {
+    pub size: UVec2,
+    pub full_view: TextureView,
+    pub mip_views: Vec<TextureView>,
+    /// `downsample_bind_groups[i]` reduces mip `i` into mip `i + 1`.
+    pub downsample_bind_groups: Vec<BindGroup>,
+    /// Whether the pyramid was built in the previous frame.
+    pub is_built: bool,
+}
+
+impl DepthPyramid {
+    fn new(render_device: &RenderDevice, pipelines: &DepthPyramidPipelines, size: UVec2) -> Self {
+        let num_mips = 32 - size.max_element().leading_zeros();
+        let texture = create_pyramid_texture(render_device, size, num_mips);
+        let full_view = texture.create_view(&TextureViewDescriptor::default());
+        let mip_views: Vec<_> = (0..num_mips)
+            .map(|mip| {
+                texture.create_view(&TextureViewDescriptor {
+                    base_mip_level: mip,
+                    mip_level_count: NonZeroU32::new(1),
+                    ..default()
+                })
+            })
+            .collect();
+        let downsample_bind_groups = mip_views
+            .windows(2)
+            .map(|views| {
+                render_device.create_bind_group(&BindGroupDescriptor {
+                    label: Some("cuboids_downsample_depth_bind_group"),
+                    layout: &pipelines.downsample_layout,
+                    entries: &[
+                        BindGroupEntry {
+                            binding: 0,
+                            resource: BindingResource::TextureView(&views[0]),
+                        },
+                        BindGroupEntry {
+                            binding: 1,
+                            resource: BindingResource::TextureView(&views[1]),
+                        },
+                    ],
+                })
+            })
+            .collect();
+        Self {
+            size,
+            full_view,
+            mip_views,
+            downsample_bind_groups,
+            is_built: false,
+        }
+    }
+
+    fn num_mips(&self) -> u32 {
+        self.mip_views.len() as u32
+    }
+}
+
+/// Makes the depth texture of every camera readable by the pyramid pass.
+pub(crate) fn enable_camera_depth_binding(
+    settings: Res<OcclusionCullingSettings>,
+    mut cameras: Query<&mut Camera3d>,
+) {
+    if !settings.enabled {
+        return;
+    }
+    for mut camera in cameras.iter_mut() {
+        let usages = TextureUsages::from(camera.depth_texture_usages);
+        if !usages.contains(TextureUsages::TEXTURE_BINDING) {
+            camera.depth_texture_usages = (usages | TextureUsages::TEXTURE_BINDING).into();
+        }
+    }
+}
+
+pub(crate) fn prepare_occlusion_culling(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    settings: Res<OcclusionCullingSettings>,
+    shader_defs: Res<CuboidsShaderDefs>,
+    pipelines: Res<DepthPyramidPipelines>,
+    mut culling_cache: ResMut<CuboidsCullingCache>,
+    views: Query<(Entity, &ExtractedView, Option<&ExtractedCamera>), With<RenderPhase<Opaque3d>>>,
+) {
+    for (entity, view, camera) in views.iter() {
+        let Some(view_culling) = culling_cache.views.get_mut(&entity) else {
+            continue;
+        };
+        let occlusion = &mut view_culling.occlusion;
+
+        let size = camera.and_then(|c| c.physical_target_size);
+        // The depth pyramid is built for reverse Z.
+        let Some(size) = size.filter(|_| settings.enabled && !shader_defs.forward_z) else {
+            occlusion.pyramid = None;
+            occlusion.uniform.set(GpuOcclusionCulling::default());
+            occlusion
+                .uniform
+                .write_buffer(&render_device, &render_queue);
+            occlusion.bind_group = Some(create_occlusion_bind_group(
+                &render_device,
+                &pipelines,
+                &pipelines.fallback_view,
+                &occlusion.uniform,
+            ));
+            continue;
+        };
+
+        let view_proj = view.projection * view.transform.compute_matrix().inverse();
+        let prev_view_proj = std::mem::replace(&mut occlusion.prev_view_proj, view_proj);
+        // Cameras with a viewport only render into part of the depth texture.
+        let target_size = size.as_vec2();
+        let viewport = view.viewport.as_vec4()
+            / Vec4::new(target_size.x, target_size.y, target_size.x, target_size.y);
+        let prev_viewport = std::mem::replace(&mut occlusion.prev_viewport, viewport);
+
+        if occlusion.pyramid.as_ref().map(|p| p.size) != Some(size) {
+            occlusion.pyramid = Some(DepthPyramid::new(&render_device, &pipelines, size));
+        }
+        let pyramid = occlusion.pyramid.as_mut().unwrap();
+
+        occlusion.uniform.set(GpuOcclusionCulling {
+            view_proj: prev_view_proj,
+            pyramid_size: pyramid.size,
+            num_mips: pyramid.num_mips(),
+            enabled: pyramid.is_built as u32,
+            viewport: prev_viewport,
+        });
+        occlusion
+            .uniform
+            .write_buffer(&render_device, &render_queue);
+        let pyramid_view = if pyramid.is_built {
+            &pyramid.full_view
+        } else {
+            &pipelines.fallback_view
+        };
+        occlusion.bind_group = Some(create_occlusion_bind_group(
+            &render_device,
+            &pipelines,
+            pyramid_view,
+            &occlusion.uniform,
+        ));
+
+        // The pyramid node runs for this view after the main pass.
+        pyramid.is_built = true;
+    }
+}
+
+fn create_occlusion_bind_group(
+    render_device: &RenderDevice,
+    pipelines: &DepthPyramidPipelines,
+    pyramid_view: &TextureView,
+    uniform: &UniformBuffer<GpuOcclusionCulling>,
+) -> BindGroup {
+    render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("cuboids_occlusion_bind_group"),
+        layout: &pipelines.occlusion_layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(pyramid_view),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: uniform.binding().unwrap(),
+            },
+        ],
+    })
+}
+
+/// Copies the depth of a view into its pyramid and downsamples it.
+pub(crate) struct CuboidsDepthPyramidNode {
+    view_query: QueryState<&'static ViewDepthTexture>,
+}
+
+impl CuboidsDepthPyramidNode {
+    pub const IN_VIEW: &'static str = "view";
+
+    pub fn new(world: &mut World) -> Self {
+        Self {
+            view_query: QueryState::new(world),
+        }
+    }
+}
+
+impl Node for CuboidsDepthPyramidNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(Self::IN_VIEW, SlotType::Entity)]
+    }
+
+    fn update(&mut self, world: &mut World) {
+        self.view_query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+        let Ok(depth) = self.view_query.get_manual(world, view_entity) else {
+            return Ok(());
+        };
+        let Some(pyramid) = world
+            .resource::<CuboidsCullingCache>()
+            .views
+            .get(&view_entity)
+            .and_then(|v| v.occlusion.pyramid.as_ref())
+        else {
+            return Ok(());
+        };
+        let pipelines = world.resource::<DepthPyramidPipelines>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let multisampled = (world.resource::<Msaa>().samples() > 1) as usize;
+        let (Some(copy_depth_pipeline), Some(downsample_pipeline)) = (
+            pipeline_cache.get_compute_pipeline(pipelines.copy_depth_pipeline_ids[multisampled]),
+            pipeline_cache.get_compute_pipeline(pipelines.downsample_pipeline_id),
+        ) else {
+            return Ok(());
+        };
+
+        // The depth texture can change between frames.
+        let copy_depth_bind_group =
+            render_context
+                .render_device()
+                .create_bind_group(&BindGroupDescriptor {
+                    label: Some("cuboids_copy_depth_bind_group"),
+                    layout: &pipelines.copy_depth_layouts[multisampled],
+                    entries: &[
+                        BindGroupEntry {
+                            binding: 0,
+                            resource: BindingResource::TextureView(&depth.view),
+                        },
+                        BindGroupEntry {
+                            binding: 1,
+                            resource: BindingResource::TextureView(&pyramid.mip_views[0]),
+                        },
+                    ],
+                });
+
+        let timestamps = world.get_resource::<CuboidsTimestamps>();
+        let query =
+            timestamps.and_then(|t| t.begin(render_context.command_encoder(), TimedPass::Cull));
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("cuboids_depth_pyramid_pass"),
+                });
+        let num_workgroups = |mip: usize| {
+            let size = (pyramid.size >> mip as u32).max(UVec2::ONE);
+            (size + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE
+        };
+
+        pass.set_pipeline(copy_depth_pipeline);
+        pass.set_bind_group(0, &copy_depth_bind_group, &[]);
+        let n = num_workgroups(0);
+        pass.dispatch_workgroups(n.x, n.y, 1);
+
+        pass.set_pipeline(downsample_pipeline);
+        for (i, bind_group) in pyramid.downsample_bind_groups.iter().enumerate() {
+            pass.set_bind_group(0, bind_group, &[]);
+            let n = num_workgroups(i + 1);
+            pass.dispatch_workgroups(n.x, n.y, 1);
+        }
+        drop(pass);
+        if let (Some(timestamps), Some(query)) = (timestamps, query) {
+            timestamps.end(render_context.command_encoder(), query);
+        }
+
+        Ok(())
+    }
+}
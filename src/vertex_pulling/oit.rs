@@ -0,0 +1,545 @@
+use super::cuboid_cache::CuboidBufferCache;
+use super::draw::DrawCuboids;
+use super::pipeline::{
+    CuboidsPipelines, CuboidsShaderDefs, TrackedSpecializedPipelines, VERTEX_PULLING_SHADER_HANDLE,
+};
+use crate::{DEPTH_MODE_ALWAYS_ON_TOP, DRAW_PHASE_AUTO};
+
+use bevy::{
+    core_pipeline::{core_3d::Camera3d, fullscreen_vertex_shader::fullscreen_shader_vertex_state},
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        camera::ExtractedCamera,
+        mesh::PrimitiveTopology,
+        render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+        render_phase::{
+            CachedRenderPipelinePhaseItem, DrawFunctionId, DrawFunctions, PhaseItem, RenderPhase,
+        },
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType,
+            BlendComponent, BlendFactor, BlendOperation, BlendState, CachedRenderPipelineId,
+            ColorTargetState, ColorWrites, CompareFunction, DepthStencilState, Extent3d,
+            FragmentState, FrontFace, LoadOp, MultisampleState, Operations, PipelineCache,
+            PolygonMode, PrimitiveState, RenderPassColorAttachment,
+            RenderPassDepthStencilAttachment, RenderPassDescriptor, RenderPipelineDescriptor,
+            ShaderStages, SpecializedRenderPipeline, TextureDescriptor, TextureDimension,
+            TextureFormat, TextureSampleType, TextureUsages, TextureViewDimension, VertexState,
+        },
+        renderer::{RenderContext, RenderDevice},
+        texture::{BevyDefault, CachedTexture, TextureCache},
+        view::{ExtractedView, ViewDepthTexture, ViewTarget, VisibleEntities},
+        Extract,
+    },
+};
+
+pub(crate) const OIT_COMPOSITE_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 6054182339129271583);
+
+/// Render graph node that draws transparent cuboids without sorting, after
+/// the main 3D pass.
+pub(crate) const CUBOIDS_OIT_NODE: &str = "cuboids_oit";
+
+/// Premultiplied colors, weighted by depth and summed.
+const ACCUM_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+/// The product of `1 - alpha` over all transparent fragments.
+const REVEALAGE_FORMAT: TextureFormat = TextureFormat::R16Float;
+
+/// A transparent batch, drawn into the accumulation targets of a view.
+pub(crate) struct CuboidsOit {
+    pub entity: Entity,
+    pub pipeline: CachedRenderPipelineId,
+    pub draw_function: DrawFunctionId,
+}
+
+impl PhaseItem for CuboidsOit {
+    // Blending is commutative, so batches are never sorted.
+    type SortKey = ();
+
+    #[inline]
+    fn entity(&self) -> Entity {
+        self.entity
+    }
+
+    #[inline]
+    fn sort_key(&self) -> Self::SortKey {}
+
+    #[inline]
+    fn draw_function(&self) -> DrawFunctionId {
+        self.draw_function
+    }
+}
+
+impl CachedRenderPipelinePhaseItem for CuboidsOit {
+    #[inline]
+    fn cached_pipeline(&self) -> CachedRenderPipelineId {
+        self.pipeline
+    }
+}
+
+/// Specializes the accumulate and composite pipelines for each
+/// [`CuboidsOitPipelineKey`].
+#[derive(Resource)]
+pub(crate) struct CuboidsOitPipelines {
+    /// Both without MSAA, and the composite pipeline for LDR views.
+    accumulate_descriptor: RenderPipelineDescriptor,
+    composite_descriptor: RenderPipelineDescriptor,
+    pub composite_layout: BindGroupLayout,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum CuboidsOitPipelineKey {
+    Accumulate { samples: u32 },
+    Composite { hdr: bool, samples: u32 },
+}
+
+impl SpecializedRenderPipeline for CuboidsOitPipelines {
+    type Key = CuboidsOitPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        match key {
+            CuboidsOitPipelineKey::Accumulate { samples } => {
+                let mut descriptor = self.accumulate_descriptor.clone();
+                descriptor.multisample.count = samples;
+                descriptor
+            }
+            CuboidsOitPipelineKey::Composite { hdr, samples } => {
+                let mut descriptor = self.composite_descriptor.clone();
+                if hdr {
+                    descriptor.label = Some("cuboids_oit_hdr_composite_pipeline".into());
+                    descriptor.fragment.as_mut().unwrap().targets[0]
+                        .as_mut()
+                        .unwrap()
+                        .format = TextureFormat::Rgba16Float;
+                }
+                descriptor.multisample.count = samples;
+                descriptor
+            }
+        }
+    }
+}
+
+impl FromWorld for CuboidsOitPipelines {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let texture_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: false },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let composite_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("cuboids_oit_composite_layout"),
+            entries: &[
+                // Accumulated colors
+                texture_entry(0),
+                // Revealage
+                texture_entry(1),
+            ],
+        });
+
+        let shader_defs = world.resource::<CuboidsShaderDefs>();
+        let mut vertex_defs = shader_defs.vertex.clone();
+        vertex_defs.push("OIT".into());
+        let mut fragment_defs = shader_defs.fragment.clone();
+        fragment_defs.push("OIT".into());
+
+        let multisample = MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        };
+        let blend = |src_factor, dst_factor| {
+            let component = BlendComponent {
+                src_factor,
+                dst_factor,
+                operation: BlendOperation::Add,
+            };
+            Some(BlendState {
+                color: component,
+                alpha: component,
+            })
+        };
+
+        let cuboids_pipelines = world.resource::<CuboidsPipelines>();
+        let accumulate_descriptor = RenderPipelineDescriptor {
+            label: Some("cuboids_oit_accumulate_pipeline".into()),
+            layout: vec![
+                cuboids_pipelines.view_layout.clone(),
+                cuboids_pipelines.aux_layout.clone(),
+                cuboids_pipelines.transforms_layout.clone(),
+                cuboids_pipelines.cuboids_layout.clone(),
+            ],
+            vertex: VertexState {
+                shader: VERTEX_PULLING_SHADER_HANDLE.typed(),
+                shader_defs: vertex_defs,
+                entry_point: "vertex".into(),
+                buffers: vec![],
+            },
+            fragment: Some(FragmentState {
+                shader: VERTEX_PULLING_SHADER_HANDLE.typed(),
+                shader_defs: fragment_defs,
+                entry_point: "fragment_oit".into(),
+                targets: vec![
+                    Some(ColorTargetState {
+                        format: ACCUM_FORMAT,
+                        blend: blend(BlendFactor::One, BlendFactor::One),
+                        write_mask: ColorWrites::ALL,
+                    }),
+                    Some(ColorTargetState {
+                        format: REVEALAGE_FORMAT,
+                        blend: blend(BlendFactor::Zero, BlendFactor::OneMinusSrc),
+                        write_mask: ColorWrites::ALL,
+                    }),
+                ],
+            }),
+            primitive: PrimitiveState {
+                front_face: FrontFace::Ccw,
+                cull_mode: None,
+                unclipped_depth: false,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+                topology: PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+            },
+            // Tested against the opaque depth, but transparent cuboids never
+            // hide each other.
+            depth_stencil: Some(DepthStencilState {
+                format: TextureFormat::Depth32Float,
+                depth_write_enabled: false,
+                depth_compare: shader_defs.depth_compare(CompareFunction::Greater),
+                stencil: default(),
+                bias: default(),
+            }),
+            multisample,
+            push_constant_ranges: Vec::new(),
+        };
+
+        // The source alpha is the revealage, i.e. how much of the opaque
+        // color shows through.
+        let composite_descriptor = RenderPipelineDescriptor {
+            label: Some("cuboids_oit_composite_pipeline".into()),
+            layout: vec![composite_layout.clone()],
+            vertex: fullscreen_shader_vertex_state(),
+            fragment: Some(FragmentState {
+                shader: OIT_COMPOSITE_SHADER_HANDLE.typed(),
+                shader_defs: vec![],
+                entry_point: "composite".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: TextureFormat::bevy_default(),
+                    blend: blend(BlendFactor::OneMinusSrcAlpha, BlendFactor::SrcAlpha),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: default(),
+            depth_stencil: None,
+            multisample,
+            push_constant_ranges: Vec::new(),
+        };
+
+        Self {
+            accumulate_descriptor,
+            composite_descriptor,
+            composite_layout,
+        }
+    }
+}
+
+pub(crate) fn extract_cuboids_oit_phases(
+    mut commands: Commands,
+    cameras: Extract<Query<(Entity, &Camera), With<Camera3d>>>,
+) {
+    for (entity, camera) in cameras.iter() {
+        if camera.is_active {
+            commands
+                .get_or_spawn(entity)
+                .insert(RenderPhase::<CuboidsOit>::default());
+        }
+    }
+}
+
+/// Queues transparent batches, which [`queue_cuboids`](super::queue::queue_cuboids)
+/// skips while this is enabled.
+pub(crate) fn queue_cuboids_oit(
+    pipelines: Res<CuboidsOitPipelines>,
+    mut specialized_pipelines: ResMut<TrackedSpecializedPipelines<CuboidsOitPipelines>>,
+    pipeline_cache: Res<PipelineCache>,
+    msaa: Res<Msaa>,
+    draw_functions: Res<DrawFunctions<CuboidsOit>>,
+    buffer_cache: Res<CuboidBufferCache>,
+    mut views: Query<(&VisibleEntities, &mut RenderPhase<CuboidsOit>)>,
+) {
+    let draw_cuboids = draw_functions.read().get_id::<DrawCuboids>().unwrap();
+    let pipeline = specialized_pipelines.specialize(
+        &pipeline_cache,
+        &pipelines,
+        CuboidsOitPipelineKey::Accumulate {
+            samples: msaa.samples(),
+        },
+    );
+    for (visible_entities, mut phase) in views.iter_mut() {
+        for &entity in &visible_entities.entities {
+            let Some(entry) = buffer_cache.entries.get(&entity) else {
+                continue;
+            };
+            // Batches on top are sorted after all other transparent geometry,
+            // and those in a phase of their choosing are drawn there.
+            if !entry.enabled
+                || !entry.transparent
+                || entry.contour_mask
+                || entry.depth_mode == DEPTH_MODE_ALWAYS_ON_TOP
+                || entry.draw_phase != DRAW_PHASE_AUTO
+            {
+                continue;
+            }
+            phase.add(CuboidsOit {
+                entity,
+                pipeline,
+                draw_function: draw_cuboids,
+            });
+        }
+    }
+}
+
+/// The accumulation targets of a view. With MSAA, they are resolved into
+/// single-sampled textures for the composite pass.
+#[derive(Component)]
+pub(crate) struct ViewOitTextures {
+    accum: CachedTexture,
+    revealage: CachedTexture,
+    resolve: Option<(CachedTexture, CachedTexture)>,
+    composite_bind_group: BindGroup,
+    composite_pipeline_id: CachedRenderPipelineId,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn prepare_cuboids_oit_textures(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    mut texture_cache: ResMut<TextureCache>,
+    msaa: Res<Msaa>,
+    pipelines: Res<CuboidsOitPipelines>,
+    mut specialized_pipelines: ResMut<TrackedSpecializedPipelines<CuboidsOitPipelines>>,
+    pipeline_cache: Res<PipelineCache>,
+    views: Query<(Entity, &ExtractedCamera, &ExtractedView), With<RenderPhase<CuboidsOit>>>,
+) {
+    let sample_count = msaa.samples();
+    for (entity, camera, view) in views.iter() {
+        let Some(size) = camera.physical_target_size else {
+            continue;
+        };
+        let mut texture = |label, format, sample_count, usage| {
+            texture_cache.get(
+                &render_device,
+                TextureDescriptor {
+                    label: Some(label),
+                    size: Extent3d {
+                        width: size.x,
+                        height: size.y,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count,
+                    dimension: TextureDimension::D2,
+                    format,
+                    usage,
+                    view_formats: &[],
+                },
+            )
+        };
+
+        let sampled_usage = TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING;
+        let (accum, revealage, resolve) = if sample_count > 1 {
+            let accum = texture(
+                "cuboids_oit_accum_texture",
+                ACCUM_FORMAT,
+                sample_count,
+                TextureUsages::RENDER_ATTACHMENT,
+            );
+            let revealage = texture(
+                "cuboids_oit_revealage_texture",
+                REVEALAGE_FORMAT,
+                sample_count,
+                TextureUsages::RENDER_ATTACHMENT,
+            );
+            let resolve = (
+                texture(
+                    "cuboids_oit_accum_resolve_texture",
+                    ACCUM_FORMAT,
+                    1,
+                    sampled_usage,
+                ),
+                texture(
+                    "cuboids_oit_revealage_resolve_texture",
+                    REVEALAGE_FORMAT,
+                    1,
+                    sampled_usage,
+                ),
+            );
+            (accum, revealage, Some(resolve))
+        } else {
+            let accum = texture("cuboids_oit_accum_texture", ACCUM_FORMAT, 1, sampled_usage);
+            let revealage = texture(
+                "cuboids_oit_revealage_texture",
+                REVEALAGE_FORMAT,
+                1,
+                sampled_usage,
+            );
+            (accum, revealage, None)
+        };
+
+        let (sampled_accum, sampled_revealage) = match &resolve {
+            Some((accum, revealage)) => (accum, revealage),
+            None => (&accum, &revealage),
+        };
+        let composite_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("cuboids_oit_composite_bind_group"),
+            layout: &pipelines.composite_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&sampled_accum.default_view),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(&sampled_revealage.default_view),
+                },
+            ],
+        });
+
+        let composite_pipeline_id = specialized_pipelines.specialize(
+            &pipeline_cache,
+            &pipelines,
+            CuboidsOitPipelineKey::Composite {
+                hdr: view.hdr,
+                samples: sample_count,
+            },
+        );
+        commands.entity(entity).insert(ViewOitTextures {
+            accum,
+            revealage,
+            resolve,
+            composite_bind_group,
+            composite_pipeline_id,
+        });
+    }
+}
+
+/// Accumulates the transparent batches of a view, then composites them over
+/// the view target.
+pub(crate) struct CuboidsOitNode {
+    view_query: QueryState<(
+        &'static ExtractedCamera,
+        &'static RenderPhase<CuboidsOit>,
+        &'static ViewTarget,
+        &'static ViewDepthTexture,
+        &'static ViewOitTextures,
+    )>,
+}
+
+impl CuboidsOitNode {
+    pub const IN_VIEW: &'static str = "view";
+
+    pub fn new(world: &mut World) -> Self {
+        Self {
+            view_query: QueryState::new(world),
+        }
+    }
+}
+
+impl Node for CuboidsOitNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(Self::IN_VIEW, SlotType::Entity)]
+    }
+
+    fn update(&mut self, world: &mut World) {
+        self.view_query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+        let Ok((camera, phase, target, depth, textures)) =
+            self.view_query.get_manual(world, view_entity)
+        else {
+            return Ok(());
+        };
+        if phase.items.is_empty() {
+            return Ok(());
+        }
+        let Some(composite_pipeline) = world
+            .resource::<PipelineCache>()
+            .get_render_pipeline(textures.composite_pipeline_id)
+        else {
+            return Ok(());
+        };
+
+        {
+            let (accum_resolve, revealage_resolve) = match &textures.resolve {
+                Some((accum, revealage)) => {
+                    (Some(&accum.default_view), Some(&revealage.default_view))
+                }
+                None => (None, None),
+            };
+            let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+                label: Some("cuboids_oit_accumulate_pass"),
+                color_attachments: &[
+                    Some(RenderPassColorAttachment {
+                        view: &textures.accum.default_view,
+                        resolve_target: accum_resolve,
+                        ops: Operations {
+                            load: LoadOp::Clear(default()),
+                            store: true,
+                        },
+                    }),
+                    Some(RenderPassColorAttachment {
+                        view: &textures.revealage.default_view,
+                        resolve_target: revealage_resolve,
+                        ops: Operations {
+                            load: LoadOp::Clear(Color::WHITE.into()),
+                            store: true,
+                        },
+                    }),
+                ],
+                depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                    view: &depth.view,
+                    depth_ops: Some(Operations {
+                        load: LoadOp::Load,
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            if let Some(viewport) = camera.viewport.as_ref() {
+                pass.set_camera_viewport(viewport);
+            }
+            phase.render(&mut pass, world, view_entity);
+        }
+
+        let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("cuboids_oit_composite_pass"),
+            color_attachments: &[Some(target.get_color_attachment(Operations {
+                load: LoadOp::Load,
+                store: true,
+            }))],
+            depth_stencil_attachment: None,
+        });
+        if let Some(viewport) = camera.viewport.as_ref() {
+            pass.set_camera_viewport(viewport);
+        }
+        pass.set_render_pipeline(composite_pipeline);
+        pass.set_bind_group(0, &textures.composite_bind_group, &[]);
+        pass.draw(0..3, 0..1);
+
+        Ok(())
+    }
+}
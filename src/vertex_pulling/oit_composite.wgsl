@@ -0,0 +1,22 @@
+// Composites weighted blended order-independent transparency over the view
+// target, see `fragment_oit` in the cuboids shader.
+
+@group(0) @binding(0)
+var accum_texture: texture_2d<f32>;
+
+@group(0) @binding(1)
+var revealage_texture: texture_2d<f32>;
+
+@fragment
+fn composite(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    let texel = vec2<i32>(position.xy);
+    let revealage = textureLoad(revealage_texture, texel, 0).r;
+    // No transparent cuboid covers this pixel.
+    if (revealage >= 1.0) {
+        discard;
+    }
+    let accum = textureLoad(accum_texture, texel, 0);
+    let average_color = accum.rgb / max(accum.a, 1e-5);
+    // Blended with the revealage as the alpha of the opaque color.
+    return vec4<f32>(average_color, revealage);
+}
@@ -0,0 +1,148 @@
+use bevy::{
+    core_pipeline::core_3d::Camera3d,
+    prelude::*,
+    render::{
+        camera::ExtractedCamera,
+        render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+        render_phase::{CachedRenderPipelinePhaseItem, DrawFunctionId, PhaseItem, RenderPhase},
+        render_resource::{
+            CachedRenderPipelineId, LoadOp, Operations, RenderPassDepthStencilAttachment,
+            RenderPassDescriptor,
+        },
+        renderer::RenderContext,
+        view::{ViewDepthTexture, ViewTarget},
+        Extract,
+    },
+    utils::FloatOrd,
+};
+
+/// Node of Bevy's 3D render graph that draws the batches of materials in
+/// [`DRAW_PHASE_OVERLAY`](crate::DRAW_PHASE_OVERLAY), after the main pass and
+/// before tonemapping.
+///
+/// Render plugins that draw their own overlays can add edges to or from it to
+/// draw before or after the cuboid overlays.
+pub const CUBOIDS_OVERLAY_NODE: &str = "cuboids_overlay";
+
+/// A batch drawn over the main 3D pass of a view.
+pub(crate) struct CuboidsOverlay {
+    pub distance: f32,
+    pub entity: Entity,
+    pub pipeline: CachedRenderPipelineId,
+    pub draw_function: DrawFunctionId,
+}
+
+impl PhaseItem for CuboidsOverlay {
+    // Back to front, like `Transparent3d`.
+    type SortKey = FloatOrd;
+
+    #[inline]
+    fn entity(&self) -> Entity {
+        self.entity
+    }
+
+    #[inline]
+    fn sort_key(&self) -> Self::SortKey {
+        FloatOrd(self.distance)
+    }
+
+    #[inline]
+    fn draw_function(&self) -> DrawFunctionId {
+        self.draw_function
+    }
+
+    // Stable, so that batches with the same distance keep their queue order.
+    #[inline]
+    fn sort(items: &mut [Self]) {
+        items.sort_by_key(|item| item.sort_key());
+    }
+}
+
+impl CachedRenderPipelinePhaseItem for CuboidsOverlay {
+    #[inline]
+    fn cached_pipeline(&self) -> CachedRenderPipelineId {
+        self.pipeline
+    }
+}
+
+pub(crate) fn extract_cuboids_overlay_phases(
+    mut commands: Commands,
+    cameras: Extract<Query<(Entity, &Camera), With<Camera3d>>>,
+) {
+    for (entity, camera) in cameras.iter() {
+        if camera.is_active {
+            commands
+                .get_or_spawn(entity)
+                .insert(RenderPhase::<CuboidsOverlay>::default());
+        }
+    }
+}
+
+/// Draws the overlay batches of a view into its target, tested against and
+/// writing into the depth of the main pass.
+pub(crate) struct CuboidsOverlayNode {
+    view_query: QueryState<(
+        &'static ExtractedCamera,
+        &'static RenderPhase<CuboidsOverlay>,
+        &'static ViewTarget,
+        &'static ViewDepthTexture,
+    )>,
+}
+
+impl CuboidsOverlayNode {
+    pub const IN_VIEW: &'static str = "view";
+
+    pub fn new(world: &mut World) -> Self {
+        Self {
+            view_query: QueryState::new(world),
+        }
+    }
+}
+
+impl Node for CuboidsOverlayNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(Self::IN_VIEW, SlotType::Entity)]
+    }
+
+    fn update(&mut self, world: &mut World) {
+        self.view_query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+        let Ok((camera, phase, target, depth)) = self.view_query.get_manual(world, view_entity)
+        else {
+            return Ok(());
+        };
+        if phase.items.is_empty() {
+            return Ok(());
+        }
+
+        let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("cuboids_overlay_pass"),
+            color_attachments: &[Some(target.get_color_attachment(Operations {
+                load: LoadOp::Load,
+                store: true,
+            }))],
+            depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                view: &depth.view,
+                depth_ops: Some(Operations {
+                    load: LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        if let Some(viewport) = camera.viewport.as_ref() {
+            pass.set_camera_viewport(viewport);
+        }
+        phase.render(&mut pass, world, view_entity);
+
+        Ok(())
+    }
+}
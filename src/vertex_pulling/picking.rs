@@ -0,0 +1,553 @@
+use super::buffers::CuboidsViewUniformOffset;
+use super::cuboid_cache::CuboidBufferCache;
+use super::draw::{AuxiliaryMeta, TransformsMeta, ViewMeta};
+use super::index_buffer::{
+    CuboidsIndexBuffer, CUBE_INDICES, CUBE_INDICES_HANDLE, TRANSFORM_INDEX_SHIFT,
+};
+use super::pipeline::{CuboidsPipelines, CuboidsShaderDefs, VERTEX_PULLING_SHADER_HANDLE};
+use crate::picking::{GpuPickingRequests, GpuPickingResults};
+use crate::CuboidPickedEvent;
+
+use bevy::{
+    core::cast_slice,
+    prelude::*,
+    render::{
+        mesh::PrimitiveTopology,
+        render_asset::RenderAssets,
+        render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+        render_resource::{
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType, Buffer,
+            BufferBindingType, BufferDescriptor, BufferSize, BufferUsages, CachedRenderPipelineId,
+            ColorTargetState, ColorWrites, CompareFunction, DepthStencilState, Extent3d,
+            FragmentState, FrontFace, ImageCopyBuffer, ImageCopyTexture, ImageDataLayout, LoadOp,
+            Maintain, MapMode, MultisampleState, Operations, Origin3d, PipelineCache, PolygonMode,
+            PrimitiveState, RenderPassColorAttachment, RenderPassDepthStencilAttachment,
+            RenderPassDescriptor, RenderPipelineDescriptor, ShaderStages, ShaderType, Texture,
+            TextureAspect, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+            TextureView, TextureViewDescriptor, UniformBuffer, VertexState,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        view::{ExtractedView, ViewUniformOffset, VisibleEntities},
+    },
+    utils::HashMap,
+};
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Arc,
+};
+
+/// Render graph node that draws instance IDs after the main 3D pass.
+pub(crate) const CUBOIDS_PICKING_NODE: &str = "cuboids_picking";
+
+/// One texel of the picking target.
+const PICKING_FORMAT: TextureFormat = TextureFormat::Rg32Uint;
+const PICKING_TEXEL_SIZE: u64 = 8;
+
+/// The instance index of the first cuboid in a chunk.
+#[derive(Clone, Default, ShaderType)]
+pub(crate) struct GpuPickingChunk {
+    pub first_instance: u32,
+}
+
+#[derive(Resource)]
+pub(crate) struct CuboidsPickingPipeline {
+    pub cuboids_layout: BindGroupLayout,
+    pub pipeline_id: CachedRenderPipelineId,
+}
+
+impl FromWorld for CuboidsPickingPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let storage_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::VERTEX,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: BufferSize::new(0),
+            },
+            count: None,
+        };
+        let cuboids_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("cuboids_picking_instances_layout"),
+            entries: &[
+                // Instances
+                storage_entry(0),
+                // Rotations
+                storage_entry(1),
+                // Hidden instance mask
+                storage_entry(3),
+                // Colors
+                storage_entry(4),
+                // User data
+                storage_entry(5),
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::VERTEX,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(GpuPickingChunk::min_size()),
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        // Every instance is drawn, so the culling results aren't needed.
+        let shader_defs = world.resource::<CuboidsShaderDefs>();
+        let mut vertex_defs = shader_defs.unculled_vertex();
+        vertex_defs.push("PICKING".into());
+        let mut fragment_defs = shader_defs.fragment.clone();
+        fragment_defs.push("PICKING".into());
+
+        let cuboids_pipelines = world.resource::<CuboidsPipelines>();
+        let descriptor = RenderPipelineDescriptor {
+            label: Some("cuboids_picking_pipeline".into()),
+            layout: vec![
+                cuboids_pipelines.view_layout.clone(),
+                cuboids_pipelines.aux_layout.clone(),
+                cuboids_pipelines.transforms_layout.clone(),
+                cuboids_layout.clone(),
+            ],
+            vertex: VertexState {
+                shader: VERTEX_PULLING_SHADER_HANDLE.typed(),
+                shader_defs: vertex_defs,
+                entry_point: "vertex".into(),
+                buffers: vec![],
+            },
+            fragment: Some(FragmentState {
+                shader: VERTEX_PULLING_SHADER_HANDLE.typed(),
+                shader_defs: fragment_defs,
+                entry_point: "fragment_picking".into(),
+                targets: vec![Some(ColorTargetState {
+                    format: PICKING_FORMAT,
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState {
+                front_face: FrontFace::Ccw,
+                cull_mode: None,
+                unclipped_depth: false,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+                topology: PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+            },
+            depth_stencil: Some(DepthStencilState {
+                format: TextureFormat::Depth32Float,
+                depth_write_enabled: true,
+                depth_compare: shader_defs.depth_compare(CompareFunction::Greater),
+                stencil: default(),
+                bias: default(),
+            }),
+            multisample: MultisampleState::default(),
+            push_constant_ranges: Vec::new(),
+        };
+        let pipeline_id = world
+            .resource_mut::<PipelineCache>()
+            .queue_render_pipeline(descriptor);
+
+        Self {
+            cuboids_layout,
+            pipeline_id,
+        }
+    }
+}
+
+/// Picking passes of the current frame, and the readbacks of earlier ones.
+#[derive(Default, Resource)]
+pub(crate) struct CuboidsPicking {
+    views: HashMap<Entity, ViewPicking>,
+    readbacks: Vec<PickingReadback>,
+}
+
+struct ViewPicking {
+    pixel: UVec2,
+    color_texture: Texture,
+    color_view: TextureView,
+    depth_view: TextureView,
+    batches: Vec<PickingBatch>,
+    readback: PickingReadback,
+}
+
+struct PickingBatch {
+    transform_index: u32,
+    material_index: u32,
+    chunks: Vec<PickingChunk>,
+}
+
+struct PickingChunk {
+    bind_group: BindGroup,
+    num_instances: u32,
+    /// Kept alive for the bind group.
+    _uniform: UniformBuffer<GpuPickingChunk>,
+}
+
+struct PickingReadback {
+    buffer: Buffer,
+    /// The batch entity of each transform index; `None` for occluders.
+    entities: Vec<Option<Entity>>,
+    state: Arc<AtomicU8>,
+}
+
+const READBACK_PENDING: u8 = 0;
+const READBACK_MAPPED: u8 = 1;
+const READBACK_FAILED: u8 = 2;
+
+impl PickingReadback {
+    fn map(&self) {
+        let state = self.state.clone();
+        self.buffer
+            .slice(..)
+            .map_async(MapMode::Read, move |result| {
+                // A failed mapping is treated as a miss.
+                let new_state = match result {
+                    Ok(()) => READBACK_MAPPED,
+                    Err(err) => {
+                        warn!("Failed to read back cuboid picking result: {err}");
+                        READBACK_FAILED
+                    }
+                };
+                state.store(new_state, Ordering::Release);
+            });
+    }
+
+    fn read(&self) -> Option<CuboidPickedEvent> {
+        let [batch, index] = {
+            let data = self.buffer.slice(..).get_mapped_range();
+            let ids: &[u32] = cast_slice(&data);
+            [ids[0], ids[1]]
+        };
+        self.buffer.unmap();
+        // Zero is the clear value, so batches start at one.
+        let entity = (*self.entities.get(batch.checked_sub(1)? as usize)?)?;
+        // User data and scalars are looked up in the main world.
+        Some(CuboidPickedEvent {
+            entity,
+            index: index as usize,
+            user_data: 0,
+            scalar: None,
+        })
+    }
+}
+
+fn create_target(
+    render_device: &RenderDevice,
+    label: &'static str,
+    size: UVec2,
+    format: TextureFormat,
+    usage: TextureUsages,
+) -> Texture {
+    render_device.create_texture(&TextureDescriptor {
+        label: Some(label),
+        size: Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format,
+        usage,
+        view_formats: &[],
+    })
+}
+
+pub(crate) fn prepare_cuboids_picking(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    pipeline: Res<CuboidsPickingPipeline>,
+    requests: Res<GpuPickingRequests>,
+    buffer_cache: Res<CuboidBufferCache>,
+    mut picking: ResMut<CuboidsPicking>,
+    views: Query<(&ExtractedView, &VisibleEntities)>,
+) {
+    picking.views.clear();
+    for request in requests.iter() {
+        let Ok((view, visible_entities)) = views.get(request.camera) else {
+            continue;
+        };
+        let size = view.viewport.zw();
+        if request.pixel.cmpge(size).any() || picking.views.contains_key(&request.camera) {
+            continue;
+        }
+
+        // Targets are only allocated for the frames that pick.
+        let color_texture = create_target(
+            &render_device,
+            "cuboids_picking_texture",
+            size,
+            PICKING_FORMAT,
+            TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+        );
+        let depth_texture = create_target(
+            &render_device,
+            "cuboids_picking_depth_texture",
+            size,
+            TextureFormat::Depth32Float,
+            TextureUsages::RENDER_ATTACHMENT,
+        );
+
+        let mut entities = Vec::new();
+        let mut batches = Vec::new();
+        // Only batches this camera draws, which respects `RenderLayers`.
+        for &entity in &visible_entities.entities {
+            let Some(entry) = buffer_cache.entries.get(&entity) else {
+                continue;
+            };
+            // Gizmos would hide what's behind them, and can't be picked.
+            if !entry.enabled || entry.gizmo {
+                continue;
+            }
+            // Occluders are drawn, but never picked.
+            let index = entry.transform_index as usize;
+            if entities.len() <= index {
+                entities.resize(index + 1, None);
+            }
+            entities[index] = (!entry.occluder).then_some(entity);
+
+            let chunks = entry
+                .current()
+                .chunks
+                .iter()
+                .enumerate()
+                .map(|(i, chunk)| {
+                    let mut uniform = UniformBuffer::from(GpuPickingChunk {
+                        first_instance: (i * buffer_cache.max_chunk_instances) as u32,
+                    });
+                    uniform.write_buffer(&render_device, &render_queue);
+                    let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+                        label: Some("cuboids_picking_instances_bind_group"),
+                        layout: &pipeline.cuboids_layout,
+                        entries: &[
+                            BindGroupEntry {
+                                binding: 0,
+                                resource: chunk.instances_binding(),
+                            },
+                            BindGroupEntry {
+                                binding: 1,
+                                resource: chunk.rotations.binding().unwrap(),
+                            },
+                            BindGroupEntry {
+                                binding: 2,
+                                resource: uniform.binding().unwrap(),
+                            },
+                            BindGroupEntry {
+                                binding: 3,
+                                resource: chunk.hidden_mask.binding().unwrap(),
+                            },
+                            BindGroupEntry {
+                                binding: 4,
+                                resource: chunk.colors.binding().unwrap(),
+                            },
+                            BindGroupEntry {
+                                binding: 5,
+                                resource: chunk.user_data.binding().unwrap(),
+                            },
+                        ],
+                    });
+                    PickingChunk {
+                        bind_group,
+                        num_instances: chunk.len as u32,
+                        _uniform: uniform,
+                    }
+                })
+                .collect();
+            batches.push(PickingBatch {
+                transform_index: entry.transform_index,
+                material_index: entry.material_index,
+                chunks,
+            });
+        }
+
+        let readback = PickingReadback {
+            buffer: render_device.create_buffer(&BufferDescriptor {
+                label: Some("cuboids_picking_readback_buffer"),
+                size: PICKING_TEXEL_SIZE,
+                usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            }),
+            entities,
+            state: default(),
+        };
+        picking.views.insert(
+            request.camera,
+            ViewPicking {
+                pixel: request.pixel,
+                color_view: color_texture.create_view(&TextureViewDescriptor::default()),
+                depth_view: depth_texture.create_view(&TextureViewDescriptor::default()),
+                color_texture,
+                batches,
+                readback,
+            },
+        );
+    }
+}
+
+/// Maps the readbacks of this frame, and sends the results of earlier ones.
+///
+/// Runs after the render graph, since buffers can't be mapped while the
+/// commands that write them are pending.
+pub(crate) fn read_back_cuboids_picking(
+    render_device: Res<RenderDevice>,
+    results: Res<GpuPickingResults>,
+    mut picking: ResMut<CuboidsPicking>,
+) {
+    let CuboidsPicking { views, readbacks } = &mut *picking;
+    for (_, view_picking) in views.drain() {
+        view_picking.readback.map();
+        readbacks.push(view_picking.readback);
+    }
+
+    render_device.poll(Maintain::Poll);
+    readbacks.retain(|readback| match readback.state.load(Ordering::Acquire) {
+        READBACK_PENDING => true,
+        READBACK_MAPPED => {
+            if let Some(event) = readback.read() {
+                results.send(event);
+            }
+            false
+        }
+        _ => false,
+    });
+}
+
+/// Draws the instance ID of every cuboid that covers the picked pixel.
+pub(crate) struct CuboidsPickingNode {
+    view_query: QueryState<(
+        &'static ViewUniformOffset,
+        &'static CuboidsViewUniformOffset,
+    )>,
+}
+
+impl CuboidsPickingNode {
+    pub const IN_VIEW: &'static str = "view";
+
+    pub fn new(world: &mut World) -> Self {
+        Self {
+            view_query: QueryState::new(world),
+        }
+    }
+}
+
+impl Node for CuboidsPickingNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(Self::IN_VIEW, SlotType::Entity)]
+    }
+
+    fn update(&mut self, world: &mut World) {
+        self.view_query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+        let Some(view_picking) = world.resource::<CuboidsPicking>().views.get(&view_entity) else {
+            return Ok(());
+        };
+        let Ok((view_uniform_offset, cuboids_view_uniform_offset)) =
+            self.view_query.get_manual(world, view_entity)
+        else {
+            return Ok(());
+        };
+        let (
+            Some(view_bind_group),
+            Some(aux_bind_group),
+            Some(transforms_bind_group),
+            Some(pipeline),
+            Some(index_buffer),
+        ) = (
+            world
+                .resource::<ViewMeta>()
+                .view_bind_groups
+                .get(&view_entity),
+            world.resource::<AuxiliaryMeta>().bind_group.as_ref(),
+            world
+                .resource::<TransformsMeta>()
+                .transform_buffer_bind_group
+                .as_ref(),
+            world
+                .resource::<PipelineCache>()
+                .get_render_pipeline(world.resource::<CuboidsPickingPipeline>().pipeline_id),
+            world
+                .resource::<RenderAssets<CuboidsIndexBuffer>>()
+                .get(&CUBE_INDICES_HANDLE.typed()),
+        )
+        else {
+            return Ok(());
+        };
+
+        {
+            let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+                label: Some("cuboids_picking_pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &view_picking.color_view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(default()),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                    view: &view_picking.depth_view,
+                    depth_ops: Some(Operations {
+                        load: LoadOp::Clear(world.resource::<CuboidsShaderDefs>().far_depth()),
+                        store: false,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            // Only the picked pixel is rasterized.
+            let pixel = view_picking.pixel;
+            pass.set_scissor_rect(pixel.x, pixel.y, 1, 1);
+            pass.set_render_pipeline(pipeline);
+            pass.set_bind_group(
+                0,
+                view_bind_group,
+                &[view_uniform_offset.offset, cuboids_view_uniform_offset.0],
+            );
+            pass.set_bind_group(2, transforms_bind_group, &[]);
+            pass.set_index_buffer(index_buffer.buffer.slice(..), 0, index_buffer.index_format);
+            for batch in view_picking.batches.iter() {
+                let base_vertex = (batch.transform_index << TRANSFORM_INDEX_SHIFT) as i32;
+                pass.set_bind_group(1, aux_bind_group, &[batch.material_index]);
+                for chunk in batch.chunks.iter() {
+                    pass.set_bind_group(3, &chunk.bind_group, &[]);
+                    pass.draw_indexed(
+                        0..(CUBE_INDICES.len() as u32),
+                        base_vertex,
+                        0..chunk.num_instances,
+                    );
+                }
+            }
+        }
+
+        render_context.command_encoder().copy_texture_to_buffer(
+            ImageCopyTexture {
+                texture: &view_picking.color_texture,
+                mip_level: 0,
+                origin: Origin3d {
+                    x: view_picking.pixel.x,
+                    y: view_picking.pixel.y,
+                    z: 0,
+                },
+                aspect: TextureAspect::All,
+            },
+            ImageCopyBuffer {
+                buffer: &view_picking.readback.buffer,
+                layout: ImageDataLayout::default(),
+            },
+            Extent3d::default(),
+        );
+
+        Ok(())
+    }
+}
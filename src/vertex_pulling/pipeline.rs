@@ -1,6 +1,12 @@
-use crate::clipping_planes::GpuClippingPlaneRanges;
-use crate::{cuboids::CuboidsTransform, CuboidMaterial};
+use super::buffers::GpuCuboidsView;
+use crate::clipping_planes::{GpuClippingPlaneRanges, GpuClippingVolumes};
+use crate::{
+    colormap::GpuColormaps, cuboids::CuboidsTransform, material::GpuSlottedMaterials,
+    CuboidMaterial, CuboidsInstanceFormat, DepthMode, DEPTH_MODE_ALWAYS_ON_TOP, DEPTH_MODE_DEFAULT,
+    DEPTH_MODE_NO_WRITE,
+};
 
+use bevy::core_pipeline::prepass::NORMAL_PREPASS_FORMAT;
 use bevy::render::render_resource::ShaderDefVal;
 use bevy::render::texture::BevyDefault;
 use bevy::{
@@ -13,21 +19,31 @@ use bevy::{
             BlendState, BufferBindingType, BufferSize, CachedRenderPipelineId, ColorTargetState,
             ColorWrites, CompareFunction, DepthBiasState, DepthStencilState, FragmentState,
             FrontFace, MultisampleState, PipelineCache, PolygonMode, PrimitiveState,
-            RenderPipelineDescriptor, ShaderStages, ShaderType, StencilFaceState, StencilState,
-            TextureFormat, VertexState,
+            RenderPipelineDescriptor, SamplerBindingType, ShaderStages, ShaderType,
+            SpecializedRenderPipeline, SpecializedRenderPipelines, StencilFaceState, StencilState,
+            TextureFormat, TextureSampleType, TextureViewDimension, VertexState,
         },
         renderer::RenderDevice,
         view::ViewUniform,
     },
+    utils::HashSet,
 };
 
+/// Specializes the pipelines of the main and prepasses for each
+/// [`CuboidsPipelineKey`].
 #[derive(Resource)]
 pub(crate) struct CuboidsPipelines {
-    pub pipeline_id: CachedRenderPipelineId,
-    pub hdr_pipeline_id: CachedRenderPipelineId,
+    #[cfg(feature = "shadows")]
+    pub shadow_pipeline_id: CachedRenderPipelineId,
+    #[cfg(feature = "shadows")]
+    pub directional_shadow_pipeline_id: CachedRenderPipelineId,
+    shader_defs: CuboidsShaderDefs,
 
     pub aux_layout: BindGroupLayout,
     pub cuboids_layout: BindGroupLayout,
+    /// Instances without culling results, for passes that draw every instance.
+    /// The same as `cuboids_layout` without GPU culling.
+    pub unculled_cuboids_layout: BindGroupLayout,
     pub transforms_layout: BindGroupLayout,
     pub view_layout: BindGroupLayout,
 }
@@ -35,9 +51,37 @@ pub(crate) struct CuboidsPipelines {
 pub(crate) const VERTEX_PULLING_SHADER_HANDLE: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 17343092250772987267);
 
+pub(crate) const VERTEX_PULLING_SHADER_SOURCE: &str = include_str!("vertex_pulling.wgsl");
+
+/// The shader of [`VertexPullingRenderPlugin::shader`](crate::VertexPullingRenderPlugin::shader).
+#[derive(Resource)]
+pub(crate) struct CuboidsShaderOverride(pub Handle<Shader>);
+
+/// Copies the overriding shader over the built-in one whenever it's loaded or
+/// changes, so that every pipeline is rebuilt with it.
+pub(crate) fn override_cuboids_shader(
+    shader_override: Res<CuboidsShaderOverride>,
+    mut events: EventReader<AssetEvent<Shader>>,
+    mut shaders: ResMut<Assets<Shader>>,
+) {
+    let changed = events.iter().any(|event| match event {
+        AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+            *handle == shader_override.0
+        }
+        AssetEvent::Removed { .. } => false,
+    });
+    if !changed {
+        return;
+    }
+    if let Some(shader) = shaders.get(&shader_override.0).cloned() {
+        shaders.set_untracked(VERTEX_PULLING_SHADER_HANDLE, shader);
+    }
+}
+
 impl FromWorld for CuboidsPipelines {
     fn from_world(world: &mut World) -> Self {
         let render_device = world.resource::<RenderDevice>();
+        let max_clipping_planes = world.resource::<CuboidsShaderDefs>().max_clipping_planes;
 
         let view_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
             label: Some("cuboids_view_layout"),
@@ -45,7 +89,9 @@ impl FromWorld for CuboidsPipelines {
                 // View
                 BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+                    visibility: ShaderStages::VERTEX
+                        | ShaderStages::FRAGMENT
+                        | ShaderStages::COMPUTE,
                     ty: BindingType::Buffer {
                         ty: BufferBindingType::Uniform,
                         has_dynamic_offset: true,
@@ -53,54 +99,180 @@ impl FromWorld for CuboidsPipelines {
                     },
                     count: None,
                 },
-            ],
-        });
-
-        let aux_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-            label: Some("aux_layout"),
-            entries: &[
+                // Cuboids-specific view constants
                 BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+                    binding: 1,
+                    visibility: ShaderStages::VERTEX
+                        | ShaderStages::FRAGMENT
+                        | ShaderStages::COMPUTE,
                     ty: BindingType::Buffer {
                         ty: BufferBindingType::Uniform,
                         has_dynamic_offset: true,
-                        min_binding_size: Some(CuboidMaterial::min_size()),
+                        min_binding_size: Some(GpuCuboidsView::min_size()),
                     },
                     count: None,
                 },
+                // Clipping planes of the view, also read by the fragment
+                // shader for clipping caps.
                 BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStages::VERTEX,
+                    binding: 2,
+                    visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
                     ty: BindingType::Buffer {
                         ty: BufferBindingType::Uniform,
                         has_dynamic_offset: false,
-                        min_binding_size: Some(GpuClippingPlaneRanges::min_size()),
+                        min_binding_size: Some(GpuClippingPlaneRanges::uniform_size(
+                            max_clipping_planes,
+                        )),
                     },
                     count: None,
                 },
             ],
         });
 
+        #[allow(unused_mut)]
+        let mut aux_entries = vec![
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: Some(CuboidMaterial::min_size()),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 5,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(GpuClippingVolumes::min_size()),
+                },
+                count: None,
+            },
+            // Materials of `CuboidMaterialSlots`
+            BindGroupLayoutEntry {
+                binding: 6,
+                visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(GpuSlottedMaterials::min_size()),
+                },
+                count: None,
+            },
+            // `CuboidsAtlas`
+            BindGroupLayoutEntry {
+                binding: 7,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: true },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 8,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                count: None,
+            },
+            // `CuboidColormaps`
+            BindGroupLayoutEntry {
+                binding: 9,
+                visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(GpuColormaps::min_size()),
+                },
+                count: None,
+            },
+        ];
+        #[cfg(feature = "color_keyframes")]
+        {
+            use crate::color_keyframes::GpuColorKeyframesHeader;
+            aux_entries.push(BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(GpuColorKeyframesHeader::min_size()),
+                },
+                count: None,
+            });
+            aux_entries.push(BindGroupLayoutEntry {
+                binding: 3,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(0),
+                },
+                count: None,
+            });
+        }
+        #[cfg(feature = "lighting")]
+        {
+            use crate::lighting::GpuCuboidLights;
+            // Cuboids are lit per vertex, spheres per fragment.
+            aux_entries.push(BindGroupLayoutEntry {
+                binding: 4,
+                visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(GpuCuboidLights::min_size()),
+                },
+                count: None,
+            });
+        }
+        let aux_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("aux_layout"),
+            entries: &aux_entries,
+        });
+
+        let shader_defs = world.resource::<CuboidsShaderDefs>();
+
+        // Without storage buffers, transforms and instances are read from
+        // textures instead.
+        let data_texture_entry = BindGroupLayoutEntry {
+            binding: 0,
+            visibility: ShaderStages::VERTEX,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Uint,
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+
+        let transforms_entry = if shader_defs.data_textures {
+            data_texture_entry
+        } else {
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::VERTEX | ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    // All batch transforms live in one buffer.
+                    min_binding_size: Some(CuboidsTransform::min_size()),
+                },
+                count: None,
+            }
+        };
         let transforms_layout =
             render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
                 label: Some("transforms_layout"),
-                entries: &[BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::VERTEX,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: true,
-                        // We always have a single transform for each instance buffer.
-                        min_binding_size: Some(CuboidsTransform::min_size()),
-                    },
-                    count: None,
-                }],
+                entries: &[transforms_entry],
             });
 
-        let cuboids_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-            label: Some("cuboid_instances_layout"),
-            entries: &[BindGroupLayoutEntry {
+        let storage_cuboids_entries = [
+            BindGroupLayoutEntry {
                 binding: 0,
                 visibility: ShaderStages::VERTEX,
                 ty: BindingType::Buffer {
@@ -109,106 +281,392 @@ impl FromWorld for CuboidsPipelines {
                     min_binding_size: BufferSize::new(0),
                 },
                 count: None,
-            }],
-        });
+            },
+            // Rotations
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(0),
+                },
+                count: None,
+            },
+            // Hidden instance mask
+            BindGroupLayoutEntry {
+                binding: 3,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(0),
+                },
+                count: None,
+            },
+            // Colors
+            BindGroupLayoutEntry {
+                binding: 4,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(0),
+                },
+                count: None,
+            },
+            // User data
+            BindGroupLayoutEntry {
+                binding: 5,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(0),
+                },
+                count: None,
+            },
+        ];
+        let data_texture_cuboids_entries = [data_texture_entry];
+        let unculled_cuboids_entries: &[BindGroupLayoutEntry] = if shader_defs.data_textures {
+            &data_texture_cuboids_entries
+        } else {
+            &storage_cuboids_entries
+        };
+        let unculled_cuboids_layout =
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("cuboid_instances_layout"),
+                entries: unculled_cuboids_entries,
+            });
+        let cuboids_layout = if shader_defs.gpu_culling {
+            let mut cuboids_entries = unculled_cuboids_entries.to_vec();
+            // Visible instance indices, written by the culling pass.
+            cuboids_entries.push(BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(0),
+                },
+                count: None,
+            });
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("cuboid_culled_instances_layout"),
+                entries: &cuboids_entries,
+            })
+        } else {
+            unculled_cuboids_layout.clone()
+        };
 
-        let sample_count = world.resource::<Msaa>().samples();
-        let shader_defs = world.resource::<CuboidsShaderDefs>();
+        #[allow(unused_mut)]
+        let mut pipelines = Self {
+            #[cfg(feature = "shadows")]
+            shadow_pipeline_id: CachedRenderPipelineId::INVALID,
+            #[cfg(feature = "shadows")]
+            directional_shadow_pipeline_id: CachedRenderPipelineId::INVALID,
+            shader_defs: shader_defs.clone(),
+            view_layout,
+            aux_layout,
+            cuboids_layout,
+            unculled_cuboids_layout,
+            transforms_layout,
+        };
 
-        let layout = vec![
-            view_layout.clone(),
-            aux_layout.clone(),
-            transforms_layout.clone(),
-            cuboids_layout.clone(),
-        ];
-        let vertex = VertexState {
-            shader: VERTEX_PULLING_SHADER_HANDLE.typed(),
-            shader_defs: shader_defs.vertex.clone(),
-            entry_point: "vertex".into(),
-            buffers: vec![],
+        // Shadow maps only need depth. Directional lights clamp the depth of
+        // casters behind their near plane, like Bevy's own shadow pass.
+        #[cfg(feature = "shadows")]
+        {
+            let base_descriptor = pipelines.specialize(CuboidsPipelineKey {
+                pass: CuboidsPass::Opaque,
+                hdr: false,
+                samples: 1,
+                hook_shader: None,
+                depth_mode: DEPTH_MODE_DEFAULT,
+            });
+            let shadow_vertex_defs = pipelines.shader_defs.shadow_vertex();
+            let shadow_pipeline_descriptor = RenderPipelineDescriptor {
+                label: Some("cuboids_shadow_pipeline".into()),
+                layout: vec![
+                    pipelines.view_layout.clone(),
+                    pipelines.aux_layout.clone(),
+                    pipelines.transforms_layout.clone(),
+                    pipelines.unculled_cuboids_layout.clone(),
+                ],
+                vertex: VertexState {
+                    shader_defs: shadow_vertex_defs.clone(),
+                    ..base_descriptor.vertex.clone()
+                },
+                fragment: None,
+                depth_stencil: Some(DepthStencilState {
+                    format: bevy::pbr::SHADOW_FORMAT,
+                    depth_compare: CompareFunction::GreaterEqual,
+                    ..base_descriptor.depth_stencil.clone().unwrap()
+                }),
+                multisample: MultisampleState::default(),
+                ..base_descriptor.clone()
+            };
+            let mut directional_vertex_defs = shadow_vertex_defs;
+            directional_vertex_defs.push("DEPTH_CLAMP_ORTHO".into());
+            let directional_shadow_pipeline_descriptor = RenderPipelineDescriptor {
+                label: Some("cuboids_directional_shadow_pipeline".into()),
+                vertex: VertexState {
+                    shader_defs: directional_vertex_defs,
+                    ..base_descriptor.vertex
+                },
+                ..shadow_pipeline_descriptor.clone()
+            };
+
+            let pipeline_cache = world.resource_mut::<PipelineCache>();
+            pipelines.shadow_pipeline_id =
+                pipeline_cache.queue_render_pipeline(shadow_pipeline_descriptor);
+            pipelines.directional_shadow_pipeline_id =
+                pipeline_cache.queue_render_pipeline(directional_shadow_pipeline_descriptor);
+        }
+
+        pipelines
+    }
+}
+
+/// The pass that a cuboid pipeline draws in.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum CuboidsPass {
+    Opaque,
+    /// Like `Opaque`, but only writes depth.
+    Occluder,
+    Transparent,
+    /// Writes depth for views with a `DepthPrepass`.
+    Prepass,
+    /// Writes depth and normals for views with a `NormalPrepass`.
+    NormalPrepass,
+    /// Blends the parts of [`DEPTH_MODE_XRAY`](crate::DEPTH_MODE_XRAY) batches
+    /// that are hidden by other geometry over it.
+    XRay,
+}
+
+/// Everything about a view and batch that the cuboid pipelines depend on, so
+/// that changing `Msaa` or `Camera::hdr` at runtime specializes new pipelines.
+///
+/// The color mode is not part of the key, because batches with
+/// [`CuboidMaterialSlots`](crate::CuboidMaterialSlots) mix color modes within
+/// a draw.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct CuboidsPipelineKey {
+    pub pass: CuboidsPass,
+    pub hdr: bool,
+    pub samples: u32,
+    /// The cuboid shader with a [`CuboidShaderHook`](crate::CuboidShaderHook)
+    /// appended, only used in the `Opaque`, `Occluder`, `Transparent` and
+    /// `XRay` passes.
+    pub hook_shader: Option<Handle<Shader>>,
+    /// [`CuboidMaterial::depth_mode`] of the batch, only used in the `Opaque`,
+    /// `Occluder` and `Transparent` passes.
+    pub depth_mode: DepthMode,
+}
+
+impl SpecializedRenderPipeline for CuboidsPipelines {
+    type Key = CuboidsPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let shader_defs = &self.shader_defs;
+        let color_format = if key.hdr {
+            TextureFormat::Rgba16Float
+        } else {
+            TextureFormat::bevy_default()
         };
-        let fragment_target = |texture_format| FragmentState {
-            shader: VERTEX_PULLING_SHADER_HANDLE.typed(),
-            shader_defs: shader_defs.fragment.clone(),
-            entry_point: "fragment".into(),
-            targets: vec![Some(ColorTargetState {
-                format: texture_format,
-                blend: Some(BlendState::REPLACE),
-                write_mask: ColorWrites::ALL,
-            })],
+        let color_target = |blend, write_mask| {
+            Some(ColorTargetState {
+                format: color_format,
+                blend: Some(blend),
+                write_mask,
+            })
         };
-        let primitive = PrimitiveState {
-            front_face: FrontFace::Ccw,
-            cull_mode: None,
-            unclipped_depth: false,
-            polygon_mode: PolygonMode::Fill,
-            conservative: false,
-            topology: PrimitiveTopology::TriangleList,
-            strip_index_format: None,
+        let (label, targets) = match key.pass {
+            CuboidsPass::Opaque => (
+                "cuboids_pipeline",
+                vec![color_target(BlendState::REPLACE, ColorWrites::ALL)],
+            ),
+            CuboidsPass::Occluder => (
+                "cuboids_occluder_pipeline",
+                vec![color_target(BlendState::REPLACE, ColorWrites::empty())],
+            ),
+            // Transparent cuboids are blended over the opaque pass, without
+            // hiding each other.
+            CuboidsPass::Transparent => (
+                "cuboids_transparent_pipeline",
+                vec![color_target(BlendState::ALPHA_BLENDING, ColorWrites::ALL)],
+            ),
+            // Views without a `NormalPrepass` have no color target.
+            CuboidsPass::Prepass => ("cuboids_prepass_pipeline", vec![]),
+            CuboidsPass::NormalPrepass => (
+                "cuboids_normal_prepass_pipeline",
+                vec![Some(ColorTargetState {
+                    format: NORMAL_PREPASS_FORMAT,
+                    blend: Some(BlendState::REPLACE),
+                    write_mask: ColorWrites::ALL,
+                })],
+            ),
+            CuboidsPass::XRay => (
+                "cuboids_xray_pipeline",
+                vec![color_target(BlendState::ALPHA_BLENDING, ColorWrites::ALL)],
+            ),
         };
-        let depth_stencil = Some(DepthStencilState {
-            format: TextureFormat::Depth32Float,
-            depth_write_enabled: true,
-            depth_compare: CompareFunction::Greater,
-            stencil: StencilState {
-                front: StencilFaceState::IGNORE,
-                back: StencilFaceState::IGNORE,
-                read_mask: 0,
-                write_mask: 0,
-            },
-            bias: DepthBiasState {
-                constant: 0,
-                slope_scale: 0.0,
-                clamp: 0.0,
-            },
-        });
-        let multisample = MultisampleState {
-            count: sample_count,
-            mask: !0,
-            alpha_to_coverage_enabled: false,
+
+        // Prepasses draw every instance, since they run before the culling
+        // pass.
+        let prepass = matches!(key.pass, CuboidsPass::Prepass | CuboidsPass::NormalPrepass);
+        let (cuboids_layout, vertex_defs, mut fragment_defs, fragment_entry_point) = if prepass {
+            (
+                self.unculled_cuboids_layout.clone(),
+                shader_defs.prepass_vertex(),
+                shader_defs.prepass_fragment(),
+                "prepass_fragment",
+            )
+        } else {
+            (
+                self.cuboids_layout.clone(),
+                shader_defs.vertex.clone(),
+                shader_defs.fragment.clone(),
+                "fragment",
+            )
         };
+        let mut fragment_shader = VERTEX_PULLING_SHADER_HANDLE.typed();
+        if let Some(hook_shader) = key.hook_shader.filter(|_| !prepass) {
+            fragment_shader = hook_shader;
+            fragment_defs.push("CUBOID_SHADER_HOOK".into());
+        }
+        if key.pass == CuboidsPass::XRay {
+            fragment_defs.push("XRAY".into());
+        }
 
-        let pipeline_descriptor = RenderPipelineDescriptor {
-            label: Some("cuboids_pipeline".into()),
-            layout: layout.clone(),
-            vertex: vertex.clone(),
-            fragment: Some(fragment_target(TextureFormat::bevy_default())),
-            primitive,
-            depth_stencil: depth_stencil.clone(),
-            multisample,
-            push_constant_ranges: Vec::new(),
+        // Depth is reversed, so hidden fragments are the ones with less depth
+        // than the depth buffer, unless the plugin is set to forward Z.
+        let depth_write_enabled = match key.pass {
+            CuboidsPass::Transparent | CuboidsPass::XRay => false,
+            _ => !matches!(
+                key.depth_mode,
+                DEPTH_MODE_NO_WRITE | DEPTH_MODE_ALWAYS_ON_TOP
+            ),
         };
+        let depth_compare = self.shader_defs.depth_compare(match key.pass {
+            CuboidsPass::XRay => CompareFunction::Less,
+            _ if key.depth_mode == DEPTH_MODE_ALWAYS_ON_TOP => CompareFunction::Always,
+            _ => CompareFunction::GreaterEqual,
+        });
 
-        let hdr_pipeline_descriptor = RenderPipelineDescriptor {
-            label: Some("cuboids_hdr_pipeline".into()),
-            layout,
-            vertex,
-            fragment: Some(fragment_target(TextureFormat::Rgba16Float)),
-            primitive,
-            depth_stencil,
-            multisample,
+        RenderPipelineDescriptor {
+            label: Some(label.into()),
+            layout: vec![
+                self.view_layout.clone(),
+                self.aux_layout.clone(),
+                self.transforms_layout.clone(),
+                cuboids_layout,
+            ],
+            vertex: VertexState {
+                shader: VERTEX_PULLING_SHADER_HANDLE.typed(),
+                shader_defs: vertex_defs,
+                entry_point: "vertex".into(),
+                buffers: vec![],
+            },
+            fragment: Some(FragmentState {
+                shader: fragment_shader,
+                shader_defs: fragment_defs,
+                entry_point: fragment_entry_point.into(),
+                targets,
+            }),
+            primitive: PrimitiveState {
+                front_face: FrontFace::Ccw,
+                cull_mode: None,
+                unclipped_depth: false,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+                topology: PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+            },
+            // Fragments that were already written by a prepass still pass.
+            depth_stencil: Some(DepthStencilState {
+                format: TextureFormat::Depth32Float,
+                depth_write_enabled,
+                depth_compare,
+                stencil: StencilState {
+                    front: StencilFaceState::IGNORE,
+                    back: StencilFaceState::IGNORE,
+                    read_mask: 0,
+                    write_mask: 0,
+                },
+                bias: DepthBiasState {
+                    constant: 0,
+                    slope_scale: 0.0,
+                    clamp: 0.0,
+                },
+            }),
+            multisample: MultisampleState {
+                count: key.samples,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
             push_constant_ranges: Vec::new(),
-        };
+        }
+    }
+}
 
-        let pipeline_cache = world.resource_mut::<PipelineCache>();
-        let pipeline_id = pipeline_cache.queue_render_pipeline(pipeline_descriptor);
-        let hdr_pipeline_id = pipeline_cache.queue_render_pipeline(hdr_pipeline_descriptor);
+impl CuboidsPipelines {
+    /// The pipelines that are never specialized.
+    pub fn ids(&self) -> Vec<CachedRenderPipelineId> {
+        #[allow(unused_mut)]
+        let mut ids = Vec::new();
+        #[cfg(feature = "shadows")]
+        ids.extend([self.shadow_pipeline_id, self.directional_shadow_pipeline_id]);
+        ids
+    }
+}
 
+/// [`SpecializedRenderPipelines`] that remember every pipeline they queued, so
+/// that compilation errors can be reported.
+#[derive(Resource)]
+pub(crate) struct TrackedSpecializedPipelines<S: SpecializedRenderPipeline> {
+    pipelines: SpecializedRenderPipelines<S>,
+    ids: HashSet<CachedRenderPipelineId>,
+}
+
+impl<S: SpecializedRenderPipeline> Default for TrackedSpecializedPipelines<S> {
+    fn default() -> Self {
         Self {
-            pipeline_id,
-            hdr_pipeline_id,
-            view_layout,
-            aux_layout,
-            cuboids_layout,
-            transforms_layout,
+            pipelines: default(),
+            ids: default(),
         }
     }
 }
 
+impl<S: SpecializedRenderPipeline> TrackedSpecializedPipelines<S> {
+    pub fn specialize(
+        &mut self,
+        cache: &PipelineCache,
+        pipeline: &S,
+        key: S::Key,
+    ) -> CachedRenderPipelineId {
+        let id = self.pipelines.specialize(cache, pipeline, key);
+        self.ids.insert(id);
+        id
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = CachedRenderPipelineId> + '_ {
+        self.ids.iter().copied()
+    }
+}
+
 #[derive(Clone, Default, Resource)]
 pub(crate) struct CuboidsShaderDefs {
     pub vertex: Vec<ShaderDefVal>,
     pub fragment: Vec<ShaderDefVal>,
+    pub gpu_culling: bool,
+    pub max_clipping_planes: usize,
+    /// Instances and transforms are bound as textures, for devices without
+    /// storage buffers.
+    pub data_textures: bool,
+    pub instance_format: CuboidsInstanceFormat,
+    /// See [`DepthConvention::ForwardZ`](crate::DepthConvention::ForwardZ).
+    pub forward_z: bool,
 }
 
 impl CuboidsShaderDefs {
@@ -216,4 +674,128 @@ impl CuboidsShaderDefs {
         self.vertex.push("OUTLINES".into());
         self.fragment.push("OUTLINES".into());
     }
+
+    /// Sizes the clipping plane array. Must be called exactly once.
+    pub fn set_max_clipping_planes(&mut self, max_clipping_planes: usize) {
+        let def = ShaderDefVal::UInt("MAX_CLIPPING_PLANES".into(), max_clipping_planes as u32);
+        self.vertex.push(def.clone());
+        self.fragment.push(def);
+        self.max_clipping_planes = max_clipping_planes;
+    }
+
+    pub fn enable_clipping_caps(&mut self) {
+        self.vertex.push("CLIPPING_CAPS".into());
+        self.fragment.push("CLIPPING_CAPS".into());
+    }
+
+    pub fn enable_data_textures(&mut self) {
+        self.vertex.push("DATA_TEXTURES".into());
+        self.data_textures = true;
+    }
+
+    pub fn enable_gpu_culling(&mut self) {
+        self.vertex.push("GPU_CULLING".into());
+        self.gpu_culling = true;
+    }
+
+    pub fn enable_forward_z(&mut self) {
+        self.vertex.push("FORWARD_Z".into());
+        self.forward_z = true;
+    }
+
+    /// `reverse_z`, or the same test with forward Z, where nearer fragments
+    /// have less depth.
+    pub fn depth_compare(&self, reverse_z: CompareFunction) -> CompareFunction {
+        if !self.forward_z {
+            return reverse_z;
+        }
+        match reverse_z {
+            CompareFunction::Less => CompareFunction::Greater,
+            CompareFunction::LessEqual => CompareFunction::GreaterEqual,
+            CompareFunction::Greater => CompareFunction::Less,
+            CompareFunction::GreaterEqual => CompareFunction::LessEqual,
+            other => other,
+        }
+    }
+
+    /// The depth that the depth textures of cuboid passes are cleared to.
+    pub fn far_depth(&self) -> f32 {
+        if self.forward_z {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    pub fn set_instance_format(&mut self, format: CuboidsInstanceFormat) {
+        self.vertex.extend(instance_format_defs(format));
+        self.instance_format = format;
+    }
+
+    /// Shader definitions for the culling pass, which reads instances like
+    /// the vertex shader.
+    pub fn culling(&self) -> Vec<ShaderDefVal> {
+        instance_format_defs(self.instance_format)
+    }
+
+    /// Vertex shader definitions for passes that draw every instance.
+    pub fn unculled_vertex(&self) -> Vec<ShaderDefVal> {
+        let gpu_culling: ShaderDefVal = "GPU_CULLING".into();
+        self.vertex
+            .iter()
+            .filter(|&d| d != &gpu_culling)
+            .cloned()
+            .collect()
+    }
+
+    /// Vertex shader definitions for shadow maps, which Bevy always renders
+    /// with reverse Z.
+    #[cfg(feature = "shadows")]
+    pub fn shadow_vertex(&self) -> Vec<ShaderDefVal> {
+        let forward_z: ShaderDefVal = "FORWARD_Z".into();
+        let mut defs = self.unculled_vertex();
+        defs.retain(|d| d != &forward_z);
+        defs.push("SHADOW".into());
+        defs
+    }
+
+    /// Shader definitions for the depth and normal prepasses.
+    pub fn prepass_vertex(&self) -> Vec<ShaderDefVal> {
+        let mut defs = self.unculled_vertex();
+        defs.push("PREPASS".into());
+        defs
+    }
+
+    pub fn prepass_fragment(&self) -> Vec<ShaderDefVal> {
+        let mut defs = self.fragment.clone();
+        defs.push("PREPASS".into());
+        defs
+    }
+
+    #[cfg(feature = "color_keyframes")]
+    pub fn enable_color_keyframes(&mut self) {
+        self.vertex.push("COLOR_KEYFRAMES".into());
+    }
+
+    #[cfg(feature = "lighting")]
+    pub fn enable_lighting(&mut self) {
+        self.vertex.push("LIGHTING".into());
+        self.fragment.push("LIGHTING".into());
+    }
+
+    #[cfg(feature = "fog")]
+    pub fn enable_fog(&mut self) {
+        self.vertex.push("FOG".into());
+        self.fragment.push("FOG".into());
+    }
+}
+
+fn instance_format_defs(format: CuboidsInstanceFormat) -> Vec<ShaderDefVal> {
+    match format {
+        CuboidsInstanceFormat::Full => Vec::new(),
+        CuboidsInstanceFormat::Half => vec!["PACKED_INSTANCES".into(), "HALF_INSTANCES".into()],
+        CuboidsInstanceFormat::Quantized => {
+            vec!["PACKED_INSTANCES".into(), "QUANTIZED_INSTANCES".into()]
+        }
+    }
 }
@@ -1,34 +1,553 @@
 use super::buffers::*;
-use super::cuboid_cache::CuboidBufferCache;
-use super::draw::{AuxiliaryMeta, DrawCuboids, TransformsMeta, ViewMeta};
-use super::extract::{extract_clipping_planes, extract_cuboids};
-use super::pipeline::{CuboidsPipelines, CuboidsShaderDefs, VERTEX_PULLING_SHADER_HANDLE};
+use super::compute_source::{
+    prepare_cuboid_compute_sources, CuboidsComputeSourceChunks, CuboidsComputeSourcePipelines,
+    CuboidsComputeSourcesNode, CUBOIDS_COMPUTE_SOURCES_NODE,
+};
+use super::contour::{
+    extract_cuboids_contour, prepare_cuboids_contour_textures, queue_cuboids_contour,
+    CuboidsContourMask, CuboidsContourNode, CuboidsContourPipelines, CuboidsContourSettings,
+    CONTOUR_SHADER_HANDLE, CUBOIDS_CONTOUR_NODE,
+};
+use super::cuboid_cache::{
+    max_chunk_instances, CuboidBufferCache, InstanceBuffering, DYNAMIC_INSTANCE_BUFFER_COUNT,
+};
+use super::culling::{
+    max_culled_chunk_instances, prepare_cuboids_culling, CuboidsCullingCache, CuboidsCullingNode,
+    CuboidsCullingPipeline, CUBOIDS_CULLING_NODE, CULLING_SHADER_HANDLE,
+};
+use super::culling_stats::{
+    prepare_cuboids_culling_stats, read_back_cuboids_culling_stats, CuboidsCullingStats,
+    CuboidsCullingStatsNode, CUBOIDS_CULLING_STATS_NODE,
+};
+use super::data_texture::{DataTexture, INSTANCE_TEXELS};
+use super::draw::{
+    AuxiliaryMeta, DrawCuboids, DrawCuboidsMultiDraw, DrawCuboidsPrepass, TransformsMeta, ViewMeta,
+};
+use super::extract::{
+    extract_clipping_planes, extract_clipping_volumes, extract_cuboids, extract_view_clipping,
+};
+use super::multi_draw::{prepare_cuboids_multi_draw, CuboidsMultiDraw};
+use super::occlusion::{
+    enable_camera_depth_binding, prepare_occlusion_culling, CuboidsDepthPyramidNode,
+    DepthPyramidPipelines, OcclusionCullingSettings, CUBOIDS_DEPTH_PYRAMID_NODE,
+    DEPTH_PYRAMID_SHADER_HANDLE,
+};
+use super::oit::{
+    extract_cuboids_oit_phases, prepare_cuboids_oit_textures, queue_cuboids_oit, CuboidsOit,
+    CuboidsOitNode, CuboidsOitPipelines, CUBOIDS_OIT_NODE, OIT_COMPOSITE_SHADER_HANDLE,
+};
+use super::overlay::{
+    extract_cuboids_overlay_phases, CuboidsOverlay, CuboidsOverlayNode, CUBOIDS_OVERLAY_NODE,
+};
+use super::picking::{
+    prepare_cuboids_picking, read_back_cuboids_picking, CuboidsPicking, CuboidsPickingNode,
+    CuboidsPickingPipeline, CUBOIDS_PICKING_NODE,
+};
+use super::pipeline::{
+    override_cuboids_shader, CuboidsPipelines, CuboidsShaderDefs, CuboidsShaderOverride,
+    TrackedSpecializedPipelines, VERTEX_PULLING_SHADER_HANDLE,
+};
 use super::prepare::{
     prepare_auxiliary_bind_group, prepare_clipping_planes, prepare_cuboid_transforms,
-    prepare_cuboids, prepare_cuboids_view_bind_group, prepare_materials,
+    prepare_cuboids, prepare_cuboids_view_bind_group, prepare_cuboids_view_uniforms,
+    prepare_materials,
+};
+use super::primitives::{
+    extract_primitives, prepare_primitives, queue_primitives, DrawPrimitives, PrimitiveBatch,
+    PrimitiveBufferCache, PrimitivePipelines,
+};
+use super::queue::{
+    queue_cuboids, queue_cuboids_prepass, report_cuboids_draw_stats, report_pipeline_errors,
+};
+use super::shader_hook::{extract_cuboid_shader_hooks, CuboidsHookShaders};
+use super::timestamps::{
+    prepare_cuboids_timestamps, read_back_cuboids_timestamps, CuboidsDrawTimestampNode,
+    CuboidsTimestamps, CuboidsTimestampsNode, CUBOIDS_DRAW_BEGIN_NODE, CUBOIDS_DRAW_END_NODE,
+    CUBOIDS_TIMESTAMPS_NODE,
+};
+use crate::clipping_planes::{
+    update_clipping_plane_gizmos, update_clipping_plane_sweeps, update_clipping_plane_tweens,
+    ClippingPlaneGizmos, ClippingPlaneSweepEvent, GpuClippingPlaneRanges,
+};
+use crate::cuboids::{
+    clear_cuboids_edits, send_cuboids_uploaded, update_cuboids_aabbs, validate_cuboids,
+    CuboidsUploads,
 };
-use super::queue::queue_cuboids;
-use crate::CuboidMaterialMap;
-use bevy::core_pipeline::core_3d::Opaque3d;
+use crate::cuboids_animation::update_cuboids_animation_aabbs;
+use crate::cuboids_asset::update_cuboids_from_assets;
+use crate::cuboids_builder::apply_pending_cuboids;
+use crate::cuboids_commands::{apply_cuboids_commands, CuboidsCommands};
+use crate::cylinders::update_cylinders_aabbs;
+use crate::draw_stats::{
+    setup_cuboids_draw_stats_diagnostics, update_cuboids_draw_stats, RenderedCuboidsDrawStats,
+};
+use crate::effects::finish_cuboid_spawn_animations;
+use crate::error::send_cuboids_errors;
+use crate::lod::select_cuboids_lod_levels;
+use crate::material::InternalCuboidMaterials;
+use crate::mesh_instances::update_mesh_instances_aabbs;
+use crate::picking::{
+    clear_gpu_picking_requests, pick_cuboids, request_gpu_pick_on_click, send_cuboid_hovers,
+    send_gpu_picks, GpuPickingRequests, GpuPickingResults,
+};
+use crate::scalar_range::{update_auto_scalar_ranges, update_cuboid_color_legends};
+use crate::selection::{
+    select_picked_cuboids, update_cuboid_selection_highlights, CuboidSelection,
+};
+use crate::shader_hook::{add_cuboid_shader_hook_shaders, CuboidShaderHookShaders};
+use crate::spheres::update_spheres_aabbs;
+use crate::transform_interpolation::{
+    count_cuboids_fixed_steps, interpolate_cuboids_transforms, CuboidsFixedSteps,
+};
+use crate::{
+    CuboidColorLegends, CuboidColormaps, CuboidEffects, CuboidHoverEvent, CuboidHoverSettings,
+    CuboidMaterialMap, CuboidPickedEvent, CuboidsAnimation, CuboidsAsset, CuboidsAssetLoader,
+    CuboidsAtlas, CuboidsBufferEvent, CuboidsComputeSource, CuboidsDrawStats, CuboidsError,
+    CuboidsErrors, CuboidsLod, CuboidsSpawnEvent, CuboidsUploadedEvent, Cylinders, MeshInstances,
+    Spheres, MAX_CLIPPING_PLANES,
+};
+use bevy::asset::load_internal_asset;
+use bevy::core_pipeline::core_3d::{self, AlphaMask3d, Opaque3d, Transparent3d};
+use bevy::core_pipeline::prepass::Opaque3dPrepass;
 use bevy::prelude::*;
-use bevy::render::view::ViewSet;
+use bevy::render::extract_component::ExtractComponentPlugin;
+use bevy::render::extract_resource::ExtractResourcePlugin;
+use bevy::render::main_graph;
+use bevy::render::render_graph::RenderGraph;
+use bevy::render::render_resource::ShaderType;
+use bevy::render::renderer::{RenderDevice, RenderQueue};
+use bevy::render::settings::WgpuFeatures;
+use bevy::render::view::{ViewSet, VisibilitySystems};
 use bevy::render::RenderSet;
-use bevy::render::{render_phase::AddRenderCommand, RenderApp};
+use bevy::render::{
+    render_phase::{sort_phase_system, AddRenderCommand, DrawFunctions},
+    RenderApp,
+};
+use std::time::Duration;
 
-/// Renders the [`Cuboids`](crate::Cuboids) component using the "vertex pulling" technique.
+/// Renders the [`Cuboids`](crate::Cuboids),
+/// [`CuboidsAnimation`](crate::CuboidsAnimation), [`Spheres`](crate::Spheres),
+/// [`Cylinders`](crate::Cylinders) and [`MeshInstances`](crate::MeshInstances)
+/// components using the "vertex pulling" technique.
+///
+/// Everything is forward shaded, in Bevy's main opaque and transparent passes.
+/// Opaque cuboids also write the depth and normal prepasses, but the Bevy
+/// version this crate targets has no deferred renderer or G-buffer to write
+/// into. Nor does it have a motion vector prepass or temporal anti-aliasing,
+/// so there are no motion vectors to write either, and previous-frame batch
+/// transforms aren't kept.
+///
+/// Every camera is drawn as its own view, with its own uniforms, culling, LOD
+/// and clipping. Stereo and XR rendering work with a camera per eye, on
+/// separate targets or side by side in the
+/// [`Camera::viewport`](bevy::render::camera::Camera::viewport)s of a
+/// double-wide target. That Bevy version has no multiview render passes
+/// either, so the eyes can't share a single instanced draw.
+///
+/// Pipelines are specialized for the MSAA sample count and the HDR setting of
+/// each camera as they are used, so both can be changed at runtime.
 #[derive(Default)]
 pub struct VertexPullingRenderPlugin {
+    /// Darkens the edges of every face, about two pixels wide whatever the
+    /// distance, in perspective and orthographic views alike.
+    /// [`Cuboid::set_outline`](crate::Cuboid::set_outline) overrides this per
+    /// instance.
     pub outlines: bool,
+    /// Number of cuboids to allocate GPU instance memory for at startup, see
+    /// [`Self::prewarm`].
+    ///
+    /// This avoids a hitch when a large [`Cuboids`](crate::Cuboids) is first
+    /// rendered. A single buffer is allocated, and only one batch claims it:
+    /// the first batch extracted with at most this many instances, in no
+    /// particular order among batches spawned in the same frame. Later
+    /// batches, and larger ones, allocate their own buffers as usual. A
+    /// [`dynamic`](crate::Cuboids::dynamic) batch cycles to a buffer of its
+    /// own on its first upload, so only the uploads after that reuse the
+    /// claimed one. Unclaimed memory counts toward
+    /// [`gpu_memory_budget`](Self::gpu_memory_budget), and is freed before
+    /// any batch is evicted. Zero disables prewarming.
+    pub prewarm_cuboids: usize,
+    /// Upper bound on the number of cuboids bound per draw call.
+    ///
+    /// Larger batches are split into chunks of at most this many cuboids, which
+    /// are drawn separately. The bound is always clamped to what fits in the
+    /// device's `max_storage_buffer_binding_size` and `max_buffer_size`, and
+    /// with [`gpu_culling`](Self::gpu_culling) to its
+    /// `max_compute_workgroups_per_dimension` times 256, so `None` only chunks
+    /// when the device requires it.
+    pub max_cuboids_per_chunk: Option<usize>,
+    /// Upper bound on the number of [`ClippingPlaneRange`](crate::ClippingPlaneRange)
+    /// entities that apply at once, [`MAX_CLIPPING_PLANES`] if `None`.
+    ///
+    /// Every cuboid is tested against every plane, and the shaders and
+    /// clipping uniform are sized for this many planes. A slab range counts
+    /// as a single plane. The bound is clamped to what fits in the device's
+    /// `max_uniform_buffer_binding_size`.
+    pub max_clipping_planes: Option<usize>,
+    /// Cuts cuboids that cross a [`ClippingPlaneRange`](crate::ClippingPlaneRange)
+    /// boundary exactly at the plane, and fills the cut with a cap in the
+    /// instance color, so cross sections look solid.
+    ///
+    /// Without caps, each cuboid is kept or clipped as a whole, depending on
+    /// its centroid. Caps write fragment depth, which disables early depth
+    /// testing for all cuboids. Clipping volumes still use centroids.
+    pub clipping_caps: bool,
+    /// Culls instances against each view frustum in a compute pass, and only
+    /// draws the ones that are visible.
+    ///
+    /// This pays off for large scenes where most cuboids are off-screen. Culled
+    /// batches are drawn in an arbitrary instance order, which matters for
+    /// [`Cuboids::sort_back_to_front`](crate::Cuboids::sort_back_to_front).
+    pub gpu_culling: bool,
+    /// Reads back how many instances of each batch survive
+    /// [`gpu_culling`](Self::gpu_culling), into
+    /// [`CuboidsDrawStats::gpu_culling`](crate::CuboidsDrawStats::gpu_culling).
+    ///
+    /// Counts are copied out of the indirect draw arguments of every culled
+    /// chunk, and mapped asynchronously, so they never stall a frame.
+    pub gpu_culling_stats: bool,
+    /// Copies the instances of small static batches into shared buffers, and
+    /// draws all of them that a view sees with a single indirect draw per
+    /// material, instead of one draw per batch.
+    ///
+    /// This cuts the CPU cost of render commands when there are thousands of
+    /// small batches, like in chunked worlds. It applies to opaque batches
+    /// that aren't [`Cuboids::dynamic`](crate::Cuboids::dynamic), fit in one
+    /// chunk, and have no face colors or atlas tiles. The copies cost as much
+    /// GPU memory again, and are rebuilt whenever one of them changes.
+    ///
+    /// Needs a device with `MULTI_DRAW_INDIRECT` and `INDIRECT_FIRST_INSTANCE`,
+    /// and is ignored with a warning otherwise. Also ignored with
+    /// [`gpu_culling`](Self::gpu_culling) and with data textures.
+    pub multi_draw_indirect: bool,
+    /// Resolves clicks with an instance ID pass on the GPU, instead of
+    /// raycasting every instance on the CPU.
+    ///
+    /// This makes picking cost independent of the number of cuboids on the CPU,
+    /// at the cost of [`CuboidPickedEvent`]s arriving a few frames late. Also
+    /// enables [`GpuPickingRequests`](crate::GpuPickingRequests) for picking
+    /// arbitrary pixels.
+    pub gpu_picking: bool,
+    /// Streams full uploads of large static batches to the GPU over several
+    /// frames, in chunks of at most this many cuboids.
+    ///
+    /// Uploading a large [`Cuboids`](crate::Cuboids) at once can stall the
+    /// frame it was spawned or changed in. Batches with more than one chunk
+    /// instead upload a single chunk per frame, shared between all streamed
+    /// batches, and are only drawn once every chunk is uploaded. A
+    /// [`CuboidsUploadedEvent`] is sent when that happens. Smaller batches,
+    /// [`Cuboids::dynamic`](crate::Cuboids::dynamic) batches, and
+    /// in-place edits are uploaded right away. This also bounds
+    /// [`max_cuboids_per_chunk`](Self::max_cuboids_per_chunk).
+    pub streaming_chunk_cuboids: Option<usize>,
+    /// Upper bound on the GPU memory for instance data, in bytes.
+    ///
+    /// While it's exceeded, the instance buffers of the least recently drawn
+    /// [`Cuboids`](crate::Cuboids) are freed. Their CPU-side instances are
+    /// kept, and uploaded again once the batch is visible. Batches that are
+    /// currently visible are never evicted.
+    pub gpu_memory_budget: Option<u64>,
+    /// Bytes of instance data to upload per frame before holding back the
+    /// first uploads of further batches until later frames.
+    ///
+    /// Spawning many [`Cuboids`](crate::Cuboids) at once, e.g. when loading a
+    /// scene, otherwise creates and writes all of their buffers in one frame.
+    /// Visible batches are uploaded first, and a batch that is held back isn't
+    /// drawn until it is uploaded, when a [`CuboidsUploadedEvent`] is sent.
+    /// Batches already on the GPU and in-place edits are always uploaded
+    /// right away, and so is at least one new batch per frame, so the budget
+    /// can be exceeded by up to one batch. Batches that are
+    /// [streamed](Self::streaming_chunk_cuboids) keep their own pace.
+    pub upload_budget_bytes: Option<u64>,
+    /// Like [`upload_budget_bytes`](Self::upload_budget_bytes), for the CPU
+    /// time spent writing instance data each frame. Both can be set, and
+    /// uploads are held back once either is spent.
+    pub upload_budget_time: Option<Duration>,
+    /// Allocates the instance buffers of a batch with room for this many times
+    /// its instances, when it grows beyond its current buffers.
+    ///
+    /// Batches that grow a bit at a time, or with
+    /// [`Cuboids::append`](crate::Cuboids::append), then reuse their buffers
+    /// instead of reallocating and uploading all instances, and appends that
+    /// fit are uploaded on their own. The spare room costs GPU memory and is
+    /// never given back while the batch exists. Values of 1 or less, like the
+    /// default, allocate exactly what is needed. Ignored with data textures.
+    pub buffer_growth_factor: f32,
+    /// Number of instance buffers that [`Cuboids::dynamic`](crate::Cuboids::dynamic)
+    /// batches cycle through, 3 if `None`.
+    ///
+    /// Each full upload goes into the least recently written buffer, so that
+    /// it never waits on the GPU to finish drawing a previous frame from it.
+    /// Two buffers are enough unless the GPU is more than a frame behind, and
+    /// each costs as much GPU memory again. One buffer disables this, and
+    /// in-place edits like [`Cuboids::update_range`](crate::Cuboids::update_range)
+    /// always write into the current buffer.
+    pub dynamic_instance_buffers: Option<usize>,
+    /// Buffers batches that are fully uploaded this many frames in a row like
+    /// [`Cuboids::dynamic`](crate::Cuboids::dynamic) batches, even though
+    /// they aren't marked as such.
+    ///
+    /// They go back to a single buffer on their next upload once they haven't
+    /// been uploaded for as many frames. Disabled if `None`.
+    pub auto_dynamic_frames: Option<u32>,
+    /// How instance bounds are stored in the buffers that the shaders read.
+    ///
+    /// Compressed formats need less GPU memory and bandwidth for the same
+    /// instances, at the cost of precision. The CPU-side instances keep full
+    /// precision, so CPU picking and bounds are unaffected. Ignored with data
+    /// textures.
+    pub instance_format: CuboidsInstanceFormat,
+    /// Draws [`CuboidMaterial::alpha_blend`](crate::CuboidMaterial::alpha_blend)
+    /// materials with weighted blended order-independent transparency, instead
+    /// of sorted alpha blending.
+    ///
+    /// Transparent cuboids are accumulated into two extra targets per camera
+    /// after the main pass, and composited over it, so neither batches nor
+    /// instances need to be sorted. The result approximates the sorted blend,
+    /// and is exact for cuboids of the same color. Other transparent geometry
+    /// in the scene is not blended with the cuboids in depth order.
+    pub order_independent_transparency: bool,
+    /// Replaces the built-in cuboid shader with this one, e.g. a modified copy
+    /// of `vertex_pulling.wgsl` loaded through the asset server, which is
+    /// applied again whenever the asset is hot reloaded.
+    ///
+    /// The shader must keep the entry points, bindings and shader defs of the
+    /// built-in one. The culling pass, and the fragment shaders of
+    /// [`CuboidShaderHook`](crate::CuboidShaderHook)s, still use the built-in
+    /// source.
+    pub shader: Option<Handle<Shader>>,
+    /// Times the culling and main passes with GPU timestamp queries, into
+    /// [`CuboidsDrawStats::timings`](crate::CuboidsDrawStats::timings).
+    ///
+    /// Needs a device with `TIMESTAMP_QUERY`, and is ignored with a warning
+    /// otherwise.
+    pub gpu_timestamps: bool,
+    /// Which way the depth of the cameras' projections grows.
+    ///
+    /// Bevy's projections, infinite or not, use reverse Z, which is the
+    /// default. Custom projections with standard depth need
+    /// [`DepthConvention::ForwardZ`], which flips the depth tests and biases
+    /// of every cuboid pipeline.
+    pub depth_convention: DepthConvention,
+}
+
+/// The depth range of the cameras' projections, see
+/// [`VertexPullingRenderPlugin::depth_convention`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DepthConvention {
+    /// The near plane is at depth one, and the far plane, possibly at
+    /// infinity, at zero, like Bevy's own projections.
+    #[default]
+    ReverseZ,
+    /// The near plane is at depth zero, and the far plane at one.
+    ///
+    /// Bevy's meshes and shadow maps keep using reverse Z, so this only suits
+    /// apps that draw everything else with their own pipelines. Shadows cast
+    /// by cuboids still use reverse Z, and occlusion culling is ignored with
+    /// a warning.
+    ForwardZ,
+}
+
+/// The layout of each instance on the GPU, see
+/// [`VertexPullingRenderPlugin::instance_format`].
+///
+/// Colors, rotations and user data are stored apart from the bounds, and are
+/// the same in every format.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CuboidsInstanceFormat {
+    /// [`Cuboid`](crate::Cuboid) as is, 32 bytes per instance.
+    #[default]
+    Full,
+    /// `minimum` and `maximum` as half-precision floats, 16 bytes per
+    /// instance with the meta bits.
+    ///
+    /// Halves have 11 significant bits, so coordinates in a batch's local
+    /// space are only kept to within about 1/2048 of their magnitude, e.g. to
+    /// 0.5 near 1000. Local coordinates past 65504 overflow. Coordinates are
+    /// rounded to the nearest half, so instances that share a face still
+    /// meet.
+    Half,
+    /// `minimum` and `maximum` as 16-bit fixed-point steps between the
+    /// smallest and largest corners of the batch, 16 bytes per instance with
+    /// the meta bits.
+    ///
+    /// Unlike [`Self::Half`], precision is the same everywhere in the batch:
+    /// 1/65535 of its size along each axis, so batches shouldn't be much
+    /// larger than their finest detail times 65535. Corners on a regular grid
+    /// whose size divides evenly into 65535 steps are kept exactly. Edits and
+    /// appends that reach outside of the current bounds upload the whole
+    /// batch again.
+    Quantized,
+}
+
+impl VertexPullingRenderPlugin {
+    /// Allocates GPU instance memory for `max_cuboids` at startup, for apps
+    /// that know their largest batch at launch, see
+    /// [`Self::prewarm_cuboids`].
+    pub fn prewarm(mut self, max_cuboids: usize) -> Self {
+        self.prewarm_cuboids = max_cuboids;
+        self
+    }
 }
 
 impl Plugin for VertexPullingRenderPlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<CuboidMaterialMap>();
+        app.init_resource::<CuboidMaterialMap>()
+            .init_resource::<CuboidShaderHookShaders>()
+            .add_system(add_cuboid_shader_hook_shaders)
+            .init_resource::<ClippingPlaneGizmos>()
+            .init_resource::<InternalCuboidMaterials>()
+            .init_resource::<CuboidsLod>()
+            .add_plugin(ExtractResourcePlugin::<CuboidsLod>::default())
+            .init_resource::<CuboidEffects>()
+            .add_plugin(ExtractResourcePlugin::<CuboidEffects>::default())
+            .add_plugin(ExtractResourcePlugin::<CuboidsAtlas>::default())
+            .init_resource::<CuboidColormaps>()
+            .add_plugin(ExtractResourcePlugin::<CuboidColormaps>::default())
+            .init_resource::<CuboidColorLegends>()
+            .add_systems(
+                (update_auto_scalar_ranges, update_cuboid_color_legends)
+                    .chain()
+                    .in_base_set(CoreSet::PostUpdate),
+            )
+            .add_system(clear_cuboids_edits.in_base_set(CoreSet::First))
+            .init_resource::<CuboidsCommands>()
+            .init_resource::<CuboidSelection>()
+            .add_systems(
+                (select_picked_cuboids, update_cuboid_selection_highlights)
+                    .chain()
+                    .in_base_set(CoreSet::PostUpdate)
+                    .before(VisibilitySystems::CalculateBounds),
+            )
+            .add_system(apply_pending_cuboids.in_base_set(CoreSet::PreUpdate))
+            .add_system(
+                apply_cuboids_commands
+                    .in_base_set(CoreSet::PostUpdate)
+                    .before(VisibilitySystems::CalculateBounds),
+            )
+            .add_event::<CuboidsSpawnEvent>()
+            .add_system(
+                finish_cuboid_spawn_animations
+                    .in_base_set(CoreSet::PostUpdate)
+                    .after(apply_cuboids_commands)
+                    .before(VisibilitySystems::CalculateBounds),
+            )
+            .add_systems(
+                (
+                    update_cuboids_aabbs,
+                    update_spheres_aabbs,
+                    update_cylinders_aabbs,
+                    update_mesh_instances_aabbs,
+                    update_cuboids_animation_aabbs,
+                )
+                    .in_base_set(CoreSet::PostUpdate)
+                    .in_set(VisibilitySystems::CalculateBounds),
+            )
+            .add_system(
+                select_cuboids_lod_levels
+                    .in_base_set(CoreSet::PostUpdate)
+                    .after(VisibilitySystems::CheckVisibility),
+            )
+            .init_resource::<CuboidsFixedSteps>()
+            .add_system(count_cuboids_fixed_steps.in_schedule(CoreSchedule::FixedUpdate))
+            .add_system(
+                interpolate_cuboids_transforms
+                    .in_base_set(CoreSet::PostUpdate)
+                    .after(TransformSystem::TransformPropagate),
+            )
+            .add_system(update_clipping_plane_gizmos)
+            .add_system(update_clipping_plane_tweens)
+            .add_event::<ClippingPlaneSweepEvent>()
+            .add_system(update_clipping_plane_sweeps);
+        if cfg!(debug_assertions) {
+            app.add_system(
+                validate_cuboids
+                    .in_base_set(CoreSet::PostUpdate)
+                    .after(apply_cuboids_commands)
+                    .before(VisibilitySystems::CalculateBounds),
+            );
+        }
 
-        app.world.resource_mut::<Assets<Shader>>().set_untracked(
+        let errors = CuboidsErrors::default();
+        app.add_event::<CuboidsError>()
+            .insert_resource(errors.clone())
+            .add_system(send_cuboids_errors);
+
+        // Instances, colors, rotations, user data, hidden masks and transforms, plus the
+        // keyframe table. Devices with fewer storage buffers, like WebGL2,
+        // read instances and transforms from data textures instead, which
+        // GPU culling and picking don't support.
+        let render_device = app.sub_app(RenderApp).world.resource::<RenderDevice>();
+        let available_storage_buffers = render_device.limits().max_storage_buffers_per_shader_stage;
+        let num_keyframe_buffers = cfg!(feature = "color_keyframes") as u32;
+        let data_textures = available_storage_buffers < 6 + num_keyframe_buffers;
+        if data_textures && (self.gpu_culling || self.gpu_picking) {
+            warn!(
+                "Cuboids are read from data textures on this device, so GPU culling and picking \
+                 are disabled"
+            );
+        }
+        let gpu_culling = self.gpu_culling && !data_textures;
+        let gpu_picking = self.gpu_picking && !data_textures;
+        let required_storage_buffers = if data_textures {
+            num_keyframe_buffers
+        } else {
+            // Plus the visible indices.
+            6 + num_keyframe_buffers + gpu_culling as u32
+        };
+        if available_storage_buffers < required_storage_buffers {
+            errors.send(CuboidsError::UnsupportedDevice {
+                required: required_storage_buffers,
+                available: available_storage_buffers,
+            });
+        }
+
+        let uploads = CuboidsUploads::default();
+        app.add_event::<CuboidsUploadedEvent>()
+            .add_event::<CuboidsBufferEvent>()
+            .insert_resource(uploads.clone())
+            .add_system(send_cuboids_uploaded);
+
+        let draw_stats = RenderedCuboidsDrawStats::default();
+        app.init_resource::<CuboidsDrawStats>()
+            .insert_resource(draw_stats.clone())
+            .add_startup_system(setup_cuboids_draw_stats_diagnostics)
+            .add_system(update_cuboids_draw_stats);
+
+        app.add_event::<CuboidPickedEvent>()
+            .add_event::<CuboidHoverEvent>()
+            .init_resource::<CuboidHoverSettings>()
+            .add_system(send_cuboid_hovers);
+        let picking_results = GpuPickingResults::default();
+        if gpu_picking {
+            app.init_resource::<GpuPickingRequests>()
+                .insert_resource(picking_results.clone())
+                .add_plugin(ExtractResourcePlugin::<GpuPickingRequests>::default())
+                .add_system(clear_gpu_picking_requests.in_base_set(CoreSet::First))
+                .add_system(request_gpu_pick_on_click)
+                .add_system(send_gpu_picks);
+        } else {
+            app.add_system(pick_cuboids);
+        }
+
+        // With the `shader_hot_reload` feature, these are reloaded from the
+        // crate's sources when they change.
+        load_internal_asset!(
+            app,
             VERTEX_PULLING_SHADER_HANDLE,
-            Shader::from_wgsl(include_str!("vertex_pulling.wgsl")),
+            "vertex_pulling.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            CULLING_SHADER_HANDLE,
+            "culling.wgsl",
+            Shader::from_wgsl
         );
+        if let Some(shader) = &self.shader {
+            app.insert_resource(CuboidsShaderOverride(shader.clone()))
+                .add_system(override_cuboids_shader);
+        }
         {
             use super::index_buffer::{CuboidsIndexBuffer, CUBE_INDICES_HANDLE};
             use bevy::render::render_asset::RenderAssetPlugin;
@@ -36,8 +555,11 @@ impl Plugin for VertexPullingRenderPlugin {
                 .add_plugin(RenderAssetPlugin::<CuboidsIndexBuffer>::default());
             app.world
                 .resource_mut::<Assets<CuboidsIndexBuffer>>()
-                .set_untracked(CUBE_INDICES_HANDLE, CuboidsIndexBuffer);
+                .set_untracked(CUBE_INDICES_HANDLE, CuboidsIndexBuffer::default());
         }
+        app.add_asset::<CuboidsAsset>()
+            .init_asset_loader::<CuboidsAssetLoader>()
+            .add_system(update_cuboids_from_assets);
 
         let maybe_msaa = app.world.get_resource::<Msaa>().cloned();
         let render_app = app.sub_app_mut(RenderApp);
@@ -45,36 +567,521 @@ impl Plugin for VertexPullingRenderPlugin {
         if let Some(msaa) = maybe_msaa {
             render_app.insert_resource(msaa);
         }
+        let device_max_clipping_planes = GpuClippingPlaneRanges::max_capacity(
+            render_app
+                .world
+                .resource::<RenderDevice>()
+                .limits()
+                .max_uniform_buffer_binding_size,
+        );
+        let max_clipping_planes = self
+            .max_clipping_planes
+            .unwrap_or(MAX_CLIPPING_PLANES)
+            .min(device_max_clipping_planes)
+            .max(1);
         let mut shader_defs = CuboidsShaderDefs::default();
+        shader_defs.set_max_clipping_planes(max_clipping_planes);
         if self.outlines {
             shader_defs.enable_outlines();
         }
+        if self.clipping_caps {
+            shader_defs.enable_clipping_caps();
+        }
+        if data_textures {
+            shader_defs.enable_data_textures();
+        }
+        if gpu_culling {
+            shader_defs.enable_gpu_culling();
+        }
+        if !data_textures {
+            shader_defs.set_instance_format(self.instance_format);
+        }
+        if self.depth_convention == DepthConvention::ForwardZ {
+            shader_defs.enable_forward_z();
+            if gpu_culling {
+                warn!("Occlusion culling is ignored with DepthConvention::ForwardZ");
+            }
+        }
+        #[cfg(feature = "color_keyframes")]
+        shader_defs.enable_color_keyframes();
+        #[cfg(feature = "lighting")]
+        shader_defs.enable_lighting();
+        #[cfg(feature = "fog")]
+        shader_defs.enable_fog();
         render_app.insert_resource(shader_defs);
+        render_app.insert_resource(errors.clone());
+        render_app.insert_resource(uploads);
+        render_app.insert_resource(draw_stats);
 
         render_app
             .add_render_command::<Opaque3d, DrawCuboids>()
+            .add_render_command::<AlphaMask3d, DrawCuboids>()
+            .add_render_command::<Transparent3d, DrawCuboids>()
+            .add_render_command::<Opaque3dPrepass, DrawCuboidsPrepass>()
             .init_resource::<AuxiliaryMeta>()
             .init_resource::<CuboidBufferCache>()
             .init_resource::<CuboidsPipelines>()
+            .init_resource::<TrackedSpecializedPipelines<CuboidsPipelines>>()
+            .init_resource::<CuboidsHookShaders>()
             .init_resource::<DynamicUniformBufferOfCuboidMaterial>()
-            .init_resource::<DynamicUniformBufferOfCuboidTransforms>()
+            .init_resource::<CuboidMaterialIndices>()
+            .init_resource::<DynamicUniformBufferOfGpuCuboidsView>()
+            .init_resource::<StorageBufferOfCuboidTransforms>()
             .init_resource::<TransformsMeta>()
-            .init_resource::<UniformBufferOfGpuClippingPlaneRanges>()
+            .insert_resource(ViewClippingPlanes::new(max_clipping_planes))
+            .init_resource::<UniformBufferOfGpuClippingVolumes>()
+            .init_resource::<UniformBufferOfGpuSlottedMaterials>()
+            .init_resource::<UniformBufferOfGpuColormaps>()
             .init_resource::<ViewMeta>()
-            .add_systems((extract_cuboids, extract_clipping_planes).in_schedule(ExtractSchedule))
+            .add_systems(
+                (
+                    extract_cuboids,
+                    extract_clipping_planes,
+                    extract_clipping_volumes,
+                    extract_view_clipping,
+                    extract_cuboid_shader_hooks,
+                )
+                    .in_schedule(ExtractSchedule),
+            )
             .add_systems(
                 (
                     prepare_materials,
-                    prepare_clipping_planes,
+                    prepare_clipping_planes.after(ViewSet::PrepareUniforms),
                     prepare_auxiliary_bind_group
                         .after(prepare_materials)
                         .after(prepare_clipping_planes),
                     prepare_cuboid_transforms,
                     prepare_cuboids,
-                    prepare_cuboids_view_bind_group.after(ViewSet::PrepareUniforms),
+                    // Light views are spawned before the view uniforms.
+                    prepare_cuboids_view_uniforms.after(ViewSet::PrepareUniforms),
+                    prepare_cuboids_view_bind_group
+                        .after(ViewSet::PrepareUniforms)
+                        .after(prepare_cuboids_view_uniforms)
+                        .after(prepare_clipping_planes),
                 )
                     .in_set(RenderSet::Prepare),
             )
-            .add_system(queue_cuboids.in_set(RenderSet::Queue));
+            .add_systems(
+                (
+                    queue_cuboids,
+                    queue_cuboids_prepass,
+                    report_cuboids_draw_stats,
+                    report_pipeline_errors,
+                )
+                    .in_set(RenderSet::Queue),
+            );
+
+        app.add_plugin(ExtractComponentPlugin::<CuboidsComputeSource>::default());
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<CuboidsComputeSourcePipelines>()
+            .init_resource::<CuboidsComputeSourceChunks>()
+            .add_system(
+                prepare_cuboid_compute_sources
+                    .after(prepare_cuboids)
+                    .in_set(RenderSet::Prepare),
+            );
+        let mut graph = render_app.world.resource_mut::<RenderGraph>();
+        graph.add_node(CUBOIDS_COMPUTE_SOURCES_NODE, CuboidsComputeSourcesNode);
+        graph.add_node_edge(
+            CUBOIDS_COMPUTE_SOURCES_NODE,
+            main_graph::node::CAMERA_DRIVER,
+        );
+
+        #[cfg(feature = "shadows")]
+        {
+            use super::draw::DrawCuboidShadows;
+            use super::queue::queue_cuboid_shadows;
+            use bevy::pbr::Shadow;
+
+            app.sub_app_mut(RenderApp)
+                .add_render_command::<Shadow, DrawCuboidShadows>()
+                .add_system(queue_cuboid_shadows.in_set(RenderSet::Queue));
+        }
+
+        #[cfg(feature = "lighting")]
+        {
+            use super::extract::extract_cuboid_lights;
+            use super::prepare::prepare_cuboid_lights;
+
+            app.sub_app_mut(RenderApp)
+                .init_resource::<UniformBufferOfGpuCuboidLights>()
+                .add_system(extract_cuboid_lights.in_schedule(ExtractSchedule))
+                .add_system(
+                    prepare_cuboid_lights
+                        .before(prepare_auxiliary_bind_group)
+                        .in_set(RenderSet::Prepare),
+                );
+        }
+
+        #[cfg(feature = "fog")]
+        {
+            use super::extract::extract_cuboids_fog;
+
+            app.sub_app_mut(RenderApp)
+                .add_system(extract_cuboids_fog.in_schedule(ExtractSchedule));
+        }
+
+        #[cfg(feature = "color_keyframes")]
+        {
+            use super::extract::extract_color_keyframes;
+            use super::prepare::prepare_color_keyframes;
+
+            app.init_resource::<crate::ColorKeyframes>();
+            app.sub_app_mut(RenderApp)
+                .init_resource::<ColorKeyframeBuffers>()
+                .add_system(extract_color_keyframes.in_schedule(ExtractSchedule))
+                .add_system(
+                    prepare_color_keyframes
+                        .before(prepare_auxiliary_bind_group)
+                        .in_set(RenderSet::Prepare),
+                );
+        }
+
+        if gpu_culling {
+            app.init_resource::<OcclusionCullingSettings>()
+                .add_plugin(ExtractResourcePlugin::<OcclusionCullingSettings>::default())
+                .add_system(enable_camera_depth_binding);
+            app.world.resource_mut::<Assets<Shader>>().set_untracked(
+                DEPTH_PYRAMID_SHADER_HANDLE,
+                Shader::from_wgsl(include_str!("depth_pyramid.wgsl")),
+            );
+
+            let render_app = app.sub_app_mut(RenderApp);
+            render_app
+                .init_resource::<CuboidsCullingCache>()
+                .init_resource::<DepthPyramidPipelines>()
+                .init_resource::<CuboidsCullingPipeline>()
+                .add_systems(
+                    (
+                        prepare_cuboids_culling.after(prepare_cuboids),
+                        prepare_occlusion_culling.after(prepare_cuboids_culling),
+                    )
+                        .in_set(RenderSet::Prepare),
+                );
+
+            let culling_node = CuboidsCullingNode::new(&mut render_app.world);
+            let mut graph = render_app.world.resource_mut::<RenderGraph>();
+            let draw_3d_graph = graph.get_sub_graph_mut(core_3d::graph::NAME).unwrap();
+            draw_3d_graph.add_node(CUBOIDS_CULLING_NODE, culling_node);
+            let input_node_id = draw_3d_graph.input_node().id;
+            draw_3d_graph.add_slot_edge(
+                input_node_id,
+                core_3d::graph::input::VIEW_ENTITY,
+                CUBOIDS_CULLING_NODE,
+                CuboidsCullingNode::IN_VIEW,
+            );
+            draw_3d_graph.add_node_edge(CUBOIDS_CULLING_NODE, core_3d::graph::node::MAIN_PASS);
+
+            if self.gpu_culling_stats {
+                render_app
+                    .init_resource::<CuboidsCullingStats>()
+                    .add_system(
+                        prepare_cuboids_culling_stats
+                            .after(prepare_cuboids_culling)
+                            .in_set(RenderSet::Prepare),
+                    )
+                    .add_system(read_back_cuboids_culling_stats.in_set(RenderSet::Cleanup));
+                let mut graph = render_app.world.resource_mut::<RenderGraph>();
+                graph.add_node(CUBOIDS_CULLING_STATS_NODE, CuboidsCullingStatsNode);
+                graph.add_node_edge(main_graph::node::CAMERA_DRIVER, CUBOIDS_CULLING_STATS_NODE);
+            }
+
+            let depth_pyramid_node = CuboidsDepthPyramidNode::new(&mut render_app.world);
+            let mut graph = render_app.world.resource_mut::<RenderGraph>();
+            let draw_3d_graph = graph.get_sub_graph_mut(core_3d::graph::NAME).unwrap();
+            draw_3d_graph.add_node(CUBOIDS_DEPTH_PYRAMID_NODE, depth_pyramid_node);
+            draw_3d_graph.add_slot_edge(
+                input_node_id,
+                core_3d::graph::input::VIEW_ENTITY,
+                CUBOIDS_DEPTH_PYRAMID_NODE,
+                CuboidsDepthPyramidNode::IN_VIEW,
+            );
+            draw_3d_graph
+                .add_node_edge(core_3d::graph::node::MAIN_PASS, CUBOIDS_DEPTH_PYRAMID_NODE);
+        }
+
+        if self.order_independent_transparency {
+            app.world.resource_mut::<Assets<Shader>>().set_untracked(
+                OIT_COMPOSITE_SHADER_HANDLE,
+                Shader::from_wgsl(include_str!("oit_composite.wgsl")),
+            );
+
+            let render_app = app.sub_app_mut(RenderApp);
+            render_app
+                .init_resource::<DrawFunctions<CuboidsOit>>()
+                .add_render_command::<CuboidsOit, DrawCuboids>()
+                .init_resource::<CuboidsOitPipelines>()
+                .init_resource::<TrackedSpecializedPipelines<CuboidsOitPipelines>>()
+                .add_system(extract_cuboids_oit_phases.in_schedule(ExtractSchedule))
+                .add_system(prepare_cuboids_oit_textures.in_set(RenderSet::Prepare))
+                .add_system(queue_cuboids_oit.in_set(RenderSet::Queue));
+
+            let oit_node = CuboidsOitNode::new(&mut render_app.world);
+            let mut graph = render_app.world.resource_mut::<RenderGraph>();
+            let draw_3d_graph = graph.get_sub_graph_mut(core_3d::graph::NAME).unwrap();
+            draw_3d_graph.add_node(CUBOIDS_OIT_NODE, oit_node);
+            let input_node_id = draw_3d_graph.input_node().id;
+            draw_3d_graph.add_slot_edge(
+                input_node_id,
+                core_3d::graph::input::VIEW_ENTITY,
+                CUBOIDS_OIT_NODE,
+                CuboidsOitNode::IN_VIEW,
+            );
+            draw_3d_graph.add_node_edge(core_3d::graph::node::MAIN_PASS, CUBOIDS_OIT_NODE);
+            draw_3d_graph.add_node_edge(CUBOIDS_OIT_NODE, core_3d::graph::node::TONEMAPPING);
+        }
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<DrawFunctions<CuboidsOverlay>>()
+            .add_render_command::<CuboidsOverlay, DrawCuboids>()
+            .add_system(extract_cuboids_overlay_phases.in_schedule(ExtractSchedule))
+            .add_system(sort_phase_system::<CuboidsOverlay>.in_set(RenderSet::PhaseSort));
+
+        let overlay_node = CuboidsOverlayNode::new(&mut render_app.world);
+        let mut graph = render_app.world.resource_mut::<RenderGraph>();
+        let draw_3d_graph = graph.get_sub_graph_mut(core_3d::graph::NAME).unwrap();
+        draw_3d_graph.add_node(CUBOIDS_OVERLAY_NODE, overlay_node);
+        let input_node_id = draw_3d_graph.input_node().id;
+        draw_3d_graph.add_slot_edge(
+            input_node_id,
+            core_3d::graph::input::VIEW_ENTITY,
+            CUBOIDS_OVERLAY_NODE,
+            CuboidsOverlayNode::IN_VIEW,
+        );
+        draw_3d_graph.add_node_edge(core_3d::graph::node::MAIN_PASS, CUBOIDS_OVERLAY_NODE);
+        if self.order_independent_transparency {
+            draw_3d_graph.add_node_edge(CUBOIDS_OIT_NODE, CUBOIDS_OVERLAY_NODE);
+        }
+        draw_3d_graph.add_node_edge(CUBOIDS_OVERLAY_NODE, core_3d::graph::node::TONEMAPPING);
+
+        // Selection contours cost nothing until they are drawn.
+        app.world.resource_mut::<Assets<Shader>>().set_untracked(
+            CONTOUR_SHADER_HANDLE,
+            Shader::from_wgsl(include_str!("contour.wgsl")),
+        );
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<DrawFunctions<CuboidsContourMask>>()
+            .add_render_command::<CuboidsContourMask, DrawCuboids>()
+            .init_resource::<CuboidsContourSettings>()
+            .init_resource::<CuboidsContourPipelines>()
+            .init_resource::<TrackedSpecializedPipelines<CuboidsContourPipelines>>()
+            .add_system(extract_cuboids_contour.in_schedule(ExtractSchedule))
+            .add_system(prepare_cuboids_contour_textures.in_set(RenderSet::Prepare))
+            .add_system(queue_cuboids_contour.in_set(RenderSet::Queue));
+
+        let contour_node = CuboidsContourNode::new(&mut render_app.world);
+        let mut graph = render_app.world.resource_mut::<RenderGraph>();
+        let draw_3d_graph = graph.get_sub_graph_mut(core_3d::graph::NAME).unwrap();
+        draw_3d_graph.add_node(CUBOIDS_CONTOUR_NODE, contour_node);
+        let input_node_id = draw_3d_graph.input_node().id;
+        draw_3d_graph.add_slot_edge(
+            input_node_id,
+            core_3d::graph::input::VIEW_ENTITY,
+            CUBOIDS_CONTOUR_NODE,
+            CuboidsContourNode::IN_VIEW,
+        );
+        draw_3d_graph.add_node_edge(core_3d::graph::node::MAIN_PASS, CUBOIDS_CONTOUR_NODE);
+        if self.order_independent_transparency {
+            draw_3d_graph.add_node_edge(CUBOIDS_OIT_NODE, CUBOIDS_CONTOUR_NODE);
+        }
+        draw_3d_graph.add_node_edge(CUBOIDS_OVERLAY_NODE, CUBOIDS_CONTOUR_NODE);
+        draw_3d_graph.add_node_edge(CUBOIDS_CONTOUR_NODE, core_3d::graph::node::TONEMAPPING);
+
+        if gpu_picking {
+            let render_app = app.sub_app_mut(RenderApp);
+            render_app
+                .insert_resource(picking_results)
+                .init_resource::<CuboidsPicking>()
+                .init_resource::<CuboidsPickingPipeline>()
+                .add_system(
+                    prepare_cuboids_picking
+                        .after(prepare_cuboids)
+                        .in_set(RenderSet::Prepare),
+                )
+                .add_system(read_back_cuboids_picking.in_set(RenderSet::Cleanup));
+
+            let picking_node = CuboidsPickingNode::new(&mut render_app.world);
+            let mut graph = render_app.world.resource_mut::<RenderGraph>();
+            let draw_3d_graph = graph.get_sub_graph_mut(core_3d::graph::NAME).unwrap();
+            draw_3d_graph.add_node(CUBOIDS_PICKING_NODE, picking_node);
+            let input_node_id = draw_3d_graph.input_node().id;
+            draw_3d_graph.add_slot_edge(
+                input_node_id,
+                core_3d::graph::input::VIEW_ENTITY,
+                CUBOIDS_PICKING_NODE,
+                CuboidsPickingNode::IN_VIEW,
+            );
+            draw_3d_graph.add_node_edge(core_3d::graph::node::MAIN_PASS, CUBOIDS_PICKING_NODE);
+        }
+
+        let render_app = app.sub_app_mut(RenderApp);
+        let render_device = render_app.world.resource::<RenderDevice>().clone();
+        let render_queue = render_app.world.resource::<RenderQueue>().clone();
+
+        let multi_draw_features =
+            WgpuFeatures::MULTI_DRAW_INDIRECT | WgpuFeatures::INDIRECT_FIRST_INSTANCE;
+        let multi_draw_supported = render_device.features().contains(multi_draw_features);
+        if self.multi_draw_indirect && !multi_draw_supported {
+            warn!(
+                "This device doesn't support multi-draw indirect with a first instance, so \
+                 multi-draw batching is disabled"
+            );
+        }
+        if self.multi_draw_indirect && multi_draw_supported && !gpu_culling && !data_textures {
+            render_app
+                .init_resource::<CuboidsMultiDraw>()
+                .add_render_command::<Opaque3d, DrawCuboidsMultiDraw>()
+                .add_system(
+                    prepare_cuboids_multi_draw
+                        .in_set(RenderSet::Prepare)
+                        .before(prepare_cuboids),
+                );
+        }
+
+        let timestamps_supported = render_device
+            .features()
+            .contains(WgpuFeatures::TIMESTAMP_QUERY);
+        if self.gpu_timestamps && !timestamps_supported {
+            warn!("This device doesn't support timestamp queries, so GPU timestamps are disabled");
+        }
+        if self.gpu_timestamps && timestamps_supported {
+            render_app
+                .init_resource::<CuboidsTimestamps>()
+                .add_system(prepare_cuboids_timestamps.in_set(RenderSet::Prepare))
+                .add_system(read_back_cuboids_timestamps.in_set(RenderSet::Cleanup));
+
+            let mut graph = render_app.world.resource_mut::<RenderGraph>();
+            graph.add_node(CUBOIDS_TIMESTAMPS_NODE, CuboidsTimestampsNode);
+            graph.add_node_edge(main_graph::node::CAMERA_DRIVER, CUBOIDS_TIMESTAMPS_NODE);
+
+            let draw_3d_graph = graph.get_sub_graph_mut(core_3d::graph::NAME).unwrap();
+            draw_3d_graph.add_node(CUBOIDS_DRAW_BEGIN_NODE, CuboidsDrawTimestampNode::begin());
+            draw_3d_graph.add_node(CUBOIDS_DRAW_END_NODE, CuboidsDrawTimestampNode::end());
+            let input_node_id = draw_3d_graph.input_node().id;
+            for node in [CUBOIDS_DRAW_BEGIN_NODE, CUBOIDS_DRAW_END_NODE] {
+                draw_3d_graph.add_slot_edge(
+                    input_node_id,
+                    core_3d::graph::input::VIEW_ENTITY,
+                    node,
+                    CuboidsDrawTimestampNode::IN_VIEW,
+                );
+            }
+            draw_3d_graph.add_node_edge(core_3d::graph::node::PREPASS, CUBOIDS_DRAW_BEGIN_NODE);
+            if gpu_culling {
+                draw_3d_graph.add_node_edge(CUBOIDS_CULLING_NODE, CUBOIDS_DRAW_BEGIN_NODE);
+            }
+            draw_3d_graph.add_node_edge(CUBOIDS_DRAW_BEGIN_NODE, core_3d::graph::node::MAIN_PASS);
+            draw_3d_graph.add_node_edge(core_3d::graph::node::MAIN_PASS, CUBOIDS_DRAW_END_NODE);
+            if self.order_independent_transparency {
+                draw_3d_graph.add_node_edge(CUBOIDS_OIT_NODE, CUBOIDS_DRAW_END_NODE);
+            }
+            draw_3d_graph.add_node_edge(CUBOIDS_OVERLAY_NODE, CUBOIDS_DRAW_END_NODE);
+            draw_3d_graph.add_node_edge(CUBOIDS_DRAW_END_NODE, core_3d::graph::node::TONEMAPPING);
+        }
+
+        let mut buffer_cache = render_app.world.resource_mut::<CuboidBufferCache>();
+
+        // Each chunk is a whole buffer, so it must fit both limits. Some
+        // devices allow bindings larger than their largest buffer. Data
+        // textures are bounded by their size instead. Chunks culled on the
+        // GPU must also fit in one dispatch.
+        let limits = render_device.limits();
+        let max_chunk_size =
+            u64::from(limits.max_storage_buffer_binding_size).min(limits.max_buffer_size);
+        let max_instances = self
+            .max_cuboids_per_chunk
+            .unwrap_or(usize::MAX)
+            .min(self.streaming_chunk_cuboids.unwrap_or(usize::MAX));
+        let max_instances = if gpu_culling {
+            max_instances.min(max_culled_chunk_instances(&limits))
+        } else {
+            max_instances
+        };
+        buffer_cache.max_chunk_instances = if data_textures {
+            let max_texels = DataTexture::max_texels(&render_device) / u64::from(INSTANCE_TEXELS);
+            (max_texels as usize).min(max_instances).max(1)
+        } else {
+            max_chunk_instances(max_chunk_size, max_instances)
+        };
+        buffer_cache.streaming = self.streaming_chunk_cuboids.is_some();
+        buffer_cache.memory_budget = self.gpu_memory_budget;
+        buffer_cache.upload_budget_bytes = self.upload_budget_bytes;
+        buffer_cache.upload_budget_time = self.upload_budget_time;
+        buffer_cache.data_textures = data_textures;
+        buffer_cache.growth_factor = if data_textures {
+            1.0
+        } else {
+            self.buffer_growth_factor
+        };
+        buffer_cache.buffering = InstanceBuffering {
+            dynamic_buffers: self
+                .dynamic_instance_buffers
+                .unwrap_or(DYNAMIC_INSTANCE_BUFFER_COUNT),
+            auto_dynamic_frames: self.auto_dynamic_frames,
+        };
+        buffer_cache.instance_format = if data_textures {
+            CuboidsInstanceFormat::Full
+        } else {
+            self.instance_format
+        };
+
+        if self.prewarm_cuboids > 0 {
+            buffer_cache.prewarm(self.prewarm_cuboids, &render_device, &render_queue);
+        }
+
+        // Other primitives are only read from storage buffers.
+        if !data_textures {
+            add_primitive_batches::<Spheres>(
+                render_app,
+                max_chunk_size,
+                self.max_cuboids_per_chunk,
+            );
+            add_primitive_batches::<Cylinders>(
+                render_app,
+                max_chunk_size,
+                self.max_cuboids_per_chunk,
+            );
+            add_primitive_batches::<MeshInstances>(
+                render_app,
+                max_chunk_size,
+                self.max_cuboids_per_chunk,
+            );
+            add_primitive_batches::<CuboidsAnimation>(
+                render_app,
+                max_chunk_size,
+                self.max_cuboids_per_chunk,
+            );
+        }
     }
 }
+
+/// Draws batches of `P` alongside cuboids, in chunks of at most
+/// `max_chunk_size` bytes and `max_instances_per_chunk` instances.
+fn add_primitive_batches<P: PrimitiveBatch>(
+    render_app: &mut App,
+    max_chunk_size: u64,
+    max_instances_per_chunk: Option<usize>,
+) {
+    let max_chunk_instances = ((max_chunk_size / P::Instance::min_size().get()) as usize)
+        .min(max_instances_per_chunk.unwrap_or(usize::MAX))
+        .max(1);
+    render_app
+        .add_render_command::<Opaque3d, DrawPrimitives<P>>()
+        .add_render_command::<Transparent3d, DrawPrimitives<P>>()
+        .insert_resource(PrimitiveBufferCache::<P> {
+            max_chunk_instances,
+            ..default()
+        })
+        .init_resource::<PrimitivePipelines<P>>()
+        .init_resource::<TrackedSpecializedPipelines<PrimitivePipelines<P>>>()
+        .add_system(
+            extract_primitives::<P>
+                .after(extract_cuboids)
+                .in_schedule(ExtractSchedule),
+        )
+        .add_system(prepare_primitives::<P>.in_set(RenderSet::Prepare))
+        .add_system(queue_primitives::<P>.in_set(RenderSet::Queue));
+}
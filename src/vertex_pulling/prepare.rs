@@ -1,58 +1,215 @@
 use super::buffers::*;
-use super::cuboid_cache::CuboidBufferCache;
+use super::cuboid_cache::{CuboidBufferCache, InstanceChunk};
+use super::data_texture::{write_transforms_data_texture, DataTexture, TRANSFORM_TEXELS};
 use super::draw::{AuxiliaryMeta, TransformsMeta, ViewMeta};
-use super::pipeline::CuboidsPipelines;
+use super::pipeline::{CuboidsPipelines, CuboidsShaderDefs};
+use crate::clipping_planes::{GpuClippingPlaneRanges, ViewClipping};
+use crate::cuboids::CuboidsUploads;
+use crate::effects::GpuCuboidEffects;
+use crate::{
+    CuboidColormaps, CuboidEffects, CuboidsAtlas, CuboidsBufferEvent, CuboidsError, CuboidsErrors,
+    CuboidsLod, CuboidsTransform, CuboidsUploadedEvent,
+};
 
 use bevy::{
     prelude::*,
     render::{
-        render_resource::{BindGroupDescriptor, BindGroupEntry},
+        render_asset::RenderAssets,
+        render_resource::{BindGroupDescriptor, BindGroupEntry, BindingResource, ShaderType},
         renderer::{RenderDevice, RenderQueue},
-        view::ViewUniforms,
+        texture::FallbackImage,
+        view::{ExtractedView, ViewUniforms},
     },
+    tasks::ComputeTaskPool,
+    utils::Instant,
 };
 
 pub(crate) fn prepare_clipping_planes(
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
-    mut clipping_plane_uniform: ResMut<UniformBufferOfGpuClippingPlaneRanges>,
+    mut view_clipping_planes: ResMut<ViewClippingPlanes>,
+    mut clipping_volume_uniform: ResMut<UniformBufferOfGpuClippingVolumes>,
+    views: Query<(Entity, Option<&ViewClipping>), With<ExtractedView>>,
+    errors: Res<CuboidsErrors>,
 ) {
     // Values already pushed in extract stage.
-    clipping_plane_uniform.write_buffer(&render_device, &render_queue);
+    clipping_volume_uniform.write_buffer(&render_device, &render_queue);
+
+    let view_clipping_planes = view_clipping_planes.as_mut();
+    let max = view_clipping_planes.capacity;
+    let mut max_count = 0;
+    view_clipping_planes
+        .uniforms
+        .retain(|view, _| views.contains(*view));
+    for (view, clipping) in views.iter() {
+        let planes: Vec<_> = view_clipping_planes.planes(view).cloned().collect();
+        max_count = max_count.max(planes.len());
+
+        let uniform = view_clipping_planes
+            .uniforms
+            .entry(view)
+            .or_insert_with(|| UniformBufferOfGpuClippingPlaneRanges::new(max));
+        uniform.planes = GpuClippingPlaneRanges {
+            ranges: planes.into_iter().take(max).collect(),
+            combine: clipping.map(|c| c.combine).unwrap_or_default(),
+        };
+        uniform.write_buffer(&render_device, &render_queue);
+    }
+    if max_count > max {
+        errors.send(CuboidsError::TooManyClippingPlanes {
+            count: max_count,
+            max,
+        });
+    }
 }
 
 pub(crate) fn prepare_materials(
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
     mut material_uniforms: ResMut<DynamicUniformBufferOfCuboidMaterial>,
+    mut slotted_materials: ResMut<UniformBufferOfGpuSlottedMaterials>,
+    colormaps: Option<Res<CuboidColormaps>>,
+    mut colormaps_uniform: ResMut<UniformBufferOfGpuColormaps>,
 ) {
     // Values already pushed in extract stage.
     material_uniforms.write_buffer(&render_device, &render_queue);
+    slotted_materials.write_buffer(&render_device, &render_queue);
+
+    let colormaps_changed = colormaps.as_ref().map_or(false, |c| c.is_changed());
+    if colormaps_changed || colormaps_uniform.buffer().is_none() {
+        if let Some(colormaps) = colormaps {
+            colormaps_uniform.set(colormaps.to_gpu());
+        }
+        colormaps_uniform.write_buffer(&render_device, &render_queue);
+    }
+}
+
+#[cfg(feature = "color_keyframes")]
+pub(crate) fn prepare_color_keyframes(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut buffers: ResMut<ColorKeyframeBuffers>,
+    errors: Res<CuboidsErrors>,
+) {
+    // Values already pushed in extract stage.
+    buffers.header.write_buffer(&render_device, &render_queue);
+    if buffers.table_dirty {
+        // The table is a single binding, it isn't chunked like instances.
+        let size = (buffers.table.get().len() * std::mem::size_of::<u32>()) as u64;
+        let limits = render_device.limits();
+        let max_size =
+            u64::from(limits.max_storage_buffer_binding_size).min(limits.max_buffer_size);
+        if size > max_size {
+            errors.send(CuboidsError::BufferTooLarge {
+                label: "color_keyframe_table",
+                size,
+                max_size,
+            });
+            return;
+        }
+        buffers.table.write_buffer(&render_device, &render_queue);
+        buffers.table_dirty = false;
+    }
+}
+
+#[cfg(feature = "lighting")]
+pub(crate) fn prepare_cuboid_lights(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut lights_uniform: ResMut<UniformBufferOfGpuCuboidLights>,
+) {
+    // Values already pushed in extract stage.
+    lights_uniform.write_buffer(&render_device, &render_queue);
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn prepare_auxiliary_bind_group(
     pipeline: Res<CuboidsPipelines>,
     render_device: Res<RenderDevice>,
     mut aux_meta: ResMut<AuxiliaryMeta>,
-    clipping_plane_uniform: Res<UniformBufferOfGpuClippingPlaneRanges>,
+    clipping_volume_uniform: Res<UniformBufferOfGpuClippingVolumes>,
     material_uniform: Res<DynamicUniformBufferOfCuboidMaterial>,
+    slotted_materials: Res<UniformBufferOfGpuSlottedMaterials>,
+    colormaps_uniform: Res<UniformBufferOfGpuColormaps>,
+    atlas: Option<Res<CuboidsAtlas>>,
+    images: Res<RenderAssets<Image>>,
+    fallback_image: Res<FallbackImage>,
+    #[cfg(feature = "color_keyframes")] keyframe_buffers: Res<ColorKeyframeBuffers>,
+    #[cfg(feature = "lighting")] lights_uniform: Res<UniformBufferOfGpuCuboidLights>,
 ) {
-    if let (Some(color_binding), Some(planes_binding)) =
-        (material_uniform.binding(), clipping_plane_uniform.binding())
-    {
+    if let (
+        Some(color_binding),
+        Some(volumes_binding),
+        Some(slotted_binding),
+        Some(colormaps_binding),
+    ) = (
+        material_uniform.binding(),
+        clipping_volume_uniform.binding(),
+        slotted_materials.binding(),
+        colormaps_uniform.binding(),
+    ) {
+        let atlas_image = atlas
+            .and_then(|a| images.get(&a.image))
+            .unwrap_or(&**fallback_image);
+        #[allow(unused_mut)]
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: color_binding,
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: volumes_binding,
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: slotted_binding,
+            },
+            BindGroupEntry {
+                binding: 7,
+                resource: BindingResource::TextureView(&atlas_image.texture_view),
+            },
+            BindGroupEntry {
+                binding: 8,
+                resource: BindingResource::Sampler(&atlas_image.sampler),
+            },
+            BindGroupEntry {
+                binding: 9,
+                resource: colormaps_binding,
+            },
+        ];
+        #[cfg(feature = "color_keyframes")]
+        {
+            let (Some(header_binding), Some(table_binding)) = (
+                keyframe_buffers.header.binding(),
+                keyframe_buffers.table.binding(),
+            ) else {
+                return;
+            };
+            entries.push(BindGroupEntry {
+                binding: 2,
+                resource: header_binding,
+            });
+            entries.push(BindGroupEntry {
+                binding: 3,
+                resource: table_binding,
+            });
+        }
+        #[cfg(feature = "lighting")]
+        {
+            let Some(lights_binding) = lights_uniform.binding() else {
+                return;
+            };
+            entries.push(BindGroupEntry {
+                binding: 4,
+                resource: lights_binding,
+            });
+        }
+
         aux_meta.bind_group = Some(render_device.create_bind_group(&BindGroupDescriptor {
             label: Some("auxiliary_bind_group"),
             layout: &pipeline.aux_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: color_binding,
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: planes_binding,
-                },
-            ],
+            entries: &entries,
         }));
     }
 }
@@ -62,14 +219,69 @@ pub(crate) fn prepare_cuboid_transforms(
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
     mut transforms_meta: ResMut<TransformsMeta>,
-    mut transform_uniforms: ResMut<DynamicUniformBufferOfCuboidTransforms>,
+    mut transforms: ResMut<StorageBufferOfCuboidTransforms>,
+    shader_defs: Res<CuboidsShaderDefs>,
+    errors: Res<CuboidsErrors>,
 ) {
+    if transforms.get().is_empty() {
+        // Empty storage bindings are invalid, and there's nothing to draw.
+        transforms_meta.transform_buffer_bind_group = None;
+        return;
+    }
+
+    if shader_defs.data_textures {
+        let texels = transforms.get().len() as u64 * u64::from(TRANSFORM_TEXELS);
+        let max_texels = DataTexture::max_texels(&render_device);
+        if texels > max_texels {
+            errors.send(CuboidsError::BufferTooLarge {
+                label: "gpu_cuboids_transforms_data_texture",
+                size: texels * 16,
+                max_size: max_texels * 16,
+            });
+            transforms_meta.transform_buffer_bind_group = None;
+            return;
+        }
+        let TransformsMeta {
+            transform_buffer_bind_group,
+            data_texture,
+        } = &mut *transforms_meta;
+        write_transforms_data_texture(
+            data_texture,
+            &render_device,
+            &render_queue,
+            transforms.get(),
+        );
+        *transform_buffer_bind_group =
+            Some(render_device.create_bind_group(&BindGroupDescriptor {
+                label: Some("gpu_cuboids_transforms_bind_group"),
+                layout: &pipeline.transforms_layout,
+                entries: &[BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&data_texture.as_ref().unwrap().view),
+                }],
+            }));
+        return;
+    }
+
+    let size = transforms.get().len() as u64 * CuboidsTransform::min_size().get();
+    let limits = render_device.limits();
+    let max_size = u64::from(limits.max_storage_buffer_binding_size).min(limits.max_buffer_size);
+    if size > max_size {
+        errors.send(CuboidsError::BufferTooLarge {
+            label: "gpu_cuboids_transforms",
+            size,
+            max_size,
+        });
+        transforms_meta.transform_buffer_bind_group = None;
+        return;
+    }
+
     let write_transform_buffer_span =
         bevy::log::info_span!("prepare_cuboids::write_transform_buffer");
     write_transform_buffer_span.in_scope(|| {
-        transform_uniforms.write_buffer(&render_device, &render_queue);
+        transforms.write_buffer(&render_device, &render_queue);
     });
-    if let Some(transforms_binding) = transform_uniforms.binding() {
+    if let Some(transforms_binding) = transforms.binding() {
         let create_bind_group_span = bevy::log::info_span!("prepare_cuboids::create_bind_group");
         transforms_meta.transform_buffer_bind_group = create_bind_group_span.in_scope(|| {
             Some(render_device.create_bind_group(&BindGroupDescriptor {
@@ -81,8 +293,6 @@ pub(crate) fn prepare_cuboid_transforms(
                 }],
             }))
         });
-    } else {
-        assert!(transform_uniforms.is_empty());
     }
 }
 
@@ -91,37 +301,236 @@ pub(crate) fn prepare_cuboids(
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
     mut cuboid_buffers: ResMut<CuboidBufferCache>,
+    uploads: Res<CuboidsUploads>,
+    errors: Res<CuboidsErrors>,
 ) {
+    let start = Instant::now();
     let write_instance_buffer_span =
         bevy::log::info_span!("prepare_cuboids::write_instance_buffer");
     let create_bind_group_span = bevy::log::info_span!("prepare_cuboids::create_bind_group");
 
-    // Write all dirty buffers from the cuboids cache.
-    for entry in cuboid_buffers.entries.values_mut() {
-        if !entry.dirty {
-            assert!(entry.instance_buffer_bind_group.is_some());
-            continue;
-        }
+    // Streamed batches share a single chunk upload per frame.
+    let mut streamed_chunk = false;
+    let mut uploaded_bytes = 0;
+    // At least one batch that isn't on the GPU yet is uploaded per frame,
+    // whatever the budget.
+    let mut uploaded_new_batch = false;
+    let budget_bytes = cuboid_buffers.upload_budget_bytes;
+    let budget_time = cuboid_buffers.upload_budget_time;
 
+    let data_textures = cuboid_buffers.data_textures;
+    // Chunks are encoded and written on all cores, since this dominates full
+    // uploads of large batches.
+    let (render_device, render_queue): (&RenderDevice, &RenderQueue) =
+        (render_device, render_queue);
+    let upload_chunk = |chunk: &mut InstanceChunk| -> u64 {
+        if data_textures {
+            write_instance_buffer_span.in_scope(|| {
+                chunk.write_data_texture(render_device, render_queue);
+            });
+            chunk.bind_group = create_bind_group_span.in_scope(|| {
+                Some(render_device.create_bind_group(&BindGroupDescriptor {
+                    label: Some("cuboids_instance_data_texture_bind_group"),
+                    layout: &pipeline.unculled_cuboids_layout,
+                    entries: &[BindGroupEntry {
+                        binding: 0,
+                        resource: BindingResource::TextureView(
+                            &chunk.data_texture.as_ref().unwrap().view,
+                        ),
+                    }],
+                }))
+            });
+            return chunk.gpu_size();
+        }
         write_instance_buffer_span.in_scope(|| {
-            entry
-                .instance_buffer
-                .write_buffer(&render_device, &render_queue);
+            chunk.write_instances(render_device, render_queue);
+            chunk.colors.write_buffer(render_device, render_queue);
+            chunk.rotations.write_buffer(render_device, render_queue);
+            chunk.user_data.write_buffer(render_device, render_queue);
+            chunk.hidden_mask.write_buffer(render_device, render_queue);
         });
 
-        entry.instance_buffer_bind_group = create_bind_group_span.in_scope(|| {
+        // With GPU culling, the main passes bind instances per view
+        // instead, so this is only used by passes that draw every instance.
+        chunk.bind_group = create_bind_group_span.in_scope(|| {
             Some(render_device.create_bind_group(&BindGroupDescriptor {
                 label: Some("cuboids_instance_buffer_bind_group"),
-                layout: &pipeline.cuboids_layout,
-                entries: &[BindGroupEntry {
-                    binding: 0,
-                    resource: entry.instance_buffer.binding().unwrap(),
-                }],
+                layout: &pipeline.unculled_cuboids_layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: chunk.instances_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: chunk.rotations.binding().unwrap(),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: chunk.hidden_mask.binding().unwrap(),
+                    },
+                    BindGroupEntry {
+                        binding: 4,
+                        resource: chunk.colors.binding().unwrap(),
+                    },
+                    BindGroupEntry {
+                        binding: 5,
+                        resource: chunk.user_data.binding().unwrap(),
+                    },
+                ],
             }))
         });
+        chunk.gpu_size()
+    };
 
+    // Write all dirty buffers from the cuboids cache, visible batches first so
+    // that they get the upload budget.
+    let mut entities: Vec<_> = cuboid_buffers
+        .entries
+        .iter()
+        .map(|(&entity, entry)| (!entry.enabled, entity))
+        .collect();
+    entities.sort_by_key(|&(hidden, _)| hidden);
+    for (_, entity) in entities {
+        let entry = cuboid_buffers.entries.get_mut(&entity).unwrap();
+        if entry.evicted {
+            continue;
+        }
+        // Data textures are only ever uploaded whole.
+        if data_textures
+            && (entry.visibility_dirty
+                || !entry.dirty_ranges.is_empty()
+                || !entry.dirty_color_ranges.is_empty())
+        {
+            entry.visibility_dirty = false;
+            entry.dirty_ranges.clear();
+            entry.dirty_color_ranges.clear();
+            entry.dirty = true;
+        }
+        if entry.visibility_dirty {
+            // Same size as before, so the bind groups stay valid.
+            for chunk in entry.current_mut().chunks.iter_mut() {
+                write_instance_buffer_span.in_scope(|| {
+                    chunk.hidden_mask.write_buffer(render_device, render_queue);
+                });
+                uploaded_bytes += chunk.hidden_mask.buffer().map_or(0, |b| b.size());
+            }
+            entry.visibility_dirty = false;
+        }
+        for (chunk_index, range) in std::mem::take(&mut entry.dirty_ranges) {
+            uploaded_bytes += write_instance_buffer_span
+                .in_scope(|| entry.current().chunks[chunk_index].write_range(render_queue, range));
+        }
+        for (chunk_index, range) in std::mem::take(&mut entry.dirty_color_ranges) {
+            uploaded_bytes += write_instance_buffer_span.in_scope(|| {
+                entry.current().chunks[chunk_index].write_color_range(render_queue, range)
+            });
+        }
+        if !entry.dirty && !entry.streaming && !entry.upload_deferred {
+            if entry.instance_buffers.is_empty() || !entry.current().is_ready() {
+                errors.send(CuboidsError::BuffersNotReady { entity });
+                entry.enabled = false;
+                entry.on_gpu = false;
+            }
+            continue;
+        }
+        if !entry.streaming && !entry.on_gpu {
+            let over_budget = budget_bytes.map_or(false, |budget| uploaded_bytes >= budget)
+                || budget_time.map_or(false, |budget| start.elapsed() >= budget);
+            if over_budget && uploaded_new_batch {
+                entry.upload_deferred = true;
+                entry.enabled = false;
+                continue;
+            }
+            uploaded_new_batch = true;
+        }
+
+        let chunks = if entry.streaming {
+            if streamed_chunk {
+                entry.enabled = false;
+                continue;
+            }
+            streamed_chunk = true;
+            entry.streamed_chunks..entry.streamed_chunks + 1
+        } else {
+            0..entry.current().chunks.len()
+        };
+        let chunks = &mut entry.current_mut().chunks[chunks];
+        uploaded_bytes += if chunks.len() > 1 {
+            ComputeTaskPool::get()
+                .scope(|scope| {
+                    for chunk in chunks.iter_mut() {
+                        let upload_chunk = &upload_chunk;
+                        scope.spawn(async move { upload_chunk(chunk) });
+                    }
+                })
+                .into_iter()
+                .sum::<u64>()
+        } else {
+            chunks.iter_mut().map(&upload_chunk).sum()
+        };
+
+        if entry.streaming {
+            entry.streamed_chunks += 1;
+            if entry.streamed_chunks < entry.current().chunks.len() {
+                // Not drawn until every chunk is uploaded.
+                entry.enabled = false;
+                continue;
+            }
+            entry.streaming = false;
+            uploads.send(CuboidsUploadedEvent { entity });
+        } else if entry.upload_deferred {
+            entry.upload_deferred = false;
+            uploads.send(CuboidsUploadedEvent { entity });
+        }
         entry.dirty = false;
+        entry.on_gpu = true;
+        uploads.send_buffer_event(CuboidsBufferEvent::Uploaded {
+            entity,
+            bytes: entry.gpu_size(),
+        });
     }
+    cuboid_buffers.uploaded_bytes = uploaded_bytes;
+    cuboid_buffers.prepare_time = start.elapsed();
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn prepare_cuboids_view_uniforms(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut cuboids_view_uniforms: ResMut<DynamicUniformBufferOfGpuCuboidsView>,
+    lod: Res<CuboidsLod>,
+    effects: Res<CuboidEffects>,
+    time: Res<Time>,
+    atlas: Option<Res<CuboidsAtlas>>,
+    images: Res<RenderAssets<Image>>,
+    views: Query<(Entity, Option<&ViewClipping>), With<ExtractedView>>,
+    #[cfg(feature = "fog")] fogs: Query<&crate::fog::GpuCuboidsFog>,
+) {
+    cuboids_view_uniforms.clear();
+    // Tiles are only drawn once the image is uploaded.
+    let atlas_grid = match atlas {
+        Some(atlas) if images.get(&atlas.image).is_some() => UVec2::new(atlas.columns, atlas.rows),
+        _ => UVec2::ZERO,
+    };
+    let effects = GpuCuboidEffects::new(&effects, &time);
+    for (entity, maybe_clipping) in views.iter() {
+        let clipping_enabled = maybe_clipping.map(|c| c.enabled).unwrap_or(true);
+        let offset = cuboids_view_uniforms.push(GpuCuboidsView {
+            clipping_enabled: clipping_enabled.into(),
+            lod_level: lod.current_level.into(),
+            atlas_grid,
+            effects: effects.clone(),
+            // Shadow views have no fog.
+            #[cfg(feature = "fog")]
+            fog: fogs.get(entity).cloned().unwrap_or_default(),
+        });
+        commands
+            .entity(entity)
+            .insert(CuboidsViewUniformOffset(offset));
+    }
+    cuboids_view_uniforms.write_buffer(&render_device, &render_queue);
 }
 
 pub(crate) fn prepare_cuboids_view_bind_group(
@@ -129,16 +538,46 @@ pub(crate) fn prepare_cuboids_view_bind_group(
     cuboids_pipeline: Res<CuboidsPipelines>,
     mut view_meta: ResMut<ViewMeta>,
     view_uniforms: Res<ViewUniforms>,
+    cuboids_view_uniforms: Res<DynamicUniformBufferOfGpuCuboidsView>,
+    view_clipping_planes: Res<ViewClippingPlanes>,
+    views: Query<Entity, With<ExtractedView>>,
 ) {
-    if let Some(view_binding) = view_uniforms.uniforms.binding() {
-        view_meta.cuboids_view_bind_group =
-            Some(render_device.create_bind_group(&BindGroupDescriptor {
-                entries: &[BindGroupEntry {
+    view_meta.view_bind_groups.clear();
+    let (Some(view_binding), Some(cuboids_view_binding)) = (
+        view_uniforms.uniforms.binding(),
+        cuboids_view_uniforms.binding(),
+    ) else {
+        return;
+    };
+    // Every camera and shadow view gets its own bind group, with its own
+    // clipping planes. The view uniforms are shared between them, and
+    // selected with dynamic offsets.
+    for view in views.iter() {
+        let Some(planes_binding) = view_clipping_planes
+            .uniforms
+            .get(&view)
+            .and_then(|u| u.binding())
+        else {
+            continue;
+        };
+        let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            entries: &[
+                BindGroupEntry {
                     binding: 0,
-                    resource: view_binding,
-                }],
-                label: Some("cuboids_view_bind_group"),
-                layout: &cuboids_pipeline.view_layout,
-            }));
+                    resource: view_binding.clone(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: cuboids_view_binding.clone(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: planes_binding,
+                },
+            ],
+            label: Some("cuboids_view_bind_group"),
+            layout: &cuboids_pipeline.view_layout,
+        });
+        view_meta.view_bind_groups.insert(view, bind_group);
     }
 }
@@ -0,0 +1,656 @@
+use super::buffers::{CuboidMaterialIndices, StorageBufferOfCuboidTransforms};
+use super::draw::{AuxiliaryMeta, SetCuboidsViewBindGroup, SetGpuTransformBufferBindGroup};
+use super::pipeline::{
+    CuboidsPipelines, CuboidsShaderDefs, TrackedSpecializedPipelines, VERTEX_PULLING_SHADER_HANDLE,
+};
+use crate::cuboids_animation::AnimatedCuboid;
+use crate::{
+    Cuboid, CuboidMaterialId, CuboidMaterialMap, CuboidsAnimation, CuboidsTransform, Cylinder,
+    Cylinders, MeshInstances, Sphere, Spheres, TemplateVertex,
+};
+
+use bevy::{
+    core_pipeline::core_3d::{Opaque3d, Transparent3d},
+    ecs::system::{lifetimeless::*, SystemParamItem},
+    prelude::*,
+    render::{
+        mesh::PrimitiveTopology,
+        render_phase::{
+            DrawFunctions, PhaseItem, RenderCommand, RenderCommandResult, RenderPhase,
+            SetItemPipeline, TrackedRenderPass,
+        },
+        render_resource::{
+            encase::{internal::WriteInto, ShaderSize},
+            BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType, BlendState,
+            BufferBindingType, BufferSize, ColorTargetState, ColorWrites, CompareFunction,
+            DepthBiasState, DepthStencilState, FragmentState, FrontFace, MultisampleState,
+            PipelineCache, PolygonMode, PrimitiveState, RenderPipelineDescriptor, ShaderStages,
+            ShaderType, SpecializedRenderPipeline, StencilFaceState, StencilState, StorageBuffer,
+            TextureFormat, VertexState,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        texture::BevyDefault,
+        view::{ExtractedView, VisibleEntities},
+        Extract,
+    },
+    utils::HashMap,
+};
+use std::{borrow::Cow, marker::PhantomData};
+
+/// A batch component of instances that aren't cuboids, but are drawn like them:
+/// with the material, transform and clipping of the batch, and the same number
+/// of vertices for every instance, pulled from storage buffers.
+pub(crate) trait PrimitiveBatch: Component {
+    type Instance: ShaderType + ShaderSize + WriteInto + Clone + Send + Sync + 'static;
+
+    /// Used in the labels of GPU resources.
+    const LABEL: &'static str;
+    /// Enables the primitive's entry points in the cuboids shader.
+    const SHADER_DEF: &'static str;
+    const VERTEX_ENTRY_POINT: &'static str;
+    const FRAGMENT_ENTRY_POINT: &'static str;
+    const TOPOLOGY: PrimitiveTopology;
+    /// Binds [`PrimitiveBatch::template`] next to the instances.
+    const HAS_TEMPLATE: bool = false;
+
+    fn instances(&self) -> Cow<[Self::Instance]>;
+
+    fn num_instances(&self) -> usize {
+        self.instances().len()
+    }
+
+    /// When `Some`, instances are only uploaded when this changes, rather than
+    /// on every change to the component.
+    fn revision(&self) -> Option<u64> {
+        None
+    }
+
+    /// Passed to the shader with the batch transform.
+    fn animation_t(&self) -> f32 {
+        0.0
+    }
+
+    /// The vertex index is the batch transform index times this, plus the
+    /// vertex of the instance.
+    fn vertices_per_instance(&self) -> u32;
+
+    /// Vertices shared by all instances of the batch.
+    fn template(&self) -> &[TemplateVertex] {
+        &[]
+    }
+}
+
+impl PrimitiveBatch for Spheres {
+    type Instance = Sphere;
+
+    const LABEL: &'static str = "spheres";
+    const SHADER_DEF: &'static str = "SPHERES";
+    const VERTEX_ENTRY_POINT: &'static str = "sphere_vertex";
+    const FRAGMENT_ENTRY_POINT: &'static str = "sphere_fragment";
+    // A quad facing the camera.
+    const TOPOLOGY: PrimitiveTopology = PrimitiveTopology::TriangleStrip;
+
+    fn instances(&self) -> Cow<[Sphere]> {
+        Cow::Borrowed(&self.instances)
+    }
+
+    fn vertices_per_instance(&self) -> u32 {
+        4
+    }
+}
+
+impl PrimitiveBatch for Cylinders {
+    type Instance = Cylinder;
+
+    const LABEL: &'static str = "cylinders";
+    const SHADER_DEF: &'static str = "CYLINDERS";
+    const VERTEX_ENTRY_POINT: &'static str = "cylinder_vertex";
+    const FRAGMENT_ENTRY_POINT: &'static str = "cylinder_fragment";
+    // The 3 faces of the box around the cylinder that face the camera, as 2
+    // triangles each.
+    const TOPOLOGY: PrimitiveTopology = PrimitiveTopology::TriangleList;
+
+    fn instances(&self) -> Cow<[Cylinder]> {
+        Cow::Borrowed(&self.instances)
+    }
+
+    fn vertices_per_instance(&self) -> u32 {
+        18
+    }
+}
+
+impl PrimitiveBatch for MeshInstances {
+    type Instance = Cuboid;
+
+    const LABEL: &'static str = "mesh_instances";
+    const SHADER_DEF: &'static str = "MESH_INSTANCES";
+    const VERTEX_ENTRY_POINT: &'static str = "mesh_instance_vertex";
+    const FRAGMENT_ENTRY_POINT: &'static str = "shaded_vertex_fragment";
+    const TOPOLOGY: PrimitiveTopology = PrimitiveTopology::TriangleList;
+    const HAS_TEMPLATE: bool = true;
+
+    fn instances(&self) -> Cow<[Cuboid]> {
+        Cow::Borrowed(&self.instances)
+    }
+
+    fn vertices_per_instance(&self) -> u32 {
+        self.template.vertices.len().try_into().unwrap()
+    }
+
+    fn template(&self) -> &[TemplateVertex] {
+        &self.template.vertices
+    }
+}
+
+impl PrimitiveBatch for CuboidsAnimation {
+    type Instance = AnimatedCuboid;
+
+    const LABEL: &'static str = "cuboids_animation";
+    const SHADER_DEF: &'static str = "CUBOIDS_ANIMATION";
+    const VERTEX_ENTRY_POINT: &'static str = "animated_cuboid_vertex";
+    const FRAGMENT_ENTRY_POINT: &'static str = "shaded_vertex_fragment";
+    const TOPOLOGY: PrimitiveTopology = PrimitiveTopology::TriangleList;
+
+    fn instances(&self) -> Cow<[AnimatedCuboid]> {
+        Cow::Owned(
+            self.from()
+                .iter()
+                .zip(self.to())
+                .map(|(&from, &to)| AnimatedCuboid { from, to })
+                .collect(),
+        )
+    }
+
+    fn num_instances(&self) -> usize {
+        self.from().len()
+    }
+
+    fn revision(&self) -> Option<u64> {
+        Some(self.revision())
+    }
+
+    fn animation_t(&self) -> f32 {
+        self.t.clamp(0.0, 1.0)
+    }
+
+    fn vertices_per_instance(&self) -> u32 {
+        // All 6 faces, as 2 triangles each.
+        36
+    }
+}
+
+pub(crate) type DrawPrimitives<P> = (
+    SetItemPipeline,
+    SetCuboidsViewBindGroup<0>,
+    SetPrimitivesAuxBindGroup<P, 1>,
+    SetGpuTransformBufferBindGroup<2>,
+    DrawVertexPulledPrimitives<P, 3>,
+);
+
+#[derive(Resource)]
+pub(crate) struct PrimitiveBufferCache<P: PrimitiveBatch> {
+    pub entries: HashMap<Entity, CachedPrimitiveBuffers<P>>,
+    /// Like cuboids, batches are split into chunks that fit the device's
+    /// binding size, each drawn separately.
+    pub max_chunk_instances: usize,
+}
+
+impl<P: PrimitiveBatch> Default for PrimitiveBufferCache<P> {
+    fn default() -> Self {
+        Self {
+            entries: default(),
+            max_chunk_instances: usize::MAX,
+        }
+    }
+}
+
+pub(crate) struct CachedPrimitiveBuffers<P: PrimitiveBatch> {
+    pub material_index: u32,
+    pub transform_index: u32,
+    pub vertices_per_instance: u32,
+    pub revision: Option<u64>,
+    pub position: Vec3,
+    pub dirty: bool,
+    pub enabled: bool,
+    pub transparent: bool,
+    pub keep_alive: bool,
+    /// Only used with [`PrimitiveBatch::HAS_TEMPLATE`].
+    pub template: StorageBuffer<Vec<TemplateVertex>>,
+    pub chunks: Vec<PrimitiveChunk<P>>,
+}
+
+impl<P: PrimitiveBatch> Default for CachedPrimitiveBuffers<P> {
+    fn default() -> Self {
+        Self {
+            material_index: 0,
+            transform_index: 0,
+            vertices_per_instance: 0,
+            revision: None,
+            position: Vec3::ZERO,
+            dirty: false,
+            enabled: false,
+            transparent: false,
+            keep_alive: false,
+            template: default(),
+            chunks: Vec::new(),
+        }
+    }
+}
+
+pub(crate) struct PrimitiveChunk<P: PrimitiveBatch> {
+    pub buffer: StorageBuffer<Vec<P::Instance>>,
+    pub bind_group: Option<BindGroup>,
+}
+
+impl<P: PrimitiveBatch> Default for PrimitiveChunk<P> {
+    fn default() -> Self {
+        Self {
+            buffer: default(),
+            bind_group: None,
+        }
+    }
+}
+
+impl<P: PrimitiveBatch> CachedPrimitiveBuffers<P> {
+    fn set_instances(&mut self, instances: &[P::Instance], max_chunk_instances: usize) {
+        let max_chunk_instances = max_chunk_instances.max(1);
+        let num_chunks = (instances.len() + max_chunk_instances - 1) / max_chunk_instances;
+        self.chunks.resize_with(num_chunks, Default::default);
+        for (chunk, instances) in self
+            .chunks
+            .iter_mut()
+            .zip(instances.chunks(max_chunk_instances))
+        {
+            chunk.buffer.set(instances.to_vec());
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub(crate) fn extract_primitives<P: PrimitiveBatch>(
+    mut commands: Commands,
+    batches: Extract<
+        Query<(
+            Entity,
+            &P,
+            &GlobalTransform,
+            &CuboidMaterialId,
+            Option<&ComputedVisibility>,
+            Or<(Added<P>, Changed<P>)>,
+        )>,
+    >,
+    materials: Extract<Res<CuboidMaterialMap>>,
+    material_indices: Res<CuboidMaterialIndices>,
+    mut buffers: ResMut<PrimitiveBufferCache<P>>,
+    mut transforms: ResMut<StorageBufferOfCuboidTransforms>,
+) {
+    // The cuboids extraction already reported an empty material map.
+    if materials.is_empty() {
+        return;
+    }
+
+    let mut extracted_entities = Vec::new();
+    let max_chunk_instances = buffers.max_chunk_instances;
+    for (entity, batch, transform, materials_id, maybe_visibility, instances_changed) in
+        batches.iter()
+    {
+        if batch.num_instances() == 0 || batch.vertices_per_instance() == 0 {
+            continue;
+        }
+        // Reported by the cuboids extraction for cuboid batches.
+        let Some(material_index) = material_indices.get(materials_id.0) else {
+            continue;
+        };
+
+        extracted_entities.push((entity, ()));
+
+        // Pushed after all cuboid batches, into the same buffer.
+        let mut transform = CuboidsTransform::from_matrix(transform.compute_matrix());
+        transform.animation_t = batch.animation_t();
+        let entry = buffers.entries.entry(entity).or_default();
+        let is_new = entry.chunks.is_empty();
+        let revision = batch.revision();
+        let changed = match revision {
+            Some(_) => entry.revision != revision,
+            None => instances_changed,
+        } || is_new;
+        if changed {
+            entry.set_instances(&batch.instances(), max_chunk_instances);
+            if P::HAS_TEMPLATE {
+                entry.template.set(batch.template().to_vec());
+            }
+        }
+        entry.revision = revision;
+        entry.vertices_per_instance = batch.vertices_per_instance();
+        entry.dirty = changed;
+        entry.material_index = material_index.0;
+        entry.enabled = maybe_visibility
+            .map(ComputedVisibility::is_visible)
+            .unwrap_or(true);
+        entry.transparent = materials.get(*materials_id).alpha_blend != 0;
+        entry.keep_alive = true;
+        entry.position = transform.position();
+        entry.transform_index = transforms.get().len().try_into().unwrap();
+        transforms.get_mut().push(transform);
+    }
+    commands.insert_or_spawn_batch(extracted_entities);
+
+    buffers
+        .entries
+        .retain(|_, entry| std::mem::take(&mut entry.keep_alive));
+}
+
+pub(crate) fn prepare_primitives<P: PrimitiveBatch>(
+    pipelines: Res<PrimitivePipelines<P>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut buffers: ResMut<PrimitiveBufferCache<P>>,
+) {
+    for entry in buffers.entries.values_mut() {
+        if !entry.dirty {
+            continue;
+        }
+        if P::HAS_TEMPLATE {
+            entry.template.write_buffer(&render_device, &render_queue);
+        }
+        for chunk in entry.chunks.iter_mut() {
+            chunk.buffer.write_buffer(&render_device, &render_queue);
+            let mut entries = vec![BindGroupEntry {
+                binding: 0,
+                resource: chunk.buffer.binding().unwrap(),
+            }];
+            if P::HAS_TEMPLATE {
+                entries.push(BindGroupEntry {
+                    binding: 1,
+                    resource: entry.template.binding().unwrap(),
+                });
+            }
+            chunk.bind_group = Some(render_device.create_bind_group(&BindGroupDescriptor {
+                label: Some(&format!("{}_instance_bind_group", P::LABEL)),
+                layout: &pipelines.instances_layout,
+                entries: &entries,
+            }));
+        }
+        entry.dirty = false;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn queue_primitives<P: PrimitiveBatch>(
+    pipelines: Res<PrimitivePipelines<P>>,
+    mut specialized_pipelines: ResMut<TrackedSpecializedPipelines<PrimitivePipelines<P>>>,
+    pipeline_cache: Res<PipelineCache>,
+    msaa: Res<Msaa>,
+    opaque_3d_draw_functions: Res<DrawFunctions<Opaque3d>>,
+    transparent_3d_draw_functions: Res<DrawFunctions<Transparent3d>>,
+    buffers: Res<PrimitiveBufferCache<P>>,
+    mut views: Query<(
+        &ExtractedView,
+        &VisibleEntities,
+        &mut RenderPhase<Opaque3d>,
+        &mut RenderPhase<Transparent3d>,
+    )>,
+) {
+    let draw_opaque = opaque_3d_draw_functions
+        .read()
+        .get_id::<DrawPrimitives<P>>()
+        .unwrap();
+    let draw_transparent = transparent_3d_draw_functions
+        .read()
+        .get_id::<DrawPrimitives<P>>()
+        .unwrap();
+
+    for (view, visible_entities, mut opaque_phase, mut transparent_phase) in views.iter_mut() {
+        let inverse_view_row_2 = view.transform.compute_matrix().inverse().row(2);
+
+        for &entity in &visible_entities.entities {
+            let Some(entry) = buffers.entries.get(&entity) else {
+                continue;
+            };
+            if !entry.enabled {
+                continue;
+            }
+            let distance = inverse_view_row_2.dot(entry.position.extend(1.0));
+            let pipeline = specialized_pipelines.specialize(
+                &pipeline_cache,
+                &pipelines,
+                PrimitivePipelineKey {
+                    transparent: entry.transparent,
+                    hdr: view.hdr,
+                    samples: msaa.samples(),
+                },
+            );
+            if entry.transparent {
+                transparent_phase.add(Transparent3d {
+                    pipeline,
+                    entity,
+                    distance,
+                    draw_function: draw_transparent,
+                });
+            } else {
+                opaque_phase.add(Opaque3d {
+                    pipeline,
+                    entity,
+                    distance,
+                    draw_function: draw_opaque,
+                });
+            }
+        }
+    }
+}
+
+/// Specializes the pipelines of a [`PrimitiveBatch`] for each
+/// [`PrimitivePipelineKey`].
+#[derive(Resource)]
+pub(crate) struct PrimitivePipelines<P: PrimitiveBatch> {
+    /// The opaque pipeline for LDR views without MSAA, which the others are
+    /// derived from.
+    descriptor: RenderPipelineDescriptor,
+
+    pub instances_layout: BindGroupLayout,
+    marker: PhantomData<P>,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct PrimitivePipelineKey {
+    pub transparent: bool,
+    pub hdr: bool,
+    pub samples: u32,
+}
+
+impl<P: PrimitiveBatch> FromWorld for PrimitivePipelines<P> {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let cuboids_pipelines = world.resource::<CuboidsPipelines>();
+        let shader_defs = world.resource::<CuboidsShaderDefs>();
+
+        let storage_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::VERTEX,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: BufferSize::new(0),
+            },
+            count: None,
+        };
+        let mut instances_entries = vec![storage_entry(0)];
+        if P::HAS_TEMPLATE {
+            instances_entries.push(storage_entry(1));
+        }
+        let instances_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some(&format!("{}_instances_layout", P::LABEL)),
+            entries: &instances_entries,
+        });
+
+        // Shares every bind group but the instances with the cuboids pipelines.
+        let layout = vec![
+            cuboids_pipelines.view_layout.clone(),
+            cuboids_pipelines.aux_layout.clone(),
+            cuboids_pipelines.transforms_layout.clone(),
+            instances_layout.clone(),
+        ];
+        let mut vertex_defs = shader_defs.unculled_vertex();
+        vertex_defs.push(P::SHADER_DEF.into());
+        let mut fragment_defs = shader_defs.fragment.clone();
+        fragment_defs.push(P::SHADER_DEF.into());
+
+        let descriptor = RenderPipelineDescriptor {
+            label: Some(format!("{}_pipeline", P::LABEL).into()),
+            layout,
+            vertex: VertexState {
+                shader: VERTEX_PULLING_SHADER_HANDLE.typed(),
+                shader_defs: vertex_defs,
+                entry_point: P::VERTEX_ENTRY_POINT.into(),
+                buffers: vec![],
+            },
+            fragment: Some(FragmentState {
+                shader: VERTEX_PULLING_SHADER_HANDLE.typed(),
+                shader_defs: fragment_defs,
+                entry_point: P::FRAGMENT_ENTRY_POINT.into(),
+                targets: vec![Some(ColorTargetState {
+                    format: TextureFormat::bevy_default(),
+                    blend: Some(BlendState::REPLACE),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState {
+                front_face: FrontFace::Ccw,
+                cull_mode: None,
+                unclipped_depth: false,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+                topology: P::TOPOLOGY,
+                strip_index_format: None,
+            },
+            depth_stencil: Some(DepthStencilState {
+                format: TextureFormat::Depth32Float,
+                depth_write_enabled: true,
+                depth_compare: shader_defs.depth_compare(CompareFunction::Greater),
+                stencil: StencilState {
+                    front: StencilFaceState::IGNORE,
+                    back: StencilFaceState::IGNORE,
+                    read_mask: 0,
+                    write_mask: 0,
+                },
+                bias: DepthBiasState {
+                    constant: 0,
+                    slope_scale: 0.0,
+                    clamp: 0.0,
+                },
+            }),
+            multisample: MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            push_constant_ranges: Vec::new(),
+        };
+
+        Self {
+            descriptor,
+            instances_layout,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<P: PrimitiveBatch> SpecializedRenderPipeline for PrimitivePipelines<P> {
+    type Key = PrimitivePipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let mut descriptor = self.descriptor.clone();
+        descriptor.label = Some(
+            format!(
+                "{}_{}{}pipeline",
+                P::LABEL,
+                if key.hdr { "hdr_" } else { "" },
+                if key.transparent { "transparent_" } else { "" },
+            )
+            .into(),
+        );
+        let target = descriptor.fragment.as_mut().unwrap().targets[0]
+            .as_mut()
+            .unwrap();
+        if key.hdr {
+            target.format = TextureFormat::Rgba16Float;
+        }
+        if key.transparent {
+            target.blend = Some(BlendState::ALPHA_BLENDING);
+            descriptor
+                .depth_stencil
+                .as_mut()
+                .unwrap()
+                .depth_write_enabled = false;
+        }
+        descriptor.multisample.count = key.samples;
+        descriptor
+    }
+}
+
+/// Like [`SetAuxBindGroup`](super::draw::SetAuxBindGroup), with the material of
+/// a primitive batch.
+pub(crate) struct SetPrimitivesAuxBindGroup<P, const I: usize>(PhantomData<P>);
+
+impl<P: PrimitiveBatch, Item: PhaseItem, const I: usize> RenderCommand<Item>
+    for SetPrimitivesAuxBindGroup<P, I>
+{
+    type Param = (SRes<PrimitiveBufferCache<P>>, SRes<AuxiliaryMeta>);
+    type ItemWorldQuery = Entity;
+    type ViewWorldQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        _item: &Item,
+        _view: (),
+        entity: Entity,
+        (buffers, aux_meta): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let entry = buffers.into_inner().entries.get(&entity).unwrap();
+        let Some(bind_group) = aux_meta.into_inner().bind_group.as_ref() else {
+            return RenderCommandResult::Failure;
+        };
+        pass.set_bind_group(I, bind_group, &[entry.material_index]);
+        RenderCommandResult::Success
+    }
+}
+
+/// Binds each chunk of a primitive batch at group `I` and draws it.
+pub(crate) struct DrawVertexPulledPrimitives<P, const I: usize>(PhantomData<P>);
+
+impl<P: PrimitiveBatch, Item: PhaseItem, const I: usize> RenderCommand<Item>
+    for DrawVertexPulledPrimitives<P, I>
+{
+    type Param = SRes<PrimitiveBufferCache<P>>;
+    type ItemWorldQuery = Entity;
+    type ViewWorldQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        _item: &Item,
+        _view: (),
+        entity: Entity,
+        buffers: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let entry = buffers.into_inner().entries.get(&entity).unwrap();
+        // Unlike cuboids, the vertex index is not split into bit fields, so
+        // that templates may have any number of vertices.
+        let vertices_per_instance = entry.vertices_per_instance;
+        let first_vertex = entry.transform_index * vertices_per_instance;
+        for chunk in entry.chunks.iter() {
+            let Some(bind_group) = chunk.bind_group.as_ref() else {
+                return RenderCommandResult::Failure;
+            };
+            let num_instances = chunk.buffer.get().len().try_into().unwrap();
+            pass.set_bind_group(I, bind_group, &[]);
+            pass.draw(
+                first_vertex..first_vertex + vertices_per_instance,
+                0..num_instances,
+            );
+        }
+        RenderCommandResult::Success
+    }
+}
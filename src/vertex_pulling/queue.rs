@@ -1,24 +1,102 @@
+use super::contour::CuboidsContourPipelines;
 use super::cuboid_cache::CuboidBufferCache;
-use super::draw::DrawCuboids;
-use super::pipeline::CuboidsPipelines;
+#[cfg(feature = "shadows")]
+use super::draw::DrawCuboidShadows;
+use super::draw::{DrawCuboids, DrawCuboidsMultiDraw, DrawCuboidsPrepass};
+use super::multi_draw::CuboidsMultiDraw;
+use super::oit::CuboidsOitPipelines;
+use super::overlay::CuboidsOverlay;
+use super::picking::CuboidsPickingPipeline;
+use super::pipeline::{
+    CuboidsPass, CuboidsPipelineKey, CuboidsPipelines, TrackedSpecializedPipelines,
+};
+use super::primitives::PrimitivePipelines;
+use super::shader_hook::CuboidsHookShaders;
+use crate::draw_stats::RenderedCuboidsDrawStats;
+use crate::{
+    CuboidsAnimation, CuboidsDrawStats, CuboidsError, CuboidsErrors, Cylinders, MeshInstances,
+    Spheres, DEPTH_MODE_ALWAYS_ON_TOP, DEPTH_MODE_DEFAULT, DEPTH_MODE_NO_WRITE, DEPTH_MODE_XRAY,
+    DRAW_PHASE_ALPHA_MASK, DRAW_PHASE_AUTO, DRAW_PHASE_OPAQUE, DRAW_PHASE_OVERLAY,
+    DRAW_PHASE_TRANSPARENT,
+};
 
-use bevy::core_pipeline::core_3d::Opaque3d;
+use bevy::core_pipeline::core_3d::{AlphaMask3d, Opaque3d, Transparent3d};
+use bevy::core_pipeline::prepass::{NormalPrepass, Opaque3dPrepass};
+#[cfg(feature = "shadows")]
+use bevy::pbr::{LightEntity, Shadow};
 use bevy::prelude::*;
 use bevy::render::render_phase::{DrawFunctions, RenderPhase};
+use bevy::render::render_resource::{CachedPipelineState, CachedRenderPipelineId, PipelineCache};
+use bevy::render::renderer::{RenderDevice, RenderQueue};
 use bevy::render::view::{ExtractedView, VisibleEntities};
+use bevy::utils::{HashMap, HashSet};
 
+/// The distance that batches drawn on top are sorted by, before their sort key
+/// offset. Beyond that of anything in front of the camera, which is negative,
+/// and small enough to keep the precision of the offsets.
+const ON_TOP_DISTANCE: f32 = 1.0e6;
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn queue_cuboids(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
     cuboids_pipelines: Res<CuboidsPipelines>,
+    mut specialized_pipelines: ResMut<TrackedSpecializedPipelines<CuboidsPipelines>>,
+    pipeline_cache: Res<PipelineCache>,
+    msaa: Res<Msaa>,
+    hook_shaders: Res<CuboidsHookShaders>,
     opaque_3d_draw_functions: Res<DrawFunctions<Opaque3d>>,
+    alpha_mask_3d_draw_functions: Res<DrawFunctions<AlphaMask3d>>,
+    transparent_3d_draw_functions: Res<DrawFunctions<Transparent3d>>,
+    overlay_draw_functions: Res<DrawFunctions<CuboidsOverlay>>,
     buffer_cache: Res<CuboidBufferCache>,
-    mut views: Query<(&ExtractedView, &VisibleEntities, &mut RenderPhase<Opaque3d>)>,
+    oit_pipelines: Option<Res<CuboidsOitPipelines>>,
+    mut multi_draw: Option<ResMut<CuboidsMultiDraw>>,
+    mut views: Query<(
+        &ExtractedView,
+        &VisibleEntities,
+        &mut RenderPhase<Opaque3d>,
+        &mut RenderPhase<AlphaMask3d>,
+        &mut RenderPhase<Transparent3d>,
+        &mut RenderPhase<CuboidsOverlay>,
+    )>,
 ) {
-    let draw_cuboids = opaque_3d_draw_functions
+    let draw_opaque_cuboids = opaque_3d_draw_functions
+        .read()
+        .get_id::<DrawCuboids>()
+        .unwrap();
+    let draw_alpha_mask_cuboids = alpha_mask_3d_draw_functions
         .read()
         .get_id::<DrawCuboids>()
         .unwrap();
+    let draw_transparent_cuboids = transparent_3d_draw_functions
+        .read()
+        .get_id::<DrawCuboids>()
+        .unwrap();
+    let draw_overlay_cuboids = overlay_draw_functions
+        .read()
+        .get_id::<DrawCuboids>()
+        .unwrap();
+    let draw_multi_draw_cuboids = opaque_3d_draw_functions
+        .read()
+        .get_id::<DrawCuboidsMultiDraw>();
+    if let Some(multi_draw) = multi_draw.as_mut() {
+        multi_draw.clear_draws();
+    }
+    // Members of `CuboidsMultiDraw` drawn by the current view, by pipeline
+    // and material.
+    let mut multi_draws = HashMap::default();
 
-    for (view, visible_entities, mut opaque_phase) in views.iter_mut() {
+    for (
+        view,
+        visible_entities,
+        mut opaque_phase,
+        mut alpha_mask_phase,
+        mut transparent_phase,
+        mut overlay_phase,
+    ) in views.iter_mut()
+    {
         // TODO: add method so we can use this on a vector
         // let range_finder = view.rangefinder3d();
         let inverse_view_matrix = view.transform.compute_matrix().inverse();
@@ -26,20 +104,291 @@ pub(crate) fn queue_cuboids(
 
         for &entity in &visible_entities.entities {
             if let Some(entry) = buffer_cache.entries.get(&entity) {
-                if entry.enabled {
-                    let pipeline = if view.hdr {
-                        cuboids_pipelines.hdr_pipeline_id
+                // Order-independent transparency and contour masks have their
+                // own phases, unless the batch is drawn on top of the former
+                // or in a phase of its choosing.
+                let on_top = entry.depth_mode == DEPTH_MODE_ALWAYS_ON_TOP;
+                let draw_phase = match entry.draw_phase {
+                    DRAW_PHASE_AUTO if entry.transparent || on_top => DRAW_PHASE_TRANSPARENT,
+                    DRAW_PHASE_AUTO => DRAW_PHASE_OPAQUE,
+                    draw_phase => draw_phase,
+                };
+                let oit = entry.draw_phase == DRAW_PHASE_AUTO && entry.transparent && !on_top;
+                if !entry.enabled || entry.contour_mask || (oit && oit_pipelines.is_some()) {
+                    continue;
+                }
+                let distance =
+                    inverse_view_row_2.dot(entry.position.extend(1.0)) + entry.sort_key_offset;
+                // The transparent and overlay phases sort batches back-to-front
+                // by increasing distance, so batches on top are drawn last.
+                let sorted_distance = if on_top {
+                    ON_TOP_DISTANCE + entry.sort_key_offset
+                } else {
+                    distance
+                };
+                let pass = if entry.transparent {
+                    CuboidsPass::Transparent
+                } else if entry.occluder {
+                    CuboidsPass::Occluder
+                } else {
+                    CuboidsPass::Opaque
+                };
+                let key = CuboidsPipelineKey {
+                    pass,
+                    hdr: view.hdr,
+                    samples: msaa.samples(),
+                    hook_shader: hook_shaders.get(entry.material_id).cloned(),
+                    depth_mode: entry.depth_mode,
+                };
+                if entry.depth_mode == DEPTH_MODE_XRAY {
+                    let pipeline = specialized_pipelines.specialize(
+                        &pipeline_cache,
+                        &cuboids_pipelines,
+                        CuboidsPipelineKey {
+                            pass: CuboidsPass::XRay,
+                            ..key.clone()
+                        },
+                    );
+                    if draw_phase == DRAW_PHASE_OVERLAY {
+                        overlay_phase.add(CuboidsOverlay {
+                            distance,
+                            entity,
+                            pipeline,
+                            draw_function: draw_overlay_cuboids,
+                        });
                     } else {
-                        cuboids_pipelines.pipeline_id
-                    };
-                    opaque_phase.add(Opaque3d {
+                        transparent_phase.add(Transparent3d {
+                            pipeline,
+                            entity,
+                            distance,
+                            draw_function: draw_transparent_cuboids,
+                        });
+                    }
+                }
+                let pipeline =
+                    specialized_pipelines.specialize(&pipeline_cache, &cuboids_pipelines, key);
+                match draw_phase {
+                    DRAW_PHASE_OPAQUE => {
+                        if let Some(draw) = multi_draw.as_ref().and_then(|m| m.draw(entity, entry))
+                        {
+                            multi_draws
+                                .entry((pipeline, entry.material_index))
+                                .or_insert_with(Vec::new)
+                                .push((distance, draw));
+                        } else {
+                            opaque_phase.add(Opaque3d {
+                                pipeline,
+                                entity,
+                                distance,
+                                draw_function: draw_opaque_cuboids,
+                            });
+                        }
+                    }
+                    DRAW_PHASE_ALPHA_MASK => alpha_mask_phase.add(AlphaMask3d {
+                        pipeline,
+                        entity,
+                        distance,
+                        draw_function: draw_alpha_mask_cuboids,
+                    }),
+                    DRAW_PHASE_OVERLAY => overlay_phase.add(CuboidsOverlay {
+                        distance: sorted_distance,
+                        entity,
+                        pipeline,
+                        draw_function: draw_overlay_cuboids,
+                    }),
+                    _ => transparent_phase.add(Transparent3d {
                         pipeline,
                         entity,
-                        distance: inverse_view_row_2.dot(entry.position.extend(1.0)),
-                        draw_function: draw_cuboids,
-                    });
+                        distance: sorted_distance,
+                        draw_function: draw_transparent_cuboids,
+                    }),
                 }
             }
         }
+
+        let (Some(multi_draw), Some(draw_function)) =
+            (multi_draw.as_mut(), draw_multi_draw_cuboids)
+        else {
+            continue;
+        };
+        for ((pipeline, material_index), draws) in multi_draws.drain() {
+            // Sorted by the nearest member, which is drawn first.
+            let distance = draws.iter().map(|&(d, _)| d).fold(f32::MAX, f32::min);
+            let item = multi_draw.push_draws(material_index, draws.into_iter().map(|(_, d)| d));
+            opaque_phase.add(Opaque3d {
+                pipeline,
+                entity: commands.spawn(item).id(),
+                distance,
+                draw_function,
+            });
+        }
+    }
+    if let Some(multi_draw) = multi_draw.as_mut() {
+        multi_draw.write_draws(&render_device, &render_queue);
+    }
+}
+
+/// Queues opaque batches into the depth and normal prepass of each view that
+/// has one, so that effects reading the prepass textures see cuboids too.
+pub(crate) fn queue_cuboids_prepass(
+    cuboids_pipelines: Res<CuboidsPipelines>,
+    mut specialized_pipelines: ResMut<TrackedSpecializedPipelines<CuboidsPipelines>>,
+    pipeline_cache: Res<PipelineCache>,
+    msaa: Res<Msaa>,
+    prepass_draw_functions: Res<DrawFunctions<Opaque3dPrepass>>,
+    buffer_cache: Res<CuboidBufferCache>,
+    mut views: Query<(
+        &ExtractedView,
+        &VisibleEntities,
+        &mut RenderPhase<Opaque3dPrepass>,
+        Option<&NormalPrepass>,
+    )>,
+) {
+    let draw_cuboids_prepass = prepass_draw_functions
+        .read()
+        .get_id::<DrawCuboidsPrepass>()
+        .unwrap();
+
+    for (view, visible_entities, mut prepass_phase, normal_prepass) in views.iter_mut() {
+        let pass = if normal_prepass.is_some() {
+            CuboidsPass::NormalPrepass
+        } else {
+            CuboidsPass::Prepass
+        };
+        let pipeline = specialized_pipelines.specialize(
+            &pipeline_cache,
+            &cuboids_pipelines,
+            CuboidsPipelineKey {
+                pass,
+                hdr: view.hdr,
+                samples: msaa.samples(),
+                hook_shader: None,
+                depth_mode: DEPTH_MODE_DEFAULT,
+            },
+        );
+        let inverse_view_row_2 = view.transform.compute_matrix().inverse().row(2);
+        for &entity in &visible_entities.entities {
+            let Some(entry) = buffer_cache.entries.get(&entity) else {
+                continue;
+            };
+            // Only batches that write depth in the main pass write the prepass,
+            // and only those drawn before the transparent geometry.
+            let writes_depth = !matches!(
+                entry.depth_mode,
+                DEPTH_MODE_NO_WRITE | DEPTH_MODE_ALWAYS_ON_TOP
+            ) && !matches!(
+                entry.draw_phase,
+                DRAW_PHASE_TRANSPARENT | DRAW_PHASE_OVERLAY
+            );
+            if !entry.enabled || entry.transparent || entry.contour_mask || !writes_depth {
+                continue;
+            }
+            prepass_phase.add(Opaque3dPrepass {
+                entity,
+                pipeline_id: pipeline,
+                draw_function: draw_cuboids_prepass,
+                distance: inverse_view_row_2.dot(entry.position.extend(1.0)),
+            });
+        }
+    }
+}
+
+/// Queues the batches that cast shadows into the shadow views of each light.
+#[cfg(feature = "shadows")]
+pub(crate) fn queue_cuboid_shadows(
+    cuboids_pipelines: Res<CuboidsPipelines>,
+    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
+    buffer_cache: Res<CuboidBufferCache>,
+    mut views: Query<(&LightEntity, &VisibleEntities, &mut RenderPhase<Shadow>)>,
+) {
+    let draw_cuboid_shadows = shadow_draw_functions
+        .read()
+        .get_id::<DrawCuboidShadows>()
+        .unwrap();
+
+    for (light_entity, visible_entities, mut shadow_phase) in views.iter_mut() {
+        let pipeline = match light_entity {
+            LightEntity::Directional { .. } => cuboids_pipelines.directional_shadow_pipeline_id,
+            LightEntity::Point { .. } | LightEntity::Spot { .. } => {
+                cuboids_pipelines.shadow_pipeline_id
+            }
+        };
+        for &entity in &visible_entities.entities {
+            let Some(entry) = buffer_cache.entries.get(&entity) else {
+                continue;
+            };
+            if !entry.enabled || !entry.casts_shadows {
+                continue;
+            }
+            shadow_phase.add(Shadow {
+                pipeline,
+                entity,
+                distance: 0.0,
+                draw_function: draw_cuboid_shadows,
+            });
+        }
+    }
+}
+
+/// Counts the batches that are drawn this frame, for [`CuboidsDrawStats`].
+pub(crate) fn report_cuboids_draw_stats(
+    buffer_cache: Res<CuboidBufferCache>,
+    multi_draw: Option<Res<CuboidsMultiDraw>>,
+    stats: Res<RenderedCuboidsDrawStats>,
+) {
+    let mut draw_stats = CuboidsDrawStats {
+        gpu_bytes: buffer_cache.gpu_size() + multi_draw.map_or(0, |m| m.gpu_size()),
+        upload_bytes: buffer_cache.uploaded_bytes,
+        ..default()
+    };
+    for entry in buffer_cache.entries.values().filter(|entry| !entry.gizmo) {
+        if entry.enabled {
+            draw_stats.batches_drawn += 1;
+            draw_stats.instances_drawn += entry.num_instances;
+        } else if entry.frustum_culled {
+            draw_stats.instances_culled += entry.num_instances;
+        }
+    }
+    stats.set(draw_stats);
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn report_pipeline_errors(
+    cuboids_pipelines: Res<CuboidsPipelines>,
+    specialized_pipelines: Res<TrackedSpecializedPipelines<CuboidsPipelines>>,
+    picking_pipeline: Option<Res<CuboidsPickingPipeline>>,
+    oit_pipelines: Option<Res<TrackedSpecializedPipelines<CuboidsOitPipelines>>>,
+    contour_pipelines: Res<TrackedSpecializedPipelines<CuboidsContourPipelines>>,
+    spheres_pipelines: Option<Res<TrackedSpecializedPipelines<PrimitivePipelines<Spheres>>>>,
+    cylinders_pipelines: Option<Res<TrackedSpecializedPipelines<PrimitivePipelines<Cylinders>>>>,
+    mesh_instances_pipelines: Option<
+        Res<TrackedSpecializedPipelines<PrimitivePipelines<MeshInstances>>>,
+    >,
+    animation_pipelines: Option<
+        Res<TrackedSpecializedPipelines<PrimitivePipelines<CuboidsAnimation>>>,
+    >,
+    pipeline_cache: Res<PipelineCache>,
+    errors: Res<CuboidsErrors>,
+    mut reported: Local<HashSet<CachedRenderPipelineId>>,
+) {
+    let picking_pipeline_id = picking_pipeline.map(|p| p.pipeline_id);
+    let mut ids = cuboids_pipelines.ids();
+    ids.extend(specialized_pipelines.ids());
+    ids.extend(picking_pipeline_id);
+    ids.extend(oit_pipelines.iter().flat_map(|p| p.ids()));
+    ids.extend(contour_pipelines.ids());
+    ids.extend(spheres_pipelines.iter().flat_map(|p| p.ids()));
+    ids.extend(cylinders_pipelines.iter().flat_map(|p| p.ids()));
+    ids.extend(mesh_instances_pipelines.iter().flat_map(|p| p.ids()));
+    ids.extend(animation_pipelines.iter().flat_map(|p| p.ids()));
+    for pipeline in ids {
+        if let CachedPipelineState::Err(err) = pipeline_cache.get_render_pipeline_state(pipeline) {
+            if reported.insert(pipeline) {
+                errors.send(CuboidsError::PipelineCompileFailed {
+                    pipeline,
+                    message: err.to_string(),
+                });
+            }
+        }
     }
 }
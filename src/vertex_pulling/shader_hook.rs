@@ -0,0 +1,33 @@
+use crate::shader_hook::CuboidShaderHookShaders;
+use crate::CuboidMaterialMap;
+
+use bevy::prelude::*;
+use bevy::render::Extract;
+use bevy::utils::HashMap;
+
+/// The shader of each material with a [`CuboidShaderHook`](crate::CuboidShaderHook),
+/// which its main pipelines are specialized with, see
+/// [`CuboidsPipelineKey::hook_shader`](super::pipeline::CuboidsPipelineKey::hook_shader).
+#[derive(Default, Resource)]
+pub(crate) struct CuboidsHookShaders {
+    by_material: HashMap<usize, Handle<Shader>>,
+}
+
+impl CuboidsHookShaders {
+    pub fn get(&self, material_id: usize) -> Option<&Handle<Shader>> {
+        self.by_material.get(&material_id)
+    }
+}
+
+pub(crate) fn extract_cuboid_shader_hooks(
+    materials: Extract<Res<CuboidMaterialMap>>,
+    hook_shaders: Extract<Res<CuboidShaderHookShaders>>,
+    mut extracted: ResMut<CuboidsHookShaders>,
+) {
+    extracted.by_material.clear();
+    for (id, hook) in materials.shader_hooks() {
+        if let Some(shader) = hook_shaders.handles.get(hook) {
+            extracted.by_material.insert(id.0, shader.clone_weak());
+        }
+    }
+}
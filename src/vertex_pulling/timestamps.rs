@@ -0,0 +1,311 @@
+use super::cuboid_cache::CuboidBufferCache;
+use crate::draw_stats::RenderedCuboidsDrawStats;
+use crate::CuboidsTimings;
+
+use bevy::{
+    core::cast_slice,
+    prelude::*,
+    render::{
+        render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+        render_resource::{Buffer, BufferDescriptor, BufferUsages, Maintain, MapMode},
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+    },
+    utils::HashMap,
+};
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Arc, Mutex,
+};
+use wgpu::{CommandEncoder, QuerySet, QuerySetDescriptor, QueryType};
+
+/// Render graph nodes that write a timestamp before and after the main 3D pass
+/// of each camera.
+pub(crate) const CUBOIDS_DRAW_BEGIN_NODE: &str = "cuboids_draw_begin_timestamp";
+pub(crate) const CUBOIDS_DRAW_END_NODE: &str = "cuboids_draw_end_timestamp";
+/// Main render graph node that resolves the timestamps of a frame, once every
+/// camera is drawn.
+pub(crate) const CUBOIDS_TIMESTAMPS_NODE: &str = "cuboids_resolve_timestamps";
+
+/// Pairs of begin and end timestamps that can be written per frame. Passes
+/// beyond this aren't timed.
+const MAX_TIMED_PASSES: u32 = 64;
+/// Frames that can wait to be read back at once. Later frames aren't timed
+/// until one is.
+const MAX_PENDING_READBACKS: usize = 3;
+const TIMESTAMP_SIZE: u64 = std::mem::size_of::<u64>() as u64;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum TimedPass {
+    Cull,
+    Draw,
+}
+
+/// Timestamp queries around the culling and main passes, read back into
+/// [`CuboidsTimings`] a few frames later.
+#[derive(Resource)]
+pub(crate) struct CuboidsTimestamps {
+    query_set: QuerySet,
+    resolve_buffer: Buffer,
+    /// Nanoseconds per timestamp tick.
+    period: f32,
+    /// The readback of this frame, `None` while too many are pending.
+    frame: Option<TimestampReadback>,
+    pending: Vec<TimestampReadback>,
+    free_buffers: Vec<Buffer>,
+}
+
+struct TimestampReadback {
+    buffer: Buffer,
+    /// The pass timed by each pair of queries.
+    passes: Mutex<Vec<TimedPass>>,
+    /// The begin query of each camera's main pass, until its end is written.
+    open_draws: Mutex<HashMap<Entity, u32>>,
+    state: Arc<AtomicU8>,
+}
+
+const READBACK_PENDING: u8 = 0;
+const READBACK_MAPPED: u8 = 1;
+const READBACK_FAILED: u8 = 2;
+
+impl TimestampReadback {
+    fn new(buffer: Buffer) -> Self {
+        Self {
+            buffer,
+            passes: default(),
+            open_draws: default(),
+            state: default(),
+        }
+    }
+
+    fn map(&self) {
+        let state = self.state.clone();
+        self.buffer
+            .slice(..)
+            .map_async(MapMode::Read, move |result| {
+                let new_state = match result {
+                    Ok(()) => READBACK_MAPPED,
+                    Err(err) => {
+                        warn!("Failed to read back cuboid timestamps: {err}");
+                        READBACK_FAILED
+                    }
+                };
+                state.store(new_state, Ordering::Release);
+            });
+    }
+
+    /// Milliseconds of the culling and main passes, summed over every camera.
+    fn read(&self, period: f32) -> (f32, f32) {
+        let passes = self.passes.lock().unwrap();
+        let ticks: Vec<u64> = {
+            let data = self.buffer.slice(..).get_mapped_range();
+            cast_slice(&data).to_vec()
+        };
+        self.buffer.unmap();
+        let (mut cull_ms, mut draw_ms) = (0.0, 0.0);
+        for (pass, pair) in passes.iter().zip(ticks.chunks_exact(2)) {
+            // Timestamps can go backwards on some devices.
+            let ms = pair[1].saturating_sub(pair[0]) as f32 * period / 1e6;
+            match pass {
+                TimedPass::Cull => cull_ms += ms,
+                TimedPass::Draw => draw_ms += ms,
+            }
+        }
+        (cull_ms, draw_ms)
+    }
+}
+
+impl FromWorld for CuboidsTimestamps {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let query_set = render_device
+            .wgpu_device()
+            .create_query_set(&QuerySetDescriptor {
+                label: Some("cuboids_timestamps"),
+                ty: QueryType::Timestamp,
+                count: 2 * MAX_TIMED_PASSES,
+            });
+        let resolve_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("cuboids_timestamps_resolve_buffer"),
+            size: 2 * u64::from(MAX_TIMED_PASSES) * TIMESTAMP_SIZE,
+            usage: BufferUsages::QUERY_RESOLVE | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        Self {
+            query_set,
+            resolve_buffer,
+            period: world.resource::<RenderQueue>().get_timestamp_period(),
+            frame: None,
+            pending: Vec::new(),
+            free_buffers: Vec::new(),
+        }
+    }
+}
+
+impl CuboidsTimestamps {
+    /// Writes the begin timestamp of a new pair, if this frame is timed and
+    /// has room for it.
+    pub fn begin(&self, encoder: &mut CommandEncoder, pass: TimedPass) -> Option<u32> {
+        let mut passes = self.frame.as_ref()?.passes.lock().unwrap();
+        if passes.len() as u32 >= MAX_TIMED_PASSES {
+            return None;
+        }
+        let query = 2 * passes.len() as u32;
+        passes.push(pass);
+        encoder.write_timestamp(&self.query_set, query);
+        Some(query)
+    }
+
+    /// Writes the end timestamp of the pair started by [`Self::begin`].
+    pub fn end(&self, encoder: &mut CommandEncoder, query: u32) {
+        encoder.write_timestamp(&self.query_set, query + 1);
+    }
+}
+
+/// Starts timing a frame, unless too many frames are still being read back.
+pub(crate) fn prepare_cuboids_timestamps(
+    render_device: Res<RenderDevice>,
+    mut timestamps: ResMut<CuboidsTimestamps>,
+) {
+    if timestamps.frame.is_some() || timestamps.pending.len() >= MAX_PENDING_READBACKS {
+        return;
+    }
+    let buffer = timestamps.free_buffers.pop().unwrap_or_else(|| {
+        render_device.create_buffer(&BufferDescriptor {
+            label: Some("cuboids_timestamps_readback_buffer"),
+            size: 2 * u64::from(MAX_TIMED_PASSES) * TIMESTAMP_SIZE,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        })
+    });
+    timestamps.frame = Some(TimestampReadback::new(buffer));
+}
+
+/// Maps the timestamps of this frame, and reports the newest of the earlier
+/// ones that are mapped.
+///
+/// Runs after the render graph, like
+/// [`read_back_cuboids_picking`](super::picking::read_back_cuboids_picking).
+pub(crate) fn read_back_cuboids_timestamps(
+    render_device: Res<RenderDevice>,
+    buffer_cache: Res<CuboidBufferCache>,
+    stats: Res<RenderedCuboidsDrawStats>,
+    mut timestamps: ResMut<CuboidsTimestamps>,
+) {
+    let CuboidsTimestamps {
+        period,
+        frame,
+        pending,
+        free_buffers,
+        ..
+    } = &mut *timestamps;
+    if let Some(frame) = frame.take() {
+        if frame.passes.lock().unwrap().is_empty() {
+            free_buffers.push(frame.buffer);
+        } else {
+            frame.map();
+            pending.push(frame);
+        }
+    }
+
+    render_device.poll(Maintain::Poll);
+    let mut gpu_timings = None;
+    pending.retain(|readback| match readback.state.load(Ordering::Acquire) {
+        READBACK_PENDING => true,
+        READBACK_MAPPED => {
+            gpu_timings = Some(readback.read(*period));
+            free_buffers.push(readback.buffer.clone());
+            false
+        }
+        _ => false,
+    });
+    if let Some((cull_ms, draw_ms)) = gpu_timings {
+        stats.set_timings(CuboidsTimings {
+            prepare_ms: buffer_cache.prepare_time.as_secs_f32() * 1e3,
+            cull_ms,
+            draw_ms,
+        });
+    }
+}
+
+/// Writes the timestamp before or after the main 3D pass of a camera.
+pub(crate) struct CuboidsDrawTimestampNode {
+    end: bool,
+}
+
+impl CuboidsDrawTimestampNode {
+    pub const IN_VIEW: &'static str = "view";
+
+    pub fn begin() -> Self {
+        Self { end: false }
+    }
+
+    pub fn end() -> Self {
+        Self { end: true }
+    }
+}
+
+impl Node for CuboidsDrawTimestampNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(Self::IN_VIEW, SlotType::Entity)]
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+        let timestamps = world.resource::<CuboidsTimestamps>();
+        let Some(frame) = timestamps.frame.as_ref() else {
+            return Ok(());
+        };
+        let encoder = render_context.command_encoder();
+        if self.end {
+            let query = frame.open_draws.lock().unwrap().remove(&view_entity);
+            if let Some(query) = query {
+                timestamps.end(encoder, query);
+            }
+        } else if let Some(query) = timestamps.begin(encoder, TimedPass::Draw) {
+            frame.open_draws.lock().unwrap().insert(view_entity, query);
+        }
+        Ok(())
+    }
+}
+
+/// Copies the timestamps of every camera into the readback buffer of the
+/// frame.
+pub(crate) struct CuboidsTimestampsNode;
+
+impl Node for CuboidsTimestampsNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let timestamps = world.resource::<CuboidsTimestamps>();
+        let Some(frame) = timestamps.frame.as_ref() else {
+            return Ok(());
+        };
+        let num_queries = 2 * frame.passes.lock().unwrap().len() as u32;
+        if num_queries == 0 {
+            return Ok(());
+        }
+        let encoder = render_context.command_encoder();
+        encoder.resolve_query_set(
+            &timestamps.query_set,
+            0..num_queries,
+            &timestamps.resolve_buffer,
+            0,
+        );
+        encoder.copy_buffer_to_buffer(
+            &timestamps.resolve_buffer,
+            0,
+            &frame.buffer,
+            0,
+            u64::from(num_queries) * TIMESTAMP_SIZE,
+        );
+        Ok(())
+    }
+}
@@ -21,6 +21,30 @@ fn hsl_to_nonlinear_srgb(hue: f32, saturation: f32, lightness: f32) -> vec3<f32>
     return rgb_temp + lightness_match;
 }
 
+fn unpack_rgba(color: u32) -> vec4<f32> {
+    return vec4<f32>(
+        f32(color & 0xFFu),
+        f32((color >> 8u) & 0xFFu),
+        f32((color >> 16u) & 0xFFu),
+        f32(color >> 24u)
+    ) / 255.0;
+}
+
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    let low = color / 12.92;
+    let high = pow((color + vec3<f32>(0.055)) / 1.055, vec3<f32>(2.4));
+    return select(high, low, color <= vec3<f32>(0.04045));
+}
+
+fn unpack_rgb(color: u32) -> vec4<f32> {
+    return vec4<f32>(
+        f32(color & 0xFFu),
+        f32((color >> 8u) & 0xFFu),
+        f32((color >> 16u) & 0xFFu),
+        255.0
+    ) / 255.0;
+}
+
 struct View {
     view_proj: mat4x4<f32>,
     inverse_view_proj: mat4x4<f32>,
@@ -33,6 +57,41 @@ struct View {
     height: f32,
 }
 
+#ifdef FOG
+struct CuboidsFog {
+    // Linear RGB, with the alpha scaling the amount of fog.
+    color: vec4<f32>,
+    falloff: vec3<f32>,
+    // 0 = off, 1 = linear, 2 = exponential, 3 = exponential squared, 4 = atmospheric
+    mode: u32,
+    inscattering: vec3<f32>,
+}
+#endif
+
+struct CuboidEffects {
+    // Seconds, wrapped.
+    time: f32,
+    pulse_period: f32,
+    pulse_brightness: f32,
+    pulse_scale: f32,
+    flash_period: f32,
+    flash_brightness: f32,
+    time_wrap_period: f32,
+    spawn_duration: f32,
+    despawn_duration: f32,
+}
+
+struct CuboidsView {
+    clipping_enabled: u32,
+    lod_level: u32,
+    // Zero without a `CuboidsAtlas`.
+    atlas_grid: vec2<u32>,
+    effects: CuboidEffects,
+    #ifdef FOG
+    fog: CuboidsFog,
+    #endif
+}
+
 struct ScalarHueOptions {
     min_visible: f32,
     max_visible: f32,
@@ -47,10 +106,33 @@ struct ScalarHueOptions {
 struct CuboidMaterial {
     color_mode: u32,
     wireframe: u32, // Any nonzero value means "on".
-    _pad0: u32,
+    ignore_clipping: u32, // Any nonzero value means "on".
     _pad1: u32,
     scalar_hue: ScalarHueOptions,
     emissive_gain: vec3<f32>,
+    alpha_blend: u32, // Any nonzero value means "on".
+    cast_shadows: u32,
+    lit: u32, // Any nonzero value means "on".
+    colormap: u32, // Index + 1 into `colormaps`, or 0 for the HSL ramp.
+    depth_mode: u32, // Only used on the CPU.
+    depth_bias: u32, // Added to the bias of each instance.
+    tint: vec4<f32>,
+    scalar_offset: f32,
+    dither_alpha: u32, // Any nonzero value means "on", unless blended.
+    explode_factor: f32,
+    explode_plane: vec4<f32>, // Unit normal and distance from the origin.
+    bevel_width: f32,
+    face_shading: vec3<f32>, // Up, sideways and down.
+    hemisphere_ambient: u32, // Any nonzero value means "on".
+    sky_color: vec3<f32>,
+    ground_color: vec3<f32>,
+    color_space: u32, // 1 for sRGB.
+    cutout_pattern: u32, // 1 for a grid, 2 for hatching.
+    cutout_space: u32, // 1 for object space.
+    cutout_spacing: f32,
+    cutout_coverage: f32,
+    draw_phase: u32, // Only used on the CPU.
+    sort_key_offset: f32, // Only used on the CPU.
 }
 
 struct ClippingPlaneRange {
@@ -58,17 +140,21 @@ struct ClippingPlaneRange {
     unit_normal: vec3<f32>,
     min_sdist: f32,
     max_sdist: f32,
+    keep_outside: u32, // Any nonzero value means "on".
 }
 
 struct ClippingPlaneRanges {
-    ranges: array<ClippingPlaneRange, 16>,
     num_ranges: u32,
+    // Nonzero to keep what any range keeps, instead of what all of them keep.
+    combine_any: u32,
+    ranges: array<ClippingPlaneRange, #{MAX_CLIPPING_PLANES}u>,
 }
 
 struct Cuboid {
     min: vec3<f32>,
     meta_bits: u32,
     max: vec3<f32>,
+    // Not kept up to date by `Cuboids::recolor`, see `colors`.
     color: u32,
 }
 
@@ -79,139 +165,963 @@ struct Cuboids {
 struct Transform {
     m: mat4x4<f32>,
     m_inv: mat4x4<f32>,
+    interior_color: u32,
+    has_interior_color: u32,
+    animation_t: f32,
+    // Bit 0 is set when `colors` holds six face colors per instance after the
+    // instance colors, and bit 1 when it then holds an atlas tile per instance.
+    // Bit 2 is set for batches that are never occlusion culled, and bit 3 when
+    // `colors` then holds the spawn and despawn times of each instance.
+    color_layout: u32,
+    // One byte per material slot, see `instance_slotted_material`.
+    material_slots: vec4<u32>,
+    // Bounds of the instances of a batch with quantized instances.
+    quantization_min: vec3<f32>,
+    quantization_step: vec3<f32>,
+}
+
+struct Transforms {
+    data: array<Transform>,
 }
 
 @group(0) @binding(0)
 var<uniform> view: View;
 
-@group(1) @binding(0)
-var<uniform> material: CuboidMaterial;
+@group(0) @binding(1)
+var<uniform> cuboids_view: CuboidsView;
 
-@group(1) @binding(1)
+// Planes that apply to this view.
+@group(0) @binding(2)
 var<uniform> clipping_planes: ClippingPlaneRanges;
 
+@group(1) @binding(0)
+var<uniform> batch_material: CuboidMaterial;
+
+struct SlottedMaterials {
+    materials: array<CuboidMaterial, 64>,
+}
+
+@group(1) @binding(6)
+var<uniform> slotted_materials: SlottedMaterials;
+
+@group(1) @binding(7)
+var atlas_texture: texture_2d<f32>;
+
+@group(1) @binding(8)
+var atlas_sampler: sampler;
+
+struct Colormaps {
+    // 16 evenly spaced stops per colormap.
+    stops: array<vec4<f32>, 256>,
+}
+
+@group(1) @binding(9)
+var<uniform> colormaps: Colormaps;
+
+// Interpolates `CuboidColormaps` entry `index` at `s` in [0, 1].
+fn sample_colormap(index: u32, s: f32) -> vec4<f32> {
+    let x = s * 15.0;
+    let below = min(u32(x), 14u);
+    let first = 16u * min(index, 15u) + below;
+    return mix(colormaps.stops[first], colormaps.stops[first + 1u], x - f32(below));
+}
+
+// The material being shaded with, set by `select_material` at the start of
+// every entry point.
+var<private> material: CuboidMaterial;
+
+// Selects the batch material for 0, and otherwise slotted material
+// `slotted - 1`.
+fn select_material(slotted: u32) {
+    if (slotted == 0u) {
+        material = batch_material;
+    } else {
+        material = slotted_materials.materials[slotted - 1u];
+    }
+}
+
+// The `CuboidMaterialSlots` entry for the slot in `meta_bits`, packed as the
+// material id + 1, or 0 for the batch material.
+fn instance_slotted_material(transform: Transform, meta_bits: u32) -> u32 {
+    let slot = (meta_bits >> 4u) & 0xFu;
+    return (transform.material_slots[slot >> 2u] >> ((slot & 3u) * 8u)) & 0xFFu;
+}
+
+struct ClippingVolume {
+    volume_from_world: mat4x4<f32>,
+    // Only x is used for spheres, as the radius.
+    half_extents: vec3<f32>,
+    is_sphere: u32,
+    clip_outside: u32,
+}
+
+struct ClippingVolumes {
+    volumes: array<ClippingVolume, 8>,
+    num_volumes: u32,
+}
+
+@group(1) @binding(5)
+var<uniform> clipping_volumes: ClippingVolumes;
+
+#ifdef COLOR_KEYFRAMES
+struct ColorKeyframesHeader {
+    num_keyframes: u32,
+    time: f32,
+}
+
+struct ColorKeyframeTable {
+    data: array<u32>,
+}
+
+@group(1) @binding(2)
+var<uniform> color_keyframes: ColorKeyframesHeader;
+
+@group(1) @binding(3)
+var<storage> color_keyframe_table: ColorKeyframeTable;
+#endif
+
+#ifdef LIGHTING
+struct DirectionalLight {
+    color: vec3<f32>,
+    direction_to_light: vec3<f32>,
+}
+
+struct Lights {
+    directional: array<DirectionalLight, 4>,
+    ambient: vec3<f32>,
+    num_directional: u32,
+}
+
+@group(1) @binding(4)
+var<uniform> lights: Lights;
+#endif
+
+#ifdef DATA_TEXTURES
+// Devices without storage buffers read the same data from textures, with
+// 10 texels per transform and 4 texels per cuboid, laid out row by row.
+@group(2) @binding(0)
+var transforms_texture: texture_2d<u32>;
+
+@group(3) @binding(0)
+var instances_texture: texture_2d<u32>;
+
+fn transform_texel(i: u32) -> vec4<u32> {
+    let width = u32(textureDimensions(transforms_texture).x);
+    return textureLoad(transforms_texture, vec2<i32>(i32(i % width), i32(i / width)), 0);
+}
+
+fn instance_texel(i: u32) -> vec4<u32> {
+    let width = u32(textureDimensions(instances_texture).x);
+    return textureLoad(instances_texture, vec2<i32>(i32(i % width), i32(i / width)), 0);
+}
+
+fn load_transform(index: u32) -> Transform {
+    let first = index * 10u;
+    let m = mat4x4<f32>(
+        bitcast<vec4<f32>>(transform_texel(first)),
+        bitcast<vec4<f32>>(transform_texel(first + 1u)),
+        bitcast<vec4<f32>>(transform_texel(first + 2u)),
+        bitcast<vec4<f32>>(transform_texel(first + 3u)),
+    );
+    let m_inv = mat4x4<f32>(
+        bitcast<vec4<f32>>(transform_texel(first + 4u)),
+        bitcast<vec4<f32>>(transform_texel(first + 5u)),
+        bitcast<vec4<f32>>(transform_texel(first + 6u)),
+        bitcast<vec4<f32>>(transform_texel(first + 7u)),
+    );
+    let interior = transform_texel(first + 8u);
+    return Transform(
+        m,
+        m_inv,
+        interior.x,
+        interior.y,
+        bitcast<f32>(interior.z),
+        interior.w,
+        transform_texel(first + 9u),
+        // Instances are never quantized in data textures.
+        vec3<f32>(0.0),
+        vec3<f32>(0.0),
+    );
+}
+
+fn load_cuboid(index: u32) -> Cuboid {
+    let min_texel = instance_texel(index * 4u);
+    let max_texel = instance_texel(index * 4u + 1u);
+    return Cuboid(
+        bitcast<vec3<f32>>(min_texel.xyz),
+        min_texel.w,
+        bitcast<vec3<f32>>(max_texel.xyz),
+        max_texel.w,
+    );
+}
+
+fn load_color(index: u32) -> u32 {
+    return instance_texel(index * 4u + 1u).w;
+}
+
+fn load_rotation(index: u32) -> vec4<f32> {
+    return bitcast<vec4<f32>>(instance_texel(index * 4u + 2u));
+}
+
+fn is_hidden(index: u32) -> bool {
+    return instance_texel(index * 4u + 3u).x != 0u;
+}
+
+fn load_user_data(index: u32) -> u32 {
+    return instance_texel(index * 4u + 3u).y;
+}
+#else
 @group(2) @binding(0)
-var<uniform> transform: Transform;
+var<storage> transforms: Transforms;
+
+struct Rotations {
+    data: array<vec4<f32>>,
+}
+
+#ifdef PACKED_INSTANCES
+// The bounds in pairs of 16 bits, then the meta bits, see
+// `CuboidsInstanceFormat::pack`.
+struct PackedCuboids {
+    data: array<vec4<u32>>,
+}
+
+#ifdef HALF_INSTANCES
+fn unpack_bounds(word: u32) -> vec2<f32> {
+    return unpack2x16float(word);
+}
+#else
+// Steps of the quantized batch bounds, see `dequantize_cuboid`.
+fn unpack_bounds(word: u32) -> vec2<f32> {
+    return vec2<f32>(f32(word & 0xFFFFu), f32(word >> 16u));
+}
+#endif
 
+@group(3) @binding(0)
+var<storage> cuboids: PackedCuboids;
+#else
 @group(3) @binding(0)
 var<storage> cuboids: Cuboids;
+#endif
+
+// Either one quaternion per cuboid, or a single identity.
+@group(3) @binding(1)
+var<storage> rotations: Rotations;
+
+struct HiddenMask {
+    data: array<u32>,
+}
+
+// One bit per cuboid, set for hidden cuboids.
+@group(3) @binding(3)
+var<storage> hidden_mask: HiddenMask;
+
+struct Colors {
+    data: array<u32>,
+}
+
+// One color per cuboid, kept apart from the bounds so it can be rewritten on its own.
+@group(3) @binding(4)
+var<storage> colors: Colors;
+
+struct UserData {
+    data: array<u32>,
+}
+
+// Either one word per cuboid, or a single zero.
+@group(3) @binding(5)
+var<storage> user_data: UserData;
+
+fn load_transform(index: u32) -> Transform {
+    return transforms.data[index];
+}
+
+fn load_cuboid(index: u32) -> Cuboid {
+    #ifdef PACKED_INSTANCES
+    let packed = cuboids.data[index];
+    let min_xy = unpack_bounds(packed.x);
+    let min_z_max_x = unpack_bounds(packed.y);
+    let max_yz = unpack_bounds(packed.z);
+    return Cuboid(
+        vec3<f32>(min_xy, min_z_max_x.x),
+        packed.w,
+        vec3<f32>(min_z_max_x.y, max_yz),
+        load_color(index),
+    );
+    #else
+    return cuboids.data[index];
+    #endif
+}
+
+fn load_color(index: u32) -> u32 {
+    return colors.data[index];
+}
+
+#ifdef QUANTIZED_INSTANCES
+// Maps quantized bounds into the local space of the batch.
+fn dequantize_cuboid(transform: Transform, cuboid: Cuboid) -> Cuboid {
+    var out = cuboid;
+    out.min = transform.quantization_min + cuboid.min * transform.quantization_step;
+    out.max = transform.quantization_min + cuboid.max * transform.quantization_step;
+    return out;
+}
+#endif
+
+fn load_rotation(index: u32) -> vec4<f32> {
+    return rotations.data[min(index, arrayLength(&rotations.data) - 1u)];
+}
+
+fn is_hidden(index: u32) -> bool {
+    return ((hidden_mask.data[index >> 5u] >> (index & 31u)) & 1u) != 0u;
+}
+
+fn load_user_data(index: u32) -> u32 {
+    return user_data.data[min(index, arrayLength(&user_data.data) - 1u)];
+}
+
+// The number of instances in this chunk, from the words per instance in
+// `colors`.
+fn num_chunk_instances(color_layout: u32) -> u32 {
+    let words = 1u + 6u * (color_layout & 1u) + ((color_layout >> 1u) & 1u) +
+        2u * ((color_layout >> 3u) & 1u);
+    return arrayLength(&colors.data) / words;
+}
+
+// One of the `Cuboids::face_colors` of an instance, ordered -X, +X, -Y, +Y,
+// -Z, +Z.
+fn load_face_color(color_layout: u32, index: u32, face: u32) -> u32 {
+    let num_instances = num_chunk_instances(color_layout);
+    return colors.data[num_instances + 6u * index + face];
+}
+
+fn load_atlas_tile(color_layout: u32, index: u32) -> u32 {
+    if ((color_layout & 2u) == 0u) {
+        // `NO_ATLAS_TILE`
+        return 0xFFFFFFFFu;
+    }
+    let num_instances = num_chunk_instances(color_layout);
+    return colors.data[num_instances * (1u + 6u * (color_layout & 1u)) + index];
+}
+
+// Seconds since the spawn and despawn of an instance, negative when it has no
+// such animation.
+fn load_spawn_ages(color_layout: u32, index: u32) -> vec2<f32> {
+    let num_instances = num_chunk_instances(color_layout);
+    let words = 1u + 6u * (color_layout & 1u) + ((color_layout >> 1u) & 1u);
+    let offset = num_instances * words + 2u * index;
+    var ages = vec2<f32>(-1.0);
+    for (var i = 0u; i < 2u; i += 1u) {
+        let time = colors.data[offset + i];
+        // `NO_SPAWN_TIME`
+        if (time != 0xFFFFFFFFu) {
+            ages[i] = seconds_since(bitcast<f32>(time));
+        }
+    }
+    return ages;
+}
+#endif
+
+// Seconds from `time` to now on the wrapped clock of `CuboidEffects`.
+fn seconds_since(time: f32) -> f32 {
+    let effects = cuboids_view.effects;
+    let age = effects.time - time;
+    return select(age, age + effects.time_wrap_period, age < 0.0);
+}
+
+#ifdef GPU_CULLING
+struct VisibleIndices {
+    data: array<u32>,
+}
+
+// Indices of the instances that passed the culling pass.
+@group(3) @binding(2)
+var<storage> visible_indices: VisibleIndices;
+#endif
+
+#ifdef PICKING
+struct PickingChunk {
+    first_instance: u32,
+}
+
+@group(3) @binding(2)
+var<uniform> picking_chunk: PickingChunk;
+#endif
+
+fn quat_rotate(q: vec4<f32>, v: vec3<f32>) -> vec3<f32> {
+    let t = 2.0 * cross(q.xyz, v);
+    return v + q.w * t + cross(q.xyz, t);
+}
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) color: vec4<f32>,
+    // The face coordinates of the corner in [-1, 1]^2, and its position in
+    // the plane of the face in the local units of the batch.
+    @location(1) face_position: vec4<f32>,
 
-    #ifdef OUTLINES
-    @location(1) face_center_to_corner: vec2<f32>,
+    @location(2) @interpolate(flat) interior_color: vec4<f32>,
+    // Bit 0 is set when the face winding was reversed by mirroring, bits 1 and
+    // 2 are the outline bits of `meta_bits`.
+    @location(3) @interpolate(flat) flags: u32,
+
+    #ifdef PICKING
+    // The batch transform index plus one, and the instance index in the batch.
+    @location(4) @interpolate(flat) picking_id: vec2<u32>,
+    #else
+    // See `bevel_factor`.
+    @location(4) @interpolate(flat) bevel: vec4<f32>,
+    #endif
+
+    #ifdef CLIPPING_CAPS
+    @location(5) world_position: vec3<f32>,
+    // Nonzero when the cuboid crosses a clipping boundary.
+    @location(6) @interpolate(flat) cut: u32,
+    @location(7) @interpolate(flat) box_center: vec3<f32>,
+    // Rows of the world to box matrix, where the box spans [-1, 1]^3.
+    @location(8) @interpolate(flat) box_from_world_x: vec3<f32>,
+    @location(9) @interpolate(flat) box_from_world_y: vec3<f32>,
+    @location(10) @interpolate(flat) box_from_world_z: vec3<f32>,
+    #endif
+
+    #ifdef FOG
+    @location(11) fog_world_position: vec3<f32>,
     #endif
+
+    #ifdef OIT
+    @location(12) view_depth: f32,
+    #endif
+
+    // Prepasses never draw transparent cuboids, so this shares a location with
+    // `view_depth`.
+    #ifdef PREPASS
+    @location(12) @interpolate(flat) world_normal: vec3<f32>,
+    #endif
+
+    // `Cuboids::user_data` of the instance.
+    @location(13) @interpolate(flat) user_data: u32,
+    // See `select_material`.
+    @location(14) @interpolate(flat) slotted_material: u32,
+    @location(15) @interpolate(flat) atlas_tile: u32,
 }
 
-fn discard_vertex() -> VertexOutput {
-    var out = VertexOutput();
-    // Apparently GPUs understand this magic.
-    out.clip_position.x = bitcast<f32>(0x7fc00000); // nan
-    return out;
+struct InstanceColor {
+    color: vec4<f32>,
+    // False for scalars outside of the material's visible range.
+    visible: bool,
 }
 
-@vertex
-fn vertex(@builtin(vertex_index) vertex_index: u32, @builtin(instance_index) instance_index: u32) -> VertexOutput {
-    var out: VertexOutput;
+// The color of an instance in the material's color mode, before emissive gain.
+fn instance_color(color: u32, meta_bits: u32) -> InstanceColor {
+    // Color keyframe playback mixes between two color values.
+    var color_a = color;
+    var color_b = color;
+    var color_t = 0.0;
 
-    let cuboid = cuboids.data[instance_index];
+    #ifdef COLOR_KEYFRAMES
+    let sequence = (meta_bits >> 8u) & 0x3Fu;
+    let num_keyframes = color_keyframes.num_keyframes;
+    if (sequence != 0u && num_keyframes > 0u) {
+        let last = num_keyframes - 1u;
+        let t = clamp(color_keyframes.time, 0.0, f32(last));
+        let k = min(u32(t), last);
+        let base = (sequence - 1u) * num_keyframes;
+        color_a = color_keyframe_table.data[base + k];
+        color_b = color_keyframe_table.data[base + min(k + 1u, last)];
+        color_t = t - f32(k);
+    }
+    #endif
 
-    // Check visibility mask.
-    if ((cuboid.meta_bits & 0x01u) != 0u) {
-        // DISCARD CUBOID
-        return discard_vertex();
+    var out = mixed_color(color_a, color_b, color_t);
+    out.color = vec4<f32>(out.color.rgb * effect_brightness(meta_bits), out.color.a);
+    return out;
+}
+
+// In [-1, 1], the phase of pulsing instances.
+fn pulse_wave() -> f32 {
+    let effects = cuboids_view.effects;
+    return sin(6.2831853 * effects.time / effects.pulse_period);
+}
+
+// The brightness factor of the pulse and flash bits in `meta_bits`.
+fn effect_brightness(meta_bits: u32) -> f32 {
+    let effects = cuboids_view.effects;
+    var brightness = 1.0;
+    if ((meta_bits & 0x4000u) != 0u) {
+        brightness *= 1.0 + effects.pulse_brightness * pulse_wave();
+    }
+    if ((meta_bits & 0x8000u) != 0u && fract(effects.time / effects.flash_period) >= 0.5) {
+        brightness *= effects.flash_brightness;
     }
+    return brightness;
+}
+
+// Mixes two color values in the material's color mode, by `color_t`.
+fn mixed_color(color_a: u32, color_b: u32, color_t: f32) -> InstanceColor {
+    var out: InstanceColor;
+    out.visible = true;
 
     if (material.color_mode == 1u) {
         // SCALAR HUE
         let opt = material.scalar_hue;
 
-        let scalar = bitcast<f32>(cuboid.color);
+        let scalar = mix(bitcast<f32>(color_a), bitcast<f32>(color_b), color_t)
+            + material.scalar_offset;
         if (scalar < opt.min_visible ||
             scalar > opt.max_visible)
         {
-            // DISCARD CUBOID
-            return discard_vertex();
+            out.visible = false;
+            return out;
         }
 
-        // HSL
         let cmin = opt.clamp_min;
         let cmax = opt.clamp_max;
         let s = (clamp(scalar, cmin, cmax) - cmin) / (cmax - cmin);
-        let hue = (360.0 + (opt.hue_zero + s * opt.hue_slope)) % 360.0;
-        out.color = vec4<f32>(hsl_to_nonlinear_srgb(hue, opt.saturation, opt.lightness), 1.0);
+        if (material.colormap != 0u) {
+            out.color = sample_colormap(material.colormap - 1u, s);
+        } else {
+            // HSL
+            let hue = (360.0 + (opt.hue_zero + s * opt.hue_slope)) % 360.0;
+            out.color = vec4<f32>(hsl_to_nonlinear_srgb(hue, opt.saturation, opt.lightness), 1.0);
+        }
     } else {
         // RGB
-        out.color = vec4<f32>(
-            f32(cuboid.color & 0xFFu),
-            f32((cuboid.color >> 8u) & 0xFFu),
-            f32((cuboid.color >> 16u) & 0xFFu),
-            255.0
-        ) / 255.0;
+        var rgba_a = unpack_rgb(color_a);
+        var rgba_b = unpack_rgb(color_b);
+        if (material.alpha_blend != 0u || material.dither_alpha != 0u) {
+            rgba_a = unpack_rgba(color_a);
+            rgba_b = unpack_rgba(color_b);
+        }
+        if (material.color_space == 1u) {
+            rgba_a = vec4<f32>(srgb_to_linear(rgba_a.rgb), rgba_a.a);
+            rgba_b = vec4<f32>(srgb_to_linear(rgba_b.rgb), rgba_b.a);
+        }
+        out.color = mix(rgba_a, rgba_b, color_t);
+    }
+    out.color *= material.tint;
+    return out;
+}
+
+// Whether `range` keeps points at the signed distance `sdist` from its plane.
+fn range_keeps(range: ClippingPlaneRange, sdist: f32) -> bool {
+    let inside = sdist >= range.min_sdist && sdist <= range.max_sdist;
+    return inside != (range.keep_outside != 0u);
+}
+
+// Whether the clipping planes remove the instance centered at `world_position`.
+fn clipped_by_planes(world_position: vec3<f32>) -> bool {
+    let combine_any = clipping_planes.combine_any != 0u;
+    for (var i = 0u; i < clipping_planes.num_ranges; i++) {
+        let range = clipping_planes.ranges[i];
+        let kept = range_keeps(range, dot(world_position - range.origin, range.unit_normal));
+        // One range decides when it clips all, or keeps any.
+        if (kept == combine_any) {
+            return !kept;
+        }
+    }
+    return combine_any && clipping_planes.num_ranges > 0u;
+}
+
+// Whether a clipping volume removes the instance centered at `world_position`.
+fn clipped_by_volumes(world_position: vec3<f32>) -> bool {
+    for (var i = 0u; i < clipping_volumes.num_volumes; i++) {
+        let volume = clipping_volumes.volumes[i];
+        let p = (volume.volume_from_world * vec4<f32>(world_position, 1.0)).xyz;
+        var inside: bool;
+        if (volume.is_sphere != 0u) {
+            inside = length(p) <= volume.half_extents.x;
+        } else {
+            inside = all(abs(p) <= volume.half_extents);
+        }
+        if (inside != (volume.clip_outside != 0u)) {
+            return true;
+        }
+    }
+    return false;
+}
+
+#ifdef LIGHTING
+fn directional_light(world_normal: vec3<f32>) -> vec3<f32> {
+    var light = vec3<f32>(0.0);
+    for (var i = 0u; i < lights.num_directional; i++) {
+        let directional = lights.directional[i];
+        light += directional.color * max(dot(world_normal, directional.direction_to_light), 0.0);
+    }
+    return light;
+}
+
+// Ambient plus directional light on a surface with `world_normal`.
+fn incident_light(world_normal: vec3<f32>) -> vec3<f32> {
+    return lights.ambient + directional_light(world_normal);
+}
+#endif
+
+// What the color of a cuboid face with `world_normal` is multiplied by: the
+// material's face orientation factors, its hemisphere ambient, and lights.
+fn face_light(world_normal: vec3<f32>) -> vec3<f32> {
+    let up = world_normal.y;
+    let orientation = select(
+        mix(material.face_shading.y, material.face_shading.z, -up),
+        mix(material.face_shading.y, material.face_shading.x, up),
+        up >= 0.0,
+    );
+    let hemisphere = material.hemisphere_ambient != 0u;
+    var light = vec3<f32>(1.0);
+    if (hemisphere) {
+        light = mix(material.ground_color, material.sky_color, 0.5 + 0.5 * up);
+    }
+    #ifdef LIGHTING
+    if (material.lit != 0u) {
+        light = select(lights.ambient, light, hemisphere) + directional_light(world_normal);
+    }
+    #endif
+    return orientation * light;
+}
+
+fn discard_vertex() -> VertexOutput {
+    var out = VertexOutput();
+    // Apparently GPUs understand this magic.
+    out.clip_position.x = bitcast<f32>(0x7fc00000); // nan
+    return out;
+}
+
+// The world space normal of the face that `vertex_index` lies on, after
+// mirroring the template towards the camera.
+fn face_world_normal(vertex_index: u32, mirror_mask: u32, rotation: vec4<f32>, transform: Transform) -> vec3<f32> {
+    // Face 0 is normal to Z, face 1 to Y, and face 2 to X. Template faces
+    // are on the minus side until mirrored.
+    let axis = 2u - ((vertex_index >> 3u) & 0x3u);
+    var normal = vec3<f32>(0.0);
+    normal[axis] = select(-1.0, 1.0, ((mirror_mask >> axis) & 0x1u) != 0u);
+    return normalize((vec4<f32>(quat_rotate(rotation, normal), 0.0) * transform.m_inv).xyz);
+}
+
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32, @builtin(instance_index) instance_index: u32) -> VertexOutput {
+    var out: VertexOutput;
+
+    // The base vertex of each draw encodes the batch transform index.
+    let transform = load_transform(vertex_index >> 5u);
+    #ifdef GPU_CULLING
+    let cuboid_index = visible_indices.data[instance_index];
+    #else
+    let cuboid_index = instance_index;
+    #endif
+    #ifdef QUANTIZED_INSTANCES
+    var cuboid = dequantize_cuboid(transform, load_cuboid(cuboid_index));
+    #else
+    var cuboid = load_cuboid(cuboid_index);
+    #endif
+    if ((cuboid.meta_bits & 0x4000u) != 0u && cuboids_view.effects.pulse_scale != 0.0) {
+        // Pulses about the center.
+        let center = (cuboid.min + cuboid.max) / 2.0;
+        let scale = 1.0 + cuboids_view.effects.pulse_scale * pulse_wave();
+        let half_extents = (cuboid.max - cuboid.min) * (scale / 2.0);
+        cuboid.min = center - half_extents;
+        cuboid.max = center + half_extents;
+    }
+    var spawn_alpha = 1.0;
+    #ifndef DATA_TEXTURES
+    if ((transform.color_layout & 8u) != 0u) {
+        // Grows from the center after spawning, and shrinks back while fading
+        // out after despawning.
+        let effects = cuboids_view.effects;
+        let ages = load_spawn_ages(transform.color_layout, cuboid_index);
+        var scale = 1.0;
+        if (ages.x >= 0.0) {
+            scale *= smoothstep(0.0, 1.0, ages.x / effects.spawn_duration);
+        }
+        if (ages.y >= 0.0) {
+            spawn_alpha = 1.0 - smoothstep(0.0, 1.0, ages.y / effects.despawn_duration);
+            scale *= spawn_alpha;
+        }
+        let center = (cuboid.min + cuboid.max) / 2.0;
+        let half_extents = (cuboid.max - cuboid.min) * (scale / 2.0);
+        cuboid.min = center - half_extents;
+        cuboid.max = center + half_extents;
+    }
+    #endif
+    out.slotted_material = instance_slotted_material(transform, cuboid.meta_bits);
+    select_material(out.slotted_material);
+    if (material.explode_factor != 0.0) {
+        // Moves away from the pivot plane, keeping the size.
+        let plane = material.explode_plane;
+        let center = (cuboid.min + cuboid.max) / 2.0;
+        let offset = plane.xyz * (material.explode_factor * (dot(center, plane.xyz) - plane.w));
+        cuboid.min += offset;
+        cuboid.max += offset;
+    }
+
+    // Check visibility mask.
+    if ((cuboid.meta_bits & 0x01u) != 0u || is_hidden(cuboid_index)) {
+        // DISCARD CUBOID
+        return discard_vertex();
+    }
+
+    // Check manual LOD level.
+    if (((cuboid.meta_bits >> 2u) & 0x3u) > cuboids_view.lod_level) {
+        // DISCARD CUBOID
+        return discard_vertex();
+    }
+
+    let color = instance_color(load_color(cuboid_index), cuboid.meta_bits);
+    if (!color.visible) {
+        // DISCARD CUBOID
+        return discard_vertex();
+    }
+    out.color = color.color;
+
+    if (transform.has_interior_color != 0u) {
+        var interior_rgb = unpack_rgb(transform.interior_color).rgb;
+        if (material.color_space == 1u) {
+            interior_rgb = srgb_to_linear(interior_rgb);
+        }
+        out.interior_color = vec4<f32>(interior_rgb, out.color.a);
+    } else {
+        out.interior_color = out.color;
     }
 
     if ((cuboid.meta_bits & 0x02u) != 0u) {
         out.color *= vec4(material.emissive_gain, 1.0);
+        out.interior_color *= vec4(material.emissive_gain, 1.0);
     }
 
     let cuboid_center = (cuboid.min + cuboid.max) / 2.0;
+    let rotation = load_rotation(cuboid_index);
+    let inv_rotation = vec4<f32>(-rotation.xyz, rotation.w);
 
-    if (clipping_planes.num_ranges > 0u) {
+    if (cuboids_view.clipping_enabled != 0u && material.ignore_clipping == 0u &&
+        (clipping_planes.num_ranges > 0u || clipping_volumes.num_volumes > 0u))
+    {
         let tfm_cuboid_center_v4 = transform.m * vec4<f32>(cuboid_center, 1.0);
         let tfm_cuboid_center = tfm_cuboid_center_v4.xyz / tfm_cuboid_center_v4.w;
 
+        #ifdef CLIPPING_CAPS
+        let half_extents = (cuboid.max - cuboid.min) / 2.0;
+        let half_x = (transform.m * vec4<f32>(quat_rotate(rotation, vec3<f32>(half_extents.x, 0.0, 0.0)), 0.0)).xyz;
+        let half_y = (transform.m * vec4<f32>(quat_rotate(rotation, vec3<f32>(0.0, half_extents.y, 0.0)), 0.0)).xyz;
+        let half_z = (transform.m * vec4<f32>(quat_rotate(rotation, vec3<f32>(0.0, 0.0, half_extents.z)), 0.0)).xyz;
+        #endif
+
         // Clip any cuboid instance that falls out of the allowed ranges.
+        #ifdef CLIPPING_CAPS
+        // Only cuboids that are entirely clipped are discarded. The ones
+        // crossing a boundary are cut in the fragment shader.
+        let combine_any = clipping_planes.combine_any != 0u;
+        var any_kept = false;
+        var any_clipped = false;
+        var all_clipped = true;
         for (var i = 0u; i < clipping_planes.num_ranges; i++) {
             let range = clipping_planes.ranges[i];
             let sdist_to_plane = dot(tfm_cuboid_center - range.origin, range.unit_normal);
-            if sdist_to_plane < range.min_sdist || sdist_to_plane > range.max_sdist {
+            let radius = abs(dot(half_x, range.unit_normal)) +
+                abs(dot(half_y, range.unit_normal)) +
+                abs(dot(half_z, range.unit_normal));
+            let lo = sdist_to_plane - radius;
+            let hi = sdist_to_plane + radius;
+            let fully_inside = lo >= range.min_sdist && hi <= range.max_sdist;
+            let fully_outside = hi < range.min_sdist || lo > range.max_sdist;
+            let keep_outside = range.keep_outside != 0u;
+            let kept = select(fully_inside, fully_outside, keep_outside);
+            let clipped = select(fully_outside, fully_inside, keep_outside);
+            any_kept = any_kept || kept;
+            any_clipped = any_clipped || clipped;
+            all_clipped = all_clipped && clipped;
+            if (!kept && !clipped) {
+                out.cut = 1u;
+            }
+        }
+        if (combine_any && clipping_planes.num_ranges > 0u) {
+            if (all_clipped) {
                 // DISCARD CUBOID
                 return discard_vertex();
             }
+            if (any_kept) {
+                out.cut = 0u;
+            }
+        } else if (any_clipped) {
+            // DISCARD CUBOID
+            return discard_vertex();
+        }
+        #else
+        if (clipped_by_planes(tfm_cuboid_center)) {
+            // DISCARD CUBOID
+            return discard_vertex();
+        }
+        #endif
+
+        #ifdef CLIPPING_CAPS
+        let det = dot(half_x, cross(half_y, half_z));
+        if (out.cut != 0u && det != 0.0) {
+            out.box_center = tfm_cuboid_center;
+            out.box_from_world_x = cross(half_y, half_z) / det;
+            out.box_from_world_y = cross(half_z, half_x) / det;
+            out.box_from_world_z = cross(half_x, half_y) / det;
+        } else {
+            out.cut = 0u;
+        }
+        #endif
+
+        if (clipped_by_volumes(tfm_cuboid_center)) {
+            // DISCARD CUBOID
+            return discard_vertex();
         }
     }
 
     // Need to do this calculation in cuboid (model) space so our offsets are grid-aligned.
-    let camera_in_cuboid_space_v4 = transform.m_inv * vec4<f32>(view.world_position, 1.0);
-    let camera_in_cuboid_space = camera_in_cuboid_space_v4.xyz / camera_in_cuboid_space_v4.w;
-    let offset = camera_in_cuboid_space - cuboid_center;
+    var to_camera: vec3<f32>;
+    if (view.projection[3].w == 1.0) {
+        // Orthographic views, including directional light views, see the same
+        // faces of every cuboid, whatever their position relative to the
+        // camera. The view's local Z axis points back towards the viewer.
+        to_camera = (transform.m_inv * vec4<f32>(view.view[2].xyz, 0.0)).xyz;
+    } else {
+        let camera_in_cuboid_space_v4 = transform.m_inv * vec4<f32>(view.world_position, 1.0);
+        let camera_in_cuboid_space = camera_in_cuboid_space_v4.xyz / camera_in_cuboid_space_v4.w;
+        to_camera = camera_in_cuboid_space - cuboid_center;
+    }
+    let offset = quat_rotate(inv_rotation, to_camera);
     let mirror_mask =
         u32(offset.x > 0.0) |
         u32(offset.y > 0.0) << 1u |
         u32(offset.z > 0.0) << 2u;
     let visible_vertex_index = vertex_index ^ mirror_mask;
 
+    // Each reflection (including one in the batch transform) reverses the winding
+    // of the template faces, which are wound counter-clockwise from outside.
+    let transform_mirrored = u32(determinant(transform.m) < 0.0);
+    out.flags = ((countOneBits(mirror_mask) + transform_mirrored) & 1u) |
+        ((cuboid.meta_bits >> 28u) & 6u);
+
+    #ifndef DATA_TEXTURES
+    if ((transform.color_layout & 1u) != 0u) {
+        // Face 0 is normal to Z, face 1 to Y, and face 2 to X. Template faces
+        // are on the minus side until mirrored.
+        let axis = 2u - ((vertex_index >> 3u) & 0x3u);
+        let face = 2u * axis + ((mirror_mask >> axis) & 0x1u);
+        let face_color = load_face_color(transform.color_layout, cuboid_index, face);
+        out.color = instance_color(face_color, cuboid.meta_bits).color;
+        if ((cuboid.meta_bits & 0x02u) != 0u) {
+            out.color *= vec4(material.emissive_gain, 1.0);
+        }
+    }
+    #endif
+    out.color.a *= spawn_alpha;
+
+    if ((cuboid.meta_bits & 0x02u) == 0u) {
+        let world_normal = face_world_normal(vertex_index, mirror_mask, rotation, transform);
+        // Interior faces are not lit.
+        out.color = vec4<f32>(out.color.rgb * face_light(world_normal), out.color.a);
+    }
+
+    #ifdef PREPASS
+    out.world_normal = face_world_normal(vertex_index, mirror_mask, rotation, transform);
+    #endif
+
     let cube_corner = vec3<f32>(
         f32(visible_vertex_index & 0x1u),
         f32((visible_vertex_index & 0x2u) >> 1u),
         f32((visible_vertex_index & 0x4u) >> 2u),
     );
-    let model_position = cube_corner * cuboid.max + (1.0 - cube_corner) * cuboid.min;
+    let center_to_corner = (cube_corner - vec3<f32>(0.5)) * (cuboid.max - cuboid.min);
+    let model_position = cuboid_center + quat_rotate(rotation, center_to_corner);
     let world_position = transform.m * vec4<f32>(model_position, 1.0);
+    #ifdef CLIPPING_CAPS
+    out.world_position = world_position.xyz / world_position.w;
+    #endif
+    #ifdef FOG
+    out.fog_world_position = world_position.xyz / world_position.w;
+    #endif
     let ndc_position = view.view_proj * world_position;
 
     out.clip_position = ndc_position;
+    #ifdef OIT
+    out.view_depth = ndc_position.w;
+    #endif
 
     // This depth biasing avoids Z-fighting when cuboids have overlapping faces.
+    // The epsilon is `DEPTH_BIAS_EPSILON`.
     let depth_bias_eps = 8e-8;
-    let depth_bias = f32(cuboid.meta_bits >> 16u) * depth_bias_eps;
-    let nudge_z = (ndc_position.z / ndc_position.w) * (1.0 - depth_bias);
+    let depth_bias = f32(((cuboid.meta_bits >> 16u) & 0x1FFFu) + material.depth_bias) * depth_bias_eps;
+    #ifdef FORWARD_Z
+    // Depth grows away from the camera, so the bias scales the depth left to
+    // the far plane, to the same effect near the camera.
+    var nudge_z = 1.0 - (1.0 - ndc_position.z / ndc_position.w) * (1.0 - depth_bias);
+    if (view.projection[3].w == 1.0) {
+        nudge_z = min(ndc_position.z + depth_bias, 1.0);
+    }
+    #else
+    var nudge_z = (ndc_position.z / ndc_position.w) * (1.0 - depth_bias);
+    if (view.projection[3].w == 1.0) {
+        // Orthographic depth is linear, and reaches zero at the far plane, so
+        // the bias is a fraction of the depth range instead.
+        nudge_z = max(ndc_position.z - depth_bias, 0.0);
+    }
+    #endif
     out.clip_position.z = nudge_z * ndc_position.w;
 
-    #ifdef OUTLINES
+    #ifndef SHADOW
+    // With bit 31, the cuboid is moved to the near plane, where it passes the
+    // depth test against everything else.
+    if ((cuboid.meta_bits & 0x80000000u) != 0u) {
+        #ifdef FORWARD_Z
+        out.clip_position.z = 0.0;
+        #else
+        out.clip_position.z = ndc_position.w;
+        #endif
+    }
+    #endif
+
+    #ifdef DEPTH_CLAMP_ORTHO
+    // Casters behind the near plane of a directional light still cast shadows.
+    out.clip_position.z = min(out.clip_position.z, 1.0);
+    #endif
 
     let centroid_to_corner = 2.0 * (cube_corner - vec3<f32>(0.5));
+    // Along the axes of the instance, for patterns that follow its rotation.
+    let local_corner = cuboid_center + center_to_corner;
     let face = (vertex_index >> 3u) & 0x3u;
     if face == 0u {
-        out.face_center_to_corner = centroid_to_corner.xy;
+        out.face_position = vec4<f32>(centroid_to_corner.xy, local_corner.xy);
     } else if face == 1u {
-        out.face_center_to_corner = centroid_to_corner.xz;
+        out.face_position = vec4<f32>(centroid_to_corner.xz, local_corner.xz);
     } else {
-        out.face_center_to_corner = centroid_to_corner.yz;
+        out.face_position = vec4<f32>(centroid_to_corner.yz, local_corner.yz);
     }
 
+    #ifndef PICKING
+    // The direction to the camera in the frame of the face, with the face
+    // coordinates as X and Y and the outward normal as Z, and the bevel width
+    // in face coordinates.
+    let bevel_widths = material.bevel_width / max(0.5 * (cuboid.max - cuboid.min), vec3<f32>(1e-6));
+    var face_to_camera: vec3<f32>;
+    var face_bevel_widths: vec2<f32>;
+    if face == 0u {
+        face_to_camera = vec3<f32>(offset.xy, abs(offset.z));
+        face_bevel_widths = bevel_widths.xy;
+    } else if face == 1u {
+        face_to_camera = vec3<f32>(offset.xz, abs(offset.y));
+        face_bevel_widths = bevel_widths.xz;
+    } else {
+        face_to_camera = vec3<f32>(offset.yz, abs(offset.x));
+        face_bevel_widths = bevel_widths.yz;
+    }
+    let to_camera_length = length(face_to_camera);
+    face_to_camera = select(vec3<f32>(0.0, 0.0, 1.0), face_to_camera / to_camera_length, to_camera_length > 0.0);
+    // Z is positive, so it is left out.
+    out.bevel = vec4<f32>(face_to_camera.xy, min(face_bevel_widths, vec2<f32>(1.0)));
+    #endif
+
+    out.user_data = load_user_data(cuboid_index);
+    #ifdef DATA_TEXTURES
+    out.atlas_tile = 0xFFFFFFFFu;
+    #else
+    out.atlas_tile = load_atlas_tile(transform.color_layout, cuboid_index);
+    #endif
+
+    #ifdef PICKING
+    out.picking_id = vec2<u32>(
+        (vertex_index >> 5u) + 1u,
+        picking_chunk.first_instance + cuboid_index,
+    );
     #endif
 
     return out;
@@ -219,43 +1129,890 @@ fn vertex(@builtin(vertex_index) vertex_index: u32, @builtin(instance_index) ins
 
 struct FragmentInput {
     @location(0) color: vec4<f32>,
+    // "normalized face coordinates" in [-1, 1]^2, and the position in the
+    // plane of the face
+    @location(1) face_position: vec4<f32>,
 
-    #ifdef OUTLINES
-    // "normalized face coordinates" in [-1, 1]^2
-    @location(1) face_center_to_fragment: vec2<f32>,
+    @location(2) @interpolate(flat) interior_color: vec4<f32>,
+    @location(3) @interpolate(flat) flags: u32,
+    @builtin(front_facing) front_facing: bool,
+    @builtin(position) frag_coord: vec4<f32>,
+    @location(4) @interpolate(flat) bevel: vec4<f32>,
+
+    #ifdef CLIPPING_CAPS
+    @location(5) world_position: vec3<f32>,
+    @location(6) @interpolate(flat) cut: u32,
+    @location(7) @interpolate(flat) box_center: vec3<f32>,
+    @location(8) @interpolate(flat) box_from_world_x: vec3<f32>,
+    @location(9) @interpolate(flat) box_from_world_y: vec3<f32>,
+    @location(10) @interpolate(flat) box_from_world_z: vec3<f32>,
+    #endif
+
+    #ifdef FOG
+    @location(11) fog_world_position: vec3<f32>,
+    #endif
+
+    #ifdef OIT
+    @location(12) view_depth: f32,
+    #endif
+
+    #ifdef PREPASS
+    @location(12) @interpolate(flat) world_normal: vec3<f32>,
     #endif
+
+    // `Cuboids::user_data` of the instance.
+    @location(13) @interpolate(flat) user_data: u32,
+    @location(14) @interpolate(flat) slotted_material: u32,
+    @location(15) @interpolate(flat) atlas_tile: u32,
 }
 
 struct FragmentOutput {
     @location(0) color: vec4<f32>,
+
+    #ifdef CLIPPING_CAPS
+    @builtin(frag_depth) depth: f32,
+    #endif
 }
 
+fn view_direction(world_position: vec3<f32>) -> vec3<f32> {
+    // Orthographic projections look along a constant direction.
+    if (view.projection[3].w == 1.0) {
+        return -view.view[2].xyz;
+    }
+    return normalize(world_position - view.world_position);
+}
+
+#ifdef CLIPPING_CAPS
+
+// Traces the view ray through the box of a cut cuboid, from the fragment where
+// it enters. Returns the distance to the first point that isn't clipped, or a
+// negative value if the whole ray through the box is clipped.
+fn distance_to_unclipped(in: FragmentInput, dir: vec3<f32>) -> f32 {
+    let to_fragment = in.world_position - in.box_center;
+    let u0 = vec3<f32>(
+        dot(in.box_from_world_x, to_fragment),
+        dot(in.box_from_world_y, to_fragment),
+        dot(in.box_from_world_z, to_fragment),
+    );
+    let du = vec3<f32>(
+        dot(in.box_from_world_x, dir),
+        dot(in.box_from_world_y, dir),
+        dot(in.box_from_world_z, dir),
+    );
+    let t_far = max((vec3<f32>(1.0) - u0) / du, (vec3<f32>(-1.0) - u0) / du);
+    let t_exit = select(vec3<f32>(1e30), t_far, du != vec3<f32>(0.0));
+
+    let t_end = min(min(t_exit.x, t_exit.y), t_exit.z);
+    var t = 0.0;
+    if (clipping_planes.combine_any != 0u) {
+        // The first point that any range keeps.
+        t = 1e30;
+        for (var i = 0u; i < clipping_planes.num_ranges; i++) {
+            t = min(t, next_kept(clipping_planes.ranges[i], in.world_position, dir, 0.0));
+        }
+    } else {
+        // Each range that clips `t` moves it to the next point it keeps. Every
+        // move passes a range boundary, so this settles on the first point that
+        // all ranges keep after at most two moves per range.
+        for (var step = 0u; step <= 2u * clipping_planes.num_ranges; step++) {
+            var moved = false;
+            for (var i = 0u; i < clipping_planes.num_ranges; i++) {
+                let next = next_kept(clipping_planes.ranges[i], in.world_position, dir, t);
+                if (next > t) {
+                    t = next;
+                    moved = true;
+                }
+            }
+            if (!moved || t > t_end) {
+                break;
+            }
+        }
+    }
+    return select(-1.0, t, t <= t_end);
+}
+
+// The distance along the ray from `origin` in direction `dir` to the first
+// point from `t` on that `range` keeps, or 1e30 if there is none.
+fn next_kept(range: ClippingPlaneRange, origin: vec3<f32>, dir: vec3<f32>, t: f32) -> f32 {
+    let s0 = dot(origin - range.origin, range.unit_normal);
+    let ds = dot(dir, range.unit_normal);
+    if (range_keeps(range, s0 + ds * t)) {
+        return t;
+    }
+    if (abs(ds) < 1e-6) {
+        return 1e30;
+    }
+    let t_min = (range.min_sdist - s0) / ds;
+    let t_max = (range.max_sdist - s0) / ds;
+    if (range.keep_outside != 0u) {
+        // Inside the range until the ray leaves it.
+        return max(t_min, t_max);
+    }
+    // Outside the range, and only kept if the ray enters it later.
+    return select(1e30, min(t_min, t_max), t < min(t_min, t_max));
+}
+#endif
+
+#ifdef FOG
+// Matches the fog of Bevy's PBR shader, without directional light inscattering.
+fn apply_fog(color: vec4<f32>, world_position: vec3<f32>) -> vec4<f32> {
+    let fog = cuboids_view.fog;
+    let distance = length(world_position - view.world_position);
+    if (fog.mode == 1u) {
+        let start = fog.falloff.x;
+        let end = fog.falloff.y;
+        let scattering = fog.color.a * (1.0 - saturate((end - distance) / (end - start)));
+        return vec4<f32>(mix(color.rgb, fog.color.rgb, scattering), color.a);
+    } else if (fog.mode == 2u) {
+        let scattering = fog.color.a * (1.0 - 1.0 / exp(distance * fog.falloff.x));
+        return vec4<f32>(mix(color.rgb, fog.color.rgb, scattering), color.a);
+    } else if (fog.mode == 3u) {
+        let density_distance = distance * fog.falloff.x;
+        let scattering = fog.color.a * (1.0 - 1.0 / exp(density_distance * density_distance));
+        return vec4<f32>(mix(color.rgb, fog.color.rgb, scattering), color.a);
+    } else if (fog.mode == 4u) {
+        let extinction = vec3<f32>(1.0) - 1.0 / exp(distance * fog.falloff);
+        let inscattering = vec3<f32>(1.0) - 1.0 / exp(distance * fog.inscattering);
+        return vec4<f32>(
+            color.rgb * (1.0 - extinction * fog.color.a) + fog.color.rgb * inscattering * fog.color.a,
+            color.a,
+        );
+    }
+    return color;
+}
+#endif
+
 // Constant-pixel-width edges:
 // https://catlikecoding.com/unity/tutorials/advanced-rendering/flat-and-wireframe-shading/
+//
+// Zero on the edges of the face, one further than two pixels from them.
+fn edge_step(face_center_to_fragment: vec2<f32>) -> f32 {
+    let dist_to_edge = vec2<f32>(1.0) - abs(face_center_to_fragment);
+    let screen_derivative = fwidth(face_center_to_fragment);
+    let step = smoothstep(vec2<f32>(0.0), 2.0 * screen_derivative, dist_to_edge);
+    return min(step.x, step.y);
+}
 
-@fragment
-fn fragment(in: FragmentInput) -> FragmentOutput {
+// Rounded-off edges: the face normal tilts towards each edge within the bevel,
+// up to 45 degrees at the edge, and is lit with a wrapped headlight. Returns
+// the ratio to the light of the flat face, so one away from the edges.
+fn bevel_factor(face_center_to_fragment: vec2<f32>, bevel: vec4<f32>) -> f32 {
+    let widths = max(bevel.zw, vec2<f32>(1e-6));
+    let into_bevel = clamp((abs(face_center_to_fragment) - (vec2<f32>(1.0) - widths)) / widths, vec2<f32>(0.0), vec2<f32>(1.0));
+    let normal = normalize(vec3<f32>(sign(face_center_to_fragment) * into_bevel, 1.0));
+    let to_camera = vec3<f32>(bevel.xy, sqrt(max(1.0 - dot(bevel.xy, bevel.xy), 0.0)));
+    return (0.5 + 0.5 * dot(normal, to_camera)) / (0.5 + 0.5 * to_camera.z);
+}
+
+// Whether the cutout pattern of the material discards the fragment at
+// `frag_coord`, at `face_position` on its face.
+fn cut_out(frag_coord: vec2<f32>, face_position: vec4<f32>) -> bool {
+    let p = select(frag_coord, face_position.zw, material.cutout_space == 1u);
+    let period = max(material.cutout_spacing, 1e-6);
+    if (material.cutout_pattern == 1u) {
+        // GRID
+        return all(fract(p / period) >= vec2<f32>(material.cutout_coverage));
+    }
+    // HATCH
+    return fract((p.x + p.y) / period) >= material.cutout_coverage;
+}
+
+// The threshold of an ordered 4x4 Bayer matrix at a pixel, in (0, 1).
+fn dither_threshold(frag_coord: vec2<f32>) -> f32 {
+    var bayer = array<u32, 16>(0u, 8u, 2u, 10u, 12u, 4u, 14u, 6u, 3u, 11u, 1u, 9u, 15u, 7u, 13u, 5u);
+    let p = vec2<u32>(frag_coord) & vec2<u32>(3u);
+    return (f32(bayer[p.y * 4u + p.x]) + 0.5) / 16.0;
+}
+
+fn shade(in: FragmentInput) -> FragmentOutput {
+    select_material(in.slotted_material);
     var out: FragmentOutput;
-    out.color = in.color;
 
-    #ifdef OUTLINES
+    // Derivatives need uniform control flow, so these come before any discard.
+    let face_center_to_fragment = in.face_position.xy;
+    let min_step = edge_step(face_center_to_fragment);
+    let face_uv = 0.5 * (face_center_to_fragment + vec2<f32>(1.0));
+    let face_uv_dx = dpdx(face_uv);
+    let face_uv_dy = dpdy(face_uv);
+    let outside = in.front_facing == ((in.flags & 1u) == 0u);
+    out.color = select(in.interior_color, in.color, outside);
 
-    let dist_to_edge = vec2<f32>(1.0) - abs(in.face_center_to_fragment);
-    let screen_derivative = fwidth(in.face_center_to_fragment);
-    let step = smoothstep(vec2<f32>(0.0), 2.0 * screen_derivative, dist_to_edge);
-    let min_step = min(step.x, step.y);
+    let atlas_grid = cuboids_view.atlas_grid;
+    if (outside && in.atlas_tile != 0xFFFFFFFFu && atlas_grid.x != 0u) {
+        let tile = vec2<u32>(in.atlas_tile % atlas_grid.x, in.atlas_tile / atlas_grid.x);
+        let tile_size = vec2<f32>(1.0) / vec2<f32>(atlas_grid);
+        let texel = textureSampleGrad(
+            atlas_texture,
+            atlas_sampler,
+            (vec2<f32>(tile) + face_uv) * tile_size,
+            face_uv_dx * tile_size,
+            face_uv_dy * tile_size,
+        );
+        out.color = vec4<f32>(out.color.rgb * mix(vec3<f32>(1.0), texel.rgb, texel.a), out.color.a);
+    }
 
-    if material.wireframe != 0u {
-        let edge_factor = mix(0.0, 1.0, min_step);
-        if edge_factor > 0.99999 {
+    #ifdef CLIPPING_CAPS
+    out.depth = in.frag_coord.z;
+    if (in.cut != 0u) {
+        let dir = view_direction(in.world_position);
+        let t = distance_to_unclipped(in, dir);
+        if (t < 0.0) {
             discard;
         }
-    } else {
+        if (t > 0.0 && material.wireframe != 0u) {
+            // Wireframes have no interior to cap.
+            discard;
+        }
+        if (t > 0.0) {
+            // Fill the cut with a cap in the instance color.
+            let cap_clip_position = view.view_proj * vec4<f32>(in.world_position + t * dir, 1.0);
+            out.depth = cap_clip_position.z / cap_clip_position.w;
+            out.color = in.color;
+            #ifdef FOG
+            out.color = apply_fog(out.color, in.world_position + t * dir);
+            #endif
+            return out;
+        }
+    }
+    #endif
+
+    // Wireframes only keep the fragments near the 12 edges of each box.
+    if material.wireframe != 0u && min_step > 0.99999 {
+        discard;
+    }
+
+    if (material.cutout_pattern != 0u && cut_out(in.frag_coord.xy, in.face_position)) {
+        discard;
+    }
+
+    if (material.dither_alpha != 0u && material.alpha_blend == 0u) {
+        if (out.color.a <= dither_threshold(in.frag_coord.xy)) {
+            discard;
+        }
+        out.color.a = 1.0;
+    }
+
+    if (outside && material.bevel_width > 0.0 && material.wireframe == 0u) {
+        out.color = vec4<f32>(out.color.rgb * bevel_factor(face_center_to_fragment, in.bevel), out.color.a);
+    }
+
+    // Per-instance outline bits override the `OUTLINES` default.
+    #ifdef OUTLINES
+    let outlined = (in.flags & 4u) == 0u;
+    #else
+    let outlined = (in.flags & 2u) != 0u;
+    #endif
+    if (outlined && material.wireframe == 0u) {
         let edge_factor = mix(0.5, 1.0, min_step);
-        out.color *= edge_factor;
+        out.color = vec4<f32>(out.color.rgb * edge_factor, out.color.a);
+    }
+
+    #ifdef FOG
+    out.color = apply_fog(out.color, in.fog_world_position);
+    #endif
+
+    return out;
+}
+
+// What a `CuboidShaderHook` can read about the fragment.
+struct CuboidHookInput {
+    // The instance color, before interior colors, edges, lighting and fog.
+    color: vec4<f32>,
+    // "normalized face coordinates" in [-1, 1]^2
+    face_center_to_fragment: vec2<f32>,
+    // `Cuboids::user_data` of the instance.
+    user_data: u32,
+    front_facing: bool,
+}
+
+#ifdef PREPASS
+struct PrepassOutput {
+    // Only consumed by views with a `NormalPrepass`.
+    @location(0) normal: vec4<f32>,
+
+    #ifdef CLIPPING_CAPS
+    @builtin(frag_depth) depth: f32,
+    #endif
+}
+
+// Writes the depth and world normal of opaque cuboids into Bevy's prepass
+// textures, discarding the same fragments as the main pass.
+@fragment
+fn prepass_fragment(in: FragmentInput) -> PrepassOutput {
+    let shaded = shade(in);
+    let outside = in.front_facing == ((in.flags & 1u) == 0u);
+
+    var out: PrepassOutput;
+    // Interior faces are seen from inside of the box.
+    let normal = select(-in.world_normal, in.world_normal, outside);
+    out.normal = vec4<f32>(normal * 0.5 + vec3<f32>(0.5), 1.0);
+    #ifdef CLIPPING_CAPS
+    out.depth = shaded.depth;
+    #endif
+    return out;
+}
+#endif
+
+@fragment
+fn fragment(in: FragmentInput) -> FragmentOutput {
+    var out = shade(in);
+    #ifdef CUBOID_SHADER_HOOK
+    // Appended to this file for each `CuboidShaderHook`.
+    out.color = cuboid_fragment_modify(
+        out.color,
+        CuboidHookInput(in.color, in.face_position.xy, in.user_data, in.front_facing),
+    );
+    #endif
+    #ifdef XRAY
+    // Only fragments hidden behind other geometry reach this pass, and are
+    // blended over it at a third of their opacity.
+    out.color = vec4<f32>(out.color.rgb, out.color.a / 3.0);
+    #endif
+    return out;
+}
+
+#ifdef CONTOUR_MASK
+// Marks the pixels covered by selection highlights, discarding the same
+// fragments as the main pass.
+@fragment
+fn fragment_contour_mask(in: FragmentInput) -> @location(0) vec4<f32> {
+    shade(in);
+    return vec4<f32>(1.0);
+}
+#endif
+
+#ifdef OIT
+struct OitOutput {
+    @location(0) accum: vec4<f32>,
+    @location(1) revealage: vec4<f32>,
+
+    #ifdef CLIPPING_CAPS
+    @builtin(frag_depth) depth: f32,
+    #endif
+}
+
+// Weighted blended order-independent transparency, with the depth weight of
+// equation 7 in https://jcgt.org/published/0002/02/09/
+@fragment
+fn fragment_oit(in: FragmentInput) -> OitOutput {
+    let shaded = shade(in);
+    let color = shaded.color;
+    let z = in.view_depth;
+    let weight = clamp(
+        color.a * 10.0 / (1e-5 + pow(z / 5.0, 2.0) + pow(z / 200.0, 6.0)),
+        1e-2,
+        3e3,
+    );
+
+    var out: OitOutput;
+    out.accum = vec4<f32>(color.rgb * color.a, color.a) * weight;
+    out.revealage = vec4<f32>(color.a);
+    #ifdef CLIPPING_CAPS
+    out.depth = shaded.depth;
+    #endif
+    return out;
+}
+#endif
+
+#ifdef PICKING
+struct PickingFragmentInput {
+    @builtin(position) frag_coord: vec4<f32>,
+    @location(1) face_position: vec4<f32>,
+
+    @location(4) @interpolate(flat) picking_id: vec2<u32>,
+    @location(14) @interpolate(flat) slotted_material: u32,
+}
+
+@fragment
+fn fragment_picking(in: PickingFragmentInput) -> @location(0) vec4<u32> {
+    select_material(in.slotted_material);
+    // Wireframes can only be picked on their edges.
+    if material.wireframe != 0u && edge_step(in.face_position.xy) > 0.99999 {
+        discard;
+    }
+    if (material.cutout_pattern != 0u && cut_out(in.frag_coord.xy, in.face_position)) {
+        discard;
+    }
+
+    return vec4<u32>(in.picking_id, 0u, 0u);
+}
+#endif
+
+// Spheres and cylinders are clipped as a whole, depending on a single point.
+fn primitive_clipped(world_position: vec3<f32>) -> bool {
+    if (cuboids_view.clipping_enabled == 0u || material.ignore_clipping != 0u) {
+        return false;
+    }
+    return clipped_by_planes(world_position) || clipped_by_volumes(world_position);
+}
+
+// Non-uniform scales are covered by the largest one.
+fn transform_max_scale(transform: Transform) -> f32 {
+    return max(
+        max(length(transform.m[0].xyz), length(transform.m[1].xyz)),
+        length(transform.m[2].xyz),
+    );
+}
+
+struct PrimitiveFragmentOutput {
+    @location(0) color: vec4<f32>,
+    @builtin(frag_depth) depth: f32,
+}
+
+// Shades the ray traced `hit` on a sphere or cylinder, seen along `dir`.
+fn shade_primitive(color: vec4<f32>, emissive: u32, hit: vec3<f32>, normal: vec3<f32>, dir: vec3<f32>) -> PrimitiveFragmentOutput {
+    var out: PrimitiveFragmentOutput;
+    let clip_position = view.view_proj * vec4<f32>(hit, 1.0);
+    out.depth = clip_position.z / clip_position.w;
+
+    out.color = color;
+    if (emissive == 0u) {
+        var light = vec3<f32>(mix(0.5, 1.0, max(dot(normal, -dir), 0.0)));
+        #ifdef LIGHTING
+        if (material.lit != 0u) {
+            light = incident_light(normal);
+        }
+        #endif
+        out.color = vec4<f32>(out.color.rgb * light, out.color.a);
     }
 
+    #ifdef FOG
+    out.color = apply_fog(out.color, hit);
+    #endif
+
+    return out;
+}
+
+struct ShadedVertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+    @location(1) world_position: vec3<f32>,
+}
+
+fn discard_shaded_vertex() -> ShadedVertexOutput {
+    var out = ShadedVertexOutput();
+    out.clip_position.x = bitcast<f32>(0x7fc00000); // nan
+    return out;
+}
+
+// Headlight or incident light on a double-sided surface at `world_position`.
+fn shade_vertex(color: vec4<f32>, world_position: vec3<f32>, world_normal: vec3<f32>) -> vec4<f32> {
+    let dir = view_direction(world_position);
+    var normal = world_normal;
+    if (dot(normal, dir) > 0.0) {
+        normal = -normal;
+    }
+    var light = vec3<f32>(mix(0.5, 1.0, max(dot(normal, -dir), 0.0)));
+    #ifdef LIGHTING
+    if (material.lit != 0u) {
+        light = incident_light(normal);
+    }
+    #endif
+    return vec4<f32>(color.rgb * light, color.a);
+}
+
+@fragment
+fn shaded_vertex_fragment(in: ShadedVertexOutput) -> @location(0) vec4<f32> {
+    var color = in.color;
+    #ifdef FOG
+    color = apply_fog(color, in.world_position);
     #endif
+    return color;
+}
+
+#ifdef SPHERES
+struct Sphere {
+    center: vec3<f32>,
+    radius: f32,
+    meta_bits: u32,
+    color: u32,
+}
+
+struct Spheres {
+    data: array<Sphere>,
+}
+
+@group(3) @binding(0)
+var<storage> spheres: Spheres;
+
+struct SphereVertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+    @location(1) world_position: vec3<f32>,
+    @location(2) @interpolate(flat) center: vec3<f32>,
+    @location(3) @interpolate(flat) radius: f32,
+    // Nonzero for emissive spheres, which are not shaded.
+    @location(4) @interpolate(flat) emissive: u32,
+}
+
+fn discard_sphere_vertex() -> SphereVertexOutput {
+    var out = SphereVertexOutput();
+    out.clip_position.x = bitcast<f32>(0x7fc00000); // nan
+    return out;
+}
 
+// Each sphere is a quad facing the camera that covers its silhouette, drawn
+// as a 4 vertex triangle strip. The sphere itself is ray traced per fragment.
+@vertex
+fn sphere_vertex(@builtin(vertex_index) vertex_index: u32, @builtin(instance_index) instance_index: u32) -> SphereVertexOutput {
+    select_material(0u);
+    var out: SphereVertexOutput;
+
+    let transform = load_transform(vertex_index / 4u);
+    let sphere = spheres.data[instance_index];
+
+    if ((sphere.meta_bits & 0x01u) != 0u ||
+        ((sphere.meta_bits >> 2u) & 0x3u) > cuboids_view.lod_level)
+    {
+        return discard_sphere_vertex();
+    }
+
+    let color = instance_color(sphere.color, sphere.meta_bits);
+    if (!color.visible) {
+        return discard_sphere_vertex();
+    }
+    out.color = color.color;
+    if ((sphere.meta_bits & 0x02u) != 0u) {
+        out.color *= vec4(material.emissive_gain, 1.0);
+        out.emissive = 1u;
+    }
+
+    let center_v4 = transform.m * vec4<f32>(sphere.center, 1.0);
+    let center = center_v4.xyz / center_v4.w;
+    let radius = sphere.radius * transform_max_scale(transform);
+
+    if (primitive_clipped(center)) {
+        return discard_sphere_vertex();
+    }
+
+    var right = view.view[0].xyz;
+    var up = view.view[1].xyz;
+    var half_size = radius;
+    if (view.projection[3].w != 1.0) {
+        // Perspective views see less than a hemisphere, through a cone that
+        // cuts the plane across the center in a larger circle.
+        let to_center = center - view.world_position;
+        let distance = length(to_center);
+        if (distance <= radius) {
+            // The camera is inside of the sphere.
+            return discard_sphere_vertex();
+        }
+        let forward = to_center / distance;
+        right = normalize(cross(forward, view.view[1].xyz));
+        up = cross(right, forward);
+        half_size = radius * distance / sqrt(distance * distance - radius * radius);
+    }
+
+    let corner = vec2<f32>(f32(vertex_index & 0x1u), f32((vertex_index >> 1u) & 0x1u)) * 2.0 - 1.0;
+    out.world_position = center + half_size * (corner.x * right + corner.y * up);
+    out.clip_position = view.view_proj * vec4<f32>(out.world_position, 1.0);
+    out.center = center;
+    out.radius = radius;
+    return out;
+}
+
+@fragment
+fn sphere_fragment(in: SphereVertexOutput) -> PrimitiveFragmentOutput {
+    select_material(0u);
+    let dir = view_direction(in.world_position);
+    // Orthographic rays start on the quad, which may be inside of the sphere.
+    let to_origin = in.world_position - in.center;
+    let b = dot(to_origin, dir);
+    let c = dot(to_origin, to_origin) - in.radius * in.radius;
+    let h = b * b - c;
+    if (h < 0.0) {
+        discard;
+    }
+    let hit = in.world_position + (-b - sqrt(h)) * dir;
+    let normal = (hit - in.center) / in.radius;
+    return shade_primitive(in.color, in.emissive, hit, normal, dir);
+}
+#endif
+
+#ifdef CYLINDERS
+struct Cylinder {
+    start: vec3<f32>,
+    radius: f32,
+    end: vec3<f32>,
+    color: u32,
+    meta_bits: u32,
+}
+
+struct Cylinders {
+    data: array<Cylinder>,
+}
+
+@group(3) @binding(0)
+var<storage> cylinders: Cylinders;
+
+struct CylinderVertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+    @location(1) world_position: vec3<f32>,
+    @location(2) @interpolate(flat) start: vec3<f32>,
+    @location(3) @interpolate(flat) end: vec3<f32>,
+    @location(4) @interpolate(flat) radius: f32,
+    // Nonzero for emissive cylinders, which are not shaded.
+    @location(5) @interpolate(flat) emissive: u32,
+}
+
+fn discard_cylinder_vertex() -> CylinderVertexOutput {
+    var out = CylinderVertexOutput();
+    out.clip_position.x = bitcast<f32>(0x7fc00000); // nan
+    return out;
+}
+
+// Each cylinder is bounded by a box around its axis, of which only the 3
+// faces towards the camera are drawn, as 18 vertices of a triangle list. The
+// cylinder itself is ray traced per fragment.
+@vertex
+fn cylinder_vertex(@builtin(vertex_index) vertex_index: u32, @builtin(instance_index) instance_index: u32) -> CylinderVertexOutput {
+    select_material(0u);
+    var out: CylinderVertexOutput;
+
+    let transform = load_transform(vertex_index / 18u);
+    let cylinder = cylinders.data[instance_index];
+
+    if ((cylinder.meta_bits & 0x01u) != 0u ||
+        ((cylinder.meta_bits >> 2u) & 0x3u) > cuboids_view.lod_level)
+    {
+        return discard_cylinder_vertex();
+    }
+
+    let color = instance_color(cylinder.color, cylinder.meta_bits);
+    if (!color.visible) {
+        return discard_cylinder_vertex();
+    }
+    out.color = color.color;
+    if ((cylinder.meta_bits & 0x02u) != 0u) {
+        out.color *= vec4(material.emissive_gain, 1.0);
+        out.emissive = 1u;
+    }
+
+    let start_v4 = transform.m * vec4<f32>(cylinder.start, 1.0);
+    let end_v4 = transform.m * vec4<f32>(cylinder.end, 1.0);
+    let start = start_v4.xyz / start_v4.w;
+    let end = end_v4.xyz / end_v4.w;
+    let radius = cylinder.radius * transform_max_scale(transform);
+    let center = 0.5 * (start + end);
+
+    if (primitive_clipped(center)) {
+        return discard_cylinder_vertex();
+    }
+
+    let segment = end - start;
+    let half_length = 0.5 * length(segment);
+    if (half_length == 0.0) {
+        return discard_cylinder_vertex();
+    }
+    // An orthonormal frame with w along the axis.
+    let w = segment / (2.0 * half_length);
+    let helper = select(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 1.0, 0.0), abs(w.x) > 0.9);
+    let u = normalize(cross(w, helper));
+    let v = cross(w, u);
+    let half_extents = vec3<f32>(radius, radius, half_length);
+
+    var to_camera = view.view[2].xyz;
+    if (view.projection[3].w != 1.0) {
+        to_camera = view.world_position - center;
+    }
+    let camera_local = vec3<f32>(dot(to_camera, u), dot(to_camera, v), dot(to_camera, w));
+    if (view.projection[3].w != 1.0 && all(abs(camera_local) <= half_extents)) {
+        // The camera is inside of the box.
+        return discard_cylinder_vertex();
+    }
+    let facing = select(vec3<f32>(-1.0), vec3<f32>(1.0), camera_local > vec3<f32>(0.0));
+
+    // Two triangles for each axis of the box, on the face towards the camera.
+    let local_index = vertex_index % 18u;
+    let axis = local_index / 6u;
+    let tri_vertex = local_index % 6u;
+    var corner = vec3<f32>(0.0);
+    corner[axis] = facing[axis];
+    corner[(axis + 1u) % 3u] = f32((0x16u >> tri_vertex) & 0x1u) * 2.0 - 1.0;
+    corner[(axis + 2u) % 3u] = f32((0x34u >> tri_vertex) & 0x1u) * 2.0 - 1.0;
+    let local = corner * half_extents;
+
+    out.world_position = center + local.x * u + local.y * v + local.z * w;
+    out.clip_position = view.view_proj * vec4<f32>(out.world_position, 1.0);
+    out.start = start;
+    out.end = end;
+    out.radius = radius;
+    return out;
+}
+
+@fragment
+fn cylinder_fragment(in: CylinderVertexOutput) -> PrimitiveFragmentOutput {
+    select_material(0u);
+    let dir = view_direction(in.world_position);
+    // Ray versus capped cylinder, from https://iquilezles.org/articles/intersectors/.
+    // Orthographic rays start on the box, which may cut through the cylinder.
+    let ba = in.end - in.start;
+    let oc = in.world_position - in.start;
+    let baba = dot(ba, ba);
+    let bard = dot(ba, dir);
+    let baoc = dot(ba, oc);
+    let k2 = baba - bard * bard;
+    let k1 = baba * dot(oc, dir) - baoc * bard;
+    let k0 = baba * dot(oc, oc) - baoc * baoc - in.radius * in.radius * baba;
+    var h = k1 * k1 - k2 * k0;
+    if (h < 0.0) {
+        discard;
+    }
+    h = sqrt(h);
+
+    // The body.
+    var t = (-k1 - h) / k2;
+    let y = baoc + t * bard;
+    var normal: vec3<f32>;
+    if (y > 0.0 && y < baba) {
+        normal = (oc + t * dir - ba * y / baba) / in.radius;
+    } else {
+        // The caps.
+        t = (select(baba, 0.0, y < 0.0) - baoc) / bard;
+        if (abs(k1 + k2 * t) >= h) {
+            discard;
+        }
+        normal = ba * sign(y) / sqrt(baba);
+    }
+
+    let hit = in.world_position + t * dir;
+    return shade_primitive(in.color, in.emissive, hit, normal, dir);
+}
+#endif
+
+#ifdef MESH_INSTANCES
+struct TemplateVertex {
+    position: vec3<f32>,
+    normal: vec3<f32>,
+}
+
+struct TemplateVertices {
+    data: array<TemplateVertex>,
+}
+
+@group(3) @binding(0)
+var<storage> mesh_instances: Cuboids;
+
+@group(3) @binding(1)
+var<storage> template_vertices: TemplateVertices;
+
+// The template is stretched from [-1, 1] on every axis onto the box of the
+// instance, and shaded per vertex.
+@vertex
+fn mesh_instance_vertex(@builtin(vertex_index) vertex_index: u32, @builtin(instance_index) instance_index: u32) -> ShadedVertexOutput {
+    select_material(0u);
+    var out: ShadedVertexOutput;
+
+    let num_template_vertices = arrayLength(&template_vertices.data);
+    let transform = load_transform(vertex_index / num_template_vertices);
+    let template_vertex = template_vertices.data[vertex_index % num_template_vertices];
+    let instance = mesh_instances.data[instance_index];
+
+    if ((instance.meta_bits & 0x01u) != 0u ||
+        ((instance.meta_bits >> 2u) & 0x3u) > cuboids_view.lod_level)
+    {
+        return discard_shaded_vertex();
+    }
+
+    let color = instance_color(instance.color, instance.meta_bits);
+    if (!color.visible) {
+        return discard_shaded_vertex();
+    }
+    out.color = color.color;
+
+    let center = 0.5 * (instance.min + instance.max);
+    let half_extents = 0.5 * (instance.max - instance.min);
+    let center_v4 = transform.m * vec4<f32>(center, 1.0);
+    if (primitive_clipped(center_v4.xyz / center_v4.w)) {
+        return discard_shaded_vertex();
+    }
+
+    let local_position = center + half_extents * template_vertex.position;
+    let world_position_v4 = transform.m * vec4<f32>(local_position, 1.0);
+    out.world_position = world_position_v4.xyz / world_position_v4.w;
+    out.clip_position = view.view_proj * vec4<f32>(out.world_position, 1.0);
+
+    if ((instance.meta_bits & 0x02u) != 0u) {
+        out.color *= vec4(material.emissive_gain, 1.0);
+        return out;
+    }
+
+    // Normals scale inversely to the stretch, like any other transform.
+    let local_normal = template_vertex.normal / max(half_extents, vec3<f32>(1e-6));
+    let world_normal = normalize((vec4<f32>(local_normal, 0.0) * transform.m_inv).xyz);
+    out.color = shade_vertex(out.color, out.world_position, world_normal);
+    return out;
+}
+#endif
+
+#ifdef CUBOIDS_ANIMATION
+struct AnimatedCuboid {
+    from: Cuboid,
+    to: Cuboid,
+}
+
+struct AnimatedCuboids {
+    data: array<AnimatedCuboid>,
+}
+
+@group(3) @binding(0)
+var<storage> animated_cuboids: AnimatedCuboids;
+
+// Each cuboid is drawn as 36 vertices, 2 triangles for each face, between the
+// bounds and colors of both snapshots.
+@vertex
+fn animated_cuboid_vertex(@builtin(vertex_index) vertex_index: u32, @builtin(instance_index) instance_index: u32) -> ShadedVertexOutput {
+    select_material(0u);
+    var out: ShadedVertexOutput;
+
+    let transform = load_transform(vertex_index / 36u);
+    let instance = animated_cuboids.data[instance_index];
+    let t = transform.animation_t;
+    let meta_bits = instance.from.meta_bits;
+
+    if ((meta_bits & 0x01u) != 0u ||
+        ((meta_bits >> 2u) & 0x3u) > cuboids_view.lod_level)
+    {
+        return discard_shaded_vertex();
+    }
+
+    let color = mixed_color(instance.from.color, instance.to.color, t);
+    if (!color.visible) {
+        return discard_shaded_vertex();
+    }
+    out.color = color.color;
+
+    let box_min = mix(instance.from.min, instance.to.min, t);
+    let box_max = mix(instance.from.max, instance.to.max, t);
+    let center = 0.5 * (box_min + box_max);
+    let center_v4 = transform.m * vec4<f32>(center, 1.0);
+    if (primitive_clipped(center_v4.xyz / center_v4.w)) {
+        return discard_shaded_vertex();
+    }
+
+    // Faces on the minus and plus side of each axis in turn.
+    let local_index = vertex_index % 36u;
+    let face = local_index / 6u;
+    let axis = face / 2u;
+    let tri_vertex = local_index % 6u;
+    var normal = vec3<f32>(0.0);
+    normal[axis] = select(-1.0, 1.0, (face & 0x1u) != 0u);
+    var corner = normal;
+    corner[(axis + 1u) % 3u] = f32((0x16u >> tri_vertex) & 0x1u) * 2.0 - 1.0;
+    corner[(axis + 2u) % 3u] = f32((0x34u >> tri_vertex) & 0x1u) * 2.0 - 1.0;
+
+    let local_position = center + 0.5 * (box_max - box_min) * corner;
+    let world_position_v4 = transform.m * vec4<f32>(local_position, 1.0);
+    out.world_position = world_position_v4.xyz / world_position_v4.w;
+    out.clip_position = view.view_proj * vec4<f32>(out.world_position, 1.0);
+
+    if ((meta_bits & 0x02u) != 0u) {
+        out.color *= vec4(material.emissive_gain, 1.0);
+        return out;
+    }
+    let world_normal = normalize((vec4<f32>(normal, 0.0) * transform.m_inv).xyz);
+    out.color = shade_vertex(out.color, out.world_position, world_normal);
     return out;
 }
+#endif